@@ -0,0 +1,163 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, StitchError};
+use crate::tile_configuration::relative_path;
+
+/**
+ * Write one subgraph as a BigStitcher/BigDataViewer `dataset.xml`. Every tile becomes a view setup
+ * loaded from its original file. Like BigStitcher's own datasets, its registration is a
+ * calibration transform scaling voxels relative to the smallest voxel side, followed by the
+ * registered position as a translation in calibrated pixels.
+ */
+pub fn write_bigstitcher_xml(
+    path: &Path,
+    tile_paths: &[PathBuf],
+    tile_sizes: &[(usize, usize, usize)],
+    subgraph: &[usize],
+    offsets: &[(f32, f32, f32)],
+    voxel_size: Option<((f32, f32, f32), &str)>,
+) -> Result<()> {
+    let (voxel_unit, voxel_size) = match voxel_size {
+        Some((size, unit)) => (unit, size),
+        None => ("pixel", (1.0, 1.0, 1.0)),
+    };
+    let smallest = voxel_size.0.min(voxel_size.1).min(voxel_size.2);
+    let calibration = (voxel_size.0 / smallest, voxel_size.1 / smallest, voxel_size.2 / smallest);
+    let voxel_size = format!("{} {} {}", voxel_size.0, voxel_size.1, voxel_size.2);
+
+    let base_path = path.parent().unwrap_or(Path::new(""));
+
+    let mut files = String::new();
+    let mut setups = String::new();
+    let mut tiles = String::new();
+    let mut registrations = String::new();
+    for (setup, (&tile, offset)) in subgraph.iter().zip(offsets).enumerate() {
+        let file = relative_path(&tile_paths[tile], base_path)?;
+        let name = tile_paths[tile]
+            .file_name()
+            .map_or_else(|| tile.to_string(), |name| name.to_string_lossy().into_owned());
+        let (width, height, depth) = tile_sizes[tile];
+
+        files.push_str(&format!(
+            "        <FileMapping view_setup=\"{}\" timepoint=\"0\" series=\"0\" channel=\"0\">\n\
+             \x20         <file type=\"relative\">{}</file>\n\
+             \x20       </FileMapping>\n",
+            setup,
+            escape_xml(&file.to_string_lossy())
+        ));
+
+        setups.push_str(&format!(
+            "      <ViewSetup>\n\
+             \x20       <id>{setup}</id>\n\
+             \x20       <name>{name}</name>\n\
+             \x20       <size>{width} {height} {depth}</size>\n\
+             \x20       <voxelSize>\n\
+             \x20         <unit>{voxel_unit}</unit>\n\
+             \x20         <size>{voxel_size}</size>\n\
+             \x20       </voxelSize>\n\
+             \x20       <attributes>\n\
+             \x20         <illumination>0</illumination>\n\
+             \x20         <channel>0</channel>\n\
+             \x20         <tile>{setup}</tile>\n\
+             \x20         <angle>0</angle>\n\
+             \x20       </attributes>\n\
+             \x20     </ViewSetup>\n",
+            setup = setup,
+            name = escape_xml(&name),
+            width = width,
+            height = height,
+            depth = depth,
+            voxel_unit = escape_xml(voxel_unit),
+            voxel_size = voxel_size
+        ));
+
+        tiles.push_str(&format!(
+            "        <Tile>\n\
+             \x20         <id>{}</id>\n\
+             \x20         <name>{}</name>\n\
+             \x20       </Tile>\n",
+            setup,
+            escape_xml(&name)
+        ));
+
+        // Transforms are listed last applied first
+        registrations.push_str(&format!(
+            "    <ViewRegistration timepoint=\"0\" setup=\"{}\">\n\
+             \x20     <ViewTransform type=\"affine\">\n\
+             \x20       <Name>Stitching Transform</Name>\n\
+             \x20       <affine>1.0 0.0 0.0 {} 0.0 1.0 0.0 {} 0.0 0.0 1.0 {}</affine>\n\
+             \x20     </ViewTransform>\n\
+             \x20     <ViewTransform type=\"affine\">\n\
+             \x20       <Name>calibration</Name>\n\
+             \x20       <affine>{} 0.0 0.0 0.0 0.0 {} 0.0 0.0 0.0 0.0 {} 0.0</affine>\n\
+             \x20     </ViewTransform>\n\
+             \x20   </ViewRegistration>\n",
+            setup,
+            offset.0 * calibration.0,
+            offset.1 * calibration.1,
+            offset.2 * calibration.2,
+            calibration.0,
+            calibration.1,
+            calibration.2
+        ));
+    }
+
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <SpimData version=\"0.2\">\n\
+         \x20 <BasePath type=\"relative\">.</BasePath>\n\
+         \x20 <SequenceDescription>\n\
+         \x20   <ImageLoader format=\"spimreconstruction.filelist\">\n\
+         \x20     <imglib2container>ArrayImgFactory</imglib2container>\n\
+         \x20     <ZGrouped>false</ZGrouped>\n\
+         \x20     <files>\n\
+         {files}\
+         \x20     </files>\n\
+         \x20   </ImageLoader>\n\
+         \x20   <ViewSetups>\n\
+         {setups}\
+         \x20     <Attributes name=\"illumination\">\n\
+         \x20       <Illumination>\n\
+         \x20         <id>0</id>\n\
+         \x20         <name>0</name>\n\
+         \x20       </Illumination>\n\
+         \x20     </Attributes>\n\
+         \x20     <Attributes name=\"channel\">\n\
+         \x20       <Channel>\n\
+         \x20         <id>0</id>\n\
+         \x20         <name>0</name>\n\
+         \x20       </Channel>\n\
+         \x20     </Attributes>\n\
+         \x20     <Attributes name=\"tile\">\n\
+         {tiles}\
+         \x20     </Attributes>\n\
+         \x20     <Attributes name=\"angle\">\n\
+         \x20       <Angle>\n\
+         \x20         <id>0</id>\n\
+         \x20         <name>0</name>\n\
+         \x20       </Angle>\n\
+         \x20     </Attributes>\n\
+         \x20   </ViewSetups>\n\
+         \x20   <Timepoints type=\"pattern\">\n\
+         \x20     <integerpattern>0</integerpattern>\n\
+         \x20   </Timepoints>\n\
+         \x20 </SequenceDescription>\n\
+         \x20 <ViewRegistrations>\n\
+         {registrations}\
+         \x20 </ViewRegistrations>\n\
+         </SpimData>\n",
+        files = files,
+        setups = setups,
+        tiles = tiles,
+        registrations = registrations
+    );
+
+    std::fs::write(path, xml).map_err(|e| StitchError::io(path, e))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
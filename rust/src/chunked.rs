@@ -0,0 +1,789 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, StitchError};
+use crate::image::unit_to_meters;
+use crate::intensity::{OutputDtype, PixelConversion};
+
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChunkedFormat {
+    /// OME-Zarr 0.4 in a Zarr v2 directory store.
+    Zarr,
+    N5,
+}
+
+impl ChunkedFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ChunkedFormat::Zarr => "zarr",
+            ChunkedFormat::N5 => "n5",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+/**
+ * `chunked_output` section of the config: write fused images as a chunked multiscale store
+ * instead of TIFF or PNG
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkedOutputSection {
+    pub format: ChunkedFormat,
+    /// Chunk size along x, y and z, or one size for every axis.
+    pub chunk_size: Option<Vec<usize>>,
+    pub compression: Option<Compression>,
+    pub compression_level: Option<u32>,
+    /// Number of resolution levels including the full resolution one.
+    pub levels: Option<usize>,
+}
+
+/**
+ * Validated `chunked_output` settings
+ */
+#[derive(Clone, Debug)]
+pub struct ChunkedOutput {
+    pub format: ChunkedFormat,
+    pub chunk_size: (usize, usize, usize),
+    pub compression: Compression,
+    pub compression_level: u32,
+    /// Number of resolution levels, or `None` to downsample until a level fits in one xy chunk.
+    pub levels: Option<usize>,
+}
+
+impl ChunkedOutputSection {
+    pub fn build(&self, is_3d: bool) -> Result<ChunkedOutput> {
+        let default_chunk = if is_3d { 64 } else { 256 };
+        let chunk_size = match self.chunk_size.as_deref() {
+            None => (default_chunk, default_chunk, if is_3d { default_chunk } else { 1 }),
+            Some(&[size]) => (size, size, if is_3d { size } else { 1 }),
+            Some(&[x, y]) => (x, y, if is_3d { default_chunk } else { 1 }),
+            Some(&[x, y, z]) => (x, y, z),
+            Some(sizes) => {
+                return Err(StitchError::config(
+                    "chunked_output.chunk_size",
+                    format!("Expected 1, 2 or 3 values but got {}", sizes.len()),
+                ))
+            }
+        };
+        if chunk_size.0 == 0 || chunk_size.1 == 0 || chunk_size.2 == 0 {
+            return Err(StitchError::config("chunked_output.chunk_size", "Chunk sizes must be positive"));
+        }
+
+        let compression_level = self.compression_level.unwrap_or(5);
+        if compression_level > 9 {
+            return Err(StitchError::config(
+                "chunked_output.compression_level",
+                "Compression level must be between 0 and 9",
+            ));
+        }
+        if self.levels == Some(0) {
+            return Err(StitchError::config("chunked_output.levels", "At least one level is required"));
+        }
+
+        Ok(ChunkedOutput {
+            format: self.format,
+            chunk_size,
+            compression: self.compression.unwrap_or(Compression::Gzip),
+            compression_level,
+            levels: self.levels,
+        })
+    }
+}
+
+/**
+ * Size and cumulative downsampling factors of one resolution level
+ */
+#[derive(Clone, Copy, Debug)]
+struct Level {
+    width: usize,
+    height: usize,
+    depth: usize,
+    factors: (usize, usize, usize),
+}
+
+/**
+ * Writes a fused image into a Zarr or N5 store. Full resolution slices are appended per channel
+ * and written a row of chunks at a time, the lower resolutions are built from the written level
+ * by [`ChunkedWriter::finish`], so neither needs the whole image in memory.
+ */
+pub struct ChunkedWriter {
+    root: PathBuf,
+    output: ChunkedOutput,
+    channels: usize,
+    /// Conversion of every channel to the stored pixel type.
+    conversions: Vec<PixelConversion>,
+    dtype: OutputDtype,
+    is_3d: bool,
+    levels: Vec<Level>,
+    /// Slices appended but not written yet, per channel.
+    pending: Vec<Vec<f32>>,
+    /// Slices written so far, per channel.
+    written: Vec<usize>,
+}
+
+impl ChunkedWriter {
+    /**
+     * Create the store and its metadata, with one channel per entry of `conversions`. `size` is
+     * width, height and depth, with no depth for 2D images, which are stored without a z axis.
+     * Physical sizes are only recorded when `voxel_size` is given.
+     */
+    pub fn create(
+        root: &Path,
+        output: &ChunkedOutput,
+        conversions: &[PixelConversion],
+        size: (usize, usize, Option<usize>),
+        voxel_size: Option<(f32, f32, f32)>,
+        unit: &str,
+    ) -> Result<ChunkedWriter> {
+        let (channels, dtype) = (conversions.len(), conversions[0].dtype);
+        let is_3d = size.2.is_some();
+        let mut output = output.clone();
+        if !is_3d {
+            output.chunk_size.2 = 1;
+        }
+
+        let mut levels = vec![Level {
+            width: size.0,
+            height: size.1,
+            depth: size.2.unwrap_or(1),
+            factors: (1, 1, 1),
+        }];
+        loop {
+            let last = levels[levels.len() - 1];
+            let done = match output.levels {
+                Some(count) => levels.len() >= count,
+                None => last.width <= output.chunk_size.0 && last.height <= output.chunk_size.1,
+            };
+            if done || (last.width == 1 && last.height == 1 && last.depth == 1) {
+                break;
+            }
+            let halve = |size: usize, factor: usize| if size > 1 { (size.div_ceil(2), factor * 2) } else { (size, factor) };
+            let (width, fx) = halve(last.width, last.factors.0);
+            let (height, fy) = halve(last.height, last.factors.1);
+            let (depth, fz) = halve(last.depth, last.factors.2);
+            levels.push(Level {
+                width,
+                height,
+                depth,
+                factors: (fx, fy, fz),
+            });
+        }
+
+        // Only clear a previous store, never an unrelated directory
+        if root.join(".zgroup").exists() || root.join("attributes.json").exists() {
+            std::fs::remove_dir_all(root).map_err(|e| StitchError::io(root, e))?;
+        }
+
+        let writer = ChunkedWriter {
+            root: root.to_path_buf(),
+            output,
+            channels,
+            conversions: conversions.to_vec(),
+            dtype,
+            is_3d,
+            levels,
+            pending: vec![vec![]; channels],
+            written: vec![0; channels],
+        };
+        for level in 0..writer.levels.len() {
+            let path = writer.dataset_path(level);
+            std::fs::create_dir_all(&path).map_err(|e| StitchError::io(&path, e))?;
+        }
+        writer.write_metadata(voxel_size, unit)?;
+
+        Ok(writer)
+    }
+
+    /**
+     * Append full resolution z-slices of one channel
+     */
+    pub fn write_slices(&mut self, channel: usize, data: &[f32]) -> Result<()> {
+        let level = self.levels[0];
+        let slice = level.width * level.height;
+        let appended = self.written[channel] + (self.pending[channel].len() + data.len()) / slice;
+        if !data.len().is_multiple_of(slice) || appended > level.depth {
+            return Err(StitchError::format(&self.root, "Slices do not fit the image size"));
+        }
+
+        let scaled = self.conversions[channel].scale_all(data);
+        self.pending[channel].extend(scaled);
+        let row = slice * self.output.chunk_size.2;
+        while self.pending[channel].len() >= row {
+            let rest = self.pending[channel].split_off(row);
+            let slices = std::mem::replace(&mut self.pending[channel], rest);
+            self.write_chunk_row(0, channel, self.written[channel], &slices)?;
+            self.written[channel] += self.output.chunk_size.2;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Write the remaining slices and build the downsampled levels. Each row of downsampled chunks
+     * reads at most twice its depth of slices of the level above, which fits in the memory of a
+     * slab when the chunk depth does not exceed the slab depth.
+     */
+    pub fn finish(mut self) -> Result<()> {
+        let full = self.levels[0];
+        for channel in 0..self.channels {
+            let slices = std::mem::take(&mut self.pending[channel]);
+            if !slices.is_empty() {
+                self.write_chunk_row(0, channel, self.written[channel], &slices)?;
+                self.written[channel] += slices.len() / (full.width * full.height);
+            }
+            if self.written[channel] != full.depth {
+                return Err(StitchError::format(
+                    &self.root,
+                    format!("Channel {} has {} of {} slices", channel, self.written[channel], full.depth),
+                ));
+            }
+        }
+
+        for level in 1..self.levels.len() {
+            let (source, target) = (self.levels[level - 1], self.levels[level]);
+            let z_factor = source.depth.div_ceil(target.depth);
+            for channel in 0..self.channels {
+                for z_start in (0..target.depth).step_by(self.output.chunk_size.2) {
+                    let z_end = (z_start + self.output.chunk_size.2).min(target.depth);
+                    let slices = self.read_slices(level - 1, channel, z_start * z_factor, (z_end * z_factor).min(source.depth))?;
+                    let mut downsampled = downsample(&slices, source, target, z_end - z_start);
+                    if self.dtype.max_value().is_some() {
+                        downsampled.iter_mut().for_each(|value| *value = value.round());
+                    }
+                    self.write_chunk_row(level, channel, z_start, &downsampled)?;
+                }
+            }
+            println!("Level {} ({} x {} x {}) written", level, target.width, target.height, target.depth);
+        }
+
+        println!("Chunked image saved to: {:?}", self.root);
+        Ok(())
+    }
+
+    fn dataset_path(&self, level: usize) -> PathBuf {
+        match self.output.format {
+            ChunkedFormat::Zarr => self.root.join(level.to_string()),
+            ChunkedFormat::N5 => self.root.join(format!("s{}", level)),
+        }
+    }
+
+    /**
+     * Path of a chunk, following the axis order of the format: `c/z/y/x` for Zarr and `x/y/z/c`
+     * for N5, leaving out the axes the image does not have
+     */
+    fn chunk_path(&self, level: usize, channel: usize, chunk: (usize, usize, usize)) -> PathBuf {
+        let mut indices = vec![];
+        if self.channels > 1 {
+            indices.push(channel);
+        }
+        if self.is_3d {
+            indices.push(chunk.2);
+        }
+        indices.extend([chunk.1, chunk.0]);
+        if self.output.format == ChunkedFormat::N5 {
+            indices.reverse();
+        }
+
+        let mut path = self.dataset_path(level);
+        for index in indices {
+            path.push(index.to_string());
+        }
+        path
+    }
+
+    /**
+     * Write every chunk of the slices starting at `z_start`, which is a multiple of the chunk depth
+     */
+    fn write_chunk_row(&self, level: usize, channel: usize, z_start: usize, slices: &[f32]) -> Result<()> {
+        let Level { width, height, .. } = self.levels[level];
+        let (chunk_x, chunk_y, chunk_z) = self.output.chunk_size;
+        let depth = slices.len() / (width * height);
+        let chunks = (0..height.div_ceil(chunk_y))
+            .flat_map(|y| (0..width.div_ceil(chunk_x)).map(move |x| (x, y)))
+            .collect::<Vec<_>>();
+
+        chunks.into_par_iter().try_for_each(|(x, y)| {
+            let (x_start, y_start) = (x * chunk_x, y * chunk_y);
+            let block = (chunk_x.min(width - x_start), chunk_y.min(height - y_start), depth);
+            let mut data = Vec::with_capacity(block.0 * block.1 * block.2);
+            for z in 0..block.2 {
+                for y in y_start..y_start + block.1 {
+                    let row = (z * height + y) * width + x_start;
+                    data.extend_from_slice(&slices[row..row + block.0]);
+                }
+            }
+
+            let path = self.chunk_path(level, channel, (x, y, z_start / chunk_z));
+            self.write_chunk(&path, &data, block)
+        })
+    }
+
+    fn write_chunk(&self, path: &Path, data: &[f32], block: (usize, usize, usize)) -> Result<()> {
+        let (chunk_x, chunk_y, chunk_z) = self.output.chunk_size;
+        let mut bytes = vec![];
+        match self.output.format {
+            // Zarr chunks always have the full chunk shape, padded with the fill value
+            ChunkedFormat::Zarr => {
+                let mut padded = vec![0.0f32; chunk_x * chunk_y * chunk_z];
+                for z in 0..block.2 {
+                    for y in 0..block.1 {
+                        let source = (z * block.1 + y) * block.0;
+                        let target = (z * chunk_y + y) * chunk_x;
+                        padded[target..target + block.0].copy_from_slice(&data[source..source + block.0]);
+                    }
+                }
+                bytes = self.encode(&padded, false);
+                bytes = self.compress(path, bytes)?;
+            }
+            // N5 blocks store their actual size in an uncompressed header
+            ChunkedFormat::N5 => {
+                let mut dims = vec![block.0, block.1];
+                if self.is_3d {
+                    dims.push(block.2);
+                }
+                if self.channels > 1 {
+                    dims.push(1);
+                }
+                bytes.extend(0u16.to_be_bytes());
+                bytes.extend((dims.len() as u16).to_be_bytes());
+                for dim in dims {
+                    bytes.extend((dim as u32).to_be_bytes());
+                }
+                let values = self.encode(data, true);
+                bytes.extend(self.compress(path, values)?);
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| StitchError::io(parent, e))?;
+        }
+        std::fs::write(path, bytes).map_err(|e| StitchError::io(path, e))
+    }
+
+    /**
+     * Read slices `z_start..z_end` of a written level back from its chunks
+     */
+    fn read_slices(&self, level: usize, channel: usize, z_start: usize, z_end: usize) -> Result<Vec<f32>> {
+        let Level { width, height, .. } = self.levels[level];
+        let (chunk_x, chunk_y, chunk_z) = self.output.chunk_size;
+        let mut slices = vec![0.0f32; (z_end - z_start) * width * height];
+
+        for chunk_row in z_start / chunk_z..z_end.div_ceil(chunk_z) {
+            let row_start = chunk_row * chunk_z;
+            let depth = chunk_z.min(self.levels[level].depth - row_start);
+            for y in 0..height.div_ceil(chunk_y) {
+                for x in 0..width.div_ceil(chunk_x) {
+                    let (x_start, y_start) = (x * chunk_x, y * chunk_y);
+                    let block = (chunk_x.min(width - x_start), chunk_y.min(height - y_start), depth);
+                    let data = self.read_chunk(&self.chunk_path(level, channel, (x, y, chunk_row)), block)?;
+
+                    for z in row_start.max(z_start)..(row_start + depth).min(z_end) {
+                        for y in 0..block.1 {
+                            let source = ((z - row_start) * block.1 + y) * block.0;
+                            let target = ((z - z_start) * height + y_start + y) * width + x_start;
+                            slices[target..target + block.0].copy_from_slice(&data[source..source + block.0]);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(slices)
+    }
+
+    /**
+     * Read one chunk written by [`ChunkedWriter::write_chunk`], cropped to `block`
+     */
+    fn read_chunk(&self, path: &Path, block: (usize, usize, usize)) -> Result<Vec<f32>> {
+        let bytes = std::fs::read(path).map_err(|e| StitchError::io(path, e))?;
+        let (chunk_x, chunk_y, _) = self.output.chunk_size;
+        match self.output.format {
+            ChunkedFormat::Zarr => {
+                let bytes = self.decompress(path, &bytes)?;
+                let values = self.decode(&bytes, false);
+                let mut data = Vec::with_capacity(block.0 * block.1 * block.2);
+                for z in 0..block.2 {
+                    for y in 0..block.1 {
+                        let source = (z * chunk_y + y) * chunk_x;
+                        data.extend_from_slice(&values[source..source + block.0]);
+                    }
+                }
+                Ok(data)
+            }
+            ChunkedFormat::N5 => {
+                let ndim = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+                let bytes = self.decompress(path, &bytes[4 + 4 * ndim..])?;
+                Ok(self.decode(&bytes, true))
+            }
+        }
+    }
+
+    /**
+     * Store values, already scaled to the output type, as bytes of that type
+     */
+    fn encode(&self, values: &[f32], big_endian: bool) -> Vec<u8> {
+        match (self.dtype, big_endian) {
+            (OutputDtype::U8, _) => values.iter().map(|value| *value as u8).collect(),
+            (OutputDtype::U16, false) => values.iter().flat_map(|value| (*value as u16).to_le_bytes()).collect(),
+            (OutputDtype::U16, true) => values.iter().flat_map(|value| (*value as u16).to_be_bytes()).collect(),
+            (OutputDtype::F32, false) => values.iter().flat_map(|value| value.to_le_bytes()).collect(),
+            (OutputDtype::F32, true) => values.iter().flat_map(|value| value.to_be_bytes()).collect(),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8], big_endian: bool) -> Vec<f32> {
+        match (self.dtype, big_endian) {
+            (OutputDtype::U8, _) => bytes.iter().map(|value| *value as f32).collect(),
+            (OutputDtype::U16, false) => bytes.chunks_exact(2).map(|value| u16::from_le_bytes([value[0], value[1]]) as f32).collect(),
+            (OutputDtype::U16, true) => bytes.chunks_exact(2).map(|value| u16::from_be_bytes([value[0], value[1]]) as f32).collect(),
+            (OutputDtype::F32, false) => bytes
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                .collect(),
+            (OutputDtype::F32, true) => bytes
+                .chunks_exact(4)
+                .map(|value| f32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+                .collect(),
+        }
+    }
+
+    fn compress(&self, path: &Path, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match self.output.compression {
+            Compression::None => Ok(bytes),
+            Compression::Gzip => {
+                let level = flate2::Compression::new(self.output.compression_level);
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(&bytes).map_err(|e| StitchError::io(path, e))?;
+                encoder.finish().map_err(|e| StitchError::io(path, e))
+            }
+        }
+    }
+
+    fn decompress(&self, path: &Path, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self.output.compression {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Gzip => {
+                let mut decompressed = vec![];
+                GzDecoder::new(bytes)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| StitchError::io(path, e))?;
+                Ok(decompressed)
+            }
+        }
+    }
+
+    fn write_metadata(&self, voxel_size: Option<(f32, f32, f32)>, unit: &str) -> Result<()> {
+        let voxel_size = voxel_size.unwrap_or((1.0, 1.0, 1.0));
+        // Spatial values in x, y, z order without z for 2D images
+        let spatial = |values: (f64, f64, f64)| -> Vec<f64> {
+            let mut values = vec![values.0, values.1, values.2];
+            values.truncate(if self.is_3d { 3 } else { 2 });
+            values
+        };
+        let scale = |level: &Level| {
+            spatial((
+                (voxel_size.0 * level.factors.0 as f32) as f64,
+                (voxel_size.1 * level.factors.1 as f32) as f64,
+                (voxel_size.2 * level.factors.2 as f32) as f64,
+            ))
+        };
+        let shape = |level: &Level| spatial((level.width as f64, level.height as f64, level.depth as f64));
+        let (chunk_x, chunk_y, chunk_z) = self.output.chunk_size;
+        let chunks = spatial((chunk_x as f64, chunk_y as f64, chunk_z as f64));
+
+        match self.output.format {
+            ChunkedFormat::Zarr => {
+                let unit = unit_to_meters(unit).and_then(ome_unit_name);
+                let mut axes = vec![];
+                if self.channels > 1 {
+                    axes.push(json!({ "name": "c", "type": "channel" }));
+                }
+                for name in ["x", "y", "z"].iter().take(chunks.len()).rev() {
+                    axes.push(match unit {
+                        Some(unit) => json!({ "name": name, "type": "space", "unit": unit }),
+                        None => json!({ "name": name, "type": "space" }),
+                    });
+                }
+
+                // Zarr lists axes slowest first, with the channel in front
+                let zarr_order = |values: Vec<f64>, channel: f64| -> Vec<f64> {
+                    let mut values = values;
+                    values.reverse();
+                    if self.channels > 1 {
+                        values.insert(0, channel);
+                    }
+                    values
+                };
+                let datasets = self
+                    .levels
+                    .iter()
+                    .enumerate()
+                    .map(|(i, level)| {
+                        json!({
+                            "path": i.to_string(),
+                            "coordinateTransformations": [{ "type": "scale", "scale": zarr_order(scale(level), 1.0) }]
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                write_json(&self.root.join(".zgroup"), &json!({ "zarr_format": 2 }))?;
+                write_json(
+                    &self.root.join(".zattrs"),
+                    &json!({
+                        "multiscales": [{
+                            "version": "0.4",
+                            "name": self.root.file_stem().map(|name| name.to_string_lossy()).unwrap_or_default(),
+                            "axes": axes,
+                            "datasets": datasets,
+                            "type": "mean"
+                        }]
+                    }),
+                )?;
+
+                let compressor = match self.output.compression {
+                    Compression::None => Value::Null,
+                    Compression::Gzip => json!({ "id": "gzip", "level": self.output.compression_level }),
+                };
+                for (i, level) in self.levels.iter().enumerate() {
+                    let as_usize = |values: Vec<f64>| values.into_iter().map(|value| value as usize).collect::<Vec<_>>();
+                    write_json(
+                        &self.dataset_path(i).join(".zarray"),
+                        &json!({
+                            "zarr_format": 2,
+                            "shape": as_usize(zarr_order(shape(level), self.channels as f64)),
+                            "chunks": as_usize(zarr_order(chunks.clone(), 1.0)),
+                            "dtype": match self.dtype {
+                                OutputDtype::U8 => "|u1",
+                                OutputDtype::U16 => "<u2",
+                                OutputDtype::F32 => "<f4",
+                            },
+                            "compressor": compressor,
+                            "fill_value": 0,
+                            "order": "C",
+                            "filters": null,
+                            "dimension_separator": "/"
+                        }),
+                    )?;
+                }
+            }
+            ChunkedFormat::N5 => {
+                // N5 lists axes fastest first, with the channel last
+                let n5_order = |values: Vec<f64>, channel: f64| -> Vec<f64> {
+                    let mut values = values;
+                    if self.channels > 1 {
+                        values.push(channel);
+                    }
+                    values
+                };
+                let as_usize = |values: Vec<f64>| values.into_iter().map(|value| value as usize).collect::<Vec<_>>();
+                let factors = |level: &Level| {
+                    as_usize(n5_order(
+                        spatial((level.factors.0 as f64, level.factors.1 as f64, level.factors.2 as f64)),
+                        1.0,
+                    ))
+                };
+                let mut axes = vec!["x", "y", "z"];
+                axes.truncate(chunks.len());
+                if self.channels > 1 {
+                    axes.push("c");
+                }
+
+                write_json(
+                    &self.root.join("attributes.json"),
+                    &json!({
+                        "n5": "2.5.1",
+                        "multiScale": true,
+                        "axes": axes,
+                        "resolution": n5_order(scale(&self.levels[0]), 1.0),
+                        "units": axes.iter().map(|axis| if *axis == "c" { "" } else { unit }).collect::<Vec<_>>(),
+                        "scales": self.levels.iter().map(factors).collect::<Vec<_>>(),
+                    }),
+                )?;
+
+                let compression = match self.output.compression {
+                    Compression::None => json!({ "type": "raw" }),
+                    Compression::Gzip => json!({ "type": "gzip", "level": self.output.compression_level }),
+                };
+                for (i, level) in self.levels.iter().enumerate() {
+                    write_json(
+                        &self.dataset_path(i).join("attributes.json"),
+                        &json!({
+                            "dimensions": as_usize(n5_order(shape(level), self.channels as f64)),
+                            "blockSize": as_usize(n5_order(chunks.clone(), 1.0)),
+                            "dataType": match self.dtype {
+                                OutputDtype::U8 => "uint8",
+                                OutputDtype::U16 => "uint16",
+                                OutputDtype::F32 => "float32",
+                            },
+                            "compression": compression,
+                            "downsamplingFactors": factors(level),
+                            "resolution": n5_order(scale(level), 1.0),
+                        }),
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Average blocks of `source` slices down to `depth` slices of the `target` level
+ */
+fn downsample(slices: &[f32], source: Level, target: Level, depth: usize) -> Vec<f32> {
+    let factor = |source: usize, target: usize| source.div_ceil(target);
+    let (fx, fy, fz) = (
+        factor(source.width, target.width),
+        factor(source.height, target.height),
+        factor(source.depth, target.depth),
+    );
+    let source_depth = slices.len() / (source.width * source.height);
+
+    let mut data = vec![0.0f32; depth * target.width * target.height];
+    data.par_chunks_mut(target.width * target.height)
+        .enumerate()
+        .for_each(|(z, slice)| {
+            for y in 0..target.height {
+                for x in 0..target.width {
+                    let (mut sum, mut count) = (0.0, 0);
+                    for sz in z * fz..((z + 1) * fz).min(source_depth) {
+                        for sy in y * fy..((y + 1) * fy).min(source.height) {
+                            for sx in x * fx..((x + 1) * fx).min(source.width) {
+                                sum += slices[(sz * source.height + sy) * source.width + sx];
+                                count += 1;
+                            }
+                        }
+                    }
+                    slice[y * target.width + x] = if count > 0 { sum / count as f32 } else { 0.0 };
+                }
+            }
+        });
+    data
+}
+
+/**
+ * OME-NGFF name of a length unit
+ */
+fn ome_unit_name(meters: f64) -> Option<&'static str> {
+    [
+        (1.0, "meter"),
+        (1e-2, "centimeter"),
+        (1e-3, "millimeter"),
+        (1e-6, "micrometer"),
+        (1e-9, "nanometer"),
+        (0.0254, "inch"),
+    ]
+    .into_iter()
+    .find(|(size, _)| (meters / size - 1.0).abs() < 1e-9)
+    .map(|(_, name)| name)
+}
+
+fn write_json(path: &Path, value: &Value) -> Result<()> {
+    let text = serde_json::to_string_pretty(value).map_err(|e| StitchError::format(path, e))?;
+    std::fs::write(path, text).map_err(|e| StitchError::io(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intensity::OutputScaling;
+
+    fn read_json(path: &Path) -> Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn write_store(root: &Path, format: ChunkedFormat, compression: Compression, channels: usize) {
+        let output = ChunkedOutput {
+            format,
+            chunk_size: (2, 2, 2),
+            compression,
+            compression_level: 5,
+            levels: None,
+        };
+        let raw = PixelConversion::new(OutputDtype::F32, OutputScaling::Raw, (0.0, 0.0), None);
+        let conversions = vec![raw; channels];
+        let mut writer =
+            ChunkedWriter::create(root, &output, &conversions, (5, 4, Some(3)), Some((0.5, 0.5, 2.0)), "um").unwrap();
+        let data = (0..5 * 4 * 3).map(|i| i as f32).collect::<Vec<_>>();
+        for channel in 0..channels {
+            // Appended in pieces that do not line up with the chunk depth
+            writer.write_slices(channel, &data[..20]).unwrap();
+            writer.write_slices(channel, &data[20..]).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn zarr_store_has_the_chunk_grid_and_levels() {
+        let root = std::env::temp_dir().join(format!("stitch-chunked-{}.zarr", std::process::id()));
+        write_store(&root, ChunkedFormat::Zarr, Compression::None, 1);
+
+        // 5 x 4 x 3 halves to 3 x 2 x 2, then to 2 x 1 x 1, which fits in one xy chunk
+        let shapes = (0..3)
+            .map(|level| read_json(&root.join(level.to_string()).join(".zarray"))["shape"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(shapes, [json!([3, 4, 5]), json!([2, 2, 3]), json!([1, 1, 2])]);
+        assert!(!root.join("3").exists());
+        let zarray = read_json(&root.join("0").join(".zarray"));
+        assert_eq!(zarray["chunks"], json!([2, 2, 2]));
+        assert_eq!(zarray["dtype"], "<f4");
+        assert_eq!(zarray["compressor"], Value::Null);
+        let multiscales = &read_json(&root.join(".zattrs"))["multiscales"][0];
+        assert_eq!(multiscales["axes"][0], json!({ "name": "z", "type": "space", "unit": "micrometer" }));
+        assert_eq!(multiscales["datasets"][1]["coordinateTransformations"][0]["scale"], json!([4.0, 1.0, 1.0]));
+
+        // 3 x 2 x 2 chunks in z/y/x order, edge chunks padded with zeros
+        let chunks = (0..2)
+            .flat_map(|z| (0..2).flat_map(move |y| (0..3).map(move |x| format!("0/{}/{}/{}", z, y, x))))
+            .filter(|chunk| root.join(chunk).is_file())
+            .count();
+        assert_eq!(chunks, 12);
+        let values = |chunk: &str| {
+            let bytes = std::fs::read(root.join(chunk)).unwrap();
+            bytes
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values("0/1/1/2"), [54.0, 0.0, 59.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        // Mean of the first 2 x 2 x 2 block of the full resolution
+        assert_eq!(values("1/0/0/0")[0], 13.0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn n5_store_lists_channels_last() {
+        let root = std::env::temp_dir().join(format!("stitch-chunked-{}.n5", std::process::id()));
+        write_store(&root, ChunkedFormat::N5, Compression::Gzip, 2);
+
+        let attributes = read_json(&root.join("attributes.json"));
+        assert_eq!(attributes["axes"], json!(["x", "y", "z", "c"]));
+        assert_eq!(attributes["scales"], json!([[1, 1, 1, 1], [2, 2, 2, 1], [4, 4, 4, 1]]));
+        let level = read_json(&root.join("s1").join("attributes.json"));
+        assert_eq!(level["dimensions"], json!([3, 2, 2, 2]));
+        assert_eq!(level["blockSize"], json!([2, 2, 2, 1]));
+        assert_eq!(level["compression"], json!({ "type": "gzip", "level": 5 }));
+
+        // Blocks at the edge store their cropped size in the header
+        let bytes = std::fs::read(root.join("s0/2/1/1/1")).unwrap();
+        assert_eq!(&bytes[..4], [0, 0, 0, 4]);
+        let dims = bytes[4..20].chunks_exact(4).map(|dim| u32::from_be_bytes([dim[0], dim[1], dim[2], dim[3]]));
+        assert_eq!(dims.collect::<Vec<_>>(), [1, 2, 1, 1]);
+        assert!(root.join("s2/0/0/0/1").is_file());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
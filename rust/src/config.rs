@@ -0,0 +1,1376 @@
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::chunked::{ChunkedOutput, ChunkedOutputSection};
+use crate::error::{Result, StitchError};
+use crate::fuse::FuseMode;
+use crate::grid::GridLayout;
+use crate::image::{read_image_metadata, resampled_size, unit_to_meters, RgbMode};
+use crate::intensity::{OutputDtype, OutputScaling};
+use crate::preprocess::{Preprocessing, PreprocessingSection};
+use crate::pyramid_tiff::{PyramidTiff, PyramidTiffSection};
+use crate::stitch3d::IBox3D;
+use crate::tile_configuration::read_tile_size;
+use crate::timelapse::{Timelapse, TimepointsSection};
+
+/**
+ * Major version of the config format understood by this build
+ */
+pub const CONFIG_VERSION: &str = "1.0";
+
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+pub enum StitchMode {
+    #[serde(rename = "2d")]
+    TwoD,
+    #[serde(rename = "3d")]
+    ThreeD,
+}
+
+/**
+ * File format of fused 3D volumes without `chunked_output`
+ */
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    Tiff,
+    /// One multi-frame DICOM file.
+    Dcm,
+    /// A directory with one DICOM file per slice.
+    DcmSeries,
+}
+
+#[derive(Clone, Debug)]
+pub struct StitchConfig {
+    pub version: String,
+    pub mode: StitchMode,
+    pub overlap_ratio: (f32, f32, f32),
+    pub correlation_threshold: f32,
+    pub relative_error_threshold: f32,
+    pub absolute_error_threshold: f32,
+    pub check_peaks: usize,
+    pub dimension_mask: (bool, bool, bool),
+    pub fuse_mode: FuseMode,
+    pub no_fuse: bool,
+    pub output_path: PathBuf,
+    pub alignment_file: Option<PathBuf>,
+    /// JSON file keeping the candidate peaks of every registered pair across runs.
+    pub pair_cache: Option<PathBuf>,
+    pub tile_paths: Vec<PathBuf>,
+    pub tile_layout: Vec<IBox3D>,
+    /// Pixels of `voxel_size` per pixel of each tile along x, y and z, for tiles given with their
+    /// own `voxel_size`. Indexed like `tile_paths`; tiles without an entry are not resampled.
+    pub tile_scales: Vec<Option<(f32, f32, f32)>>,
+    pub copy_files: bool,
+    pub use_phase_correlation: bool,
+    /// Refine pairwise shifts to fractions of a pixel.
+    pub subpixel_accuracy: bool,
+    /// Windowing and filtering of tile overlaps before phase correlation.
+    pub preprocessing: Preprocessing,
+    /// Phase correlate 3D pairs downsampled by these factors along x, y and z and refine the
+    /// shift at full resolution.
+    pub registration_downsampling: Option<(usize, usize, usize)>,
+    /// Bytes of memory shared by 3D pairs registered at the same time and their cached tiles.
+    pub registration_memory_budget: Option<usize>,
+    pub use_prior: bool,
+    pub prior_sigmas: (f32, f32, f32),
+    pub merge_subgraphs: bool,
+    pub save_float: bool,
+    /// Physical size of a pixel along x, y and z when tiles are placed by stage position.
+    pub voxel_size: Option<(f32, f32, f32)>,
+    /// Unit of `voxel_size` and stage positions.
+    pub unit: String,
+    /// Channel used for registration.
+    pub channel: usize,
+    /// Register on the weighted sum of all channels instead of `channel`.
+    pub channel_weights: Option<Vec<f32>>,
+    /// How color tiles are read.
+    pub rgb: RgbMode,
+    /// Channels to fuse, or `None` for every channel of the tiles.
+    pub fuse_channels: Option<Vec<usize>>,
+    /// Write all fused channels into one TIFF instead of one file per channel.
+    pub multichannel_output: bool,
+    /// OME-TIFF timepoint used for registration.
+    pub timepoint: usize,
+    /// Fuse 3D volumes slab by slab within this many bytes instead of in memory.
+    pub fuse_memory_budget: Option<usize>,
+    /// Write fused images as a chunked multiscale store instead of TIFF or PNG.
+    pub chunked_output: Option<ChunkedOutput>,
+    /// Write 2D fused images as tiled pyramidal BigTIFFs instead of PNG.
+    pub pyramid_tiff: Option<PyramidTiff>,
+    /// File format of fused 3D volumes.
+    pub output_format: OutputFormat,
+    /// Decode every 3D tile before fusing to find its value range, instead of using the range of
+    /// its pixel type or header.
+    pub measure_intensity: bool,
+    /// Pixel type of fused images, see [`StitchConfig::output_dtype`].
+    pub output_dtype: Option<OutputDtype>,
+    /// Mapping of fused values to output pixels, see [`StitchConfig::output_scaling`].
+    pub output_scaling: Option<OutputScaling>,
+    /// Tile paths of every timepoint of a time-lapse. `tile_paths` holds the reference timepoint.
+    pub timelapse: Option<Timelapse>,
+}
+
+impl Default for StitchConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StitchConfig {
+    pub fn new() -> Self {
+        StitchConfig {
+            version: CONFIG_VERSION.to_string(),
+            mode: StitchMode::TwoD,
+            save_float: false,
+            overlap_ratio: (0.2, 0.2, 0.2),
+            correlation_threshold: 0.3,
+            relative_error_threshold: 2.5,
+            absolute_error_threshold: 3.5,
+            check_peaks: 5,
+            dimension_mask: (true, true, true),
+            fuse_mode: FuseMode::Linear,
+            no_fuse: false,
+            output_path: PathBuf::new(),
+            alignment_file: None,
+            pair_cache: None,
+            tile_paths: vec![],
+            tile_layout: vec![],
+            tile_scales: vec![],
+            copy_files: false,
+            use_phase_correlation: true,
+            subpixel_accuracy: false,
+            preprocessing: Preprocessing::default(),
+            registration_downsampling: None,
+            registration_memory_budget: None,
+            use_prior: false,
+            prior_sigmas: (10.0, 10.0, 10.0),
+            merge_subgraphs: true,
+            voxel_size: None,
+            unit: "um".to_string(),
+            channel: 0,
+            channel_weights: None,
+            rgb: RgbMode::Luminance,
+            fuse_channels: None,
+            multichannel_output: false,
+            timepoint: 0,
+            fuse_memory_budget: None,
+            chunked_output: None,
+            pyramid_tiff: None,
+            output_format: OutputFormat::Tiff,
+            measure_intensity: false,
+            output_dtype: None,
+            output_scaling: None,
+            timelapse: None,
+        }
+    }
+
+    /**
+     * Pixel type of fused images: `output_dtype` if set, otherwise 32-bit floats in 3D or with
+     * `save_float` and 16-bit integers in 2D or for DICOM output
+     */
+    pub fn output_dtype(&self) -> OutputDtype {
+        match self.output_dtype {
+            Some(dtype) => dtype,
+            None if self.save_float => OutputDtype::F32,
+            None if self.mode == StitchMode::ThreeD && self.output_format == OutputFormat::Tiff => OutputDtype::F32,
+            None => OutputDtype::U16,
+        }
+    }
+
+    /**
+     * Pixels of `voxel_size` per pixel of tile `i` along x, y and z, or `None` if the tile is read
+     * as it is
+     */
+    pub fn tile_scale(&self, i: usize) -> Option<(f32, f32, f32)> {
+        self.tile_scales.get(i).copied().flatten()
+    }
+
+    /**
+     * Settings that are out of range or contradict each other. Checked again after command line
+     * arguments override the config file.
+     */
+    pub fn problems(&self) -> Vec<StitchError> {
+        let mut problems = vec![];
+        let (x, y, z) = self.overlap_ratio;
+        if [x, y, z].iter().any(|ratio| !(0.0..1.0).contains(ratio)) {
+            problems.push(StitchError::config("overlap_ratio", "Overlap ratios must be between 0 and 1"));
+        }
+        let (x, y, z) = self.prior_sigmas;
+        if [x, y, z].iter().any(|sigma| sigma.is_nan() || *sigma <= 0.0) {
+            problems.push(StitchError::config("prior_sigma", "Prior sigmas must be positive"));
+        }
+        if self.check_peaks == 0 {
+            problems.push(StitchError::config("check_peaks", "At least one peak must be checked"));
+        }
+        for (key, threshold) in [
+            ("correlation_threshold", self.correlation_threshold),
+            ("relative_error_threshold", self.relative_error_threshold),
+            ("absolute_error_threshold", self.absolute_error_threshold),
+        ] {
+            if !threshold.is_finite() {
+                problems.push(StitchError::config(key, "Thresholds must be finite numbers"));
+            }
+        }
+        match self.output_scaling {
+            Some(OutputScaling::Percentiles([low, high])) if !(0.0 <= low && low < high && high <= 100.0) => {
+                problems.push(StitchError::config(
+                    "output_scaling",
+                    "Percentiles must increase and lie between 0 and 100",
+                ));
+            }
+            Some(OutputScaling::Range([min, max])) if min >= max => {
+                problems.push(StitchError::config("output_scaling", "Range minimum must be below its maximum"));
+            }
+            _ => {}
+        }
+        if self.registration_memory_budget == Some(0) {
+            problems.push(StitchError::config("registration_memory_gb", "Memory budget must be positive"));
+        }
+        if self.fuse_memory_budget == Some(0) {
+            problems.push(StitchError::config("fuse_memory_gb", "Memory budget must be positive"));
+        }
+        if self.save_float && self.output_dtype.is_some_and(|dtype| dtype != OutputDtype::F32) {
+            problems.push(StitchError::config("output_dtype", "\"save_float\" requires \"output_dtype\" f32"));
+        }
+        if self.output_format != OutputFormat::Tiff {
+            if self.mode == StitchMode::TwoD {
+                problems.push(StitchError::config("output_format", "DICOM output is only written for 3D volumes"));
+            }
+            if self.chunked_output.is_some() {
+                problems.push(StitchError::config(
+                    "output_format",
+                    "Specify only one of \"chunked_output\" or DICOM output",
+                ));
+            }
+            if self.multichannel_output {
+                problems.push(StitchError::config(
+                    "output_format",
+                    "DICOM files hold one channel, unset \"multichannel_output\"",
+                ));
+            }
+            if self.save_float || self.output_dtype == Some(OutputDtype::F32) {
+                problems.push(StitchError::config("output_format", "DICOM output needs \"output_dtype\" u8 or u16"));
+            }
+        }
+        problems
+    }
+
+    /**
+     * Mapping of fused values to output pixels: `output_scaling` if set, otherwise raw values for
+     * floats and the input value range for integer types
+     */
+    pub fn output_scaling(&self) -> OutputScaling {
+        match self.output_scaling {
+            Some(scaling) => scaling,
+            None if self.output_dtype() == OutputDtype::F32 => OutputScaling::Raw,
+            None => OutputScaling::InputRange,
+        }
+    }
+
+    pub fn print_summary(&self) {
+        println!("Version: {}", self.version);
+        println!("Mode: {:?}", self.mode);
+        println!("Overlap ratio: {:?}", self.overlap_ratio);
+        println!("Correlation threshold: {}", self.correlation_threshold);
+        println!("Check peaks: {}", self.check_peaks);
+        println!("Save float: {}", self.save_float);
+        println!("Output: {:?} {:?}, {:?}", self.output_format, self.output_dtype(), self.output_scaling());
+        println!("Dimension mask: {:?}", self.dimension_mask);
+        println!("Fuse mode: {:?}", self.fuse_mode);
+        println!("Use phase correlation: {}", self.use_phase_correlation);
+        println!("Subpixel accuracy: {}", self.subpixel_accuracy);
+        if self.preprocessing != Preprocessing::default() {
+            println!("Preprocessing: {:?}", self.preprocessing);
+        }
+        if let Some(factors) = self.registration_downsampling {
+            println!("Registration downsampling: {:?}", factors);
+        }
+        if let Some(budget) = self.registration_memory_budget {
+            println!("Registration memory budget: {} bytes", budget);
+        }
+        println!("No fuse: {}", self.no_fuse);
+        if self.measure_intensity {
+            println!("Measure intensity: true");
+        }
+        if let Some(budget) = self.fuse_memory_budget {
+            println!("Fuse memory budget: {} bytes", budget);
+        }
+        if let Some(output) = &self.chunked_output {
+            println!("Chunked output: {:?}, chunks {:?}", output.format, output.chunk_size);
+        }
+        if let Some(output) = &self.pyramid_tiff {
+            println!("Pyramid TIFF: {} px tiles, {:?}", output.tile_size, output.compression);
+        }
+        println!("Use prior: {}", self.use_prior);
+        println!("Merge subgraphs: {}", self.merge_subgraphs);
+        println!("Prior sigmas: {:?}", self.prior_sigmas);
+        println!("Absolute error threshold: {}", self.absolute_error_threshold);
+        println!("Relative error threshold: {}", self.relative_error_threshold);
+        if let Some(voxel_size) = self.voxel_size {
+            println!("Voxel size: {:?} {}", voxel_size, self.unit);
+        }
+        match &self.channel_weights {
+            Some(weights) => println!("Channel weights: {:?}", weights),
+            None => println!("Channel: {}", self.channel),
+        }
+        if let Some(fuse_channels) = &self.fuse_channels {
+            println!("Fuse channels: {:?}", fuse_channels);
+        }
+        println!("Timepoint: {}", self.timepoint);
+        println!("Tiles: {}", self.tile_paths.len());
+        if let Some(timelapse) = &self.timelapse {
+            println!("Timepoints: {} (reference {})", timelapse.count(), timelapse.reference);
+        }
+    }
+}
+
+/**
+ * On-disk layout of stitch_config.json. Every key is optional except `mode`
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StitchConfigFile {
+    #[serde(rename = "$schema")]
+    pub schema: Option<String>,
+    pub version: Option<String>,
+    pub mode: StitchMode,
+    pub overlap_ratio: Option<AxisValues>,
+    pub correlation_threshold: Option<f32>,
+    pub relative_error_threshold: Option<f32>,
+    pub absolute_error_threshold: Option<f32>,
+    pub check_peaks: Option<usize>,
+    pub save_float: Option<bool>,
+    pub dimension_mask: Option<Vec<bool>>,
+    pub fuse_mode: Option<FuseMode>,
+    pub use_phase_correlation: Option<bool>,
+    pub subpixel_accuracy: Option<bool>,
+    pub preprocessing: Option<PreprocessingSection>,
+    pub registration_downsampling: Option<AxisValues>,
+    pub registration_memory_gb: Option<f32>,
+    pub no_fuse: Option<bool>,
+    pub use_prior: Option<bool>,
+    pub merge_subgraphs: Option<bool>,
+    pub prior_sigma: Option<AxisValues>,
+    pub output_path: Option<PathBuf>,
+    pub alignment_file: Option<PathBuf>,
+    pub pair_cache: Option<PathBuf>,
+    pub tiles: Option<Vec<TileEntry>>,
+    pub tile_paths: Option<Vec<PathBuf>>,
+    pub tile_layout: Option<Vec<Vec<i64>>>,
+    pub grid: Option<GridLayout>,
+    pub voxel_size: Option<AxisValues>,
+    pub unit: Option<String>,
+    pub layout_from_metadata: Option<bool>,
+    pub channel: Option<usize>,
+    pub channel_weights: Option<Vec<f32>>,
+    pub rgb: Option<RgbMode>,
+    pub fuse_channels: Option<Vec<usize>>,
+    pub multichannel_output: Option<bool>,
+    pub timepoint: Option<usize>,
+    pub timepoints: Option<TimepointsSection>,
+    pub fuse_memory_gb: Option<f32>,
+    pub chunked_output: Option<ChunkedOutputSection>,
+    pub pyramid_tiff: Option<PyramidTiffSection>,
+    pub output_format: Option<OutputFormat>,
+    pub measure_intensity: Option<bool>,
+    pub output_dtype: Option<OutputDtype>,
+    pub output_scaling: Option<OutputScaling>,
+}
+
+/**
+ * A value given either once for all axes or per axis as a 2 or 3 element array
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum AxisValues {
+    Uniform(f32),
+    PerAxis(Vec<f32>),
+}
+
+/**
+ * Entry of the `tiles` array, positioned with either `box`, `x`/`y`/`z`/`width`/`height`/`depth`
+ * in pixels, or a physical stage `position`
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TileEntry {
+    pub path: PathBuf,
+    #[serde(rename = "box")]
+    pub bbox: Option<Vec<i64>>,
+    pub x: Option<i64>,
+    pub y: Option<i64>,
+    pub z: Option<i64>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub depth: Option<i64>,
+    /// Stage position in physical units, converted to pixels with the config `voxel_size`.
+    pub position: Option<Vec<f64>>,
+    /// Pixel spacing of this tile, if it differs from the config `voxel_size`. The tile is
+    /// resampled to the config `voxel_size` when it is read.
+    pub voxel_size: Option<AxisValues>,
+}
+
+pub fn read_config_file(path: &Path) -> Result<StitchConfig> {
+    validate_config_file(path).map_err(|mut problems| problems.swap_remove(0))
+}
+
+/**
+ * Read a config file and report every problem found instead of stopping at the first one
+ */
+pub fn validate_config_file(path: &Path) -> std::result::Result<StitchConfig, Vec<StitchError>> {
+    let base_path = path
+        .parent()
+        .ok_or_else(|| vec![StitchError::config("path", "Invalid base path")])?;
+    let json_str = std::fs::read_to_string(path).map_err(|e| vec![StitchError::io(path, e)])?;
+
+    let locations = JsonLocations::new(&json_str);
+    let file: StitchConfigFile = serde_json::from_str(&json_str).map_err(|e| {
+        if e.is_data() {
+            vec![locations.serde_error(&e)]
+        } else {
+            vec![StitchError::format(path, e)]
+        }
+    })?;
+
+    let mut validator = ConfigValidator {
+        locations: &locations,
+        problems: vec![],
+    };
+    let config = validator.build(file, base_path);
+
+    if validator.problems.is_empty() {
+        Ok(config)
+    } else {
+        Err(validator.problems)
+    }
+}
+
+pub fn parse_output_format(format: &str) -> Option<OutputFormat> {
+    match format {
+        "tiff" => Some(OutputFormat::Tiff),
+        "dcm" => Some(OutputFormat::Dcm),
+        "dcm-series" => Some(OutputFormat::DcmSeries),
+        _ => None,
+    }
+}
+
+pub fn parse_fuse_mode(mode: &str) -> Option<FuseMode> {
+    match mode {
+        "average" => Some(FuseMode::Average),
+        "max" => Some(FuseMode::Max),
+        "min" => Some(FuseMode::Min),
+        "overwrite" => Some(FuseMode::Overwrite),
+        "linear" => Some(FuseMode::Linear),
+        "overwrite-prioritize-center" => Some(FuseMode::OverwritePrioritizeCenter),
+        _ => None,
+    }
+}
+
+struct ConfigValidator<'a> {
+    locations: &'a JsonLocations,
+    problems: Vec<StitchError>,
+}
+
+impl ConfigValidator<'_> {
+    fn problem(&mut self, key: &str, message: impl Into<String>) {
+        let line = self.locations.line_of(key);
+        self.problems.push(StitchError::config_at(key, line, message));
+    }
+
+    fn error(&mut self, err: StitchError) {
+        match err {
+            StitchError::Config { key, line: None, message } => self.problem(&key, message),
+            other => self.problems.push(other),
+        }
+    }
+
+    fn build(&mut self, file: StitchConfigFile, base_path: &Path) -> StitchConfig {
+        let mut config = StitchConfig::new();
+        config.mode = file.mode;
+
+        if let Some(version) = file.version {
+            if version.split('.').next() != CONFIG_VERSION.split('.').next() {
+                self.problem(
+                    "version",
+                    format!(
+                        "Unsupported config version \"{}\", expected {}",
+                        version, CONFIG_VERSION
+                    ),
+                );
+            }
+            config.version = version;
+        }
+
+        if let Some(voxel_size) = &file.voxel_size {
+            let voxel_size = self.axis_values("voxel_size", voxel_size, (1.0, 1.0, 1.0));
+            if [voxel_size.0, voxel_size.1, voxel_size.2].iter().any(|size| *size <= 0.0) {
+                self.problem("voxel_size", "Voxel sizes must be positive");
+            }
+            config.voxel_size = Some(voxel_size);
+        }
+        config.unit = file.unit.unwrap_or(config.unit);
+
+        if let Some(overlap_ratio) = &file.overlap_ratio {
+            config.overlap_ratio = self.axis_values("overlap_ratio", overlap_ratio, config.overlap_ratio);
+        }
+
+        if let Some(prior_sigma) = &file.prior_sigma {
+            config.prior_sigmas = self.axis_values("prior_sigma", prior_sigma, config.prior_sigmas);
+            let (x, y, z) = config.prior_sigmas;
+
+            // Given in physical units alongside stage positions
+            if let Some(voxel_size) = config.voxel_size {
+                config.prior_sigmas = (x / voxel_size.0, y / voxel_size.1, z / voxel_size.2);
+            }
+        }
+
+        if let Some(mask) = &file.dimension_mask {
+            if mask.len() == 2 || mask.len() == 3 {
+                let mut values = [true; 3];
+                values[..mask.len()].copy_from_slice(mask);
+                config.dimension_mask = (values[0], values[1], values[2]);
+            } else {
+                self.problem(
+                    "dimension_mask",
+                    format!("Expected 2 or 3 values but got {}", mask.len()),
+                );
+            }
+        }
+
+        config.check_peaks = file.check_peaks.unwrap_or(config.check_peaks);
+
+        config.correlation_threshold = file.correlation_threshold.unwrap_or(config.correlation_threshold);
+        config.relative_error_threshold = file
+            .relative_error_threshold
+            .unwrap_or(config.relative_error_threshold);
+        config.absolute_error_threshold = file
+            .absolute_error_threshold
+            .unwrap_or(config.absolute_error_threshold);
+        config.save_float = file.save_float.unwrap_or(config.save_float);
+        config.output_dtype = file.output_dtype;
+        config.output_scaling = file.output_scaling;
+        config.fuse_mode = file.fuse_mode.unwrap_or(config.fuse_mode);
+        config.use_phase_correlation = file.use_phase_correlation.unwrap_or(config.use_phase_correlation);
+        config.subpixel_accuracy = file.subpixel_accuracy.unwrap_or(config.subpixel_accuracy);
+        if let Some(section) = &file.preprocessing {
+            match section.build() {
+                Ok(preprocessing) => config.preprocessing = preprocessing,
+                Err(err) => self.error(err),
+            }
+        }
+        if let Some(factors) = &file.registration_downsampling {
+            let (x, y, z) = self.axis_values("registration_downsampling", factors, (1.0, 1.0, 1.0));
+            if config.mode != StitchMode::ThreeD {
+                self.problem("registration_downsampling", "Downsampled registration is only used for 3D tiles");
+            }
+            if [x, y, z].iter().any(|factor| *factor < 1.0 || factor.fract() != 0.0) {
+                self.problem("registration_downsampling", "Downsampling factors must be whole numbers of at least 1");
+            }
+            let factors = (x as usize, y as usize, z as usize);
+            config.registration_downsampling = Some(factors).filter(|factors| *factors != (1, 1, 1));
+        }
+        if let Some(memory_gb) = file.registration_memory_gb {
+            if config.mode != StitchMode::ThreeD {
+                self.problem("registration_memory_gb", "Parallel pair registration is only used for 3D tiles");
+            }
+            config.registration_memory_budget = Some((memory_gb as f64 * 1e9) as usize);
+        }
+        config.no_fuse = file.no_fuse.unwrap_or(config.no_fuse);
+        if let Some(memory_gb) = file.fuse_memory_gb {
+            config.fuse_memory_budget = Some((memory_gb as f64 * 1e9) as usize);
+        }
+        if let Some(section) = &file.chunked_output {
+            match section.build(config.mode == StitchMode::ThreeD) {
+                Ok(output) => config.chunked_output = Some(output),
+                Err(err) => self.error(err),
+            }
+        }
+        if let Some(section) = &file.pyramid_tiff {
+            if config.mode == StitchMode::ThreeD {
+                self.problem("pyramid_tiff", "Pyramid TIFFs are only written for 2D mosaics");
+            }
+            if file.chunked_output.is_some() {
+                self.problem("pyramid_tiff", "Specify only one of \"chunked_output\" or \"pyramid_tiff\"");
+            }
+            match section.build() {
+                Ok(output) => config.pyramid_tiff = Some(output),
+                Err(err) => self.error(err),
+            }
+        }
+        config.output_format = file.output_format.unwrap_or(config.output_format);
+        config.measure_intensity = file.measure_intensity.unwrap_or(config.measure_intensity);
+        config.use_prior = file.use_prior.unwrap_or(config.use_prior);
+        config.merge_subgraphs = file.merge_subgraphs.unwrap_or(config.merge_subgraphs);
+        config.channel = file.channel.unwrap_or(config.channel);
+        if let Some(weights) = file.channel_weights {
+            if file.channel.is_some() {
+                self.problem("channel_weights", "Specify only one of \"channel\" or \"channel_weights\"");
+            }
+            if weights.is_empty() || weights.iter().all(|weight| *weight == 0.0) {
+                self.problem("channel_weights", "At least one channel weight must be non-zero");
+            }
+            config.channel_weights = Some(weights);
+        }
+        config.rgb = file.rgb.unwrap_or(config.rgb);
+        if let Some(fuse_channels) = file.fuse_channels {
+            if fuse_channels.is_empty() {
+                self.problem("fuse_channels", "At least one channel must be fused");
+            }
+            config.fuse_channels = Some(fuse_channels);
+        }
+        config.multichannel_output = file.multichannel_output.unwrap_or(config.multichannel_output);
+        config.timepoint = file.timepoint.unwrap_or(config.timepoint);
+
+        config.output_path = base_path.join(file.output_path.unwrap_or_else(|| PathBuf::from("output")));
+        config.alignment_file = file.alignment_file.map(|alignment_file| base_path.join(alignment_file));
+        if let Some(alignment_file) = config.alignment_file.as_ref().filter(|path| !path.exists()) {
+            self.problem("alignment_file", format!("{:?} does not exist", alignment_file));
+        }
+        config.pair_cache = file.pair_cache.map(|pair_cache| base_path.join(pair_cache));
+
+        let layout_from_metadata = file.layout_from_metadata.unwrap_or(false);
+        let mut from_metadata = vec![];
+        let mut unknown_sizes = vec![];
+
+        let sources = [
+            file.tiles.is_some(),
+            file.tile_paths.is_some() || file.tile_layout.is_some(),
+            file.grid.is_some(),
+        ];
+        if sources.iter().filter(|&&source| source).count() > 1 {
+            self.problem(
+                "tiles",
+                "Specify only one of \"tiles\", \"tile_paths\" with \"tile_layout\", or \"grid\"",
+            );
+        } else if let Some(tiles) = file.tiles {
+            let mut uses_positions = false;
+            for (i, tile) in tiles.into_iter().enumerate() {
+                let key = format!("tiles[{}]", i);
+                let scale = self.parse_tile_voxel_size(&tile, &key, &config);
+                let layout = match (&tile.bbox, &tile.position) {
+                    (Some(_), Some(_)) => {
+                        self.problem(&key, "Expected either \"box\" or \"position\", not both");
+                        IBox3D::new(0, 0, 0, 1, 1, 1)
+                    }
+                    (Some(bbox), None) => self.parse_box(bbox, &format!("{}.box", key)),
+                    (None, Some(position)) => {
+                        uses_positions = true;
+                        if tile.width.is_none() || tile.height.is_none() || tile.depth.is_none() {
+                            unknown_sizes.push((i, [tile.width, tile.height, tile.depth]));
+                        }
+                        self.parse_stage_position(&tile, position, &key, &config)
+                    }
+                    (None, None) if layout_from_metadata && tile.x.is_none() && tile.y.is_none() => {
+                        from_metadata.push((i, key.clone(), [tile.width, tile.height, tile.depth]));
+                        IBox3D::new(0, 0, 0, 1, 1, 1)
+                    }
+                    (None, None) => self.parse_tile_fields(&tile, &key),
+                };
+                config.tile_paths.push(base_path.join(tile.path));
+                config.tile_layout.push(layout);
+                config.tile_scales.push(scale);
+            }
+
+            // Stage positions are real positions, so there is no nominal overlap to correct for
+            if uses_positions && file.overlap_ratio.is_none() {
+                config.overlap_ratio = (0.0, 0.0, 0.0);
+            }
+        } else if let Some(grid) = file.grid {
+            if file.overlap_ratio.is_some() {
+                self.problem(
+                    "overlap_ratio",
+                    "Positions generated from \"grid\" already include the overlap, set \"grid.overlap\" instead",
+                );
+            }
+            config.overlap_ratio = (0.0, 0.0, 0.0);
+
+            match grid.expand() {
+                Ok(tiles) => {
+                    for (name, layout) in tiles {
+                        config.tile_paths.push(base_path.join(name));
+                        config.tile_layout.push(layout);
+                    }
+                }
+                Err(err) => self.error(err),
+            }
+        } else {
+            match (file.tile_paths, file.tile_layout) {
+                (Some(tile_paths), Some(tile_layout)) => {
+                    if tile_paths.len() != tile_layout.len() {
+                        self.problem(
+                            "tile_layout",
+                            format!(
+                                "Tile paths and layout do not match length! ({} paths, {} boxes)",
+                                tile_paths.len(),
+                                tile_layout.len()
+                            ),
+                        );
+                    }
+
+                    config.tile_paths = tile_paths.into_iter().map(|path| base_path.join(path)).collect();
+                    config.tile_layout = tile_layout
+                        .iter()
+                        .enumerate()
+                        .map(|(i, values)| self.parse_box(values, &format!("tile_layout[{}]", i)))
+                        .collect();
+                }
+                (Some(tile_paths), None) if layout_from_metadata => {
+                    for (i, path) in tile_paths.into_iter().enumerate() {
+                        from_metadata.push((i, format!("tile_paths[{}]", i), [None, None, None]));
+                        config.tile_paths.push(base_path.join(path));
+                        config.tile_layout.push(IBox3D::new(0, 0, 0, 1, 1, 1));
+                    }
+                }
+                (Some(_), None) => {
+                    self.problem(
+                        "tile_paths",
+                        "No tile layout specified, expected \"tile_layout\" or \"layout_from_metadata\"",
+                    );
+                }
+                (None, Some(_)) => {
+                    self.problem("tile_layout", "No tile paths specified, expected \"tile_paths\"");
+                }
+                (None, None) => {
+                    self.problem(
+                        "tiles",
+                        "No tiles specified, expected \"tiles\", \"tile_paths\" or \"grid\"",
+                    );
+                }
+            }
+        }
+
+        // Expand `{t}` before anything is read from the tiles
+        if let Some(timepoints) = file.timepoints {
+            match timepoints.expand(&config.tile_paths) {
+                Ok(timelapse) => {
+                    config.tile_paths = timelapse.tile_paths[timelapse.reference].clone();
+                    config.timelapse = Some(timelapse);
+                }
+                Err(err) => self.error(err),
+            }
+        }
+
+        if !unknown_sizes.is_empty() && self.problems.is_empty() {
+            self.fill_tile_sizes(&mut config, &unknown_sizes);
+        }
+
+        // Sizes are given and read in pixels of the tile, the layout is in pixels of voxel_size
+        for (layout, scale) in config.tile_layout.iter_mut().zip(&config.tile_scales) {
+            if let Some(scale) = scale {
+                layout.width = resampled_size(layout.width as usize, scale.0) as i64;
+                layout.height = resampled_size(layout.height as usize, scale.1) as i64;
+                layout.depth = resampled_size(layout.depth as usize, scale.2) as i64;
+            }
+        }
+
+        if !from_metadata.is_empty() && self.problems.is_empty() {
+            // Header positions are real positions, so there is no nominal overlap to correct for
+            if file.overlap_ratio.is_none() {
+                config.overlap_ratio = (0.0, 0.0, 0.0);
+            }
+            self.fill_layout_from_metadata(&mut config, &from_metadata);
+        }
+
+        for problem in config.problems() {
+            self.error(problem);
+        }
+
+        if config.tile_paths.is_empty() && self.problems.is_empty() {
+            self.problem("tiles", "At least one tile is required");
+        }
+
+        config
+    }
+
+    fn axis_values(&mut self, key: &str, values: &AxisValues, default: (f32, f32, f32)) -> (f32, f32, f32) {
+        match values {
+            AxisValues::Uniform(val) => (*val, *val, *val),
+            AxisValues::PerAxis(arr) if arr.len() == 2 => (arr[0], arr[1], default.2),
+            AxisValues::PerAxis(arr) if arr.len() == 3 => (arr[0], arr[1], arr[2]),
+            AxisValues::PerAxis(arr) => {
+                self.problem(key, format!("Expected 2 or 3 values but got {}", arr.len()));
+                default
+            }
+        }
+    }
+
+    /**
+     * Convert a physical stage position into a pixel layout box using the config voxel size
+     */
+    fn parse_stage_position(&mut self, tile: &TileEntry, position: &[f64], key: &str, config: &StitchConfig) -> IBox3D {
+        let mut temp = IBox3D::new(0, 0, 0, 1, 1, 1);
+        let position_key = format!("{}.position", key);
+
+        if tile.x.is_some() || tile.y.is_some() || tile.z.is_some() {
+            self.problem(key, "Expected either \"position\" or \"x\" and \"y\", not both");
+        }
+
+        let Some(voxel_size) = config.voxel_size else {
+            self.problem(&position_key, "Stage positions need \"voxel_size\" to be converted to pixels");
+            return temp;
+        };
+
+        if position.len() != 2 && position.len() != 3 {
+            self.problem(
+                &position_key,
+                format!("Expected 2 or 3 values but got {}", position.len()),
+            );
+            return temp;
+        }
+
+        temp.x = (position[0] / voxel_size.0 as f64).round() as i64;
+        temp.y = (position[1] / voxel_size.1 as f64).round() as i64;
+        temp.z = position.get(2).map_or(0, |z| (z / voxel_size.2 as f64).round() as i64);
+
+        for (name, value, target) in [
+            ("width", tile.width, &mut temp.width),
+            ("height", tile.height, &mut temp.height),
+            ("depth", tile.depth, &mut temp.depth),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+                if value < 1 {
+                    self.problem(&format!("{}.{}", key, name), format!("Tile {} must be at least 1", name));
+                }
+            }
+        }
+
+        temp
+    }
+
+    /**
+     * Pixels of the config `voxel_size` per pixel of a tile with its own `voxel_size`, or `None`
+     * when the tile has none or the same one
+     */
+    fn parse_tile_voxel_size(&mut self, tile: &TileEntry, key: &str, config: &StitchConfig) -> Option<(f32, f32, f32)> {
+        let tile_voxel_size = tile.voxel_size.as_ref()?;
+        let voxel_key = format!("{}.voxel_size", key);
+        if tile.position.is_none() {
+            self.problem(&voxel_key, "A tile voxel size needs a stage \"position\"");
+            return None;
+        }
+        // A missing config voxel size is reported with the position
+        let voxel_size = config.voxel_size?;
+
+        let tile_voxel_size = self.axis_values(&voxel_key, tile_voxel_size, voxel_size);
+        if [tile_voxel_size.0, tile_voxel_size.1, tile_voxel_size.2].iter().any(|size| *size <= 0.0) {
+            self.problem(&voxel_key, "Voxel sizes must be positive");
+            return None;
+        }
+
+        // 2D tiles have no z to resample
+        let z_scale = match config.mode {
+            StitchMode::TwoD => 1.0,
+            StitchMode::ThreeD => tile_voxel_size.2 / voxel_size.2,
+        };
+        let scale = (tile_voxel_size.0 / voxel_size.0, tile_voxel_size.1 / voxel_size.1, z_scale);
+        let same = [scale.0, scale.1, scale.2].iter().all(|scale| (scale - 1.0).abs() <= 1e-3);
+        (!same).then_some(scale)
+    }
+
+    /**
+     * Read the pixel size of tiles whose width, height or depth was not given in the config
+     */
+    fn fill_tile_sizes(&mut self, config: &mut StitchConfig, unknown_sizes: &[(usize, [Option<i64>; 3])]) {
+        let sizes = unknown_sizes
+            .par_iter()
+            .map(|(i, _)| read_tile_size(&config.tile_paths[*i], config.mode))
+            .collect::<Vec<_>>();
+
+        for ((i, given), size) in unknown_sizes.iter().zip(sizes) {
+            match size {
+                Ok((width, height, depth)) => {
+                    let layout = &mut config.tile_layout[*i];
+                    layout.width = given[0].unwrap_or(width as i64);
+                    layout.height = given[1].unwrap_or(height as i64);
+                    layout.depth = given[2].unwrap_or(depth as i64);
+                }
+                Err(err) => self.error(err),
+            }
+        }
+    }
+
+    /**
+     * Place tiles by the pixel spacing and stage position stored in their DICOM or TIFF headers.
+     * Positions are shifted so the first corner of the layout is at the origin.
+     */
+    fn fill_layout_from_metadata(&mut self, config: &mut StitchConfig, tiles: &[(usize, String, [Option<i64>; 3])]) {
+        let Some(unit) = unit_to_meters(&config.unit) else {
+            self.problem("unit", format!("Unknown unit \"{}\"", config.unit));
+            return;
+        };
+
+        let metadata = tiles
+            .par_iter()
+            .map(|(i, _, _)| read_image_metadata(&config.tile_paths[*i]))
+            .collect::<Vec<_>>();
+
+        let mut positions = vec![];
+        for ((i, key, given), metadata) in tiles.iter().zip(metadata) {
+            let metadata = match metadata {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    self.error(err);
+                    continue;
+                }
+            };
+
+            let header_voxel_size = metadata.voxel_size.map(|(x, y, z)| {
+                ((x / unit) as f32, (y / unit) as f32, (z / unit) as f32)
+            });
+            let voxel_size = match (config.voxel_size, header_voxel_size) {
+                (Some(voxel_size), Some(header)) => {
+                    let matches = [(header.0, voxel_size.0), (header.1, voxel_size.1), (header.2, voxel_size.2)]
+                        .iter()
+                        .take(if config.mode == StitchMode::TwoD { 2 } else { 3 })
+                        .all(|(a, b)| (a - b).abs() <= 1e-3 * b.abs());
+                    if !matches {
+                        self.problem(
+                            key,
+                            format!(
+                                "Header voxel size {:?} differs from the config voxel size {:?}, resampling tiles is not supported",
+                                header, voxel_size
+                            ),
+                        );
+                    }
+                    voxel_size
+                }
+                (Some(voxel_size), None) => voxel_size,
+                (None, Some(header)) => {
+                    config.voxel_size = Some(header);
+                    header
+                }
+                (None, None) => {
+                    self.problem(key, "No pixel spacing in the image headers, set \"voxel_size\"");
+                    continue;
+                }
+            };
+
+            let Some(position) = metadata.position else {
+                self.problem(key, "No stage position in the image headers");
+                continue;
+            };
+
+            let layout = &mut config.tile_layout[*i];
+            layout.width = given[0].unwrap_or(metadata.width as i64);
+            layout.height = given[1].unwrap_or(metadata.height as i64);
+            layout.depth = match config.mode {
+                StitchMode::TwoD => 1,
+                StitchMode::ThreeD => given[2].unwrap_or(metadata.depth as i64),
+            };
+
+            let z = match config.mode {
+                StitchMode::TwoD => 0.0,
+                StitchMode::ThreeD => position.2 / unit / voxel_size.2 as f64,
+            };
+            positions.push((
+                *i,
+                [
+                    position.0 / unit / voxel_size.0 as f64,
+                    position.1 / unit / voxel_size.1 as f64,
+                    z,
+                ],
+            ));
+        }
+
+        let mut origin = [f64::INFINITY; 3];
+        for (_, position) in &positions {
+            for axis in 0..3 {
+                origin[axis] = origin[axis].min(position[axis]);
+            }
+        }
+
+        for (i, position) in positions {
+            let layout = &mut config.tile_layout[i];
+            layout.x = (position[0] - origin[0]).round() as i64;
+            layout.y = (position[1] - origin[1]).round() as i64;
+            layout.z = (position[2] - origin[2]).round() as i64;
+        }
+    }
+
+    fn parse_tile_fields(&mut self, tile: &TileEntry, key: &str) -> IBox3D {
+        let mut temp = IBox3D::new(0, 0, 0, 1, 1, 1);
+        match (tile.x, tile.y) {
+            (Some(x), Some(y)) => {
+                temp.x = x;
+                temp.y = y;
+            }
+            _ => {
+                self.problem(key, "Expected either \"box\" or \"x\" and \"y\"");
+            }
+        }
+        temp.z = tile.z.unwrap_or(temp.z);
+
+        for (name, value, target) in [
+            ("width", tile.width, &mut temp.width),
+            ("height", tile.height, &mut temp.height),
+            ("depth", tile.depth, &mut temp.depth),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+                if value < 1 {
+                    self.problem(&format!("{}.{}", key, name), format!("Tile {} must be at least 1", name));
+                }
+            }
+        }
+
+        temp
+    }
+
+    /**
+     * Parse a layout box given as [x, y], [x, y, z], [x, y, w, h] or [x, y, z, w, h, d]
+     */
+    fn parse_box(&mut self, values: &[i64], key: &str) -> IBox3D {
+        let mut temp = IBox3D::new(0, 0, 0, 1, 1, 1);
+        match values.len() {
+            2 => {
+                temp.x = values[0];
+                temp.y = values[1];
+            }
+            3 => {
+                temp.x = values[0];
+                temp.y = values[1];
+                temp.z = values[2];
+            }
+            4 => {
+                temp.x = values[0];
+                temp.y = values[1];
+                temp.width = values[2];
+                temp.height = values[3];
+            }
+            6 => {
+                temp.x = values[0];
+                temp.y = values[1];
+                temp.z = values[2];
+                temp.width = values[3];
+                temp.height = values[4];
+                temp.depth = values[5];
+            }
+            n => {
+                self.problem(
+                    key,
+                    format!("Invalid tile layout, expected 2, 3, 4 or 6 values but got {}", n),
+                );
+            }
+        }
+
+        if temp.width < 1 || temp.height < 1 || temp.depth < 1 {
+            self.problem(key, "Tile width, height and depth must be at least 1");
+        }
+
+        temp
+    }
+}
+
+/**
+ * Byte span of every object member and array element in a JSON document, keyed by JSON path
+ * (e.g. `tiles[3].width`). Serde only reports line and column, so this maps between the two.
+ */
+struct JsonLocations {
+    text_len: usize,
+    line_starts: Vec<usize>,
+    entries: Vec<(String, usize, usize)>,
+}
+
+impl JsonLocations {
+    fn new(text: &str) -> JsonLocations {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let mut locations = JsonLocations {
+            text_len: text.len(),
+            line_starts,
+            entries: vec![],
+        };
+        let mut pos = 0;
+        locations.scan_value(text.as_bytes(), &mut pos, "");
+        locations
+    }
+
+    fn line_of(&self, path: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|(entry_path, _, _)| entry_path == path)
+            .map(|(_, start, _)| self.line_starts.partition_point(|&line_start| line_start <= *start))
+    }
+
+    /**
+     * Convert a serde data error into a config error pointing at the innermost value containing it
+     */
+    fn serde_error(&self, err: &serde_json::Error) -> StitchError {
+        let suffix = format!(" at line {} column {}", err.line(), err.column());
+        let message = err.to_string().trim_end_matches(&suffix).to_string();
+
+        let offset = self
+            .line_starts
+            .get(err.line().saturating_sub(1))
+            .map(|line_start| (line_start + err.column().saturating_sub(1)).min(self.text_len));
+        let mut key = offset
+            .and_then(|offset| {
+                self.entries
+                    .iter()
+                    .rev()
+                    .find(|(_, start, end)| *start <= offset && offset < *end)
+            })
+            .map(|(path, _, _)| path.clone())
+            .unwrap_or_default();
+
+        if let Some(field) = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split('`').next())
+        {
+            key = join_key(&key, field);
+        }
+
+        let line = if err.line() > 0 { Some(err.line()) } else { None };
+        StitchError::config_at(&key, line, message)
+    }
+
+    fn scan_value(&mut self, bytes: &[u8], pos: &mut usize, path: &str) {
+        skip_whitespace(bytes, pos);
+        match bytes.get(*pos) {
+            Some(b'{') => {
+                *pos += 1;
+                loop {
+                    skip_whitespace(bytes, pos);
+                    match bytes.get(*pos) {
+                        Some(b'"') => {}
+                        Some(b'}') => {
+                            *pos += 1;
+                            return;
+                        }
+                        _ => return,
+                    }
+                    let start = *pos;
+                    let name = scan_string(bytes, pos);
+                    let child = join_key(path, &name);
+                    skip_whitespace(bytes, pos);
+                    if bytes.get(*pos) != Some(&b':') {
+                        return;
+                    }
+                    *pos += 1;
+                    let index = self.entries.len();
+                    self.entries.push((child.clone(), start, start));
+                    self.scan_value(bytes, pos, &child);
+                    self.entries[index].2 = *pos;
+                    if !scan_separator(bytes, pos, b'}') {
+                        return;
+                    }
+                }
+            }
+            Some(b'[') => {
+                *pos += 1;
+                let mut i = 0;
+                loop {
+                    skip_whitespace(bytes, pos);
+                    match bytes.get(*pos) {
+                        Some(b']') => {
+                            *pos += 1;
+                            return;
+                        }
+                        None => return,
+                        _ => {}
+                    }
+                    let child = format!("{}[{}]", path, i);
+                    let index = self.entries.len();
+                    self.entries.push((child.clone(), *pos, *pos));
+                    self.scan_value(bytes, pos, &child);
+                    self.entries[index].2 = *pos;
+                    i += 1;
+                    if !scan_separator(bytes, pos, b']') {
+                        return;
+                    }
+                }
+            }
+            Some(b'"') => {
+                scan_string(bytes, pos);
+            }
+            Some(_) => {
+                while let Some(c) = bytes.get(*pos) {
+                    if matches!(c, b',' | b']' | b'}') || c.is_ascii_whitespace() {
+                        break;
+                    }
+                    *pos += 1;
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+fn join_key(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], pos: &mut usize) {
+    while bytes.get(*pos).is_some_and(|c| c.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+}
+
+/**
+ * Consume a `,` and return true to continue, or consume the closing bracket and return false
+ */
+fn scan_separator(bytes: &[u8], pos: &mut usize, close: u8) -> bool {
+    skip_whitespace(bytes, pos);
+    match bytes.get(*pos) {
+        Some(b',') => {
+            *pos += 1;
+            true
+        }
+        Some(c) if *c == close => {
+            *pos += 1;
+            false
+        }
+        _ => false,
+    }
+}
+
+fn scan_string(bytes: &[u8], pos: &mut usize) -> String {
+    let start = *pos + 1;
+    *pos += 1;
+    while let Some(c) = bytes.get(*pos) {
+        match c {
+            b'\\' => *pos += 2,
+            b'"' => break,
+            _ => *pos += 1,
+        }
+    }
+    let end = (*pos).min(bytes.len());
+    *pos += 1;
+    String::from_utf8_lossy(&bytes[start..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serde_error(text: &str) -> StitchError {
+        let err = serde_json::from_str::<StitchConfigFile>(text).unwrap_err();
+        JsonLocations::new(text).serde_error(&err)
+    }
+
+    #[test]
+    fn locates_nested_members_and_elements() {
+        let text = [
+            "{",
+            "  \"mode\": \"3d\",",
+            "  \"tiles\": [",
+            "    {\"path\": \"a.tif\"},",
+            "    {",
+            "      \"path\": \"b.tif\"",
+            "    }",
+            "  ]",
+            "}",
+        ]
+        .join("\n");
+        let locations = JsonLocations::new(&text);
+        assert_eq!(locations.line_of("mode"), Some(2));
+        assert_eq!(locations.line_of("tiles"), Some(3));
+        assert_eq!(locations.line_of("tiles[0].path"), Some(4));
+        assert_eq!(locations.line_of("tiles[1]"), Some(5));
+        assert_eq!(locations.line_of("tiles[1].path"), Some(6));
+        assert_eq!(locations.line_of("tiles[2]"), None);
+    }
+
+    #[test]
+    fn skips_escaped_quotes_in_strings() {
+        let text = "{\"unit\": \"a \\\" }, \\\\\",\n\"mode\": \"2d\"}";
+        let locations = JsonLocations::new(text);
+        assert_eq!(locations.line_of("unit"), Some(1));
+        assert_eq!(locations.line_of("mode"), Some(2));
+    }
+
+    #[test]
+    fn keeps_members_before_malformed_input() {
+        let truncated = [
+            "{\"mode\": \"2d\",\n\"tiles\": [1, 2",
+            "{\"mode\": \"2d\",\n\"unit\" 3}",
+            "{\"mode\": \"2d\",\n\"unit\": \"",
+            "",
+        ];
+        for text in truncated {
+            let locations = JsonLocations::new(text);
+            assert_eq!(locations.line_of("mode"), (!text.is_empty()).then_some(1), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn serde_errors_point_at_innermost_value() {
+        let err = serde_error("{\n  \"mode\": \"3d\",\n  \"tiles\": [\n    {\"path\": \"a.tif\", \"x\": []}\n  ]\n}");
+        let located = matches!(&err, StitchError::Config { key, line: Some(4), .. } if key == "tiles[0].x");
+        assert!(located, "{:?}", err);
+
+        let err = serde_error("{\n  \"mode\": \"4d\"\n}");
+        assert!(matches!(&err, StitchError::Config { key, line: Some(2), .. } if key == "mode"), "{:?}", err);
+    }
+
+    #[test]
+    fn serde_errors_name_missing_fields() {
+        let err = serde_error("{\n  \"mode\": \"3d\",\n  \"tiles\": [\n    {\"box\": [0, 0, 0, 1, 1, 1]}\n  ]\n}");
+        assert!(matches!(&err, StitchError::Config { key, .. } if key == "tiles[0].path"), "{:?}", err);
+
+        let err = serde_error("{\"tiles\": []}");
+        assert!(matches!(&err, StitchError::Config { key, .. } if key == "mode"), "{:?}", err);
+    }
+
+    #[test]
+    fn tiles_with_their_own_voxel_size_are_resampled() {
+        let dir = std::env::temp_dir().join(format!("stitch-tile-voxels-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        let tile = |position: &str, voxel_size: &str| {
+            format!(
+                r#"{{ "path": "t.tif", "position": {}, "width": 10, "height": 8, "depth": 5{} }}"#,
+                position, voxel_size
+            )
+        };
+        let tiles = [
+            tile("[0, 0, 0]", ""),
+            tile("[4, 1, 2]", r#", "voxel_size": [1, 1, 2]"#),
+            tile("[9, 0, 0]", r#", "voxel_size": [0.5, 0.5]"#),
+        ];
+        let text = format!(
+            r#"{{ "mode": "3d", "voxel_size": [0.5, 0.5, 2], "tiles": [{}] }}"#,
+            tiles.join(", ")
+        );
+        std::fs::write(&path, text).unwrap();
+
+        let config = validate_config_file(&path).unwrap();
+        assert_eq!(config.tile_scales, vec![None, Some((2.0, 2.0, 1.0)), None]);
+        // Positions are physical, sizes are scaled from the pixels of the tile
+        assert_eq!(config.tile_layout[1], IBox3D::new(8, 2, 1, 20, 16, 5));
+        assert_eq!(config.tile_layout[2], IBox3D::new(18, 0, 0, 10, 8, 5));
+
+        let text = r#"{ "mode": "3d", "voxel_size": 1, "tiles": [{ "path": "t.tif", "box": [0, 0], "voxel_size": 2 }] }"#;
+        std::fs::write(&path, text).unwrap();
+        let problems = validate_config_file(&path).unwrap_err();
+        assert!(matches!(&problems[0], StitchError::Config { key, .. } if key == "tiles[0].voxel_size"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overridden_settings_are_range_checked() {
+        assert!(StitchConfig::new().problems().is_empty());
+        let mut config = StitchConfig::new();
+        config.overlap_ratio = (1.5, 0.2, 0.2);
+        config.prior_sigmas = (f32::NAN, 1.0, 1.0);
+        config.check_peaks = 0;
+        config.output_scaling = Some(OutputScaling::Percentiles([99.0, 1.0]));
+        config.fuse_memory_budget = Some(0);
+        let keys = config
+            .problems()
+            .into_iter()
+            .map(|problem| match problem {
+                StitchError::Config { key, .. } => key,
+                other => panic!("unexpected error {other}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["overlap_ratio", "prior_sigma", "check_peaks", "output_scaling", "fuse_memory_gb"]);
+    }
+}
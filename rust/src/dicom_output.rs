@@ -0,0 +1,482 @@
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::object::mem::InMemElement;
+use dicom::object::{FileMetaTableBuilder, InMemDicomObject, OpenFileOptions};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::{Result, StitchError};
+use crate::intensity::{PixelConversion, PixelData};
+
+/// Patient, study and equipment tags carried over from the first tile of a subgraph.
+const COPIED_TAGS: &[Tag] = &[
+    tags::SPECIFIC_CHARACTER_SET,
+    tags::STUDY_DATE,
+    tags::STUDY_TIME,
+    tags::ACCESSION_NUMBER,
+    tags::MODALITY,
+    tags::MANUFACTURER,
+    tags::INSTITUTION_NAME,
+    tags::REFERRING_PHYSICIAN_NAME,
+    tags::STUDY_DESCRIPTION,
+    tags::PATIENT_NAME,
+    tags::PATIENT_ID,
+    tags::PATIENT_BIRTH_DATE,
+    tags::PATIENT_SEX,
+    tags::PATIENT_AGE,
+    tags::PATIENT_SIZE,
+    tags::PATIENT_WEIGHT,
+    tags::BODY_PART_EXAMINED,
+    tags::PATIENT_POSITION,
+    tags::STUDY_INSTANCE_UID,
+    tags::STUDY_ID,
+    tags::FRAME_OF_REFERENCE_UID,
+];
+
+const SECONDARY_CAPTURE: &str = "1.2.840.10008.5.1.4.1.1.7";
+const MULTI_FRAME_BYTE_SECONDARY_CAPTURE: &str = "1.2.840.10008.5.1.4.1.1.7.2";
+const MULTI_FRAME_WORD_SECONDARY_CAPTURE: &str = "1.2.840.10008.5.1.4.1.1.7.3";
+const IMPLEMENTATION_CLASS_UID: &str = "1.3.6.1.4.1.9590.100.1.3.100.9.4";
+
+/**
+ * Geometry and copied metadata of a fused volume written as DICOM
+ */
+#[derive(Clone, Debug)]
+pub struct DicomHeader {
+    /// Spacing along x, y and z in millimetres.
+    pub spacing: (f64, f64, f64),
+    /// Image Orientation (Patient): direction cosines of a row, then of a column.
+    pub orientation: [f64; 6],
+    /// Image Position (Patient) of the first voxel in millimetres.
+    pub origin: [f64; 3],
+    /// Patient, study and equipment elements of the reference tile.
+    pub elements: Vec<InMemElement>,
+}
+
+impl DicomHeader {
+    /**
+     * Header of a fused volume. With a DICOM `reference` tile its patient and study tags are kept,
+     * its orientation is used and the origin is placed so the tile lands at its own Image Position,
+     * `reference_offset` being the tile's position in the fused volume. Otherwise `origin` is used.
+     * Spacing falls back to the reference Pixel Spacing and then to 1 mm.
+     */
+    pub fn new(
+        reference: Option<&Path>,
+        spacing: Option<(f64, f64, f64)>,
+        origin: [f64; 3],
+        reference_offset: (f32, f32, f32),
+    ) -> Result<DicomHeader> {
+        let Some(reference) = reference else {
+            return Ok(DicomHeader {
+                spacing: spacing.unwrap_or((1.0, 1.0, 1.0)),
+                orientation: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+                origin,
+                elements: vec![],
+            });
+        };
+
+        let dicom_obj = OpenFileOptions::new()
+            .read_until(tags::PIXEL_DATA)
+            .open_file(reference)
+            .map_err(|e| StitchError::format(reference, e))?;
+        let floats = |tag| -> Option<Vec<f64>> {
+            dicom_obj
+                .element_opt(tag)
+                .ok()
+                .flatten()
+                .and_then(|element| element.value().to_multi_float64().ok())
+        };
+
+        // Pixel Spacing is row spacing (y) then column spacing (x)
+        let spacing = spacing
+            .or_else(|| {
+                let pixel_spacing = floats(tags::PIXEL_SPACING).filter(|spacing| spacing.len() == 2)?;
+                let z = floats(tags::SPACING_BETWEEN_SLICES)
+                    .or_else(|| floats(tags::SLICE_THICKNESS))
+                    .and_then(|values| values.first().copied())
+                    .unwrap_or(pixel_spacing[0]);
+                Some((pixel_spacing[1], pixel_spacing[0], z))
+            })
+            .unwrap_or((1.0, 1.0, 1.0));
+        let orientation = floats(tags::IMAGE_ORIENTATION_PATIENT)
+            .and_then(|orientation| <[f64; 6]>::try_from(orientation).ok())
+            .unwrap_or([1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+
+        let mut header = DicomHeader {
+            spacing,
+            orientation,
+            origin,
+            elements: COPIED_TAGS
+                .iter()
+                .filter_map(|tag| dicom_obj.element_opt(*tag).ok().flatten().cloned())
+                .collect(),
+        };
+        if let Some(position) = floats(tags::IMAGE_POSITION_PATIENT).filter(|position| position.len() == 3) {
+            let shift = header.voxel_to_patient((
+                reference_offset.0 as f64,
+                reference_offset.1 as f64,
+                reference_offset.2 as f64,
+            ));
+            header.origin = [position[0] - shift[0], position[1] - shift[1], position[2] - shift[2]];
+        }
+
+        Ok(header)
+    }
+
+    fn normal(&self) -> [f64; 3] {
+        let (row, column) = (&self.orientation[..3], &self.orientation[3..]);
+        [
+            row[1] * column[2] - row[2] * column[1],
+            row[2] * column[0] - row[0] * column[2],
+            row[0] * column[1] - row[1] * column[0],
+        ]
+    }
+
+    /**
+     * Patient space displacement of a voxel position relative to the first voxel
+     */
+    fn voxel_to_patient(&self, position: (f64, f64, f64)) -> [f64; 3] {
+        let normal = self.normal();
+        let (row, column) = (&self.orientation[..3], &self.orientation[3..]);
+        let (x, y, z) = (position.0 * self.spacing.0, position.1 * self.spacing.1, position.2 * self.spacing.2);
+        [0, 1, 2].map(|axis| row[axis] * x + column[axis] * y + normal[axis] * z)
+    }
+
+    /**
+     * Image Position (Patient) of slice `z`
+     */
+    fn slice_position(&self, z: usize) -> [f64; 3] {
+        let shift = self.voxel_to_patient((0.0, 0.0, z as f64));
+        [0, 1, 2].map(|axis| self.origin[axis] + shift[axis])
+    }
+
+    fn slice_location(&self, z: usize) -> f64 {
+        let position = self.slice_position(z);
+        let normal = self.normal();
+        (0..3).map(|axis| position[axis] * normal[axis]).sum()
+    }
+}
+
+/**
+ * Writer of a fused volume as one multi-frame DICOM file or as a directory with one DICOM file
+ * per slice. Slices are appended in z order and pixel data is written as it arrives.
+ */
+pub struct DicomWriter {
+    path: PathBuf,
+    header: DicomHeader,
+    conversion: PixelConversion,
+    size: (usize, usize, usize),
+    series_uid: String,
+    dataset: InMemDicomObject,
+    /// Open multi-frame file, `None` when writing a series.
+    file: Option<BufWriter<std::fs::File>>,
+    slices_written: usize,
+}
+
+impl DicomWriter {
+    /**
+     * Create the output file, or the directory for a `series`. Every file gets fresh SOP
+     * Instance UIDs and one Series Instance UID; the Study Instance UID and Frame of Reference
+     * UID come from the reference tile when it has them.
+     */
+    pub fn create(
+        path: &Path,
+        header: &DicomHeader,
+        series: bool,
+        conversion: &PixelConversion,
+        size: (usize, usize, usize),
+    ) -> Result<DicomWriter> {
+        let (width, height, depth) = size;
+        let Some(max_value) = conversion.dtype.max_value() else {
+            return Err(StitchError::format(path, "DICOM output supports u8 and u16 pixels only"));
+        };
+        if width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(StitchError::format(
+                path,
+                format!("DICOM images are limited to {} pixels per side", u16::MAX),
+            ));
+        }
+
+        let mut dataset = InMemDicomObject::new_empty();
+        for element in &header.elements {
+            dataset.put(element.clone());
+        }
+        if dataset.element_opt(tags::STUDY_INSTANCE_UID).ok().flatten().is_none() {
+            put_str(&mut dataset, tags::STUDY_INSTANCE_UID, VR::UI, new_uid());
+        }
+        if dataset.element_opt(tags::FRAME_OF_REFERENCE_UID).ok().flatten().is_none() {
+            put_str(&mut dataset, tags::FRAME_OF_REFERENCE_UID, VR::UI, new_uid());
+        }
+        if dataset.element_opt(tags::MODALITY).ok().flatten().is_none() {
+            put_str(&mut dataset, tags::MODALITY, VR::CS, "OT".to_string());
+        }
+
+        let series_uid = new_uid();
+        let sop_class = match (series, conversion.dtype.bits()) {
+            (true, _) => SECONDARY_CAPTURE,
+            (false, 8) => MULTI_FRAME_BYTE_SECONDARY_CAPTURE,
+            (false, _) => MULTI_FRAME_WORD_SECONDARY_CAPTURE,
+        };
+        let bits = conversion.dtype.bits();
+        put_str(&mut dataset, tags::IMAGE_TYPE, VR::CS, "DERIVED\\SECONDARY".to_string());
+        put_str(&mut dataset, tags::SOP_CLASS_UID, VR::UI, sop_class.to_string());
+        put_str(&mut dataset, tags::CONVERSION_TYPE, VR::CS, "WSD".to_string());
+        put_str(&mut dataset, tags::SERIES_DESCRIPTION, VR::LO, "Stitched volume".to_string());
+        put_str(&mut dataset, tags::SERIES_INSTANCE_UID, VR::UI, series_uid.clone());
+        put_str(&mut dataset, tags::SERIES_NUMBER, VR::IS, "1".to_string());
+        put_str(&mut dataset, tags::IMAGE_ORIENTATION_PATIENT, VR::DS, decimal_strings(&header.orientation));
+        dataset.put(DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1_u16)));
+        put_str(&mut dataset, tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2".to_string());
+        dataset.put(DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(height as u16)));
+        dataset.put(DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(width as u16)));
+        put_str(
+            &mut dataset,
+            tags::PIXEL_SPACING,
+            VR::DS,
+            decimal_strings(&[header.spacing.1, header.spacing.0]),
+        );
+        put_str(&mut dataset, tags::SLICE_THICKNESS, VR::DS, decimal_string(header.spacing.2));
+        put_str(&mut dataset, tags::SPACING_BETWEEN_SLICES, VR::DS, decimal_string(header.spacing.2));
+        dataset.put(DataElement::new(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(bits)));
+        dataset.put(DataElement::new(tags::BITS_STORED, VR::US, PrimitiveValue::from(bits)));
+        dataset.put(DataElement::new(tags::HIGH_BIT, VR::US, PrimitiveValue::from(bits - 1)));
+        dataset.put(DataElement::new(tags::PIXEL_REPRESENTATION, VR::US, PrimitiveValue::from(0_u16)));
+        dataset.put(DataElement::new(tags::SMALLEST_IMAGE_PIXEL_VALUE, VR::US, PrimitiveValue::from(0_u16)));
+        dataset.put(DataElement::new(
+            tags::LARGEST_IMAGE_PIXEL_VALUE,
+            VR::US,
+            PrimitiveValue::from(max_value as u16),
+        ));
+        // Map stored pixels back to fused values when they were stretched over the integer range
+        if let Some((intercept, slope)) = conversion.rescale() {
+            put_str(&mut dataset, tags::RESCALE_INTERCEPT, VR::DS, decimal_string(intercept as f64));
+            put_str(&mut dataset, tags::RESCALE_SLOPE, VR::DS, decimal_string(slope as f64));
+            put_str(&mut dataset, tags::RESCALE_TYPE, VR::LO, "US".to_string());
+        }
+
+        let mut writer = DicomWriter {
+            path: path.to_path_buf(),
+            header: header.clone(),
+            conversion: *conversion,
+            size,
+            series_uid,
+            dataset,
+            file: None,
+            slices_written: 0,
+        };
+
+        if series {
+            std::fs::create_dir_all(path).map_err(|e| StitchError::io(path, e))?;
+            return Ok(writer);
+        }
+
+        let pixel_bytes = (width * height * depth * bits as usize / 8) as u64;
+        let padded_bytes = pixel_bytes + pixel_bytes % 2;
+        if padded_bytes >= u32::MAX as u64 {
+            return Err(StitchError::format(
+                path,
+                "Pixel data exceeds 4 GB, write a DICOM series with \"output_format\" dcm-series instead",
+            ));
+        }
+
+        let mut dataset = writer.dataset.clone();
+        let sop_instance_uid = new_uid();
+        put_str(&mut dataset, tags::SOP_INSTANCE_UID, VR::UI, sop_instance_uid.clone());
+        put_str(&mut dataset, tags::INSTANCE_NUMBER, VR::IS, "1".to_string());
+        put_str(&mut dataset, tags::IMAGE_POSITION_PATIENT, VR::DS, decimal_strings(&header.slice_position(0)));
+        put_str(&mut dataset, tags::NUMBER_OF_FRAMES, VR::IS, depth.to_string());
+        dataset.put(DataElement::new(
+            tags::FRAME_INCREMENT_POINTER,
+            VR::AT,
+            PrimitiveValue::from(tags::SLICE_LOCATION_VECTOR),
+        ));
+        let locations = (0..depth).map(|z| header.slice_location(z)).collect::<Vec<_>>();
+        put_str(&mut dataset, tags::SLICE_LOCATION_VECTOR, VR::DS, decimal_strings(&locations));
+
+        let file_obj = dataset
+            .with_meta(file_meta(sop_class, &sop_instance_uid))
+            .map_err(|e| StitchError::format(path, e))?;
+        let file = std::fs::File::create(path).map_err(|e| StitchError::io(path, e))?;
+        let mut file = BufWriter::new(file);
+        file_obj.write_all(&mut file).map_err(|e| StitchError::format(path, e))?;
+
+        // Pixel Data goes last and is streamed, so its explicit VR little endian header is
+        // written by hand with the final length
+        let mut element_header = vec![0xE0, 0x7F, 0x10, 0x00];
+        element_header.extend_from_slice(if bits == 8 { b"OB" } else { b"OW" });
+        element_header.extend_from_slice(&[0, 0]);
+        element_header.extend_from_slice(&(padded_bytes as u32).to_le_bytes());
+        file.write_all(&element_header).map_err(|e| StitchError::io(path, e))?;
+
+        writer.file = Some(file);
+        Ok(writer)
+    }
+
+    /**
+     * Append whole slices of the volume, given as fused values
+     */
+    pub fn write_slices(&mut self, data: &[f32]) -> Result<()> {
+        let (width, height, _) = self.size;
+        for slice in data.chunks(width * height) {
+            let bytes = match self.conversion.convert(slice) {
+                PixelData::U8(data) => data,
+                PixelData::U16(data) => data.iter().flat_map(|value| value.to_le_bytes()).collect(),
+                PixelData::F32(_) => unreachable!("float pixels are rejected in create"),
+            };
+
+            match &mut self.file {
+                Some(file) => file.write_all(&bytes).map_err(|e| StitchError::io(&self.path, e))?,
+                None => self.write_series_slice(bytes)?,
+            }
+            self.slices_written += 1;
+        }
+        Ok(())
+    }
+
+    fn write_series_slice(&self, mut bytes: Vec<u8>) -> Result<()> {
+        let z = self.slices_written;
+        let file_path = self.path.join(format!("slice_{:05}.dcm", z));
+        let sop_instance_uid = new_uid();
+        let vr = if self.conversion.dtype.bits() == 8 { VR::OB } else { VR::OW };
+        if bytes.len() % 2 == 1 {
+            bytes.push(0);
+        }
+
+        let mut dataset = self.dataset.clone();
+        put_str(&mut dataset, tags::SOP_INSTANCE_UID, VR::UI, sop_instance_uid.clone());
+        put_str(&mut dataset, tags::INSTANCE_NUMBER, VR::IS, (z + 1).to_string());
+        put_str(&mut dataset, tags::IMAGE_POSITION_PATIENT, VR::DS, decimal_strings(&self.header.slice_position(z)));
+        put_str(&mut dataset, tags::SLICE_LOCATION, VR::DS, decimal_string(self.header.slice_location(z)));
+        dataset.put(DataElement::new(tags::PIXEL_DATA, vr, PrimitiveValue::from(bytes)));
+
+        dataset
+            .with_meta(file_meta(SECONDARY_CAPTURE, &sop_instance_uid))
+            .map_err(|e| StitchError::format(&file_path, e))?
+            .write_to_file(&file_path)
+            .map_err(|e| StitchError::format(&file_path, e))
+    }
+
+    /**
+     * Pad the pixel data to an even length and close the file
+     */
+    pub fn finish(self) -> Result<()> {
+        let depth = self.size.2;
+        if self.slices_written != depth {
+            return Err(StitchError::format(
+                &self.path,
+                format!("Expected {} slices, {} were written", depth, self.slices_written),
+            ));
+        }
+
+        if let Some(mut file) = self.file {
+            let (width, height, depth) = self.size;
+            let pixel_bytes = width * height * depth * self.conversion.dtype.bits() as usize / 8;
+            if pixel_bytes % 2 == 1 {
+                file.write_all(&[0]).map_err(|e| StitchError::io(&self.path, e))?;
+            }
+            file.flush().map_err(|e| StitchError::io(&self.path, e))?;
+        }
+        println!("DICOM series {} written to {:?}", self.series_uid, self.path);
+        Ok(())
+    }
+}
+
+fn file_meta(sop_class: &str, sop_instance_uid: &str) -> FileMetaTableBuilder {
+    FileMetaTableBuilder::new()
+        .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+        .media_storage_sop_class_uid(sop_class)
+        .media_storage_sop_instance_uid(sop_instance_uid)
+        .implementation_class_uid(IMPLEMENTATION_CLASS_UID)
+}
+
+fn put_str(dataset: &mut InMemDicomObject, tag: Tag, vr: VR, value: String) {
+    dataset.put(DataElement::new(tag, vr, PrimitiveValue::from(value)));
+}
+
+/**
+ * Format a number as a DICOM decimal string, which holds at most 16 characters
+ */
+fn decimal_string(value: f64) -> String {
+    (0..=8)
+        .rev()
+        .map(|precision| format!("{:.*}", precision, value))
+        .map(|text| match text.contains('.') {
+            true => text.trim_end_matches('0').trim_end_matches('.').to_string(),
+            false => text,
+        })
+        .find(|text| text.len() <= 16)
+        .unwrap_or_else(|| format!("{:.6e}", value))
+}
+
+fn decimal_strings(values: &[f64]) -> String {
+    values.iter().map(|value| decimal_string(*value)).collect::<Vec<_>>().join("\\")
+}
+
+/**
+ * A new UID under the `2.25` root, derived from a random 128-bit number
+ */
+pub fn new_uid() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64);
+    let random = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.write_u64(std::process::id() as u64);
+        hasher.finish()
+    };
+    let value = ((random(nanos) as u128) << 64) | random(count) as u128;
+    format!("2.25.{}", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{read_dcm_files, read_dcm_frames, read_dcm_headers};
+    use crate::intensity::{OutputDtype, OutputScaling};
+
+    #[test]
+    fn fused_volumes_read_back_as_written() {
+        let dir = std::env::temp_dir().join(format!("stitch-dcm-writer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (width, height, depth) = (3, 2, 4);
+        let data = (0..width * height * depth).map(|i| i as f32 * 0.5).collect::<Vec<_>>();
+        let conversion = PixelConversion::new(OutputDtype::U16, OutputScaling::Range([0.0, 12.0]), (0.0, 0.0), None);
+        let header = DicomHeader::new(None, Some((0.5, 0.5, 2.0)), [10.0, 20.0, 30.0], (0.0, 0.0, 0.0)).unwrap();
+
+        for series in [false, true] {
+            let path = dir.join(if series { "series" } else { "volume.dcm" });
+            let mut writer = DicomWriter::create(&path, &header, series, &conversion, (width, height, depth)).unwrap();
+            writer.write_slices(&data[..width * height]).unwrap();
+            writer.write_slices(&data[width * height..]).unwrap();
+            writer.finish().unwrap();
+
+            let file = read_dcm_headers(&path).unwrap();
+            assert_eq!((file.width, file.height, file.depth), (width, height, depth));
+            let files = read_dcm_files(&path).unwrap();
+            assert_eq!(files.len(), if series { depth } else { 1 });
+            assert_eq!(files[0].position, Some([10.0, 20.0, 30.0]));
+            if series {
+                assert_eq!(files[3].position, Some([10.0, 20.0, 36.0]));
+            }
+
+            // Rescale Slope and Intercept map the stored pixels back to fused values
+            let image = file.get_image().unwrap();
+            assert!(image.data.iter().zip(&data).all(|(read, written)| (read - written).abs() < 1e-3));
+            let last_frame = if series { 0 } else { depth - 1 };
+            let frames = read_dcm_frames(&files[files.len() - 1], last_frame, last_frame + 1).unwrap();
+            let last = &data[(depth - 1) * width * height..];
+            assert!(frames.data.iter().zip(last).all(|(read, written)| (read - written).abs() < 1e-3));
+
+            // A written file can serve as the reference header of a later run
+            let reference = DicomHeader::new(Some(&files[0].path), None, [0.0; 3], (0.0, 0.0, 0.0)).unwrap();
+            assert_eq!(reference.spacing, (0.5, 0.5, 2.0));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
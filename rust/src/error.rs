@@ -0,0 +1,108 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/**
+ * Error type returned by every fallible operation in the crate
+ */
+#[derive(Debug)]
+pub enum StitchError {
+    /// The configuration is missing a key or a key has an invalid value. `key` is the JSON path
+    /// of the offending value and `line` its line in the config file, when known.
+    Config {
+        key: String,
+        line: Option<usize>,
+        message: String,
+    },
+    /// Reading or writing a file failed.
+    Io { path: PathBuf, source: std::io::Error },
+    /// A file could be opened but its contents could not be decoded or encoded.
+    Format { path: PathBuf, message: String },
+    /// The file stores pixels in a type we do not know how to convert.
+    UnsupportedPixelType { path: PathBuf, pixel_type: String },
+    /// Registration or optimization could not produce a result.
+    Numerical(String),
+}
+
+pub type Result<T> = std::result::Result<T, StitchError>;
+
+impl StitchError {
+    pub fn config(key: &str, message: impl Into<String>) -> StitchError {
+        StitchError::Config {
+            key: key.to_string(),
+            line: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn config_at(key: &str, line: Option<usize>, message: impl Into<String>) -> StitchError {
+        StitchError::Config {
+            key: key.to_string(),
+            line,
+            message: message.into(),
+        }
+    }
+
+    pub fn io(path: &Path, source: std::io::Error) -> StitchError {
+        StitchError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn format(path: &Path, message: impl fmt::Display) -> StitchError {
+        StitchError::Format {
+            path: path.to_path_buf(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn unsupported_pixel_type(path: &Path, pixel_type: impl fmt::Debug) -> StitchError {
+        StitchError::UnsupportedPixelType {
+            path: path.to_path_buf(),
+            pixel_type: format!("{:?}", pixel_type),
+        }
+    }
+
+    /**
+     * Process exit code used by the CLI for this kind of error
+     */
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StitchError::Config { .. } => 2,
+            StitchError::Io { .. } => 3,
+            StitchError::Format { .. } => 4,
+            StitchError::UnsupportedPixelType { .. } => 5,
+            StitchError::Numerical(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for StitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StitchError::Config {
+                key,
+                line: Some(line),
+                message,
+            } => write!(f, "Invalid config key \"{}\" (line {}): {}", key, line, message),
+            StitchError::Config { key, message, .. } => {
+                write!(f, "Invalid config key \"{}\": {}", key, message)
+            }
+            StitchError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            StitchError::Format { path, message } => write!(f, "{}: {}", path.display(), message),
+            StitchError::UnsupportedPixelType { path, pixel_type } => {
+                write!(f, "{}: unsupported pixel type {}", path.display(), pixel_type)
+            }
+            StitchError::Numerical(message) => write!(f, "Numerical failure: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StitchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StitchError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use rayon::prelude::*;
+use serde::Deserialize;
 
+use crate::error::Result;
 use crate::image::*;
 
 const DO_SUBPIXEL: bool = true;
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum FuseMode {
     Linear,
     Average,
@@ -25,7 +28,7 @@ pub fn get_linear_weight_3d(
     min_distance *= (offset.2.min(dim.2 - offset.2 - 1) + 1) as f32;
 
     min_distance += 1.0;
-    return min_distance.powf(alpha);
+    min_distance.powf(alpha)
 }
 
 pub fn fuse_2d(
@@ -74,8 +77,8 @@ pub fn fuse_2d(
 
         let start_x = offset_i.0.max(0);
         let start_y = offset_i.1.max(0);
-        let end_x = (offset_i.0 + image.width as i64).min(width as i64);
-        let end_y = (offset_i.1 + image.height as i64).min(height as i64);
+        let end_x = (offset_i.0 + image.width as i64).min(width);
+        let end_y = (offset_i.1 + image.height as i64).min(height);
 
         for y in start_y..end_y {
             for x in start_x..end_x {
@@ -254,9 +257,23 @@ pub fn calc_new_dim(
 }
 
 pub fn get_val(src_pos: (usize, usize, usize), offset_f: (f32, f32, f32), image: &Image3D) -> f32 {
+    get_val_in_slices(src_pos, offset_f, image, 0, image.depth)
+}
+
+/**
+ * Like [`get_val`] for an image holding only the slices of a `full_depth` tile starting at
+ * `first_slice`. `src_pos` is in full tile coordinates and the slice before it must be loaded.
+ */
+pub fn get_val_in_slices(
+    src_pos: (usize, usize, usize),
+    offset_f: (f32, f32, f32),
+    image: &Image3D,
+    first_slice: usize,
+    full_depth: usize,
+) -> f32 {
     let src_x = src_pos.0;
     let src_y = src_pos.1;
-    let src_z = src_pos.2;
+    let src_z = src_pos.2 - first_slice;
     let offset_fi = (1.0 - offset_f.0, 1.0 - offset_f.1, 1.0 - offset_f.2);
     let mut val = 0.0;
     if DO_SUBPIXEL {
@@ -265,18 +282,18 @@ pub fn get_val(src_pos: (usize, usize, usize), offset_f: (f32, f32, f32), image:
         let prev_x = if src_x > 0 {
             src_x - 1
         } else {
-            1.min(image.width - 1) as usize
+            1.min(image.width - 1)
         };
         let prev_y = if src_y > 0 {
             src_y - 1
         } else {
-            1.min(image.height - 1) as usize
+            1.min(image.height - 1)
         };
-        let prev_z = if src_z > 0 {
-            src_z - 1
+        let prev_z = if src_pos.2 > 0 {
+            src_pos.2 - 1
         } else {
-            1.min(image.depth - 1) as usize
-        };
+            1.min(full_depth - 1)
+        } - first_slice;
         val += image.get(prev_x, src_y, src_z) * offset_f.0 * offset_fi.1 * offset_fi.2;
         val += image.get(src_x, prev_y, src_z) * offset_fi.0 * offset_f.1 * offset_fi.2;
         val += image.get(src_x, src_y, prev_z) * offset_fi.0 * offset_fi.1 * offset_f.2;
@@ -290,277 +307,182 @@ pub fn get_val(src_pos: (usize, usize, usize), offset_f: (f32, f32, f32), image:
 
     val
 }
+
 pub fn fuse_3d_float(
     images: &[Image3DFile],
     subgraph_indexes: &[usize],
     offsets: &[(f32, f32, f32)],
     mode: FuseMode,
-) -> Image3D {
-    let num_images: usize = subgraph_indexes.len();
-    let (width, height, depth, min, max) = calc_new_dim(images, subgraph_indexes, offsets);
+) -> Result<Image3D> {
+    let fuser = SlabFuser::new(images, subgraph_indexes, offsets, mode);
+    println!("Fusing image {} x {} x {}", fuser.width, fuser.height, fuser.depth);
+    let image = fuser.fuse_slab(0, fuser.depth)?;
+    println!("Image fused!");
+    Ok(image)
+}
 
-    let alpha = 1.5;
-    println!("Fusing image {} x {} x {}", width, height, depth);
-    let mut new_image_counts: Vec<u8> = vec![];
-    let mut new_image_weights: Vec<f32> = vec![];
-    if mode == FuseMode::Average {
-        new_image_counts = vec![0; (width * height * depth) as usize];
-    } else if mode == FuseMode::Linear {
-        new_image_weights = vec![0.0; (width * height * depth) as usize];
-    } else if FuseMode::OverwritePrioritizeCenter == mode {
-        new_image_weights = vec![0.0; (width * height * depth) as usize];
+/**
+ * Fuses a subgraph of 3D tiles one z-slab at a time. Only the slices of each tile that intersect
+ * the slab are read, so the output can be written out slab by slab without ever being in memory.
+ */
+pub struct SlabFuser<'a> {
+    images: &'a [Image3DFile],
+    subgraph_indexes: &'a [usize],
+    offsets: &'a [(f32, f32, f32)],
+    mode: FuseMode,
+    pub width: usize,
+    pub height: usize,
+    pub depth: usize,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl<'a> SlabFuser<'a> {
+    pub fn new(
+        images: &'a [Image3DFile],
+        subgraph_indexes: &'a [usize],
+        offsets: &'a [(f32, f32, f32)],
+        mode: FuseMode,
+    ) -> SlabFuser<'a> {
+        let (width, height, depth, min, max) = calc_new_dim(images, subgraph_indexes, offsets);
+        SlabFuser {
+            images,
+            subgraph_indexes,
+            offsets,
+            mode,
+            width,
+            height,
+            depth,
+            min,
+            max,
+        }
     }
 
-    let default_value = match mode {
-        FuseMode::Average => 0,
-        FuseMode::Max => 0,
-        FuseMode::Min => 255,
-        FuseMode::Overwrite => 0,
-        FuseMode::Linear => 0,
-        FuseMode::OverwritePrioritizeCenter => 0,
-    };
-    let mut new_image_float: Vec<f32> =
-        vec![default_value as f32; (width * height * depth) as usize];
+    /**
+     * Number of output slices per slab that keeps the output, its weights, the tile slices read
+     * for it and `writer_slice` bytes buffered by the output writer per slice under
+     * `memory_budget` bytes. At least one slice is always fused.
+     */
+    pub fn slab_depth(&self, memory_budget: usize, writer_slice: usize) -> usize {
+        let output_slice = self.width * self.height * 2 * std::mem::size_of::<f32>() + writer_slice;
+        let tile_slices = self
+            .subgraph_indexes
+            .iter()
+            .map(|&i| self.images[i].width * self.images[i].height * std::mem::size_of::<f32>())
+            .sum::<usize>();
+        (memory_budget / (output_slice + tile_slices).max(1)).clamp(1, self.depth.max(1))
+    }
 
-    for i in 0..num_images {
-        let imgfile = &images[subgraph_indexes[i]];
-        let image = imgfile.get_image();
-        let (start_x, start_y, start_z, end_x, end_y, end_z, offset_i, offset_f) = calc_iter_bounds(
-            offsets[i],
-            (image.width, image.height, image.depth),
-            (width, height, depth),
-        );
+    /**
+     * Fuse output slices `z_start..z_end`
+     */
+    pub fn fuse_slab(&self, z_start: usize, z_end: usize) -> Result<Image3D> {
+        let mode = self.mode;
+        let (width, height, depth) = (self.width, self.height, self.depth);
+        let slice = width * height;
+        let slab_depth = z_end - z_start;
+        let alpha = 1.5;
+
+        let default_value = match mode {
+            FuseMode::Min => 255.0,
+            _ => 0.0,
+        };
+        let mut values: Vec<f32> = vec![default_value; slice * slab_depth];
 
-        match mode {
-            FuseMode::Average => {
-                new_image_float[start_z as usize * width * height..end_z as usize * width * height]
-                    .par_chunks_mut(width * height)
-                    .zip(
-                        new_image_counts
-                            [start_z as usize * width * height..end_z as usize * width * height]
-                            .par_chunks_mut(width * height),
-                    )
-                    .enumerate()
-                    .for_each(|(z, (chunk, counts))| {
-                        let z = z as i64 + start_z;
-                        for y in start_y..end_y {
-                            for x in start_x..end_x {
-                                let index = (x + y * width as i64) as usize;
-                                let val = get_val(
-                                    (
-                                        (x - offset_i.0) as usize,
-                                        (y - offset_i.1) as usize,
-                                        (z as i64 - offset_i.2) as usize,
-                                    ),
-                                    offset_f,
-                                    &image,
-                                );
-                                if !val.is_finite() {
-                                    continue;
-                                }
-                                counts[index] += 1;
-                                chunk[index] += val;
-                            }
-                        }
-                    });
-            }
-            FuseMode::Max => {
-                new_image_float[start_z as usize * width * height..end_z as usize * width * height]
-                    .par_chunks_mut(width * height)
-                    .enumerate()
-                    .for_each(|(z, chunk)| {
-                        let z = z as i64 + start_z;
-                        for y in start_y..end_y {
-                            for x in start_x..end_x {
-                                let index = (x + y * width as i64) as usize;
-                                let val = get_val(
-                                    (
-                                        (x - offset_i.0) as usize,
-                                        (y - offset_i.1) as usize,
-                                        (z as i64 - offset_i.2) as usize,
-                                    ),
-                                    offset_f,
-                                    &image,
-                                );
-                                if !val.is_finite() {
-                                    continue;
-                                }
-                                chunk[index] = chunk[index].max(val);
-                            }
-                        }
-                    });
+        // Sample counts for average, blending weights for linear and center priority. Other
+        // modes keep a single unused value per slice so the slices can still be zipped.
+        let needs_weights = matches!(
+            mode,
+            FuseMode::Average | FuseMode::Linear | FuseMode::OverwritePrioritizeCenter
+        );
+        let weight_stride = if needs_weights { slice } else { 1 };
+        let mut weights: Vec<f32> = vec![0.0; weight_stride * slab_depth];
+
+        for i in 0..self.subgraph_indexes.len() {
+            let imgfile = &self.images[self.subgraph_indexes[i]];
+            let tile_dim = (imgfile.width, imgfile.height, imgfile.depth);
+            let (start_x, start_y, start_z, end_x, end_y, end_z, offset_i, offset_f) =
+                calc_iter_bounds(self.offsets[i], tile_dim, (width, height, depth));
+            let start_z = start_z.max(z_start as i64);
+            let end_z = end_z.min(z_end as i64);
+            if start_x >= end_x || start_y >= end_y || start_z >= end_z {
+                continue;
             }
-            FuseMode::Min => {
-                new_image_float[start_z as usize * width * height..end_z as usize * width * height]
-                    .par_chunks_mut(width * height)
-                    .enumerate()
-                    .for_each(|(z, chunk)| {
-                        let z = z as i64 + start_z;
-                        for y in start_y..end_y {
-                            for x in start_x..end_x {
-                                let index = (x + y * width as i64) as usize;
-                                let val = get_val(
-                                    (
-                                        (x - offset_i.0) as usize,
-                                        (y - offset_i.1) as usize,
-                                        (z as i64 - offset_i.2) as usize,
-                                    ),
-                                    offset_f,
-                                    &image,
-                                );
-                                if !val.is_finite() {
-                                    continue;
-                                }
-                                chunk[index] = chunk[index].min(val);
-                            }
-                        }
-                    });
+
+            // Slices covering the slab plus the ones interpolation looks at
+            let first_slice = ((start_z - offset_i.2) as usize).saturating_sub(1);
+            let mut last_slice = (end_z - offset_i.2) as usize;
+            if first_slice == 0 {
+                last_slice = last_slice.max(2.min(imgfile.depth));
             }
-            FuseMode::Overwrite => {
-                new_image_float[start_z as usize * width * height..end_z as usize * width * height]
-                    .par_chunks_mut(width * height)
-                    .enumerate()
-                    .for_each(|(z, chunk)| {
-                        let z = z as i64 + start_z;
-                        for y in start_y..end_y {
-                            for x in start_x..end_x {
-                                let index = (x + y * width as i64) as usize;
-                                let val = get_val(
-                                    (
-                                        (x - offset_i.0) as usize,
-                                        (y - offset_i.1) as usize,
-                                        (z as i64 - offset_i.2) as usize,
-                                    ),
-                                    offset_f,
-                                    &image,
-                                );
-                                if !val.is_finite() {
-                                    continue;
-                                }
-                                chunk[index] = val;
+            let image = imgfile.get_slices(first_slice, last_slice)?;
+
+            let slab_range = (start_z as usize - z_start)..(end_z as usize - z_start);
+            values[slab_range.start * slice..slab_range.end * slice]
+                .par_chunks_mut(slice)
+                .zip(weights[slab_range.start * weight_stride..slab_range.end * weight_stride].par_chunks_mut(weight_stride))
+                .enumerate()
+                .for_each(|(z, (chunk, weight_chunk))| {
+                    let z = z as i64 + start_z;
+                    for y in start_y..end_y {
+                        for x in start_x..end_x {
+                            let index = (x + y * width as i64) as usize;
+                            let src_pos = (
+                                (x - offset_i.0) as usize,
+                                (y - offset_i.1) as usize,
+                                (z - offset_i.2) as usize,
+                            );
+                            let val = get_val_in_slices(src_pos, offset_f, &image, first_slice, imgfile.depth);
+                            if !val.is_finite() {
+                                continue;
                             }
-                        }
-                    });
-            }
-            FuseMode::Linear => {
-                new_image_float[start_z as usize * width * height..end_z as usize * width * height]
-                    .par_chunks_mut(width * height)
-                    .zip(
-                        new_image_weights
-                            [start_z as usize * width * height..end_z as usize * width * height]
-                            .par_chunks_mut(width * height),
-                    )
-                    .enumerate()
-                    .for_each(|(z, (chunk, weight_chunk))| {
-                        let z = z as i64 + start_z;
-                        for y in start_y..end_y {
-                            for x in start_x..end_x {
-                                let index = (x + y * width as i64) as usize;
-                                let val = get_val(
-                                    (
-                                        (x - offset_i.0) as usize,
-                                        (y - offset_i.1) as usize,
-                                        (z as i64 - offset_i.2) as usize,
-                                    ),
-                                    offset_f,
-                                    &image,
-                                );
-                                if !val.is_finite() {
-                                    continue;
+
+                            match mode {
+                                FuseMode::Average => {
+                                    chunk[index] += val;
+                                    weight_chunk[index] += 1.0;
                                 }
-                                let weight = get_linear_weight_3d(
-                                    (image.width, image.height, image.depth),
-                                    (
-                                        (x - offset_i.0) as usize,
-                                        (y - offset_i.1) as usize,
-                                        (z as i64 - offset_i.2) as usize,
-                                    ),
-                                    alpha,
-                                );
-
-
-                                chunk[index] +=
-                                    val * weight;
-                                weight_chunk[index] += weight;
-                            }
-                        }
-                    });
-            }
-            FuseMode::OverwritePrioritizeCenter => {
-                new_image_float[start_z as usize * width * height..end_z as usize * width * height]
-                    .par_chunks_mut(width * height)
-                    .zip(
-                        new_image_weights
-                            [start_z as usize * width * height..end_z as usize * width * height]
-                            .par_chunks_mut(width * height),
-                    )
-                    .enumerate()
-                    .for_each(|(z, (chunk, weight_chunk))| {
-                        let z = z as i64 + start_z;
-                        for y in start_y..end_y {
-                            for x in start_x..end_x {
-                                let index = (x + y * width as i64) as usize;
-                                let val = get_val(
-                                    (
-                                        (x - offset_i.0) as usize,
-                                        (y - offset_i.1) as usize,
-                                        (z as i64 - offset_i.2) as usize,
-                                    ),
-                                    offset_f,
-                                    &image,
-                                );
-                                if !val.is_finite() {
-                                    continue;
+                                FuseMode::Max => chunk[index] = chunk[index].max(val),
+                                FuseMode::Min => chunk[index] = chunk[index].min(val),
+                                FuseMode::Overwrite => chunk[index] = val,
+                                FuseMode::Linear => {
+                                    let weight = get_linear_weight_3d(tile_dim, src_pos, alpha);
+                                    chunk[index] += val * weight;
+                                    weight_chunk[index] += weight;
                                 }
-                                let weight = get_linear_weight_3d(
-                                    (image.width, image.height, image.depth),
-                                    (
-                                        (x - offset_i.0) as usize,
-                                        (y - offset_i.1) as usize,
-                                        (z as i64 - offset_i.2) as usize,
-                                    ),
-                                    alpha,
-                                );
-                                if weight > weight_chunk[index] {
-                                    chunk[index] = val;
-                                    weight_chunk[index] = weight;
+                                FuseMode::OverwritePrioritizeCenter => {
+                                    let weight = get_linear_weight_3d(tile_dim, src_pos, alpha);
+                                    if weight > weight_chunk[index] {
+                                        chunk[index] = val;
+                                        weight_chunk[index] = weight;
+                                    }
                                 }
                             }
                         }
-                    });
-            }
-        }
-
-        println!("Image {} stitched", i + 1);
-        drop(image);
-    }
+                    }
+                });
 
-    if mode == FuseMode::Average {
-        new_image_float.par_iter_mut()
-            .zip(new_image_counts.par_iter())
-            .for_each(|(val, count)| {
-                if *count > 0 {
-                    *val /= *count as f32;
-                }
-            });
-    } else if mode == FuseMode::Linear {
-        new_image_float.par_iter_mut()
-            .zip(new_image_weights.par_iter())
-            .for_each(|(val, weight)| {
-                if *weight > 0.0 {
-                    *val /= *weight;
-                }
-            });
-    }
+            println!("Image {} stitched", i + 1);
+        }
 
-    println!("Image fused!");
+        if mode == FuseMode::Average || mode == FuseMode::Linear {
+            values
+                .par_iter_mut()
+                .zip(weights.par_iter())
+                .for_each(|(val, weight)| {
+                    if *weight > 0.0 {
+                        *val /= *weight;
+                    }
+                });
+        }
 
-    Image3D {
-        width: width as usize,
-        height: height as usize,
-        depth: depth as usize,
-        data: new_image_float,
-        min,
-        max,
+        Ok(Image3D {
+            width,
+            height,
+            depth: slab_depth,
+            data: values,
+            min: self.min,
+            max: self.max,
+        })
     }
 }
@@ -0,0 +1,278 @@
+use serde::Deserialize;
+
+use crate::config::AxisValues;
+use crate::error::{Result, StitchError};
+use crate::stitch3d::IBox3D;
+
+/**
+ * Order in which tiles were acquired, matching the Grid/Collection stitching plugin
+ */
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GridOrder {
+    RowByRow,
+    SnakeByRow,
+    ColumnByColumn,
+    SnakeByColumn,
+}
+
+/**
+ * Regular grid of tiles, expanded into `tile_paths` and `tile_layout` when the config is read
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GridLayout {
+    /// Number of tiles along x, y and optionally z.
+    pub size: Vec<usize>,
+    #[serde(default = "default_order")]
+    pub order: GridOrder,
+    /// Size of a single tile in pixels along x, y and optionally z.
+    pub tile_size: Vec<i64>,
+    /// Overlap between neighbouring tiles as a fraction of the tile size.
+    pub overlap: Option<AxisValues>,
+    /// Start from the opposite end along x, y and optionally z.
+    pub reverse: Option<Vec<bool>>,
+    /// File name pattern with `{x}`, `{y}`, `{z}` grid positions and `{i}` acquisition index,
+    /// optionally zero padded as in `{x:03}`.
+    pub pattern: String,
+    /// Value of the first grid position and acquisition index in file names.
+    #[serde(default)]
+    pub first_index: usize,
+}
+
+fn default_order() -> GridOrder {
+    GridOrder::RowByRow
+}
+
+impl GridLayout {
+    /**
+     * Generate the file name and layout box of every tile in acquisition order
+     */
+    pub fn expand(&self) -> Result<Vec<(String, IBox3D)>> {
+        let size = triple(&self.size, 1, "grid.size")?;
+        let tile_size = triple(&self.tile_size, 1, "grid.tile_size")?;
+        let reverse = match &self.reverse {
+            Some(reverse) => triple(reverse, false, "grid.reverse")?,
+            None => [false; 3],
+        };
+        let overlap = match &self.overlap {
+            None => [0.0; 3],
+            Some(AxisValues::Uniform(val)) => [*val; 3],
+            Some(AxisValues::PerAxis(values)) => triple(values, 0.0, "grid.overlap")?,
+        };
+
+        if size.contains(&0) {
+            return Err(StitchError::config("grid.size", "Grid size must be at least 1 along every axis"));
+        }
+        if tile_size.iter().any(|&s| s < 1) {
+            return Err(StitchError::config("grid.tile_size", "Tile size must be at least 1 along every axis"));
+        }
+        if overlap.iter().any(|ratio| !(0.0..1.0).contains(ratio)) {
+            return Err(StitchError::config("grid.overlap", "Overlap must be between 0 and 1"));
+        }
+
+        // Axes from fastest to slowest varying
+        let (inner, middle) = match self.order {
+            GridOrder::RowByRow | GridOrder::SnakeByRow => (0, 1),
+            GridOrder::ColumnByColumn | GridOrder::SnakeByColumn => (1, 0),
+        };
+        let snake = matches!(self.order, GridOrder::SnakeByRow | GridOrder::SnakeByColumn);
+
+        let mut tiles = Vec::with_capacity(size[0] * size[1] * size[2]);
+        for k in 0..size[2] {
+            for j in 0..size[middle] {
+                for i in 0..size[inner] {
+                    let mut position = [0; 3];
+                    position[2] = k;
+                    position[middle] = if snake && k % 2 == 1 { size[middle] - 1 - j } else { j };
+                    let line = k * size[middle] + j;
+                    position[inner] = if snake && line % 2 == 1 { size[inner] - 1 - i } else { i };
+
+                    for axis in 0..3 {
+                        if reverse[axis] {
+                            position[axis] = size[axis] - 1 - position[axis];
+                        }
+                    }
+
+                    let index = tiles.len();
+                    let name = self.file_name(position, index)?;
+                    let start = |axis: usize| {
+                        (position[axis] as f32 * tile_size[axis] as f32 * (1.0 - overlap[axis])).floor() as i64
+                    };
+                    let layout = IBox3D::new(
+                        start(0),
+                        start(1),
+                        start(2),
+                        tile_size[0],
+                        tile_size[1],
+                        tile_size[2],
+                    );
+                    tiles.push((name, layout));
+                }
+            }
+        }
+
+        Ok(tiles)
+    }
+
+    fn file_name(&self, position: [usize; 3], index: usize) -> Result<String> {
+        let mut name = String::new();
+        let mut rest = self.pattern.as_str();
+        while let Some(open) = rest.find('{') {
+            name.push_str(&rest[..open]);
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| StitchError::config("grid.pattern", "Unclosed \"{\" in pattern"))?
+                + open;
+            let placeholder = &rest[open + 1..close];
+            let (key, width) = match placeholder.split_once(':') {
+                Some((key, width)) => (
+                    key,
+                    width.parse::<usize>().map_err(|_| {
+                        StitchError::config("grid.pattern", format!("Invalid width in \"{{{}}}\"", placeholder))
+                    })?,
+                ),
+                None => (placeholder, 0),
+            };
+            let value = self.first_index
+                + match key {
+                    "x" => position[0],
+                    "y" => position[1],
+                    "z" => position[2],
+                    "i" => index,
+                    // Left for the timepoints section to fill in
+                    "t" => {
+                        name.push_str(&rest[open..=close]);
+                        rest = &rest[close + 1..];
+                        continue;
+                    }
+                    _ => {
+                        return Err(StitchError::config(
+                            "grid.pattern",
+                            format!("Unknown placeholder \"{{{}}}\", expected x, y, z or i", key),
+                        ))
+                    }
+                };
+            name.push_str(&format!("{:0width$}", value, width = width));
+            rest = &rest[close + 1..];
+        }
+        name.push_str(rest);
+
+        Ok(name)
+    }
+}
+
+/**
+ * Pad a 2 or 3 element list to three values
+ */
+fn triple<T: Copy>(values: &[T], default: T, key: &str) -> Result<[T; 3]> {
+    match values.len() {
+        2 => Ok([values[0], values[1], default]),
+        3 => Ok([values[0], values[1], values[2]]),
+        n => Err(StitchError::config(key, format!("Expected 2 or 3 values but got {}", n))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(json: &str) -> GridLayout {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn positions(fields: &str) -> Vec<(i64, i64, i64)> {
+        grid(&format!(r#"{{"tile_size": [10, 10, 5], "pattern": "", {}}}"#, fields))
+            .expand()
+            .unwrap()
+            .iter()
+            .map(|(_, layout)| (layout.x, layout.y, layout.z))
+            .collect()
+    }
+
+    fn config_error(json: &str) -> (String, String) {
+        match grid(json).expand() {
+            Err(StitchError::Config { key, message, .. }) => (key, message),
+            other => panic!("Expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn row_by_row_with_overlap() {
+        let tiles = grid(r#"{"size": [3, 2], "tile_size": [100, 50], "overlap": 0.1, "pattern": "t_{x}_{y}.tif"}"#)
+            .expand()
+            .unwrap();
+        let names = tiles.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["t_0_0.tif", "t_1_0.tif", "t_2_0.tif", "t_0_1.tif", "t_1_1.tif", "t_2_1.tif"]);
+        assert_eq!(tiles[5].1, IBox3D::new(180, 45, 0, 100, 50, 1));
+    }
+
+    #[test]
+    fn snake_and_column_orders() {
+        let snake = positions(r#""size": [3, 2], "order": "snake-by-row""#);
+        assert_eq!(snake, [(0, 0, 0), (10, 0, 0), (20, 0, 0), (20, 10, 0), (10, 10, 0), (0, 10, 0)]);
+
+        let columns = positions(r#""size": [2, 2], "order": "column-by-column""#);
+        assert_eq!(columns, [(0, 0, 0), (0, 10, 0), (10, 0, 0), (10, 10, 0)]);
+
+        let snake = positions(r#""size": [2, 2], "order": "snake-by-column""#);
+        assert_eq!(snake, [(0, 0, 0), (0, 10, 0), (10, 10, 0), (10, 0, 0)]);
+    }
+
+    #[test]
+    fn snake_continues_through_z_layers() {
+        let snake = positions(r#""size": [2, 2, 2], "order": "snake-by-row""#);
+        let expected = [
+            (0, 0, 0),
+            (10, 0, 0),
+            (10, 10, 0),
+            (0, 10, 0),
+            (0, 10, 5),
+            (10, 10, 5),
+            (10, 0, 5),
+            (0, 0, 5),
+        ];
+        assert_eq!(snake, expected);
+    }
+
+    #[test]
+    fn reverse_starts_from_the_opposite_end() {
+        let reversed = positions(r#""size": [2, 2], "reverse": [true, false]"#);
+        assert_eq!(reversed, [(10, 0, 0), (0, 0, 0), (10, 10, 0), (0, 10, 0)]);
+    }
+
+    #[test]
+    fn pattern_pads_offsets_and_keeps_timepoints() {
+        let pattern = r#""pattern": "{t}/s{i:02}_{x:03}.tif", "first_index": 1"#;
+        let layout = grid(&format!(r#"{{"size": [2, 1], "tile_size": [1, 1], {}}}"#, pattern));
+        let names = layout.expand().unwrap().into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names, ["{t}/s01_001.tif", "{t}/s02_002.tif"]);
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        let cases = [
+            ("tile_{x", "Unclosed \"{\" in pattern"),
+            ("tile_{x:ab}", "Invalid width in \"{x:ab}\""),
+            ("tile_{c}", "Unknown placeholder \"{c}\", expected x, y, z or i"),
+        ];
+        for (pattern, expected) in cases {
+            let json = format!(r#"{{"size": [1, 1], "tile_size": [10, 10], "pattern": "{}"}}"#, pattern);
+            assert_eq!(config_error(&json), ("grid.pattern".to_string(), expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        let cases = [
+            (r#"{"size": [0, 2], "tile_size": [10, 10], "pattern": "a"}"#, "grid.size"),
+            (r#"{"size": [2], "tile_size": [10, 10], "pattern": "a"}"#, "grid.size"),
+            (r#"{"size": [2, 2], "tile_size": [10, 0], "pattern": "a"}"#, "grid.tile_size"),
+            (r#"{"size": [2, 2], "tile_size": [10, 10], "overlap": 1.0, "pattern": "a"}"#, "grid.overlap"),
+            (r#"{"size": [2, 2], "tile_size": [10, 10], "reverse": [true], "pattern": "a"}"#, "grid.reverse"),
+        ];
+        for (json, expected) in cases {
+            assert_eq!(config_error(json).0, expected, "{}", json);
+        }
+    }
+}
@@ -1,10 +1,19 @@
 use core::f32;
-use std::{path::{Path, PathBuf}, u16};
-use image::ImageReader;
-use dicom::{core::{DataElement, PrimitiveValue, VR}, dictionary_std::tags, pixeldata::PixelDecoder};
-use rustfft::num_traits::Pow;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use image::{DynamicImage, ImageReader};
+use rayon::prelude::*;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::{dictionary_std::tags, pixeldata::PixelDecoder};
+use serde::Deserialize;
 use tiff::decoder::DecodingResult;
 
+use crate::dicom_output::{DicomHeader, DicomWriter};
+use crate::error::{Result, StitchError};
+use crate::intensity::{PixelConversion, PixelData};
+use crate::ome::{parse_ome_xml, read_ome_metadata};
+
 /**
  * 3D image data structure
  */
@@ -17,14 +26,6 @@ pub struct Image3D {
     pub max: f32,
 }
 
-pub struct Image3D8 {
-    pub width: usize,
-    pub height: usize,
-    pub depth: usize,
-    pub data: Vec<u8>,
-}
-
-
 pub struct _Image3D16 {
     pub width: usize,
     pub height: usize,
@@ -40,12 +41,12 @@ impl Image3D {
      */
     pub fn new(width: usize, height: usize, depth: usize, min: f32, max: f32) -> Image3D {
         Image3D {
-            width: width,
-            height: height,
-            depth: depth,
+            width,
+            height,
+            depth,
             data: vec![0.0; width * height * depth],
-            min: min,
-            max: max,
+            min,
+            max,
         }
     }
 
@@ -74,7 +75,7 @@ impl Image3D {
     /**
      * Get the 2D frame at the z coordinate
      */
-    pub fn get_frame(&self, z: usize) -> FrameSlice {
+    pub fn get_frame(&self, z: usize) -> FrameSlice<'_> {
         let start = z * self.width * self.height;
         FrameSlice {
             data: &self.data[start..start + self.width * self.height],
@@ -97,36 +98,279 @@ impl Image3D {
 }
 
 
+/**
+ * Where the `min` and `max` of an [`Image3DFile`] come from
+ */
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum RangeSource {
+    /// Full range of an unsigned integer pixel type.
+    PixelType,
+    /// Range tags of the file header, or given by the caller.
+    Header,
+    /// Decoded pixels, see [`Image3DFile::measure_range`].
+    Measured,
+    /// Not known without decoding: signed or float data without range tags. `min` and `max` are 0.
+    Unknown,
+}
+
+/**
+ * Native pixel type of a tile file, before conversion to f32
+ */
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SampleType {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl SampleType {
+    /**
+     * Pixel type of the given bit depth and TIFF SampleFormat: 1 unsigned, 2 signed, 3 float
+     */
+    pub fn from_format(bits: u16, sample_format: u16) -> Option<SampleType> {
+        match (sample_format, bits) {
+            (1, 0..=8) => Some(SampleType::U8),
+            (1, 9..=16) => Some(SampleType::U16),
+            (1, 17..=32) => Some(SampleType::U32),
+            (1, 33..=64) => Some(SampleType::U64),
+            (2, 0..=8) => Some(SampleType::I8),
+            (2, 9..=16) => Some(SampleType::I16),
+            (2, 17..=32) => Some(SampleType::I32),
+            (2, 33..=64) => Some(SampleType::I64),
+            (3, 32) => Some(SampleType::F32),
+            (3, 64) => Some(SampleType::F64),
+            _ => None,
+        }
+    }
+
+    /**
+     * Largest value of unsigned integer types, `None` for signed and float types
+     */
+    pub fn max_value(&self) -> Option<f32> {
+        match self {
+            SampleType::U8 => Some(u8::MAX as f32),
+            SampleType::U16 => Some(u16::MAX as f32),
+            SampleType::U32 => Some(u32::MAX as f32),
+            SampleType::U64 => Some(u64::MAX as f32),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * How color TIFF pages are read: as one luminance channel, or with every sample (red, green,
+ * blue and alpha) as its own channel
+ */
+#[derive(PartialEq, Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RgbMode {
+    #[default]
+    Luminance,
+    Channels,
+}
+
+/// Rec. 709 weights of red, green and blue in the luminance of color pages.
+pub const LUMINANCE_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+#[derive(Clone)]
 pub struct Image3DFile {
     pub width: usize,
     pub height: usize,
     pub depth: usize,
     pub min: f32,
     pub max: f32,
+    pub range_source: RangeSource,
+    /// Pixel type of the file.
+    pub sample_type: SampleType,
     pub path: PathBuf,
+    /// Weights of the samples of color TIFF pages, or `None` for their luminance. Grayscale
+    /// pages ignore it.
+    pub sample_weights: Option<Vec<f32>>,
+    /// TIFF pages forming the z-stack, or `None` to read every page.
+    pub planes: Option<Vec<usize>>,
+    /// Z-stacks of several channels and their weights. When not empty the image is read as the
+    /// weighted sum of these stacks instead of `planes`.
+    pub weighted_planes: Vec<(Vec<usize>, f32)>,
+    /// Headers of the files of a DICOM tile in z order, read once with the tile headers: the
+    /// slices of a series directory, or the one multi-frame file.
+    pub dcm_files: Option<Arc<Vec<DcmSeriesSlice>>>,
+    /// Linear resampling of the file onto the pixel grid of the layout. `width`, `height` and
+    /// `depth` are then the resampled size.
+    pub resampling: Option<Resampling>,
+}
+
+/**
+ * Resampling of a tile whose pixel spacing differs from the `voxel_size` of the config
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resampling {
+    /// Size of the image in the file.
+    pub source_size: (usize, usize, usize),
+    /// Output pixels per pixel of the file along x, y and z.
+    pub scale: (f32, f32, f32),
+}
+
+/**
+ * Size of an axis of `size` pixels resampled to `scale` output pixels per pixel
+ */
+pub fn resampled_size(size: usize, scale: f32) -> usize {
+    ((size as f32 * scale).round() as usize).max(1)
 }
 
 impl Image3DFile {
-    pub fn new(width: usize, height: usize, depth: usize, min: f32, max: f32, path: PathBuf) -> Image3DFile {
+    pub fn new(width: usize, height: usize, depth: usize, min: f32, max: f32, path: PathBuf) -> Result<Image3DFile> {
         if !path.exists() {
-            panic!("File does not exist");
+            return Err(StitchError::io(&path, std::io::ErrorKind::NotFound.into()));
+        }
+
+        Ok(Image3DFile {
+            width,
+            height,
+            depth,
+            path,
+            min,
+            max,
+            range_source: RangeSource::Header,
+            sample_type: SampleType::F32,
+            sample_weights: None,
+            planes: None,
+            weighted_planes: vec![],
+            dcm_files: None,
+            resampling: None,
+        })
+    }
+
+    /**
+     * Decode the image slab by slab and set `min` and `max` to the range of its finite values
+     */
+    pub fn measure_range(&mut self) -> Result<()> {
+        let slab_depth = 16;
+        let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+        for start in (0..self.depth).step_by(slab_depth) {
+            let slab = self.get_slices(start, start + slab_depth)?;
+            for value in slab.data.iter().filter(|value| value.is_finite()) {
+                min = min.min(*value);
+                max = max.max(*value);
+            }
+        }
+
+        (self.min, self.max) = if min <= max { (min, max) } else { (0.0, 0.0) };
+        self.range_source = RangeSource::Measured;
+        Ok(())
+    }
+
+    /**
+     * Read the file resampled by `scale` output pixels per pixel along x, y and z
+     */
+    pub fn resample(&mut self, scale: (f32, f32, f32)) {
+        let source_size = (self.width, self.height, self.depth);
+        self.width = resampled_size(source_size.0, scale.0);
+        self.height = resampled_size(source_size.1, scale.1);
+        self.depth = resampled_size(source_size.2, scale.2);
+        self.resampling = Some(Resampling { source_size, scale });
+    }
+
+    pub fn get_image(&self) -> Result<Image3D> {
+        if self.resampling.is_some() {
+            return self.get_slices(0, self.depth);
+        }
+        if let Some(files) = &self.dcm_files {
+            return read_dcm_series_frames(&self.path, files, 0, self.depth);
+        }
+        if is_dcm(&self.path) {
+            return read_dcm(&self.path);
+        }
+
+        if self.weighted_planes.is_empty() {
+            return read_tiff_planes(&self.path, self.planes.as_deref(), self.sample_weights.as_deref());
         }
 
-        Image3DFile {
-            width: width,
-            height: height,
-            depth: depth,
-            path: path,
-            min: min,
-            max: max,
+        let mut image = Image3D::new(self.width, self.height, self.depth, self.min, self.max);
+        for (planes, weight) in &self.weighted_planes {
+            let channel = read_tiff_planes(&self.path, Some(planes), self.sample_weights.as_deref())?;
+            image.data.iter_mut().zip(&channel.data).for_each(|(val, x)| *val += x * weight);
         }
+        Ok(image)
     }
-    pub fn get_image(&self) -> Image3D {
-        if self.path.extension().unwrap() == "dcm" {
-            read_dcm(&self.path)
-        } else {
-            read_tiff(&self.path)
+
+    /**
+     * Read only the z-slices `start..end`. Uncompressed DICOM files only read the frames needed
+     * and DICOM series only the files holding them.
+     */
+    pub fn get_slices(&self, start: usize, end: usize) -> Result<Image3D> {
+        let end = end.min(self.depth);
+        if let Some(resampling) = self.resampling.filter(|_| start < end) {
+            return self.get_resampled_slices(resampling, start, end);
+        }
+        if start == 0 && end == self.depth {
+            return self.get_image();
+        }
+
+        if let Some(files) = &self.dcm_files {
+            return read_dcm_series_frames(&self.path, files, start, end);
+        }
+        if is_dcm(&self.path) {
+            return read_dcm_series_frames(&self.path, &read_dcm_files(&self.path)?, start, end);
         }
+
+        let stack = |planes: Option<&[usize]>| -> Vec<usize> {
+            match planes {
+                Some(planes) => planes[start..end].to_vec(),
+                None => (start..end).collect(),
+            }
+        };
+
+        if self.weighted_planes.is_empty() {
+            let planes = stack(self.planes.as_deref());
+            return read_tiff_planes(&self.path, Some(&planes), self.sample_weights.as_deref());
+        }
+
+        let mut image = Image3D::new(self.width, self.height, end - start, self.min, self.max);
+        for (planes, weight) in &self.weighted_planes {
+            let channel = read_tiff_planes(&self.path, Some(&stack(Some(planes))), self.sample_weights.as_deref())?;
+            image.data.iter_mut().zip(&channel.data).for_each(|(val, x)| *val += x * weight);
+        }
+        Ok(image)
+    }
+
+    /**
+     * Read the source slices around the resampled slices `start..end` and interpolate them
+     */
+    fn get_resampled_slices(&self, resampling: Resampling, start: usize, end: usize) -> Result<Image3D> {
+        let (width, height, depth) = resampling.source_size;
+        let source = Image3DFile {
+            width,
+            height,
+            depth,
+            resampling: None,
+            ..self.clone()
+        };
+
+        let mut z_taps = linear_taps(start..end, resampling.scale.2, depth);
+        let (first, last) = (z_taps[0].0, z_taps[z_taps.len() - 1].1);
+        z_taps.iter_mut().for_each(|tap| (tap.0, tap.1) = (tap.0 - first, tap.1 - first));
+        let slab = source.get_slices(first, last + 1)?;
+
+        let taps = [
+            linear_taps(0..self.width, resampling.scale.0, width),
+            linear_taps(0..self.height, resampling.scale.1, height),
+            z_taps,
+        ];
+        Ok(Image3D {
+            width: self.width,
+            height: self.height,
+            depth: end - start,
+            data: resample_linear(&slab.data, (width, height, slab.depth), &taps),
+            min: slab.min,
+            max: slab.max,
+        })
     }
 }
 
@@ -138,6 +382,7 @@ pub struct FrameSlice<'a> {
     pub max: f32,
 }
 
+#[derive(Clone)]
 pub struct Image2D {
     pub width: usize,
     pub height: usize,
@@ -153,10 +398,10 @@ impl Image2D {
      */
     pub fn new(width: usize, height: usize, min: f32, max: f32) -> Image2D {
         Image2D {
-            width: width,
-            height: height,
-            min: min,
-            max: max,
+            width,
+            height,
+            min,
+            max,
             data: vec![0.0; width * height],
         }
     }
@@ -183,6 +428,38 @@ impl Image2D {
         self.data[index] = value;
     }
 
+    /**
+     * Linearly resample the image to `scale` output pixels per pixel along x and y
+     */
+    pub fn resampled(&self, scale: (f32, f32)) -> Image2D {
+        let (width, height) = (resampled_size(self.width, scale.0), resampled_size(self.height, scale.1));
+        let taps = [
+            linear_taps(0..width, scale.0, self.width),
+            linear_taps(0..height, scale.1, self.height),
+            vec![(0, 0, 0.0)],
+        ];
+        Image2D {
+            width,
+            height,
+            data: resample_linear(&self.data, (self.width, self.height, 1), &taps),
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    /**
+     * Combine channels of the same size into one image, weighting channel `c` by `weights[c]`
+     */
+    pub fn weighted_sum(channels: &[Image2D], weights: &[f32]) -> Image2D {
+        let mut image = Image2D::new(channels[0].width, channels[0].height, 0.0, 0.0);
+        for (channel, weight) in channels.iter().zip(weights) {
+            image.data.iter_mut().zip(&channel.data).for_each(|(val, x)| *val += x * weight);
+            image.min += channel.min * weight;
+            image.max += channel.max * weight;
+        }
+        image
+    }
+
     pub fn calc_min_max(&self) -> (f32, f32) {
         let mut min = f32::MAX;
         let mut max = f32::MIN;
@@ -197,255 +474,79 @@ impl Image2D {
 }
 
 /**
- * Save the image as a DICOM file
+ * Source pixels and weight of the second one for linear interpolation of the output pixels `out`
+ * along an axis of `source_len` pixels resampled by `scale`. Pixel centers are aligned and the
+ * edge pixels are repeated.
  */
-#[allow(dead_code)]
-pub fn save_as_dcm(
-    file_path: &Path,
-    image: &Image3D
-) {
-    let depth = image.depth;
-    let width = image.width;
-    let height = image.height;
-    let pixel_data = image3d_to_u16(image);
-
-    let mut new_obj = dicom::object::InMemDicomObject::new_empty();
-    new_obj.put(DataElement::new(
-        tags::PHOTOMETRIC_INTERPRETATION,
-        VR::CS,
-        PrimitiveValue::from("MONOCHROME2"),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::SAMPLES_PER_PIXEL,
-        VR::US,
-        PrimitiveValue::from(1_u16),
-    ));
-
-    let label_vector = (0..depth)
-        .map(|x| format!("frame_{:03}", x))
-        .collect::<Vec<String>>();
-
-    new_obj.put(DataElement::new(
-        tags::FRAME_LABEL_VECTOR,
-        VR::SH,
-        PrimitiveValue::from(label_vector.join("\\")),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::FRAME_INCREMENT_POINTER,
-        VR::US,
-        PrimitiveValue::from(tags::FRAME_LABEL_VECTOR),
-    ));
-    new_obj.put(DataElement::new(
-        tags::NUMBER_OF_FRAMES,
-        VR::IS,
-        PrimitiveValue::from(depth as u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::ROWS,
-        VR::US,
-        PrimitiveValue::from(height as u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::COLUMNS,
-        VR::US,
-        PrimitiveValue::from(width as u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::BITS_ALLOCATED,
-        VR::US,
-        PrimitiveValue::from(16_u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::BITS_STORED,
-        VR::US,
-        PrimitiveValue::from(16_u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::HIGH_BIT,
-        VR::US,
-        PrimitiveValue::from(15_u16),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::PIXEL_REPRESENTATION,
-        VR::US,
-        PrimitiveValue::from(0_u16),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::SMALLEST_IMAGE_PIXEL_VALUE,
-        VR::US,
-        PrimitiveValue::from(0_u16),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::LARGEST_IMAGE_PIXEL_VALUE,
-        VR::US,
-        PrimitiveValue::from(65535_u16),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::PIXEL_DATA,
-        VR::OW,
-        PrimitiveValue::from(unsafe { pixel_data.align_to::<u8>().1 }),
-    ));
-
-    let class_uid = "1.2.840.10008.5.1.4.1.1.7.3";
-    let instance_uid = "1.3.6.1.4.1.9590.100.1.2.272040855713580315506943008480197057345";
-    let implementation_class_uid = "1.3.6.1.4.1.9590.100.1.3.100.9.4";
-    
-    let new_file = new_obj
-        .with_meta(
-            dicom::object::FileMetaTableBuilder::new()
-                // Implicit VR Little Endian
-                .transfer_syntax(dicom::dictionary_std::uids::EXPLICIT_VR_LITTLE_ENDIAN)
-                .transfer_syntax("1.2.840.10008.1.2")
-                .media_storage_sop_class_uid(class_uid)
-                .media_storage_sop_instance_uid(instance_uid)
-                .implementation_class_uid(implementation_class_uid),
-        )
-        .unwrap();
-
-    new_file.write_to_file(file_path).unwrap();
-}
-
-
-/**
- * Save the image as a DICOM file
+fn linear_taps(out: std::ops::Range<usize>, scale: f32, source_len: usize) -> Vec<(usize, usize, f32)> {
+    out.map(|i| {
+        let position = ((i as f32 + 0.5) / scale - 0.5).clamp(0.0, (source_len - 1) as f32);
+        let below = position.floor() as usize;
+        (below, (below + 1).min(source_len - 1), position - below as f32)
+    })
+    .collect()
+}
+
+/**
+ * Interpolate `data` of `size` (width, height, depth, x fastest) along x, then y, then z with the
+ * taps of each axis
  */
-#[allow(dead_code)]
-pub fn save_as_dcm_8(
+fn resample_linear(data: &[f32], size: (usize, usize, usize), taps: &[Vec<(usize, usize, f32)>; 3]) -> Vec<f32> {
+    let (width, height, depth) = size;
+    let (out_width, out_height, out_depth) = (taps[0].len(), taps[1].len(), taps[2].len());
+    let lerp = |a: f32, b: f32, t: f32| if t == 0.0 { a } else { a + (b - a) * t };
+
+    let along_x = (0..out_width * height * depth)
+        .into_par_iter()
+        .map(|i| {
+            let (a, b, t) = taps[0][i % out_width];
+            let row = i / out_width * width;
+            lerp(data[row + a], data[row + b], t)
+        })
+        .collect::<Vec<_>>();
+    let along_y = (0..out_width * out_height * depth)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y, z) = (i % out_width, i / out_width % out_height, i / (out_width * out_height));
+            let (a, b, t) = taps[1][y];
+            lerp(along_x[(z * height + a) * out_width + x], along_x[(z * height + b) * out_width + x], t)
+        })
+        .collect::<Vec<_>>();
+    let plane = out_width * out_height;
+    (0..plane * out_depth)
+        .into_par_iter()
+        .map(|i| {
+            let (a, b, t) = taps[2][i / plane];
+            lerp(along_y[a * plane + i % plane], along_y[b * plane + i % plane], t)
+        })
+        .collect()
+}
+
+/**
+ * Save the image as a multi-frame DICOM file with 8 or 16-bit pixels and 1 mm voxels
+ */
+pub fn save_as_dcm(
     file_path: &Path,
-    image: Image3D8
-) {
-    let depth = image.depth;
-    let width = image.width;
-    let height = image.height;
-    let pixel_data = image.data;
-
-    let mut new_obj = dicom::object::InMemDicomObject::new_empty();
-    new_obj.put(DataElement::new(
-        tags::PHOTOMETRIC_INTERPRETATION,
-        VR::CS,
-        PrimitiveValue::from("MONOCHROME2"),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::SAMPLES_PER_PIXEL,
-        VR::US,
-        PrimitiveValue::from(1_u16),
-    ));
-
-    let label_vector = (0..depth)
-        .map(|x| format!("frame_{:03}", x))
-        .collect::<Vec<String>>();
-
-    new_obj.put(DataElement::new(
-        tags::FRAME_LABEL_VECTOR,
-        VR::SH,
-        PrimitiveValue::from(label_vector.join("\\")),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::FRAME_INCREMENT_POINTER,
-        VR::US,
-        PrimitiveValue::from(tags::FRAME_LABEL_VECTOR),
-    ));
-    new_obj.put(DataElement::new(
-        tags::NUMBER_OF_FRAMES,
-        VR::IS,
-        PrimitiveValue::from(depth as u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::ROWS,
-        VR::US,
-        PrimitiveValue::from(height as u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::COLUMNS,
-        VR::US,
-        PrimitiveValue::from(width as u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::BITS_ALLOCATED,
-        VR::US,
-        PrimitiveValue::from(8_u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::BITS_STORED,
-        VR::US,
-        PrimitiveValue::from(8_u16),
-    ));
-    new_obj.put(DataElement::new(
-        tags::HIGH_BIT,
-        VR::US,
-        PrimitiveValue::from(7_u16),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::PIXEL_REPRESENTATION,
-        VR::US,
-        PrimitiveValue::from(0_u16),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::SMALLEST_IMAGE_PIXEL_VALUE,
-        VR::US,
-        PrimitiveValue::from(0_u16),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::LARGEST_IMAGE_PIXEL_VALUE,
-        VR::US,
-        PrimitiveValue::from(255_u16),
-    ));
-
-    new_obj.put(DataElement::new(
-        tags::PIXEL_DATA,
-        VR::OW,
-        PrimitiveValue::from(pixel_data),
-    ));
-
-    let class_uid = "1.2.840.10008.5.1.4.1.1.7.3";
-    let instance_uid = "1.3.6.1.4.1.9590.100.1.2.272040855713580315506943008480197057345";
-    let implementation_class_uid = "1.3.6.1.4.1.9590.100.1.3.100.9.4";
-    
-    let new_file = new_obj
-        .with_meta(
-            dicom::object::FileMetaTableBuilder::new()
-                // Implicit VR Little Endian
-                .transfer_syntax(dicom::dictionary_std::uids::EXPLICIT_VR_LITTLE_ENDIAN)
-                .transfer_syntax("1.2.840.10008.1.2")
-                .media_storage_sop_class_uid(class_uid)
-                .media_storage_sop_instance_uid(instance_uid)
-                .implementation_class_uid(implementation_class_uid),
-        )
-        .unwrap();
-
-    new_file.write_to_file(file_path).unwrap();
-}
-
-/**
- * Save the image as a TIFF file
+    image: &Image3D,
+    conversion: &PixelConversion,
+) -> Result<()> {
+    let header = DicomHeader::new(None, None, [0.0; 3], (0.0, 0.0, 0.0))?;
+    let size = (image.width, image.height, image.depth);
+    let mut writer = DicomWriter::create(file_path, &header, false, conversion, size)?;
+    writer.write_slices(&image.data)?;
+    writer.finish()
+}
+
+/**
+ * Save the image as a TIFF file in the output type of `conversion`
  */
-#[allow(dead_code)]
 pub fn save_as_tiff(
     file_path: &Path,
-    image: &Image3D
-) {
-    let width = image.width;
-    let height = image.height;
-    let depth = image.depth;
-
-    let pixel_data = image3d_to_u16(image);
-
-    let mut tiff = tiff::encoder::TiffEncoder::new(std::fs::File::create(file_path).unwrap()).unwrap();
-    for i in 0..depth {
-        let frame = &pixel_data[i * width * height..(i + 1) * width * height];
-        tiff.write_image::<tiff::encoder::colortype::Gray16>(width as u32, height as u32, &frame).unwrap();
-    }
+    image: &Image3D,
+    conversion: &PixelConversion,
+) -> Result<()> {
+    let mut writer = TiffStackWriter::create(file_path, None)?;
+    writer.write_slices(&conversion.convert(&image.data), image.width, image.height)
 }
 
 /**
@@ -474,189 +575,1301 @@ pub fn image2d_to_u16(image: &Image2D) -> Vec<u16> {
 }
 
 /**
- * Read the image from a dicom file
+ * Read the image from a dicom file, or from a directory of DICOM slices
  */
-pub fn read_dcm(file_path: &Path) -> Image3D {
-    let dicom_obj = dicom::object::open_file(file_path).unwrap();
+pub fn read_dcm(file_path: &Path) -> Result<Image3D> {
+    let files = read_dcm_files(file_path)?;
+    let depth = files.iter().map(|file| file.frames).sum();
+    read_dcm_series_frames(file_path, &files, 0, depth)
+}
 
-    let image = dicom_obj.decode_pixel_data().unwrap();
-    let depth = image.number_of_frames() as usize;
-    let height = image.rows() as usize;
-    let width = image.columns() as usize;
-    let vec = image.to_vec().unwrap();
+/**
+ * Headers of the files of a DICOM tile in z order: the slices of a series directory, or the one
+ * multi-frame file
+ */
+pub fn read_dcm_files(file_path: &Path) -> Result<Vec<DcmSeriesSlice>> {
+    if file_path.is_dir() {
+        read_dcm_series(file_path)
+    } else {
+        Ok(vec![read_dcm_slice_header(file_path)?])
+    }
+}
 
-    let min = 0.0;
-    let max = (2.pow(image.bits_allocated()) - 1) as f32;
+/**
+ * Largest value of unsigned DICOM pixels of `bits_allocated` bits, computed in floating point
+ * so that 32-bit data does not overflow
+ */
+fn dcm_type_max(bits_allocated: u16) -> f32 {
+    2f32.powi(bits_allocated as i32) - 1.0
+}
 
-    Image3D {
+/**
+ * Read the size of a DICOM file, or of a directory of DICOM slices, from its header without
+ * decoding pixel data. The value range is that of the allocated bits for unsigned data and
+ * unknown for signed data.
+ */
+pub fn read_dcm_headers(file_path: &Path) -> Result<Image3DFile> {
+    let files = read_dcm_files(file_path)?;
+    let depth = files.iter().map(|file| file.frames).sum();
+    let slice = files[0].clone();
+
+    // Signed data, such as CT in Hounsfield units, does not start at 0
+    let sample_type = SampleType::from_format(slice.bits_allocated, if slice.signed { 2 } else { 1 })
+        .unwrap_or(SampleType::U16);
+    let (max, range_source) = match slice.signed {
+        false => (dcm_type_max(slice.bits_allocated), RangeSource::PixelType),
+        true => (0.0, RangeSource::Unknown),
+    };
+
+    Ok(Image3DFile {
+        depth,
+        width: slice.width,
+        height: slice.height,
+        min: 0.0,
+        max,
+        range_source,
+        sample_type,
+        path: file_path.to_path_buf(),
+        sample_weights: None,
+        planes: None,
+        weighted_planes: vec![],
+        dcm_files: Some(Arc::new(files)),
+        resampling: None,
+    })
+}
+
+/**
+ * Header of one file of a DICOM tile
+ */
+#[derive(Clone, Debug)]
+pub struct DcmSeriesSlice {
+    pub path: PathBuf,
+    pub width: usize,
+    pub height: usize,
+    pub frames: usize,
+    pub bits_allocated: u16,
+    /// Pixel Representation is 1, pixels are two's complement integers.
+    pub signed: bool,
+    pub instance_number: Option<i64>,
+    /// Image Position (Patient) in millimetres.
+    pub position: Option<[f64; 3]>,
+    /// Normal of the slice plane from Image Orientation (Patient).
+    pub normal: Option<[f64; 3]>,
+    /// Bytes of one frame of pixel data.
+    pub frame_bytes: usize,
+    /// Position of the pixel data in the file when it is stored uncompressed in little endian
+    /// order, so that frames can be read without the rest of it.
+    pub pixel_data_offset: Option<u64>,
+}
+
+/**
+ * Read the headers of every slice in a DICOM series directory and sort them in z order: along
+ * the slice normal when every slice has an Image Position (Patient), otherwise by Instance Number,
+ * otherwise by file name. Hidden files, DICOMDIR and files with an extension other than `.dcm`
+ * are skipped.
+ */
+pub fn read_dcm_series(dir: &Path) -> Result<Vec<DcmSeriesSlice>> {
+    let mut paths = std::fs::read_dir(dir)
+        .map_err(|e| StitchError::io(dir, e))?
+        .map(|entry| entry.map(|entry| entry.path()).map_err(|e| StitchError::io(dir, e)))
+        .collect::<Result<Vec<_>>>()?;
+    paths.retain(|path| {
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        path.is_file()
+            && !name.starts_with('.')
+            && name != "DICOMDIR"
+            && path.extension().is_none_or(|ext| ext.eq_ignore_ascii_case("dcm"))
+    });
+    paths.sort();
+    if paths.is_empty() {
+        return Err(StitchError::format(dir, "Directory contains no DICOM slices"));
+    }
+
+    let mut slices = paths
+        .par_iter()
+        .map(|path| read_dcm_slice_header(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(slice) = slices
+        .iter()
+        .find(|slice| (slice.width, slice.height) != (slices[0].width, slices[0].height))
+    {
+        return Err(StitchError::format(
+            &slice.path,
+            format!(
+                "Slice is {} x {} but the series starts with {} x {}",
+                slice.width, slice.height, slices[0].width, slices[0].height
+            ),
+        ));
+    }
+
+    let normal = slices[0].normal.unwrap_or([0.0, 0.0, 1.0]);
+    let location = |slice: &DcmSeriesSlice| {
+        slice
+            .position
+            .map(|position| position[0] * normal[0] + position[1] * normal[1] + position[2] * normal[2])
+    };
+    if slices.iter().all(|slice| slice.position.is_some()) {
+        slices.sort_by(|a, b| location(a).unwrap_or(0.0).total_cmp(&location(b).unwrap_or(0.0)));
+    } else if slices.iter().all(|slice| slice.instance_number.is_some()) {
+        slices.sort_by_key(|slice| slice.instance_number);
+    }
+
+    Ok(slices)
+}
+
+fn read_dcm_slice_header(file_path: &Path) -> Result<DcmSeriesSlice> {
+    let dicom_obj = dicom::object::OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(file_path)
+        .map_err(|e| StitchError::format(file_path, e))?;
+
+    let floats = |tag| -> Option<Vec<f64>> {
+        dicom_obj
+            .element_opt(tag)
+            .ok()
+            .flatten()
+            .and_then(|element| element.value().to_multi_float64().ok())
+    };
+    let int = |tag| -> Option<i64> {
+        dicom_obj
+            .element_opt(tag)
+            .ok()
+            .flatten()
+            .and_then(|element| element.value().to_int::<i64>().ok())
+    };
+
+    let width = int(tags::COLUMNS).ok_or_else(|| StitchError::format(file_path, "Missing Columns"))?;
+    let height = int(tags::ROWS).ok_or_else(|| StitchError::format(file_path, "Missing Rows"))?;
+    let frames = int(tags::NUMBER_OF_FRAMES).unwrap_or(1).max(1) as usize;
+    let bits_allocated = int(tags::BITS_ALLOCATED).unwrap_or(16) as u16;
+    let samples = int(tags::SAMPLES_PER_PIXEL).unwrap_or(1).max(1) as usize;
+    let frame_bytes = width as usize * height as usize * samples * (bits_allocated as usize).div_ceil(8);
+    let pixel_data_offset =
+        native_pixel_data_offset(file_path, dicom_obj.meta().transfer_syntax(), (frames * frame_bytes) as u64)?;
+    let normal = floats(tags::IMAGE_ORIENTATION_PATIENT)
+        .filter(|orientation| orientation.len() == 6)
+        .map(|o| [o[1] * o[5] - o[2] * o[4], o[2] * o[3] - o[0] * o[5], o[0] * o[4] - o[1] * o[3]]);
+
+    Ok(DcmSeriesSlice {
+        path: file_path.to_path_buf(),
+        width: width as usize,
+        height: height as usize,
+        frames,
+        bits_allocated,
+        signed: int(tags::PIXEL_REPRESENTATION) == Some(1),
+        instance_number: int(tags::INSTANCE_NUMBER),
+        position: floats(tags::IMAGE_POSITION_PATIENT).and_then(|position| <[f64; 3]>::try_from(position).ok()),
+        normal,
+        frame_bytes,
+        pixel_data_offset,
+    })
+}
+
+/**
+ * Position of the pixel data of a file with an uncompressed little endian transfer syntax. The
+ * pixel data is expected to be the last element, and its element header is checked before it is
+ * trusted. Returns `None` for other files, which are decoded as a whole.
+ */
+fn native_pixel_data_offset(file_path: &Path, transfer_syntax: &str, data_bytes: u64) -> Result<Option<u64>> {
+    let explicit_vr = match transfer_syntax.trim_end_matches(['\0', ' ']) {
+        "1.2.840.10008.1.2" => false,
+        "1.2.840.10008.1.2.1" => true,
+        _ => return Ok(None),
+    };
+    let padded_bytes = data_bytes + data_bytes % 2;
+    let header_bytes = if explicit_vr { 12 } else { 8 };
+
+    let mut file = open_file(file_path)?;
+    let file_bytes = file.metadata().map_err(|e| StitchError::io(file_path, e))?.len();
+    let Some(offset) = file_bytes.checked_sub(padded_bytes).filter(|offset| *offset >= header_bytes) else {
+        return Ok(None);
+    };
+    let mut header = vec![0u8; header_bytes as usize];
+    file.seek(SeekFrom::Start(offset - header_bytes))
+        .and_then(|_| file.read_exact(&mut header))
+        .map_err(|e| StitchError::io(file_path, e))?;
+
+    let length = u32::from_le_bytes(header[header.len() - 4..].try_into().unwrap()) as u64;
+    let vr_matches = !explicit_vr || matches!(&header[4..6], b"OW" | b"OB");
+    let found = header[..4] == [0xE0, 0x7F, 0x10, 0x00] && vr_matches && length == padded_bytes;
+    Ok(found.then_some(offset))
+}
+
+/**
+ * Decode the z-slices `start..end` of the files of a DICOM tile, reading only the files that hold
+ * them
+ */
+fn read_dcm_series_frames(dir: &Path, slices: &[DcmSeriesSlice], start: usize, end: usize) -> Result<Image3D> {
+    let (width, height) = (slices[0].width, slices[0].height);
+    let mut first_frames = Vec::with_capacity(slices.len());
+    slices.iter().fold(0, |first, slice| {
+        first_frames.push(first);
+        first + slice.frames
+    });
+
+    let frames = slices
+        .par_iter()
+        .zip(&first_frames)
+        .filter(|(slice, &first)| first < end && first + slice.frames > start)
+        .map(|(slice, &first)| {
+            let from = start.saturating_sub(first);
+            let to = (end - first).min(slice.frames);
+            let image = read_dcm_frames(slice, from, to)?;
+            if (image.width, image.height) != (width, height) {
+                return Err(StitchError::format(&slice.path, "Slice size differs from its header"));
+            }
+            Ok(image.data)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let data = frames.concat();
+    if data.len() != (end - start) * width * height {
+        return Err(StitchError::format(dir, "Series has fewer frames than its slice headers"));
+    }
+
+    Ok(Image3D {
+        depth: end - start,
+        width,
+        height,
+        data,
+        min: 0.0,
+        max: dcm_type_max(slices[0].bits_allocated),
+    })
+}
+
+/**
+ * Decode frames `start..end` of one DICOM file. Uncompressed pixel data is read only for these
+ * frames, other files are read whole and only these frames decoded.
+ */
+pub fn read_dcm_frames(slice: &DcmSeriesSlice, start: usize, end: usize) -> Result<Image3D> {
+    let file_path = slice.path.as_path();
+    let (dicom_obj, frames) = match slice.pixel_data_offset {
+        Some(offset) => {
+            let mut bytes = vec![0u8; (end - start) * slice.frame_bytes];
+            let mut file = open_file(file_path)?;
+            file.seek(SeekFrom::Start(offset + (start * slice.frame_bytes) as u64))
+                .and_then(|_| file.read_exact(&mut bytes))
+                .map_err(|e| StitchError::io(file_path, e))?;
+
+            // The header with only these frames as pixel data
+            let mut dicom_obj = dicom::object::OpenFileOptions::new()
+                .read_until(tags::PIXEL_DATA)
+                .open_file(file_path)
+                .map_err(|e| StitchError::format(file_path, e))?;
+            let vr = if slice.bits_allocated > 8 { VR::OW } else { VR::OB };
+            dicom_obj.put(DataElement::new(
+                tags::NUMBER_OF_FRAMES,
+                VR::IS,
+                PrimitiveValue::from((end - start).to_string()),
+            ));
+            dicom_obj.put(DataElement::new(tags::PIXEL_DATA, vr, PrimitiveValue::from(bytes)));
+            (dicom_obj, 0..end - start)
+        }
+        None => {
+            let dicom_obj = dicom::object::open_file(file_path).map_err(|e| StitchError::format(file_path, e))?;
+            (dicom_obj, start..end)
+        }
+    };
+
+    let (mut width, mut height, mut bits_allocated) = (0, 0, 16);
+    let mut data = vec![];
+    for frame in frames {
+        let image = dicom_obj
+            .decode_pixel_data_frame(frame as u32)
+            .map_err(|e| StitchError::format(file_path, e))?;
+        (width, height, bits_allocated) = (image.columns() as usize, image.rows() as usize, image.bits_allocated());
+        data.extend(image.to_vec::<f32>().map_err(|e| StitchError::format(file_path, e))?);
+    }
+
+    Ok(Image3D {
+        depth: end - start,
+        width,
+        height,
+        data,
+        min: 0.0,
+        max: dcm_type_max(bits_allocated),
+    })
+}
+
+/**
+ * Read the image from a TIFF file, stacking every page as a z-slice. Color pages are read as
+ * their luminance.
+ */
+pub fn read_tiff(file_path: &Path) -> Result<Image3D> {
+    read_tiff_planes(file_path, None, None)
+}
+
+/**
+ * Read the given TIFF pages as z-slices, or every page if `planes` is `None`. The samples of
+ * color pages are summed with `sample_weights`, or reduced to their luminance if it is `None`.
+ */
+pub fn read_tiff_planes(file_path: &Path, planes: Option<&[usize]>, sample_weights: Option<&[f32]>) -> Result<Image3D> {
+    let mut decoder = tiff::decoder::Decoder::new(open_file(file_path)?).map_err(|e| tiff_error(file_path, e))?;
+    let (width, height) = decoder.dimensions().map_err(|e| tiff_error(file_path, e))?;
+    let (width, height) = (width as usize, height as usize);
+    let mut vec = Vec::new();
+    let mut max: f32 = 0.0;
+    let depth = for_each_tiff_page(file_path, &mut decoder, planes, sample_weights, |data| {
+        let (data, type_max) = data;
+        vec.extend(data);
+        max = max.max(type_max.unwrap_or(0.0));
+    })?;
+
+    Ok(Image3D {
         depth,
         width,
         height,
         data: vec,
-        min,
+        min: 0.0,
         max,
-    }
+    })
 }
 
 /**
- * Read the image from a dicom file
+ * Read the size and value range of a TIFF file from its tags. OME-TIFF files are reduced to the
+ * z-stack of their first channel and timepoint, other files use every page as a z-slice and
+ * color pages are read as their luminance.
  */
-pub fn read_dcm_headers(file_path: &Path) -> Image3DFile {
-    let dicom_obj = dicom::object::open_file(file_path).unwrap();
+pub fn read_tiff_headers(file_path: &Path) -> Result<Image3DFile> {
+    read_tiff_stack_headers(file_path, 0, 0, RgbMode::Luminance)
+}
 
-    let image = dicom_obj.decode_pixel_data().unwrap();
-    let depth = image.number_of_frames() as usize;
-    let height = image.rows() as usize;
-    let width = image.columns() as usize;
+/**
+ * Read the size and value range of one channel and timepoint of a TIFF file from the tags of
+ * its pages, without decoding pixel data. Plain TIFF files only have timepoint 0, and channel 0
+ * unless their color samples are read as channels with [`RgbMode::Channels`].
+ */
+pub fn read_tiff_stack_headers(
+    file_path: &Path,
+    channel: usize,
+    timepoint: usize,
+    rgb: RgbMode,
+) -> Result<Image3DFile> {
+    let mut decoder = tiff::decoder::Decoder::new(open_file(file_path)?).map_err(|e| tiff_error(file_path, e))?;
+    let (width, height) = decoder.dimensions().map_err(|e| tiff_error(file_path, e))?;
+    let (width, height) = (width as usize, height as usize);
+
+    let description = decoder
+        .get_tag_ascii_string(tiff::tags::Tag::ImageDescription)
+        .ok()
+        .unwrap_or_default();
+    let samples = tiff_samples_per_pixel(&mut decoder);
+    let color_channels = if rgb == RgbMode::Channels { samples } else { 1 };
+    let (planes, sample_weights) = match parse_ome_xml(file_path, &description)? {
+        Some(ome) => (Some(ome.stack_planes(file_path, channel, timepoint)?), None),
+        None if channel < color_channels && timepoint == 0 => {
+            let weights = (0..samples).map(|sample| if sample == channel { 1.0 } else { 0.0 }).collect();
+            (None, (color_channels > 1).then_some(weights))
+        }
+        None => {
+            let channels = match color_channels {
+                1 => "channel 0".to_string(),
+                n => format!("channels 0 to {}", n - 1),
+            };
+            return Err(StitchError::config(
+                "channel",
+                format!("{:?} is not an OME-TIFF and only has {} and timepoint 0", file_path, channels),
+            ));
+        }
+    };
 
-    let min = 0.0;
-    let max = (2.pow(image.bits_allocated()) - 1) as f32;
+    let depth = match &planes {
+        Some(planes) => {
+            // Only the pages of the stack need to exist
+            if let Some(&last) = planes.iter().max() {
+                decoder.seek_to_image(last).map_err(|e| tiff_error(file_path, e))?;
+            }
+            decoder.seek_to_image(planes.first().copied().unwrap_or(0)).map_err(|e| tiff_error(file_path, e))?;
+            planes.len()
+        }
+        None => {
+            let mut depth = 1;
+            while decoder.more_images() {
+                decoder.next_image().map_err(|e| tiff_error(file_path, e))?;
+                depth += 1;
+            }
+            decoder.seek_to_image(0).map_err(|e| tiff_error(file_path, e))?;
+            depth
+        }
+    };
+    let sample_type = match tiff_sample_type(&mut decoder) {
+        Some(sample_type) => sample_type,
+        None => {
+            let color_type = decoder.colortype().map_err(|e| tiff_error(file_path, e))?;
+            return Err(StitchError::unsupported_pixel_type(file_path, color_type));
+        }
+    };
+    let (min, max, range_source) = tiff_value_range(&mut decoder, sample_type);
 
-    Image3DFile {
+    Ok(Image3DFile {
         depth,
         width,
         height,
         min,
         max,
+        range_source,
+        sample_type,
         path: file_path.to_path_buf(),
-    }
+        sample_weights,
+        planes,
+        weighted_planes: vec![],
+        dcm_files: None,
+        resampling: None,
+    })
 }
 
 /**
- * Read the image from a TIFF file
+ * Read the size and value range of the weighted sum of all channels of a TIFF file at one
+ * timepoint. `weights` has one entry per channel: OME-TIFF channels, the color samples of plain
+ * TIFF files with [`RgbMode::Channels`], or a single channel otherwise.
  */
-#[allow(dead_code)]
-pub fn read_tiff(file_path: &Path) -> Image3D {
-    let mut decoder = tiff::decoder::Decoder::new(std::fs::File::open(file_path).unwrap()).unwrap();
-    let mut depth = 0;
-    let width = decoder.dimensions().unwrap().0 as usize;
-    let height = decoder.dimensions().unwrap().1 as usize;
-    let mut vec = Vec::new();
-    let mut max = 0;
-    loop {
-
-        let image: DecodingResult = decoder.read_image().unwrap();
+pub fn read_tiff_weighted_headers(
+    file_path: &Path,
+    weights: &[f32],
+    timepoint: usize,
+    rgb: RgbMode,
+) -> Result<Image3DFile> {
+    let channels = read_tiff_channel_count(file_path, rgb)?;
+    if weights.len() != channels {
+        return Err(StitchError::config(
+            "channel_weights",
+            format!("{:?} has {} channels but {} weights were given", file_path, channels, weights.len()),
+        ));
+    }
 
-        if let DecodingResult::U8(data) = image {
-            vec.extend(data.iter().map(|x| *x as f32));
-            max = max.max(255);
-        } else if let DecodingResult::U16(data) = image {
-            vec.extend(data.iter().map(|x| *x as f32));
-            max = max.max(u16::MAX);
-        } else if let DecodingResult::F32(data) = image {
-            vec.extend(data.iter().map(|x| *x));
-        } else {
-            panic!("Unsupported data type");
+    let mut image = read_tiff_stack_headers(file_path, 0, timepoint, rgb)?;
+    match read_ome_metadata(file_path)? {
+        Some(ome) => {
+            image.weighted_planes = (0..channels)
+                .map(|c| Ok((ome.stack_planes(file_path, c, timepoint)?, weights[c])))
+                .collect::<Result<Vec<_>>>()?;
         }
+        // Color samples are weighted while each page is decoded
+        None if channels > 1 => image.sample_weights = Some(weights.to_vec()),
+        None => image.weighted_planes = vec![((0..image.depth).collect(), weights[0])],
+    }
 
-        depth += 1;
+    // The weighted sum can leave the range of the pixel type
+    (image.min, image.max, image.range_source) = (0.0, 0.0, RangeSource::Unknown);
+    Ok(image)
+}
 
-        if !decoder.more_images() {
-            break;
-        }
+/**
+ * First value of an unsigned integer tag of the current TIFF page
+ */
+fn tiff_tag_first(decoder: &mut tiff::decoder::Decoder<std::fs::File>, tag: tiff::tags::Tag) -> Option<u16> {
+    decoder
+        .find_tag_unsigned_vec::<u16>(tag)
+        .ok()
+        .flatten()
+        .and_then(|values| values.first().copied())
+}
+
+/**
+ * Number of samples per pixel of the current TIFF page, 3 or 4 for color pages
+ */
+fn tiff_samples_per_pixel(decoder: &mut tiff::decoder::Decoder<std::fs::File>) -> usize {
+    tiff_tag_first(decoder, tiff::tags::Tag::SamplesPerPixel).unwrap_or(1).max(1) as usize
+}
 
-        decoder.next_image().unwrap();
+/**
+ * Pixel type of the current TIFF page from BitsPerSample and SampleFormat
+ */
+fn tiff_sample_type(decoder: &mut tiff::decoder::Decoder<std::fs::File>) -> Option<SampleType> {
+    let bits = tiff_tag_first(decoder, tiff::tags::Tag::BitsPerSample).unwrap_or(1);
+    let sample_format = tiff_tag_first(decoder, tiff::tags::Tag::SampleFormat).unwrap_or(1);
+    SampleType::from_format(bits, sample_format)
+}
+
+/**
+ * Value range of the current TIFF page from its tags: the full range of unsigned integer types,
+ * or SMinSampleValue and SMaxSampleValue for signed and float data
+ */
+fn tiff_value_range(
+    decoder: &mut tiff::decoder::Decoder<std::fs::File>,
+    sample_type: SampleType,
+) -> (f32, f32, RangeSource) {
+    use tiff::tags::Tag;
+
+    if let Some(max) = sample_type.max_value() {
+        return (0.0, max, RangeSource::PixelType);
     }
 
-    Image3D {
-        depth,
-        width,
-        height,
-        data: vec.iter().map(|x| *x as f32).collect(),
-        min: 0.0,
-        max: max as f32,
+    let number = |decoder: &mut tiff::decoder::Decoder<std::fs::File>, tag| -> Option<f64> {
+        use tiff::decoder::ifd::Value;
+        let value = match decoder.find_tag(tag).ok().flatten()? {
+            Value::List(values) => values.into_iter().next()?,
+            value => value,
+        };
+        match value {
+            Value::Byte(v) => Some(v as f64),
+            Value::Short(v) => Some(v as f64),
+            Value::Signed(v) => Some(v as f64),
+            Value::SignedBig(v) => Some(v as f64),
+            Value::Unsigned(v) => Some(v as f64),
+            Value::UnsignedBig(v) => Some(v as f64),
+            Value::Float(v) => Some(v as f64),
+            Value::Double(v) => Some(v),
+            _ => None,
+        }
+    };
+    match (number(decoder, Tag::SMinSampleValue), number(decoder, Tag::SMaxSampleValue)) {
+        (Some(min), Some(max)) if min <= max => (min as f32, max as f32, RangeSource::Header),
+        _ => (0.0, 0.0, RangeSource::Unknown),
     }
 }
 
+/**
+ * Number of channels in a TIFF file: from its OME-XML, the samples per pixel of color files
+ * with [`RgbMode::Channels`], or 1
+ */
+pub fn read_tiff_channel_count(file_path: &Path, rgb: RgbMode) -> Result<usize> {
+    if let Some(ome) = read_ome_metadata(file_path)? {
+        return Ok(ome.size_c);
+    }
+    if rgb == RgbMode::Luminance {
+        return Ok(1);
+    }
+
+    let mut decoder = tiff::decoder::Decoder::new(open_file(file_path)?).map_err(|e| tiff_error(file_path, e))?;
+    Ok(tiff_samples_per_pixel(&mut decoder))
+}
 
 /**
- * Read the image from a TIFF file
+ * Decode the given pages (or all pages) in order and pass each one as f32 values along with
+ * the maximum of its integer type, or `None` for signed and float data. Returns the page count.
  */
-#[allow(dead_code)]
-pub fn read_tiff_headers(file_path: &Path) -> Image3DFile {
-    let mut decoder = tiff::decoder::Decoder::new(std::fs::File::open(file_path).unwrap()).unwrap();
+fn for_each_tiff_page(
+    file_path: &Path,
+    decoder: &mut tiff::decoder::Decoder<std::fs::File>,
+    planes: Option<&[usize]>,
+    sample_weights: Option<&[f32]>,
+    mut f: impl FnMut((Vec<f32>, Option<f32>)),
+) -> Result<usize> {
     let mut depth = 0;
-    let width = decoder.dimensions().unwrap().0 as usize;
-    let height = decoder.dimensions().unwrap().1 as usize;
-    let mut max: f32 = 0.0;
-    let mut min: f32 = 0.0;
     loop {
-        let image: DecodingResult = decoder.read_image().unwrap();
-
-        if let DecodingResult::U8(_data) = image {
-            max = max.max(255.0);
-        } else if let DecodingResult::U16(_data) = image {
-            max = max.max(u16::MAX as f32);
-        } else if let DecodingResult::F32(data) = image {
-            data.iter().for_each(|x| {
-                if !x.is_finite() {
-                    return;
-                }
-                max = max.max(*x);
-                min = min.min(*x);
-            });
-        } else {
-            panic!("Unsupported data type");
+        if let Some(planes) = planes {
+            match planes.get(depth) {
+                Some(&plane) => decoder.seek_to_image(plane).map_err(|e| tiff_error(file_path, e))?,
+                None => break,
+            }
         }
 
+        f(read_tiff_page(file_path, decoder, sample_weights)?);
+
         depth += 1;
 
-        if !decoder.more_images() {
-            break;
+        if planes.is_none() {
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image().map_err(|e| tiff_error(file_path, e))?;
         }
+    }
+
+    Ok(depth)
+}
+
+/**
+ * Decode the current TIFF page to one f32 value per pixel. The samples of color pages are summed
+ * with `sample_weights`, or reduced to their Rec. 709 luminance if it is `None`; pages with two
+ * samples are gray and alpha and use the gray sample.
+ */
+fn read_tiff_page(
+    file_path: &Path,
+    decoder: &mut tiff::decoder::Decoder<std::fs::File>,
+    sample_weights: Option<&[f32]>,
+) -> Result<(Vec<f32>, Option<f32>)> {
+    let samples = tiff_samples_per_pixel(decoder);
+    if samples == 1 {
+        let image = decoder.read_image().map_err(|e| tiff_error(file_path, e))?;
+        return Ok(tiff_samples_to_f32(image));
+    }
 
-        decoder.next_image().unwrap();
+    let weights = match sample_weights {
+        Some(weights) => weights,
+        None if samples >= 3 => &LUMINANCE_WEIGHTS[..],
+        None => &[1.0][..],
+    };
+    if weights.len() > samples {
+        return Err(StitchError::format(
+            file_path,
+            format!("Page has {} samples per pixel but {} sample weights were given", samples, weights.len()),
+        ));
     }
 
-    Image3DFile {
-        depth,
-        width,
-        height,
-        min: min,
-        max: max,
-        path: file_path.to_path_buf(),
+    if tiff_tag_first(decoder, tiff::tags::Tag::PlanarConfiguration) == Some(2) {
+        return read_tiff_planar_page(file_path, decoder, weights, samples);
     }
+
+    let image = decoder.read_image().map_err(|e| tiff_error(file_path, e))?;
+    let (data, type_max) = tiff_samples_to_f32(image);
+    let data = data
+        .chunks_exact(samples)
+        .map(|pixel| weights.iter().zip(pixel).map(|(weight, value)| weight * value).sum())
+        .collect();
+    Ok((data, type_max))
 }
 
-pub fn read_image_2d(file_path: &Path) -> Image2D {
-    let img = ImageReader::open(file_path).unwrap().decode().unwrap();
+/**
+ * Weighted sum of the samples of a TIFF page stored one sample plane after the other
+ * (PlanarConfiguration 2). Only the strips or tiles of samples with a non-zero weight are decoded.
+ */
+fn read_tiff_planar_page(
+    file_path: &Path,
+    decoder: &mut tiff::decoder::Decoder<std::fs::File>,
+    weights: &[f32],
+    samples: usize,
+) -> Result<(Vec<f32>, Option<f32>)> {
+    let (width, height) = decoder.dimensions().map_err(|e| tiff_error(file_path, e))?;
+    let (width, height) = (width as usize, height as usize);
+    let chunk_count = match decoder.get_chunk_type() {
+        tiff::decoder::ChunkType::Strip => decoder.strip_count(),
+        tiff::decoder::ChunkType::Tile => decoder.tile_count(),
+    }
+    .map_err(|e| tiff_error(file_path, e))? as usize;
+    let chunks_per_sample = chunk_count / samples;
+    let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+    let (chunk_width, chunk_height) = (chunk_width as usize, chunk_height as usize);
+    let chunks_across = width.div_ceil(chunk_width.max(1));
+
+    let mut data = vec![0.0; width * height];
+    let mut type_max = None;
+    for (sample, &weight) in weights.iter().enumerate().filter(|(_, &weight)| weight != 0.0) {
+        for i in 0..chunks_per_sample {
+            let index = (sample * chunks_per_sample + i) as u32;
+            // Tiles past the first sample plane are decoded with their padding
+            let data_width = decoder.chunk_data_dimensions(index).0 as usize;
+            let chunk = decoder.read_chunk(index).map_err(|e| tiff_error(file_path, e))?;
+            let (values, max) = tiff_samples_to_f32(chunk);
+            type_max = max;
+
+            let (x, y) = (i % chunks_across * chunk_width, i / chunks_across * chunk_height);
+            let row_width = data_width.min(width.saturating_sub(x));
+            for (row, values) in values.chunks_exact(data_width).take(height.saturating_sub(y)).enumerate() {
+                let start = (y + row) * width + x;
+                data[start..start + row_width]
+                    .iter_mut()
+                    .zip(values)
+                    .for_each(|(value, sample_value)| *value += weight * sample_value);
+            }
+        }
+    }
+
+    Ok((data, type_max))
+}
+
+/**
+ * Convert decoded TIFF samples to f32, along with the maximum of their integer type or `None`
+ * for signed and float data
+ */
+fn tiff_samples_to_f32(image: DecodingResult) -> (Vec<f32>, Option<f32>) {
+    match image {
+        DecodingResult::U8(data) => (data.iter().map(|x| *x as f32).collect(), Some(u8::MAX as f32)),
+        DecodingResult::U16(data) => (data.iter().map(|x| *x as f32).collect(), Some(u16::MAX as f32)),
+        DecodingResult::U32(data) => (data.iter().map(|x| *x as f32).collect(), Some(u32::MAX as f32)),
+        DecodingResult::U64(data) => (data.iter().map(|x| *x as f32).collect(), Some(u64::MAX as f32)),
+        DecodingResult::I8(data) => (data.iter().map(|x| *x as f32).collect(), None),
+        DecodingResult::I16(data) => (data.iter().map(|x| *x as f32).collect(), None),
+        DecodingResult::I32(data) => (data.iter().map(|x| *x as f32).collect(), None),
+        DecodingResult::I64(data) => (data.iter().map(|x| *x as f32).collect(), None),
+        DecodingResult::F32(data) => (data, None),
+        DecodingResult::F64(data) => (data.iter().map(|x| *x as f32).collect(), None),
+    }
+}
+
+pub fn read_image_2d(file_path: &Path) -> Result<Image2D> {
+    let img = ImageReader::open(file_path)
+        .map_err(|e| StitchError::io(file_path, e))?
+        .decode()
+        .map_err(|e| StitchError::format(file_path, e))?;
+    Ok(luma_image_2d(img))
+}
+
+/**
+ * Grayscale image of a decoded image, converting color to luminance
+ */
+fn luma_image_2d(img: DynamicImage) -> Image2D {
     let width = img.width();
     let height = img.height();
     let buffer = img.into_luma16().into_vec();
-    let min = 0.0;
-    let max = u16::MAX as f32;
 
-    let image = Image2D {
+    Image2D {
         width: width as usize,
         height: height as usize,
         data: buffer.iter().map(|x| *x as f32).collect(),
-        min,
-        max,
+        min: 0.0,
+        max: u16::MAX as f32,
+    }
+}
+
+/**
+ * Read one channel and timepoint of a 2D image. OME-TIFF files are read page by page,
+ * other formats only have channel 0 and timepoint 0.
+ */
+pub fn read_image_2d_channel(file_path: &Path, channel: usize, timepoint: usize) -> Result<Image2D> {
+    let is_tiff = file_path
+        .extension()
+        .is_some_and(|ext| ext == "tif" || ext == "tiff");
+    let ome = if is_tiff { read_ome_metadata(file_path)? } else { None };
+
+    let Some(ome) = ome else {
+        if channel != 0 || timepoint != 0 {
+            return Err(StitchError::config(
+                "channel",
+                format!("{:?} is not an OME-TIFF and only has channel 0 and timepoint 0", file_path),
+            ));
+        }
+        return read_image_2d(file_path);
+    };
+
+    let planes = ome.stack_planes(file_path, channel, timepoint)?;
+    let image = read_tiff_planes(file_path, Some(&planes[..1]), None)?;
+    Ok(Image2D {
+        width: image.width,
+        height: image.height,
+        data: image.data,
+        min: image.min,
+        max: image.max,
+    })
+}
+
+/**
+ * Read every channel of a 2D image at one timepoint. OME-TIFF channels are read page by page,
+ * color images are read as their luminance or split into red, green and blue depending on `rgb`,
+ * and grayscale images have one channel.
+ */
+pub fn read_image_2d_channels(file_path: &Path, timepoint: usize, rgb: RgbMode) -> Result<Vec<Image2D>> {
+    let is_tiff = file_path
+        .extension()
+        .is_some_and(|ext| ext == "tif" || ext == "tiff");
+    if let Some(ome) = if is_tiff { read_ome_metadata(file_path)? } else { None } {
+        return (0..ome.size_c)
+            .map(|c| read_image_2d_channel(file_path, c, timepoint))
+            .collect();
+    }
+
+    if timepoint != 0 {
+        return Err(StitchError::config(
+            "timepoint",
+            format!("{:?} is not an OME-TIFF and only has timepoint 0", file_path),
+        ));
+    }
+
+    let img = ImageReader::open(file_path)
+        .map_err(|e| StitchError::io(file_path, e))?
+        .decode()
+        .map_err(|e| StitchError::format(file_path, e))?;
+    if !img.color().has_color() {
+        return Ok(vec![luma_image_2d(img)]);
+    }
+
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let buffer = img.into_rgb16().into_vec();
+    let channel = |data: Vec<f32>| Image2D {
+        width,
+        height,
+        data,
+        min: 0.0,
+        max: u16::MAX as f32,
     };
+    Ok(match rgb {
+        RgbMode::Luminance => vec![channel(
+            buffer
+                .chunks_exact(3)
+                .map(|pixel| pixel.iter().zip(LUMINANCE_WEIGHTS).map(|(value, weight)| *value as f32 * weight).sum())
+                .collect(),
+        )],
+        RgbMode::Channels => (0..3)
+            .map(|c| channel(buffer.iter().skip(c).step_by(3).map(|x| *x as f32).collect()))
+            .collect(),
+    })
+}
+
+/**
+ * Save channels of the same size as pages of one TIFF that ImageJ opens as a hyperstack, each
+ * converted with its own entry of `conversions`
+ */
+pub fn save_image_2d_channels(file_path: &Path, channels: &[Image2D], conversions: &[PixelConversion]) -> Result<()> {
+    let mut description = Some(format!("ImageJ=1.11a\nimages={0}\nchannels={0}\nhyperstack=true\n", channels.len()));
+    let mut tiff = tiff::encoder::TiffEncoder::new(create_file(file_path)?).map_err(|e| tiff_error(file_path, e))?;
+    for (channel, conversion) in channels.iter().zip(conversions) {
+        let data = conversion.convert(&channel.data);
+        write_tiff_pages(&mut tiff, file_path, &data, channel.width, channel.height, &mut description)?;
+    }
 
-    image
+    Ok(())
 }
 
-pub fn save_image_2d(file_path: &PathBuf, image: &Image2D) {
+/**
+ * Save the image as an 8 or 16-bit grayscale image in the format given by the file extension
+ */
+pub fn save_image_2d(file_path: &PathBuf, image: &Image2D, conversion: &PixelConversion) -> Result<()> {
     let width = image.width as u32;
     let height = image.height as u32;
-    let buffer = image2d_to_u16(image);
-    let img = image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::from_raw(width, height, buffer).unwrap();
-    img.save(file_path).unwrap();
+    let buffer_error = || StitchError::format(file_path, "Image buffer does not match its dimensions");
+    match conversion.convert(&image.data) {
+        PixelData::U8(buffer) => image::ImageBuffer::<image::Luma<u8>, Vec<u8>>::from_raw(width, height, buffer)
+            .ok_or_else(buffer_error)?
+            .save(file_path),
+        PixelData::U16(buffer) => image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::from_raw(width, height, buffer)
+            .ok_or_else(buffer_error)?
+            .save(file_path),
+        PixelData::F32(_) => return Err(StitchError::format(file_path, "Float pixels can only be saved as TIFF")),
+    }
+    .map_err(|e| StitchError::format(file_path, e))
+}
+
+/**
+ * Append one page per `width * height` pixels of `data`. `description` is written to the first
+ * page and taken, so later pages go without it.
+ */
+fn write_tiff_pages<W: std::io::Write + std::io::Seek, K: tiff::encoder::TiffKind>(
+    encoder: &mut tiff::encoder::TiffEncoder<W, K>,
+    file_path: &Path,
+    data: &PixelData,
+    width: usize,
+    height: usize,
+    description: &mut Option<String>,
+) -> Result<()> {
+    use tiff::encoder::colortype::{ColorType, Gray16, Gray32Float, Gray8};
+
+    fn write_pages<C: ColorType, W: std::io::Write + std::io::Seek, K: tiff::encoder::TiffKind>(
+        encoder: &mut tiff::encoder::TiffEncoder<W, K>,
+        file_path: &Path,
+        data: &[C::Inner],
+        width: usize,
+        height: usize,
+        description: &mut Option<String>,
+    ) -> Result<()>
+    where
+        [C::Inner]: tiff::encoder::TiffValue,
+    {
+        for frame in data.chunks(width * height) {
+            let mut page = encoder
+                .new_image::<C>(width as u32, height as u32)
+                .map_err(|e| tiff_error(file_path, e))?;
+            if let Some(description) = description.take() {
+                page.encoder()
+                    .write_tag(tiff::tags::Tag::ImageDescription, description.as_str())
+                    .map_err(|e| tiff_error(file_path, e))?;
+            }
+            page.write_data(frame).map_err(|e| tiff_error(file_path, e))?;
+        }
+        Ok(())
+    }
+
+    match data {
+        PixelData::U8(data) => write_pages::<Gray8, _, _>(encoder, file_path, data, width, height, description),
+        PixelData::U16(data) => write_pages::<Gray16, _, _>(encoder, file_path, data, width, height, description),
+        PixelData::F32(data) => write_pages::<Gray32Float, _, _>(encoder, file_path, data, width, height, description),
+    }
 }
 
 pub fn save_as_tiff_float(
-    file_path: &PathBuf,
+    file_path: &Path,
     image: &Image3D
-) {
+) -> Result<()> {
     let width = image.width;
     let height = image.height;
     let depth = image.depth;
 
 
-    let mut tiff = tiff::encoder::TiffEncoder::new_big(std::fs::File::create(file_path).unwrap()).unwrap();
+    let mut tiff = tiff::encoder::TiffEncoder::new_big(create_file(file_path)?).map_err(|e| tiff_error(file_path, e))?;
     for i in 0..depth {
         let frame = &image.data[i * width * height..(i + 1) * width * height];
-        tiff.write_image::<tiff::encoder::colortype::Gray32Float>(width as u32, height as u32, &frame).unwrap();
+        tiff.write_image::<tiff::encoder::colortype::Gray32Float>(width as u32, height as u32, frame).map_err(|e| tiff_error(file_path, e))?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Save channels of the same size as one float TIFF hyperstack, interleaved in ImageJ's
+ * channel-first page order
+ */
+pub fn save_as_tiff_float_channels(file_path: &Path, channels: &[Image3D]) -> Result<()> {
+    let (width, height, depth) = (channels[0].width, channels[0].height, channels[0].depth);
+    if channels.iter().any(|channel| channel.width != width || channel.height != height || channel.depth != depth) {
+        return Err(StitchError::format(file_path, "Channels do not have the same size"));
+    }
+
+    let mut writer = TiffStackWriter::create(file_path, Some(hyperstack_description(channels.len(), depth)))?;
+    for i in 0..depth {
+        for channel in channels {
+            let frame = channel.data[i * width * height..(i + 1) * width * height].to_vec();
+            writer.write_slices(&PixelData::F32(frame), width, height)?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * ImageJ description for a hyperstack with channels stored first
+ */
+pub fn hyperstack_description(channels: usize, slices: usize) -> String {
+    format!(
+        "ImageJ=1.11a\nimages={}\nchannels={}\nslices={}\nhyperstack=true\n",
+        channels * slices,
+        channels,
+        slices
+    )
+}
+
+/**
+ * BigTIFF written one or more z-slices at a time, so large volumes can be streamed to disk
+ */
+pub struct TiffStackWriter {
+    encoder: tiff::encoder::TiffEncoder<std::fs::File, tiff::encoder::TiffKindBig>,
+    path: PathBuf,
+    description: Option<String>,
+}
+
+impl TiffStackWriter {
+    /**
+     * Create the file. `description` is stored as ImageDescription of the first page.
+     */
+    pub fn create(file_path: &Path, description: Option<String>) -> Result<TiffStackWriter> {
+        let encoder =
+            tiff::encoder::TiffEncoder::new_big(create_file(file_path)?).map_err(|e| tiff_error(file_path, e))?;
+        Ok(TiffStackWriter {
+            encoder,
+            path: file_path.to_path_buf(),
+            description,
+        })
+    }
+
+    /**
+     * Append the slices in `data`, each `width * height` pixels
+     */
+    pub fn write_slices(&mut self, data: &PixelData, width: usize, height: usize) -> Result<()> {
+        write_tiff_pages(&mut self.encoder, &self.path, data, width, height, &mut self.description)
+    }
+}
+
+/**
+ * Size and physical placement of an image, read from its headers without decoding pixels.
+ * Spacing and position are in metres.
+ */
+#[derive(Clone, Debug)]
+pub struct ImageMetadata {
+    pub width: usize,
+    pub height: usize,
+    pub depth: usize,
+    pub voxel_size: Option<(f64, f64, f64)>,
+    pub position: Option<(f64, f64, f64)>,
+}
+
+/**
+ * Read image size, pixel spacing and stage position from DICOM or TIFF headers
+ */
+pub fn read_image_metadata(file_path: &Path) -> Result<ImageMetadata> {
+    if file_path.is_dir() {
+        return read_dcm_series_metadata(file_path);
+    }
+
+    if is_dcm(file_path) {
+        return read_dcm_metadata(file_path);
+    }
+
+    if file_path
+        .extension()
+        .is_some_and(|ext| ext == "tif" || ext == "tiff")
+    {
+        return read_tiff_metadata(file_path);
+    }
+
+    let (width, height) = ImageReader::open(file_path)
+        .map_err(|e| StitchError::io(file_path, e))?
+        .into_dimensions()
+        .map_err(|e| StitchError::format(file_path, e))?;
+    Ok(ImageMetadata {
+        width: width as usize,
+        height: height as usize,
+        depth: 1,
+        voxel_size: None,
+        position: None,
+    })
+}
+
+/**
+ * Read Pixel Spacing, Slice Thickness and Image Position (Patient) from a DICOM file.
+ * The patient position is projected onto the image axes given by Image Orientation (Patient).
+ */
+pub fn read_dcm_metadata(file_path: &Path) -> Result<ImageMetadata> {
+    let dicom_obj = dicom::object::OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(file_path)
+        .map_err(|e| StitchError::format(file_path, e))?;
+
+    let floats = |tag| -> Option<Vec<f64>> {
+        dicom_obj
+            .element_opt(tag)
+            .ok()
+            .flatten()
+            .and_then(|element| element.value().to_multi_float64().ok())
+    };
+    let int = |tag| -> Option<usize> {
+        dicom_obj
+            .element_opt(tag)
+            .ok()
+            .flatten()
+            .and_then(|element| element.value().to_int::<usize>().ok())
+    };
+
+    let width = int(tags::COLUMNS).ok_or_else(|| StitchError::format(file_path, "Missing Columns"))?;
+    let height = int(tags::ROWS).ok_or_else(|| StitchError::format(file_path, "Missing Rows"))?;
+    let depth = int(tags::NUMBER_OF_FRAMES).unwrap_or(1).max(1);
+
+    // Pixel Spacing is row spacing (y) then column spacing (x)
+    let voxel_size = floats(tags::PIXEL_SPACING)
+        .filter(|spacing| spacing.len() == 2)
+        .map(|spacing| {
+            let z = floats(tags::SPACING_BETWEEN_SLICES)
+                .or_else(|| floats(tags::SLICE_THICKNESS))
+                .and_then(|values| values.first().copied())
+                .unwrap_or(spacing[0]);
+            (spacing[1] * 1e-3, spacing[0] * 1e-3, z * 1e-3)
+        });
+
+    let orientation = floats(tags::IMAGE_ORIENTATION_PATIENT)
+        .filter(|orientation| orientation.len() == 6)
+        .unwrap_or_else(|| vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+    let row = [orientation[0], orientation[1], orientation[2]];
+    let column = [orientation[3], orientation[4], orientation[5]];
+    let normal = [
+        row[1] * column[2] - row[2] * column[1],
+        row[2] * column[0] - row[0] * column[2],
+        row[0] * column[1] - row[1] * column[0],
+    ];
+    let dot = |a: &[f64; 3], b: &[f64]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let position = floats(tags::IMAGE_POSITION_PATIENT)
+        .filter(|position| position.len() == 3)
+        .map(|position| {
+            (
+                dot(&row, &position) * 1e-3,
+                dot(&column, &position) * 1e-3,
+                dot(&normal, &position) * 1e-3,
+            )
+        });
+
+    Ok(ImageMetadata {
+        width,
+        height,
+        depth,
+        voxel_size,
+        position,
+    })
+}
+
+/**
+ * Read the metadata of a DICOM series from its first slice. The slice spacing is the distance
+ * between the first two slices when they have positions.
+ */
+fn read_dcm_series_metadata(dir: &Path) -> Result<ImageMetadata> {
+    let slices = read_dcm_series(dir)?;
+    let mut metadata = read_dcm_metadata(&slices[0].path)?;
+    metadata.depth = slices.iter().map(|slice| slice.frames).sum();
+
+    if let [first, second, ..] = &slices[..] {
+        if let (Some(a), Some(b), Some(voxel_size)) = (first.position, second.position, &mut metadata.voxel_size) {
+            let normal = first.normal.unwrap_or([0.0, 0.0, 1.0]);
+            let distance = (0..3).map(|axis| (b[axis] - a[axis]) * normal[axis]).sum::<f64>().abs();
+            if first.frames == 1 && distance > 0.0 {
+                voxel_size.2 = distance * 1e-3;
+            }
+        }
+    }
+
+    Ok(metadata)
+}
+
+/**
+ * Read XResolution/YResolution, XPosition/YPosition and the ImageJ `unit` and `spacing`
+ * entries of ImageDescription from a TIFF file. Pages are counted but not decoded.
+ * OME-TIFF files use the PhysicalSize, SizeZ and first Plane position of their OME-XML instead.
+ */
+pub fn read_tiff_metadata(file_path: &Path) -> Result<ImageMetadata> {
+    use tiff::tags::Tag;
+
+    let mut decoder = tiff::decoder::Decoder::new(open_file(file_path)?).map_err(|e| tiff_error(file_path, e))?;
+    let (width, height) = decoder.dimensions().map_err(|e| tiff_error(file_path, e))?;
+
+    let description = decoder
+        .get_tag_ascii_string(Tag::ImageDescription)
+        .ok()
+        .unwrap_or_default();
+    let imagej_entry = |key: &str| {
+        description
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(name, _)| name.trim() == key)
+            .map(|(_, value)| value.trim().to_string())
+    };
+
+    // ImageJ stores the calibration unit in the description and leaves ResolutionUnit at none
+    let unit = match imagej_entry("unit") {
+        Some(unit) => unit_to_meters(&unit),
+        None => match decoder.find_tag_unsigned::<u16>(Tag::ResolutionUnit).ok().flatten() {
+            Some(3) => Some(1e-2),
+            Some(1) => None,
+            _ => Some(0.0254),
+        },
+    };
+
+    let mut rational = |tag: Tag| -> Option<f64> {
+        match decoder.find_tag(tag).ok().flatten()? {
+            tiff::decoder::ifd::Value::Rational(n, d) if d != 0 => Some(n as f64 / d as f64),
+            tiff::decoder::ifd::Value::RationalBig(n, d) if d != 0 => Some(n as f64 / d as f64),
+            tiff::decoder::ifd::Value::Float(val) => Some(val as f64),
+            tiff::decoder::ifd::Value::Double(val) => Some(val),
+            _ => None,
+        }
+    };
+    let x_resolution = rational(Tag::XResolution).filter(|res| *res > 0.0);
+    let y_resolution = rational(Tag::YResolution).filter(|res| *res > 0.0);
+    let x_position = rational(Tag::Unknown(286));
+    let y_position = rational(Tag::Unknown(287));
+
+    let voxel_size = match (unit, x_resolution, y_resolution) {
+        (Some(unit), Some(x), Some(y)) => {
+            let z = imagej_entry("spacing")
+                .and_then(|spacing| spacing.parse::<f64>().ok())
+                .map_or(unit / x, |spacing| spacing * unit);
+            Some((unit / x, unit / y, z))
+        }
+        _ => None,
+    };
+
+    // XPosition/YPosition are given in ResolutionUnit
+    let position = match (unit, x_position, y_position) {
+        (Some(unit), Some(x), Some(y)) => Some((x * unit, y * unit, 0.0)),
+        _ => None,
+    };
+
+    if let Some(ome) = parse_ome_xml(file_path, &description)? {
+        let voxel_size = match ome.physical_size {
+            (Some(x), Some(y), z) => Some((x, y, z.unwrap_or(x))),
+            _ => voxel_size,
+        };
+        return Ok(ImageMetadata {
+            width: ome.size_x,
+            height: ome.size_y,
+            depth: ome.size_z,
+            voxel_size,
+            position: ome.position.or(position),
+        });
+    }
+
+    let mut depth = 1;
+    while decoder.more_images() {
+        decoder.next_image().map_err(|e| tiff_error(file_path, e))?;
+        depth += 1;
+    }
+
+    Ok(ImageMetadata {
+        width: width as usize,
+        height: height as usize,
+        depth,
+        voxel_size,
+        position,
+    })
+}
+
+/**
+ * Length of one unit in metres, for the unit names used by DICOM, TIFF and ImageJ
+ */
+pub fn unit_to_meters(unit: &str) -> Option<f64> {
+    match unit.trim() {
+        "m" | "meter" | "metre" => Some(1.0),
+        "cm" | "centimeter" => Some(1e-2),
+        "mm" | "millimeter" => Some(1e-3),
+        "um" | "µm" | "\\u00B5m" | "micron" | "microns" | "micrometer" => Some(1e-6),
+        "nm" | "nanometer" => Some(1e-9),
+        "inch" | "in" => Some(0.0254),
+        _ => None,
+    }
+}
+
+/**
+ * Check if the file should be read with the DICOM reader: a `.dcm` file, or a directory holding
+ * a DICOM series with one file per slice
+ */
+pub fn is_dcm(file_path: &Path) -> bool {
+    file_path.extension().is_some_and(|ext| ext == "dcm") || file_path.is_dir()
+}
+
+fn open_file(file_path: &Path) -> Result<std::fs::File> {
+    std::fs::File::open(file_path).map_err(|e| StitchError::io(file_path, e))
+}
+
+fn create_file(file_path: &Path) -> Result<std::fs::File> {
+    std::fs::File::create(file_path).map_err(|e| StitchError::io(file_path, e))
+}
+
+fn tiff_error(file_path: &Path, err: tiff::TiffError) -> StitchError {
+    match err {
+        tiff::TiffError::IoError(e) => StitchError::io(file_path, e),
+        e => StitchError::format(file_path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intensity::{OutputDtype, OutputScaling};
+
+    #[test]
+    fn dicom_slabs_read_only_their_frames() {
+        let dir = std::env::temp_dir().join(format!("stitch-dcm-frames-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut image = Image3D::new(3, 5, 4, 0.0, 255.0);
+        image.data.iter_mut().enumerate().for_each(|(i, value)| *value = i as f32);
+
+        // 8 bits with an odd number of pixel bytes, which are padded
+        for dtype in [OutputDtype::U8, OutputDtype::U16] {
+            let path = dir.join(format!("tile-{}.dcm", dtype.bits()));
+            save_as_dcm(&path, &image, &PixelConversion::new(dtype, OutputScaling::Raw, (0.0, 0.0), None)).unwrap();
+
+            let file = read_dcm_headers(&path).unwrap();
+            let header = &file.dcm_files.as_ref().unwrap()[0];
+            assert!(header.pixel_data_offset.is_some());
+            assert_eq!(header.frame_bytes, 15 * dtype.bits() as usize / 8);
+
+            assert_eq!(file.get_image().unwrap().data, image.data);
+            let slab = file.get_slices(1, 3).unwrap();
+            assert_eq!((slab.width, slab.height, slab.depth), (3, 5, 2));
+            assert_eq!(slab.data, image.data[15..45]);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resampling_interpolates_between_pixel_centers() {
+        let mut ramp = Image2D::new(4, 2, 0.0, 3.0);
+        ramp.data.iter_mut().enumerate().for_each(|(i, value)| *value = (i % 4) as f32);
+        let resampled = ramp.resampled((2.0, 1.0));
+        assert_eq!((resampled.width, resampled.height), (8, 2));
+        assert_eq!(resampled.data[..8], [0.0, 0.25, 0.75, 1.25, 1.75, 2.25, 2.75, 3.0]);
+        assert_eq!(ramp.resampled((0.5, 1.0)).data, vec![0.5, 2.5, 0.5, 2.5]);
+
+        // Slabs of a resampled file match the whole resampled image
+        let path = std::env::temp_dir().join(format!("stitch-resample-{}.tif", std::process::id()));
+        let mut stack = Image3D::new(2, 2, 4, 0.0, 3.0);
+        stack.data.iter_mut().enumerate().for_each(|(i, value)| *value = (i / 4) as f32);
+        save_as_tiff_float(&path, &stack).unwrap();
+        let mut file = read_tiff_headers(&path).unwrap();
+        file.resample((1.0, 1.0, 2.0));
+        assert_eq!((file.width, file.height, file.depth), (2, 2, 8));
+
+        let image = file.get_image().unwrap();
+        let slices = image.data.chunks(4).map(|slice| slice[0]).collect::<Vec<_>>();
+        assert_eq!(slices, [0.0, 0.25, 0.75, 1.25, 1.75, 2.25, 2.75, 3.0]);
+        for (start, end) in [(0, 3), (3, 6), (5, 8)] {
+            assert_eq!(file.get_slices(start, end).unwrap().data, image.data[start * 4..end * 4]);
+        }
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,315 @@
+use rayon::prelude::*;
+use serde::Deserialize;
+
+/**
+ * Pixel type of fused images
+ */
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputDtype {
+    U8,
+    U16,
+    F32,
+}
+
+impl OutputDtype {
+    /// Largest value of an integer type, `None` for floats.
+    pub fn max_value(&self) -> Option<f32> {
+        match self {
+            OutputDtype::U8 => Some(u8::MAX as f32),
+            OutputDtype::U16 => Some(u16::MAX as f32),
+            OutputDtype::F32 => None,
+        }
+    }
+
+    pub fn bits(&self) -> u16 {
+        match self {
+            OutputDtype::U8 => 8,
+            OutputDtype::U16 => 16,
+            OutputDtype::F32 => 32,
+        }
+    }
+}
+
+/**
+ * How fused values are mapped to output pixels. Every mode but `raw` picks a window of values
+ * that is stretched over the full range of integer types, or clipped to for floats.
+ */
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputScaling {
+    /// Write values as they are, rounded and clamped for integer types.
+    Raw,
+    /// Window from the value range of the input tiles.
+    InputRange,
+    /// Window between two percentiles of the fused image, each between 0 and 100.
+    Percentiles([f32; 2]),
+    /// Window given explicitly as minimum and maximum.
+    Range([f32; 2]),
+}
+
+impl OutputScaling {
+    /**
+     * Whether the window can only be found from the fused values
+     */
+    pub fn needs_histogram(&self) -> bool {
+        matches!(self, OutputScaling::Percentiles(_))
+    }
+
+    /**
+     * Whether the window depends on the value range of the input tiles, which bounds the
+     * histogram for percentiles
+     */
+    pub fn needs_input_range(&self) -> bool {
+        matches!(self, OutputScaling::InputRange | OutputScaling::Percentiles(_))
+    }
+
+    /**
+     * Window for one fused channel. `input_range` is the value range of its tiles, which is
+     * replaced by the fused value range from `histogram` when it is empty.
+     */
+    pub fn window(&self, input_range: (f32, f32), histogram: Option<&Histogram>) -> Option<(f32, f32)> {
+        match *self {
+            OutputScaling::Raw => None,
+            OutputScaling::InputRange => match histogram {
+                Some(histogram) if input_range.1 <= input_range.0 => Some(histogram.value_range()),
+                _ => Some(input_range),
+            },
+            OutputScaling::Percentiles([low, high]) => {
+                let histogram = histogram.expect("percentile scaling needs a histogram of the fused image");
+                Some((histogram.percentile(low), histogram.percentile(high)))
+            }
+            OutputScaling::Range([min, max]) => Some((min, max)),
+        }
+    }
+}
+
+/**
+ * Pixels converted to an output type
+ */
+pub enum PixelData {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    F32(Vec<f32>),
+}
+
+/**
+ * Output type and window of one fused channel
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct PixelConversion {
+    pub dtype: OutputDtype,
+    pub window: Option<(f32, f32)>,
+}
+
+impl PixelConversion {
+    pub fn new(dtype: OutputDtype, scaling: OutputScaling, input_range: (f32, f32), histogram: Option<&Histogram>) -> Self {
+        PixelConversion {
+            dtype,
+            window: scaling.window(input_range, histogram),
+        }
+    }
+
+    /**
+     * Map a fused value to the output range. Values of integer types are rounded but kept as
+     * floats, so they can still be averaged for downsampled levels.
+     */
+    pub fn scale(&self, value: f32) -> f32 {
+        let value = match (self.window, self.dtype.max_value()) {
+            (Some((low, high)), Some(max_value)) => {
+                let range = if high > low { high - low } else { 1.0 };
+                (value - low) / range * max_value
+            }
+            (Some((low, high)), None) => value.clamp(low, high.max(low)),
+            (None, _) => value,
+        };
+
+        match self.dtype.max_value() {
+            Some(max_value) if value.is_finite() => value.round().clamp(0.0, max_value),
+            Some(_) => 0.0,
+            None => value,
+        }
+    }
+
+    /**
+     * Intercept and slope mapping integer pixels back to fused values, when a window was
+     * stretched over the integer range
+     */
+    pub fn rescale(&self) -> Option<(f32, f32)> {
+        let (low, high) = self.window?;
+        let max_value = self.dtype.max_value()?;
+        let range = if high > low { high - low } else { 1.0 };
+        Some((low, range / max_value))
+    }
+
+    pub fn scale_all(&self, data: &[f32]) -> Vec<f32> {
+        data.par_iter().map(|value| self.scale(*value)).collect()
+    }
+
+    pub fn convert(&self, data: &[f32]) -> PixelData {
+        match self.dtype {
+            OutputDtype::U8 => PixelData::U8(data.par_iter().map(|value| self.scale(*value) as u8).collect()),
+            OutputDtype::U16 => PixelData::U16(data.par_iter().map(|value| self.scale(*value) as u16).collect()),
+            OutputDtype::F32 => PixelData::F32(data.par_iter().map(|value| self.scale(*value)).collect()),
+        }
+    }
+}
+
+const HISTOGRAM_BINS: usize = 65536;
+
+/**
+ * Histogram of fused values over a fixed range, used to find percentiles without keeping the
+ * fused image. Values outside the range are counted in the first or last bin.
+ */
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    min: f32,
+    max: f32,
+    counts: Vec<u64>,
+    value_min: f32,
+    value_max: f32,
+}
+
+impl Histogram {
+    pub fn new(min: f32, max: f32) -> Histogram {
+        Histogram {
+            min,
+            max: if max > min { max } else { min + 1.0 },
+            counts: vec![0; HISTOGRAM_BINS],
+            value_min: f32::INFINITY,
+            value_max: f32::NEG_INFINITY,
+        }
+    }
+
+    /**
+     * Count the finite values in `data`
+     */
+    pub fn add(&mut self, data: &[f32]) {
+        let scale = HISTOGRAM_BINS as f32 / (self.max - self.min);
+        // One set of bins per rayon split rather than per chunk
+        let counted = data
+            .par_chunks(1 << 16)
+            .fold(
+                || (vec![0u64; HISTOGRAM_BINS], f32::INFINITY, f32::NEG_INFINITY),
+                |(mut counts, mut value_min, mut value_max), chunk| {
+                    for &value in chunk.iter().filter(|value| value.is_finite()) {
+                        let bin = ((value - self.min) * scale).clamp(0.0, (HISTOGRAM_BINS - 1) as f32) as usize;
+                        counts[bin] += 1;
+                        value_min = value_min.min(value);
+                        value_max = value_max.max(value);
+                    }
+                    (counts, value_min, value_max)
+                },
+            )
+            .reduce_with(|mut a, b| {
+                a.0.iter_mut().zip(&b.0).for_each(|(a, b)| *a += b);
+                (a.0, a.1.min(b.1), a.2.max(b.2))
+            });
+        let Some((counts, value_min, value_max)) = counted else {
+            return;
+        };
+
+        self.counts.iter_mut().zip(&counts).for_each(|(a, b)| *a += b);
+        self.value_min = self.value_min.min(value_min);
+        self.value_max = self.value_max.max(value_max);
+    }
+
+    /**
+     * Smallest and largest counted value
+     */
+    pub fn value_range(&self) -> (f32, f32) {
+        if self.value_min > self.value_max {
+            (0.0, 0.0)
+        } else {
+            (self.value_min, self.value_max)
+        }
+    }
+
+    /**
+     * Approximate value below which `percentile` percent of the counted values lie
+     */
+    pub fn percentile(&self, percentile: f32) -> f32 {
+        let total = self.counts.iter().sum::<u64>();
+        if total == 0 {
+            return 0.0;
+        }
+
+        let target = (percentile.clamp(0.0, 100.0) as f64 / 100.0 * total as f64).ceil().max(1.0) as u64;
+        let mut cumulative = 0;
+        let bin = self
+            .counts
+            .iter()
+            .position(|count| {
+                cumulative += count;
+                cumulative >= target
+            })
+            .unwrap_or(HISTOGRAM_BINS - 1);
+
+        let value = self.min + (bin as f32 + 0.5) * (self.max - self.min) / HISTOGRAM_BINS as f32;
+        value.clamp(self.value_min, self.value_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(min: f32, max: f32, data: &[f32]) -> Histogram {
+        let mut histogram = Histogram::new(min, max);
+        histogram.add(data);
+        histogram
+    }
+
+    #[test]
+    fn empty_histogram() {
+        let histogram = histogram(0.0, 1.0, &[f32::NAN, f32::INFINITY]);
+        assert_eq!(histogram.percentile(50.0), 0.0);
+        assert_eq!(histogram.value_range(), (0.0, 0.0));
+    }
+
+    #[test]
+    fn percentiles_of_uniform_values() {
+        let data = (0..1000).map(|value| value as f32).collect::<Vec<_>>();
+        let histogram = histogram(0.0, 1000.0, &data);
+        // Percentiles fall on bin centers, 1000 / 65536 apart
+        for (percentile, expected) in [(0.0, 0.0), (50.0, 499.0), (99.0, 989.0), (100.0, 999.0)] {
+            let value = histogram.percentile(percentile);
+            assert!((value - expected).abs() < 0.01, "{}: {}", percentile, value);
+        }
+        assert_eq!(histogram.percentile(150.0), histogram.percentile(100.0));
+        assert_eq!(histogram.percentile(-5.0), histogram.percentile(0.0));
+    }
+
+    #[test]
+    fn values_outside_the_range_fall_in_the_end_bins() {
+        let histogram = histogram(0.0, 10.0, &[-5.0, 2.0, 20.0, f32::NAN]);
+        assert_eq!(histogram.value_range(), (-5.0, 20.0));
+        assert!(histogram.percentile(0.0) < 0.001);
+        assert!((histogram.percentile(50.0) - 2.0).abs() < 0.001);
+        assert!(histogram.percentile(100.0) > 9.999 && histogram.percentile(100.0) <= 10.0);
+    }
+
+    #[test]
+    fn constant_values() {
+        let histogram = histogram(5.0, 5.0, &[5.0; 10]);
+        assert_eq!(histogram.percentile(1.0), 5.0);
+        assert_eq!(histogram.percentile(99.0), 5.0);
+    }
+
+    #[test]
+    fn windows_and_scaling() {
+        let fused = histogram(0.0, 100.0, &[10.0, 20.0, 30.0]);
+        assert_eq!(OutputScaling::InputRange.window((0.0, 0.0), Some(&fused)), Some((10.0, 30.0)));
+        assert_eq!(OutputScaling::InputRange.window((1.0, 2.0), Some(&fused)), Some((1.0, 2.0)));
+        assert_eq!(OutputScaling::Raw.window((1.0, 2.0), Some(&fused)), None);
+
+        let u8 = PixelConversion::new(OutputDtype::U8, OutputScaling::Range([0.0, 10.0]), (0.0, 0.0), None);
+        let scaled = [5.0, -1.0, 20.0, f32::NAN].map(|value| u8.scale(value));
+        assert_eq!(scaled, [128.0, 0.0, 255.0, 0.0]);
+        assert_eq!(u8.rescale(), Some((0.0, 10.0 / 255.0)));
+
+        let f32 = PixelConversion::new(OutputDtype::F32, OutputScaling::Range([0.0, 10.0]), (0.0, 0.0), None);
+        assert_eq!([-1.0, 5.5, 20.0].map(|value| f32.scale(value)), [0.0, 5.5, 10.0]);
+        assert_eq!(f32.rescale(), None);
+    }
+}
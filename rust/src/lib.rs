@@ -0,0 +1,48 @@
+//! Tile registration and fusion for 2D and 3D microscopy acquisitions.
+//!
+//! The `stitch` binary is a thin wrapper around this crate. Embedders can
+//! build a [`StitchConfig`] directly (or load one with [`read_config_file`])
+//! and drive [`stitch2d::stitch`] / [`stitch3d::stitch`] and the fusers in
+//! [`fuse`] themselves, or hand the whole config to [`pipeline`].
+
+// The numeric kernels index several parallel buffers at once and the
+// registration entry points mirror the config fields one-to-one.
+#![allow(
+    clippy::needless_range_loop,
+    clippy::too_many_arguments,
+    clippy::type_complexity
+)]
+// The config schema is one large `json!` literal.
+#![recursion_limit = "256"]
+
+pub mod bigstitcher;
+pub mod chunked;
+pub mod config;
+pub mod dicom_output;
+pub mod error;
+pub mod fuse;
+pub mod grid;
+pub mod image;
+pub mod intensity;
+pub mod normalize;
+pub mod ome;
+pub mod pair_cache;
+pub mod pair_scheduler;
+pub mod pipeline;
+pub mod preprocess;
+pub mod pyramid_tiff;
+pub mod schema;
+pub mod stitch2d;
+pub mod stitch3d;
+pub mod tile_configuration;
+pub mod timelapse;
+
+pub use config::{read_config_file, StitchConfig, StitchMode};
+pub use error::{Result, StitchError};
+pub use fuse::{fuse_2d, fuse_3d_float, FuseMode};
+pub use image::{
+    read_dcm, read_dcm_headers, read_image_2d, read_tiff, read_tiff_headers, Image2D, Image3D,
+    Image3DFile,
+};
+pub use stitch2d::{IBox2D, Stitch2DResult};
+pub use stitch3d::{IBox3D, Stitch3DResult};
@@ -0,0 +1,303 @@
+use rayon::prelude::*;
+use std::path::Path;
+
+use crate::config::StitchConfig;
+use crate::error::{Result, StitchError};
+use crate::image::{is_dcm, read_dcm, read_tiff, save_as_tiff_float, Image3D};
+
+pub fn normalize_brightness(config: &StitchConfig) -> Result<()> {
+    let tile_paths = config.tile_paths.clone();
+    // New directory /normalized
+    let normalized_path = config.output_path.join("normalized");
+    if !normalized_path.exists() {
+        std::fs::create_dir_all(&normalized_path).map_err(|e| StitchError::io(&normalized_path, e))?;
+    }
+
+    // Get mean brightness
+    let mean_brightness = 1.0;
+
+    println!("Mean brightness: {}", mean_brightness);
+
+    // Normalize brightness
+    for path in tile_paths.iter() {
+        let image = if is_dcm(path) {
+            read_dcm(path)?
+        } else {
+            read_tiff(path)?
+        };
+
+        // let brightness = image.data.par_chunks(image.width as usize * image.height as usize).map(|chunk| {
+        //     chunk.iter().map(|x| if x.is_finite() { x } else { &0.0 }).sum::<f32>()
+        // }).sum::<f32>() / image.data.len() as f32;
+
+        let brightness = otsu_threshold(&image.data);
+
+        let normalized = image
+            .data
+            .iter()
+            .map(|x| x * mean_brightness / brightness)
+            .collect::<Vec<_>>();
+        let normalized_image = Image3D {
+            width: image.width,
+            height: image.height,
+            depth: image.depth,
+            data: normalized,
+            min: 0.0,
+            max: 0.0,
+        };
+
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| StitchError::config("tile_paths", format!("Invalid tile path {:?}", path)))?;
+        let output_file = normalized_path.join(file_name);
+
+        save_as_tiff_float(&output_file, &normalized_image)?;
+
+        println!("Normalized file saved to: {:?}", output_file);
+    }
+
+    Ok(())
+}
+
+pub fn otsu_threshold(data: &[f32]) -> f32 {
+    let mut data = data.to_vec();
+    data.retain(|&x| x.is_finite());
+
+    if data.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+
+    let max_value: f32 = data.iter().fold(data[0], |acc, &x| acc.max(x)) + 1.0;
+    let min_value: f32 = data.iter().fold(data[0], |acc, &x| acc.min(x));
+
+    let diff = max_value - min_value;
+
+    let hist_size = 512;
+    let mut hist = vec![0; hist_size];
+    for i in 0..data.len() {
+        let index = ((data[i] - min_value) / diff * hist_size as f32)
+            .floor()
+            .clamp(0.0, (hist_size - 1) as f32) as usize;
+        hist[index] += 1;
+    }
+
+    // Sum histogram, weighted
+    let mut sum = 0.0;
+    for i in 0..hist_size {
+        sum += (hist[i] as f32) * ((i as f32 / hist_size as f32) * diff + min_value);
+    }
+
+    let mut background_sum = 0.0;
+    let mut background_weight = 0.0;
+
+    let mut max_variance = 0.0;
+    let mut best_threshold = 0.0;
+
+    for i in 0..hist_size {
+        let threshold = (i as f32 / hist_size as f32 + 0.0 / hist_size as f32) * diff + min_value;
+        background_weight += hist[i] as f32;
+        if background_weight == 0.0 {
+            continue;
+        }
+
+        let foreground_weight = data.len() as f32 - background_weight;
+        if foreground_weight == 0.0 {
+            break;
+        }
+
+        background_sum += threshold * hist[i] as f32;
+        let foreground_sum = sum - background_sum;
+
+        //  println!("Background Sum: {}, Background Weight: {}, Foreground Sum: {}, Foreground Weight: {}, hist: {}", background_sum, background_weight, foreground_sum, foreground_weight, hist[i]);
+
+        let background_mean = background_sum / background_weight;
+        let foreground_mean = foreground_sum / foreground_weight;
+
+        let mean_diff_sq = (background_mean - foreground_mean).powi(2);
+        let variance = background_weight * foreground_weight * mean_diff_sq;
+
+        if variance > max_variance {
+            max_variance = variance;
+            best_threshold = threshold;
+        }
+    }
+
+    best_threshold
+}
+
+pub fn normalize2(path: &Path, dim_mask: (bool, bool, bool)) -> Result<()> {
+    let blurred_file_path = path.with_extension("blurred.tif");
+    // check if exists
+    let blurred_file = if !blurred_file_path.exists() {
+        println!("Creating blurred file");
+        let mut image = read_tiff(path)?;
+        let kw = 10;
+        let kh = kw;
+        let kd = kw;
+
+        let kernel = generate_guassian_kernel_3d(kw, 1, 1, 20.0);
+
+        for i in 0..5 {
+            if dim_mask.0 {
+                // Process x
+                image
+                    .data
+                    .par_chunks_exact_mut(image.width)
+                    .for_each(|chunk| {
+                        let copy = chunk.to_vec();
+                        for x in 0..image.width {
+                            let mut sum = 0.0;
+                            let mut weight_sum = 0.0;
+                            for kx in 0..kw {
+                                let x2 = x as i32 + kx as i32 - kw as i32 / 2;
+                                if x2 >= 0
+                                    && x2 < image.width as i32
+                                    && copy[x2 as usize].is_finite()
+                                {
+                                    let val = copy[x2 as usize];
+                                    let weight = kernel[kx];
+                                    sum += val * weight;
+                                    weight_sum += weight;
+                                }
+                            }
+                            chunk[x] = sum / weight_sum;
+                        }
+                    });
+
+                println!("Processed x");
+            }
+
+            if dim_mask.1 {
+                // Process y
+                image
+                    .data
+                    .par_chunks_exact_mut(image.width * image.height)
+                    .for_each(|chunk| {
+                        let mut copy = vec![0.0; chunk.len()];
+                        transpose::transpose(chunk, &mut copy, image.width, image.height);
+                        for x in 0..image.width {
+                            for y in 0..image.height {
+                                let mut sum = 0.0;
+                                let mut weight_sum = 0.0;
+                                for ky in 0..kh {
+                                    let y2 = y as i32 + ky as i32 - kh as i32 / 2;
+                                    if y2 >= 0
+                                        && y2 < image.height as i32
+                                        && copy[x * image.height + y2 as usize].is_finite()
+                                    {
+                                        let val = copy[x * image.height + y2 as usize];
+                                        let weight = kernel[ky];
+                                        sum += val * weight;
+                                        weight_sum += weight;
+                                    }
+                                }
+                                chunk[y * image.width + x] = sum / weight_sum;
+                            }
+                        }
+                    });
+
+                println!("Processed y");
+            }
+
+            // Process z
+            if dim_mask.2 {
+                let mut scratch = vec![0.0; image.depth];
+
+                for x in 0..image.width {
+                    for y in 0..image.height {
+                        for z in 0..image.depth {
+                            scratch[z] =
+                                image.data[z * image.width * image.height + y * image.width + x];
+                        }
+
+                        for z in 0..image.depth {
+                            let mut sum = 0.0;
+                            let mut weight_sum = 0.0;
+                            for kz in 0..kd {
+                                let z2 = z as i32 + kz as i32 - kd as i32 / 2;
+                                if z2 >= 0
+                                    && z2 < image.depth as i32
+                                    && scratch[z2 as usize].is_finite()
+                                {
+                                    let val = scratch[z2 as usize];
+                                    let weight = kernel[kz];
+                                    sum += val * weight;
+                                    weight_sum += weight;
+                                }
+                            }
+                            image.data[z * image.width * image.height + y * image.width + x] =
+                                sum / weight_sum;
+                        }
+                    }
+                }
+                println!("Processed z");
+            }
+
+            println!("Iteration: {}", i);
+        }
+        save_as_tiff_float(&blurred_file_path, &image)?;
+
+        image
+    } else {
+        println!("Reading blurred file");
+        read_tiff(&blurred_file_path)?
+    };
+
+    //let blurred_file_path2 = path.with_extension("blurred2.tif");
+
+    let mut max: f32 = 0.0;
+    blurred_file.data.iter().for_each(|x| {
+        if x.is_finite() {
+            max = max.max(*x);
+        }
+    });
+    // Threshold
+    //let threshold = otsu_threshold(&blurred_file.data);
+    //println!("Threshold: {}", threshold);
+
+    // Normalize
+    let mut image = read_tiff(path)?;
+
+    image
+        .data
+        .iter_mut()
+        .zip(blurred_file.data.iter())
+        .for_each(|(x, y)| {
+            if y.is_finite() && *y > f32::EPSILON {
+                *x = *x * max / y;
+            }
+        });
+
+    let output_file = path.with_extension("normalized.tif");
+    save_as_tiff_float(&output_file, &image)
+}
+
+pub fn generate_guassian_kernel_3d(
+    width: usize,
+    height: usize,
+    depth: usize,
+    sigma: f32,
+) -> Vec<f32> {
+    let mut kernel = vec![0.0; width * height * depth];
+    let sigma_sqr = sigma * sigma;
+    let half_width = width / 2;
+    let half_height = height / 2;
+    let half_depth = depth / 2;
+    let mut sum = 0.0;
+    for zi in 0..depth {
+        for yi in 0..height {
+            for xi in 0..width {
+                let x = xi as f32 - half_width as f32;
+                let y = yi as f32 - half_height as f32;
+                let z = zi as f32 - half_depth as f32;
+                let value = (-(x * x + y * y + z * z) / (2.0 * sigma_sqr)).exp();
+                kernel[zi * width * height + yi * width + xi] = value;
+                sum += value;
+            }
+        }
+    }
+    for i in 0..kernel.len() {
+        kernel[i] /= sum;
+    }
+    kernel
+}
@@ -0,0 +1,270 @@
+use std::path::Path;
+
+use crate::error::{Result, StitchError};
+use crate::image::unit_to_meters;
+
+/**
+ * Order in which OME-TIFF planes are stored, fastest varying axis first after XY
+ */
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DimensionOrder {
+    XYZCT,
+    XYZTC,
+    XYCZT,
+    XYCTZ,
+    XYTCZ,
+    XYTZC,
+}
+
+impl DimensionOrder {
+    pub fn parse(order: &str) -> Option<DimensionOrder> {
+        match order {
+            "XYZCT" => Some(DimensionOrder::XYZCT),
+            "XYZTC" => Some(DimensionOrder::XYZTC),
+            "XYCZT" => Some(DimensionOrder::XYCZT),
+            "XYCTZ" => Some(DimensionOrder::XYCTZ),
+            "XYTCZ" => Some(DimensionOrder::XYTCZ),
+            "XYTZC" => Some(DimensionOrder::XYTZC),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Image dimensions and calibration from the `Pixels` element of an OME-XML header.
+ * Physical sizes are in metres.
+ */
+#[derive(Clone, Debug)]
+pub struct OmeMetadata {
+    pub size_x: usize,
+    pub size_y: usize,
+    pub size_c: usize,
+    pub size_z: usize,
+    pub size_t: usize,
+    pub dimension_order: DimensionOrder,
+    pub physical_size: (Option<f64>, Option<f64>, Option<f64>),
+    /// Stage position of the first plane, if recorded.
+    pub position: Option<(f64, f64, f64)>,
+}
+
+impl OmeMetadata {
+    /**
+     * Index of the TIFF page holding the given channel, z-slice and timepoint
+     */
+    pub fn plane_index(&self, c: usize, z: usize, t: usize) -> usize {
+        let (c_size, z_size, t_size) = (self.size_c, self.size_z, self.size_t);
+        match self.dimension_order {
+            DimensionOrder::XYZCT => z + z_size * (c + c_size * t),
+            DimensionOrder::XYZTC => z + z_size * (t + t_size * c),
+            DimensionOrder::XYCZT => c + c_size * (z + z_size * t),
+            DimensionOrder::XYCTZ => c + c_size * (t + t_size * z),
+            DimensionOrder::XYTCZ => t + t_size * (c + c_size * z),
+            DimensionOrder::XYTZC => t + t_size * (z + z_size * c),
+        }
+    }
+
+    /**
+     * Pages forming the z-stack of one channel and timepoint, in z order
+     */
+    pub fn stack_planes(&self, file_path: &Path, channel: usize, timepoint: usize) -> Result<Vec<usize>> {
+        if channel >= self.size_c {
+            return Err(StitchError::config(
+                "channel",
+                format!("{:?} has {} channels, channel {} does not exist", file_path, self.size_c, channel),
+            ));
+        }
+        if timepoint >= self.size_t {
+            return Err(StitchError::config(
+                "timepoint",
+                format!("{:?} has {} timepoints, timepoint {} does not exist", file_path, self.size_t, timepoint),
+            ));
+        }
+
+        Ok((0..self.size_z)
+            .map(|z| self.plane_index(channel, z, timepoint))
+            .collect())
+    }
+}
+
+/**
+ * Parse the OME-XML stored in the ImageDescription of an OME-TIFF.
+ * Returns `None` if the description is not OME-XML.
+ */
+pub fn parse_ome_xml(file_path: &Path, description: &str) -> Result<Option<OmeMetadata>> {
+    if !description.trim_start().starts_with("<?xml") && !description.trim_start().starts_with("<OME") {
+        return Ok(None);
+    }
+
+    let document = roxmltree::Document::parse(description).map_err(|e| StitchError::format(file_path, e))?;
+    if document.root_element().tag_name().name() != "OME" {
+        return Ok(None);
+    }
+
+    // Only the first image of a multi-image file is read
+    let pixels = document
+        .descendants()
+        .find(|node| node.tag_name().name() == "Pixels")
+        .ok_or_else(|| StitchError::format(file_path, "OME-XML has no Pixels element"))?;
+
+    let size = |name: &str| -> Result<usize> {
+        let value = pixels
+            .attribute(name)
+            .ok_or_else(|| StitchError::format(file_path, format!("OME-XML Pixels is missing {}", name)))?;
+        value
+            .parse::<usize>()
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or_else(|| StitchError::format(file_path, format!("Invalid OME-XML {} \"{}\"", name, value)))
+    };
+
+    // Lengths default to microns when no unit is given
+    let length = |node: roxmltree::Node, name: &str| -> Option<f64> {
+        let value = node.attribute(name)?.parse::<f64>().ok()?;
+        let unit = node
+            .attribute(format!("{}Unit", name).as_str())
+            .map_or(Some(1e-6), unit_to_meters)?;
+        Some(value * unit)
+    };
+    let physical_size = |axis: &str| length(pixels, &format!("PhysicalSize{}", axis));
+
+    let position = pixels
+        .children()
+        .find(|node| node.tag_name().name() == "Plane")
+        .and_then(|plane| {
+            Some((
+                length(plane, "PositionX")?,
+                length(plane, "PositionY")?,
+                length(plane, "PositionZ").unwrap_or(0.0),
+            ))
+        });
+
+    let order = pixels.attribute("DimensionOrder").unwrap_or("XYZCT");
+    let dimension_order = DimensionOrder::parse(order)
+        .ok_or_else(|| StitchError::format(file_path, format!("Invalid OME-XML DimensionOrder \"{}\"", order)))?;
+
+    Ok(Some(OmeMetadata {
+        size_x: size("SizeX")?,
+        size_y: size("SizeY")?,
+        size_c: size("SizeC")?,
+        size_z: size("SizeZ")?,
+        size_t: size("SizeT")?,
+        dimension_order,
+        physical_size: (physical_size("X"), physical_size("Y"), physical_size("Z")),
+        position,
+    }))
+}
+
+/**
+ * Read the OME-XML header of a TIFF file, if it has one
+ */
+pub fn read_ome_metadata(file_path: &Path) -> Result<Option<OmeMetadata>> {
+    let file = std::fs::File::open(file_path).map_err(|e| StitchError::io(file_path, e))?;
+    let mut decoder = tiff::decoder::Decoder::new(file).map_err(|e| StitchError::format(file_path, e))?;
+    let description = decoder
+        .get_tag_ascii_string(tiff::tags::Tag::ImageDescription)
+        .ok()
+        .unwrap_or_default();
+    parse_ome_xml(file_path, &description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(xml: &str) -> Result<Option<OmeMetadata>> {
+        parse_ome_xml(Path::new("tile.ome.tif"), xml)
+    }
+
+    fn ome(pixels: &str, planes: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<OME xmlns="http://www.openmicroscopy.org/Schemas/OME/2016-06">
+  <Image ID="Image:0"><Pixels ID="Pixels:0" Type="uint16" {}>{}</Pixels></Image>
+  <Image ID="Image:1"><Pixels ID="Pixels:1" Type="uint8" SizeX="1" SizeY="1" SizeC="1" SizeZ="1" SizeT="1"/></Image>
+</OME>"#,
+            pixels, planes
+        )
+    }
+
+    fn format_message(result: Result<Option<OmeMetadata>>) -> String {
+        match result {
+            Err(StitchError::Format { message, .. }) => message,
+            other => panic!("Expected a format error, got {:?}", other),
+        }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * b.abs()
+    }
+
+    const SIZES: &str = r#"SizeX="64" SizeY="32" SizeC="2" SizeZ="5" SizeT="3""#;
+
+    #[test]
+    fn reads_the_first_image() {
+        let pixels = format!(
+            r#"{} DimensionOrder="XYCZT" PhysicalSizeX="0.5" PhysicalSizeY="500" PhysicalSizeYUnit="nm"
+               PhysicalSizeZ="2" PhysicalSizeZUnit="mm""#,
+            SIZES
+        );
+        let plane = r#"<Plane TheC="0" TheZ="0" TheT="0" PositionX="100" PositionY="-20" PositionXUnit="µm"/>"#;
+        let metadata = parse(&ome(&pixels, plane)).unwrap().unwrap();
+
+        assert_eq!((metadata.size_x, metadata.size_y), (64, 32));
+        assert_eq!((metadata.size_c, metadata.size_z, metadata.size_t), (2, 5, 3));
+        assert_eq!(metadata.dimension_order, DimensionOrder::XYCZT);
+        let (x, y, z) = metadata.physical_size;
+        assert!(close(x.unwrap(), 0.5e-6) && close(y.unwrap(), 0.5e-6) && close(z.unwrap(), 2e-3));
+        let position = metadata.position.unwrap();
+        assert!(close(position.0, 100e-6) && close(position.1, -20e-6) && position.2 == 0.0);
+    }
+
+    #[test]
+    fn defaults_order_and_leaves_unknown_units_out() {
+        let pixels = format!(r#"{} PhysicalSizeX="1" PhysicalSizeXUnit="parsec" PhysicalSizeY="x""#, SIZES);
+        let plane = r#"<Plane TheC="0" TheZ="0" TheT="0" PositionX="1"/>"#;
+        let metadata = parse(&ome(&pixels, plane)).unwrap().unwrap();
+        assert_eq!(metadata.dimension_order, DimensionOrder::XYZCT);
+        assert_eq!(metadata.physical_size, (None, None, None));
+        assert!(metadata.position.is_none());
+    }
+
+    #[test]
+    fn ignores_other_descriptions() {
+        assert!(parse("").unwrap().is_none());
+        assert!(parse("ImageJ=1.54f\nimages=10\nslices=10").unwrap().is_none());
+        assert!(parse(r#"<?xml version="1.0"?><Other/>"#).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        assert!(format_message(parse("<OME><Image>")).contains("never closed"));
+        assert_eq!(format_message(parse("<OME><Image/></OME>")), "OME-XML has no Pixels element");
+
+        let missing = ome(r#"SizeX="64" SizeY="32" SizeC="1" SizeZ="5""#, "");
+        assert_eq!(format_message(parse(&missing)), "OME-XML Pixels is missing SizeT");
+        let zero = ome(r#"SizeX="64" SizeY="32" SizeC="0" SizeZ="5" SizeT="1""#, "");
+        assert_eq!(format_message(parse(&zero)), "Invalid OME-XML SizeC \"0\"");
+        let order = ome(&format!(r#"{} DimensionOrder="XYZ""#, SIZES), "");
+        assert_eq!(format_message(parse(&order)), "Invalid OME-XML DimensionOrder \"XYZ\"");
+    }
+
+    #[test]
+    fn planes_follow_the_dimension_order() {
+        let metadata = |order: &str| {
+            let pixels = format!(r#"{} DimensionOrder="{}""#, SIZES, order);
+            parse(&ome(&pixels, "")).unwrap().unwrap()
+        };
+
+        let xyzct = metadata("XYZCT");
+        assert_eq!(xyzct.stack_planes(Path::new("a"), 1, 2).unwrap(), [25, 26, 27, 28, 29]);
+        let xyczt = metadata("XYCZT");
+        assert_eq!(xyczt.stack_planes(Path::new("a"), 1, 2).unwrap(), [21, 23, 25, 27, 29]);
+        let xytzc = metadata("XYTZC");
+        assert_eq!(xytzc.stack_planes(Path::new("a"), 0, 1).unwrap(), [1, 4, 7, 10, 13]);
+
+        for (channel, timepoint, key) in [(2, 0, "channel"), (0, 3, "timepoint")] {
+            let err = xyzct.stack_planes(Path::new("a"), channel, timepoint).unwrap_err();
+            assert!(matches!(&err, StitchError::Config { key: err_key, .. } if err_key == key), "{:?}", err);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::error::{Result, StitchError};
+use crate::image::{Image2D, Image3DFile};
+use crate::preprocess::{PreprocessFilter, Preprocessing, Window};
+
+/**
+ * Registration result of one pair: every candidate shift (x, y, z) in tile coordinates with its
+ * cross-correlation R, best first, and the sub-pixel refinement of the best one
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedPair {
+    pub peaks: Vec<(i64, i64, i64, f32)>,
+    pub subpixel: (f32, f32, f32),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PairCacheFile {
+    pairs: BTreeMap<String, CachedPair>,
+}
+
+/**
+ * Pairwise registration results kept across runs in a JSON file. Results are keyed by
+ * [`PairKey`] so that only settings of the global optimization can change between runs that
+ * reuse them.
+ */
+pub struct PairCache {
+    path: PathBuf,
+    pairs: Mutex<BTreeMap<String, CachedPair>>,
+    hits: AtomicUsize,
+}
+
+impl PairCache {
+    /**
+     * Load the cache at `path`, or start an empty one if the file does not exist yet
+     */
+    pub fn open(path: &Path) -> Result<PairCache> {
+        let file = if path.exists() {
+            let json_str = std::fs::read_to_string(path).map_err(|e| StitchError::io(path, e))?;
+            serde_json::from_str::<PairCacheFile>(&json_str).map_err(|e| StitchError::format(path, e))?
+        } else {
+            PairCacheFile::default()
+        };
+        println!("Pair cache: {} pairs in {:?}", file.pairs.len(), path);
+
+        Ok(PairCache {
+            path: path.to_path_buf(),
+            pairs: Mutex::new(file.pairs),
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedPair> {
+        let pair = self.pairs.lock().unwrap().get(key).cloned();
+        if pair.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        pair
+    }
+
+    pub fn insert(&self, key: String, pair: CachedPair) {
+        self.pairs.lock().unwrap().insert(key, pair);
+    }
+
+    /**
+     * Number of pairs found in the cache since it was opened
+     */
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let file = PairCacheFile {
+            pairs: self.pairs.lock().unwrap().clone(),
+        };
+        let json_str = serde_json::to_string(&file).map_err(|e| StitchError::format(&self.path, e))?;
+        std::fs::write(&self.path, json_str).map_err(|e| StitchError::io(&self.path, e))
+    }
+}
+
+/// Version of the layout of [`PairKey`]. Raise it whenever the values hashed into a key or the
+/// registration of a pair change, so that results of older versions are no longer found.
+const PAIR_KEY_VERSION: u32 = 1;
+
+/**
+ * Cache key of a pair from the fingerprints of its tiles, their overlaps and every setting that
+ * changes the candidate peaks. Values are hashed one by one in the order they are added, so the
+ * key does not depend on how the settings are declared.
+ */
+pub struct PairKey {
+    hash: u64,
+}
+
+impl PairKey {
+    pub fn new(fingerprint_ref: u64, fingerprint_move: u64) -> PairKey {
+        PairKey { hash: FNV_OFFSET }
+            .ints(&[PAIR_KEY_VERSION as i64])
+            .ints(&[fingerprint_ref as i64, fingerprint_move as i64])
+    }
+
+    pub fn ints(mut self, values: &[i64]) -> PairKey {
+        self.hash = fnv1a_extend(self.hash, &(values.len() as u64).to_le_bytes());
+        for value in values {
+            self.hash = fnv1a_extend(self.hash, &value.to_le_bytes());
+        }
+        self
+    }
+
+    pub fn floats(self, values: &[f32]) -> PairKey {
+        self.ints(&values.iter().map(|value| value.to_bits() as i64).collect::<Vec<_>>())
+    }
+
+    pub fn flags(self, values: &[bool]) -> PairKey {
+        self.ints(&values.iter().map(|&value| value as i64).collect::<Vec<_>>())
+    }
+
+    pub fn preprocessing(self, preprocessing: &Preprocessing) -> PairKey {
+        let window = match preprocessing.window {
+            Window::None => 0,
+            Window::Hann => 1,
+            Window::Tukey => 2,
+        };
+        let filter = match preprocessing.filter {
+            PreprocessFilter::None => 0,
+            PreprocessFilter::HighPass => 1,
+            PreprocessFilter::DifferenceOfGaussians => 2,
+            PreprocessFilter::GradientMagnitude => 3,
+        };
+        self.ints(&[window, filter])
+            .flags(&[preprocessing.subtract_mean])
+            .floats(&[preprocessing.tukey_alpha, preprocessing.sigmas.0, preprocessing.sigmas.1])
+    }
+
+    pub fn finish(&self) -> String {
+        format!("v{}-{:016x}", PAIR_KEY_VERSION, self.hash)
+    }
+}
+
+/**
+ * Fingerprint of a 3D tile from the contents of its file, or of every file of a DICOM series,
+ * and from the pages and channel weights it is read with. The path is left out so that a copied
+ * or moved dataset still finds its pairs.
+ */
+pub fn file_fingerprint(image: &Image3DFile) -> Result<u64> {
+    let mut key = PairKey { hash: FNV_OFFSET };
+    match &image.dcm_files {
+        Some(slices) => {
+            for slice in slices.iter() {
+                key.hash = hash_file(key.hash, &slice.path)?;
+            }
+        }
+        None => key.hash = hash_file(key.hash, &image.path)?,
+    }
+    if let Some(resampling) = image.resampling {
+        key = key.floats(&[resampling.scale.0, resampling.scale.1, resampling.scale.2]);
+    }
+
+    let planes = image.planes.iter().flatten().map(|&plane| plane as i64).collect::<Vec<_>>();
+    key = key.flags(&[image.planes.is_some()]).ints(&planes);
+    for (planes, weight) in &image.weighted_planes {
+        key = key
+            .ints(&planes.iter().map(|&plane| plane as i64).collect::<Vec<_>>())
+            .floats(&[*weight]);
+    }
+    key = key
+        .flags(&[image.sample_weights.is_some()])
+        .floats(image.sample_weights.as_deref().unwrap_or(&[]));
+    Ok(key.hash)
+}
+
+/**
+ * Fold every byte of the file at `path` into `hash`
+ */
+fn hash_file(mut hash: u64, path: &Path) -> Result<u64> {
+    let mut file = std::fs::File::open(path).map_err(|e| StitchError::io(path, e))?;
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer).map_err(|e| StitchError::io(path, e))?;
+        if read == 0 {
+            return Ok(hash);
+        }
+        hash = fnv1a_extend(hash, &buffer[..read]);
+    }
+}
+
+/**
+ * Fingerprint of the pixels of a 2D tile
+ */
+pub fn image_fingerprint(image: &Image2D) -> u64 {
+    let hash = fnv1a_extend(FNV_OFFSET, &[image.width as u64, image.height as u64].map(u64::to_le_bytes).concat());
+    image
+        .data
+        .iter()
+        .fold(hash, |hash, value| fnv1a_extend(hash, &value.to_bits().to_le_bytes()))
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/**
+ * 64-bit FNV-1a, which unlike the standard library hasher is the same across builds
+ */
+fn fnv1a_extend(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
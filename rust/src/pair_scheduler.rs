@@ -0,0 +1,342 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::error::Result;
+use crate::image::{Image3D, Image3DFile};
+
+/**
+ * Bytes taken by a decoded 3D tile
+ */
+pub fn tile_bytes(image: &Image3DFile) -> usize {
+    image.width * image.height * image.depth * std::mem::size_of::<f32>()
+}
+
+/**
+ * Decoded tiles shared between concurrently registered pairs. A pair pins both of its tiles at
+ * once and waits until they fit in `capacity` bytes next to the tiles other pairs have pinned,
+ * after dropping the least recently used unpinned tiles. Only a pair larger than the whole
+ * capacity runs past it, and then alone.
+ */
+pub struct TileCache<'a> {
+    images: &'a [Image3DFile],
+    capacity: usize,
+    state: Mutex<CacheState>,
+    /// Signalled when a tile is unpinned or finishes loading.
+    changed: Condvar,
+}
+
+struct CacheState {
+    /// Cached or loading tiles, least recently used first.
+    tiles: Vec<CacheEntry>,
+    /// Bytes of every entry, including tiles still being read.
+    bytes: usize,
+    peak_bytes: usize,
+    reads: usize,
+}
+
+struct CacheEntry {
+    index: usize,
+    tile: Option<Arc<Image3D>>,
+    loading: bool,
+    pins: usize,
+}
+
+/**
+ * Tile pinned in a [`TileCache`], unpinned when dropped
+ */
+pub struct PinnedTile<'c, 'a> {
+    cache: &'c TileCache<'a>,
+    index: usize,
+    tile: Arc<Image3D>,
+}
+
+impl<'a> TileCache<'a> {
+    pub fn new(images: &'a [Image3DFile], capacity: usize) -> TileCache<'a> {
+        TileCache {
+            images,
+            capacity,
+            state: Mutex::new(CacheState {
+                tiles: vec![],
+                bytes: 0,
+                peak_bytes: 0,
+                reads: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /**
+     * Tiles `i` and `j`, read from disk if they are not cached
+     */
+    pub fn get_pair(&self, i: usize, j: usize) -> Result<(PinnedTile<'_, 'a>, PinnedTile<'_, 'a>)> {
+        self.pin(&[i, j]);
+        let tile_i = match self.load(i) {
+            Ok(tile) => PinnedTile { cache: self, index: i, tile },
+            Err(err) => {
+                self.unpin(i);
+                self.unpin(j);
+                return Err(err);
+            }
+        };
+        let tile_j = match self.load(j) {
+            Ok(tile) => PinnedTile { cache: self, index: j, tile },
+            Err(err) => {
+                self.unpin(j);
+                return Err(err);
+            }
+        };
+        Ok((tile_i, tile_j))
+    }
+
+    /**
+     * Wait until the missing tiles of `indices` fit, then reserve their bytes and pin them
+     */
+    fn pin(&self, indices: &[usize]) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let missing = indices
+                .iter()
+                .enumerate()
+                .filter(|&(n, index)| !indices[..n].contains(index))
+                .filter(|(_, index)| !state.tiles.iter().any(|entry| entry.index == **index))
+                .map(|(_, &index)| tile_bytes(&self.images[index]))
+                .sum::<usize>();
+
+            while state.bytes + missing > self.capacity {
+                let Some(pos) = state
+                    .tiles
+                    .iter()
+                    .position(|entry| entry.pins == 0 && !entry.loading && !indices.contains(&entry.index))
+                else {
+                    break;
+                };
+                let evicted = state.tiles.remove(pos);
+                state.bytes -= tile_bytes(&self.images[evicted.index]);
+            }
+
+            let pinned = state.tiles.iter().any(|entry| entry.pins > 0);
+            if state.bytes + missing <= self.capacity || !pinned {
+                break;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+
+        for &index in indices {
+            let entry = match state.tiles.iter().position(|entry| entry.index == index) {
+                Some(pos) => state.tiles.remove(pos),
+                None => {
+                    state.bytes += tile_bytes(&self.images[index]);
+                    CacheEntry {
+                        index,
+                        tile: None,
+                        loading: false,
+                        pins: 0,
+                    }
+                }
+            };
+            state.tiles.push(CacheEntry {
+                pins: entry.pins + 1,
+                ..entry
+            });
+        }
+        state.peak_bytes = state.peak_bytes.max(state.bytes);
+    }
+
+    /**
+     * Pinned tile `index`, decoded here unless another pair is already reading it
+     */
+    fn load(&self, index: usize) -> Result<Arc<Image3D>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let entry = state.tiles.iter_mut().find(|entry| entry.index == index).unwrap();
+            if let Some(tile) = &entry.tile {
+                return Ok(tile.clone());
+            }
+            if !entry.loading {
+                entry.loading = true;
+                break;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+        drop(state);
+
+        let tile = self.images[index].get_image().map(Arc::new);
+
+        let mut state = self.state.lock().unwrap();
+        let entry = state.tiles.iter_mut().find(|entry| entry.index == index).unwrap();
+        entry.loading = false;
+        entry.tile = tile.as_ref().ok().cloned();
+        if tile.is_ok() {
+            state.reads += 1;
+        }
+        self.changed.notify_all();
+        tile
+    }
+
+    fn unpin(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.tiles.iter_mut().find(|entry| entry.index == index) {
+            entry.pins -= 1;
+        }
+        self.changed.notify_all();
+    }
+
+    /**
+     * Number of tiles read from disk so far
+     */
+    pub fn reads(&self) -> usize {
+        self.state.lock().unwrap().reads
+    }
+
+    /**
+     * Most bytes the cached and pinned tiles took at once
+     */
+    pub fn peak_bytes(&self) -> usize {
+        self.state.lock().unwrap().peak_bytes
+    }
+}
+
+impl std::ops::Deref for PinnedTile<'_, '_> {
+    type Target = Image3D;
+
+    fn deref(&self) -> &Image3D {
+        &self.tile
+    }
+}
+
+impl Drop for PinnedTile<'_, '_> {
+    fn drop(&mut self) {
+        self.cache.unpin(self.index);
+    }
+}
+
+/**
+ * Memory shared by concurrently registered pairs. A pair waits until its estimate fits next to
+ * the running ones; a pair larger than the whole pool runs alone.
+ */
+pub struct MemoryPool {
+    capacity: usize,
+    /// Bytes reserved and number of reservations.
+    used: Mutex<(usize, usize)>,
+    released: Condvar,
+}
+
+/**
+ * Reservation in a [`MemoryPool`], returned when dropped
+ */
+pub struct Reservation<'a> {
+    pool: &'a MemoryPool,
+    bytes: usize,
+}
+
+impl MemoryPool {
+    pub fn new(capacity: usize) -> MemoryPool {
+        MemoryPool {
+            capacity,
+            used: Mutex::new((0, 0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /**
+     * Wait until `bytes` can be reserved
+     */
+    pub fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        let mut used = self.used.lock().unwrap();
+        while used.1 > 0 && used.0 + bytes > self.capacity {
+            used = self.released.wait(used).unwrap();
+        }
+        used.0 += bytes;
+        used.1 += 1;
+        Reservation { pool: self, bytes }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut used = self.pool.used.lock().unwrap();
+        used.0 -= self.bytes;
+        used.1 -= 1;
+        self.pool.released.notify_all();
+    }
+}
+
+/**
+ * Number of the largest of `pair_bytes` that fit in `pool` together, at least one and at most
+ * `workers`, and the memory they take
+ */
+pub fn peak_concurrency(pair_bytes: &[usize], pool: usize, workers: usize) -> (usize, usize) {
+    let mut sorted = pair_bytes.to_vec();
+    sorted.sort_unstable_by(|a, b| b.cmp(a));
+    let mut count = 0;
+    let mut bytes = 0;
+    for pair in sorted.into_iter().take(workers.max(1)) {
+        if count > 0 && bytes + pair > pool {
+            break;
+        }
+        count += 1;
+        bytes += pair;
+    }
+    (count, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{read_tiff_headers, save_as_tiff_float};
+
+    #[test]
+    fn pinned_tiles_stay_within_capacity() {
+        let dir = std::env::temp_dir().join(format!("stitch-tile-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let images = (0..6)
+            .map(|n| {
+                let path = dir.join(format!("tile{}.tif", n));
+                let image = Image3D {
+                    width: 8,
+                    height: 8,
+                    depth: 4,
+                    data: vec![n as f32; 8 * 8 * 4],
+                    min: 0.0,
+                    max: 0.0,
+                };
+                save_as_tiff_float(&path, &image).unwrap();
+                read_tiff_headers(&path).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let pairs = (0..6).flat_map(|i| (i + 1..6).map(move |j| (i, j))).collect::<Vec<_>>();
+
+        let capacity = 3 * tile_bytes(&images[0]);
+        let cache = TileCache::new(&images, capacity);
+        let next = Mutex::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| loop {
+                    let index = {
+                        let mut next = next.lock().unwrap();
+                        *next += 1;
+                        *next - 1
+                    };
+                    let Some(&(i, j)) = pairs.get(index) else {
+                        break;
+                    };
+                    let (tile_i, tile_j) = cache.get_pair(i, j).unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(2));
+                    assert_eq!((tile_i.data[0], tile_j.data[0]), (i as f32, j as f32));
+                });
+            }
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(cache.peak_bytes() <= capacity, "{} > {}", cache.peak_bytes(), capacity);
+        assert!(cache.reads() >= 6);
+        assert!(cache.state.lock().unwrap().tiles.iter().all(|entry| entry.pins == 0));
+    }
+
+    #[test]
+    fn pool_runs_pairs_larger_than_itself_alone() {
+        assert_eq!(peak_concurrency(&[5, 4, 3], 8, 4), (1, 5));
+        assert_eq!(peak_concurrency(&[2, 2, 2], 5, 4), (2, 4));
+        assert_eq!(peak_concurrency(&[20], 8, 4), (1, 20));
+        assert_eq!(peak_concurrency(&[], 8, 4), (0, 0));
+    }
+}
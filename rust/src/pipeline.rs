@@ -0,0 +1,1055 @@
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::bigstitcher::write_bigstitcher_xml;
+use crate::chunked::{ChunkedOutput, ChunkedWriter};
+use crate::config::{OutputFormat, StitchConfig};
+use crate::dicom_output::{DicomHeader, DicomWriter};
+use crate::error::{Result, StitchError};
+use crate::fuse::{fuse_2d, SlabFuser};
+use crate::image::{
+    hyperstack_description, is_dcm, read_dcm_headers, read_dcm_series, read_image_2d_channels, read_tiff_channel_count,
+    read_tiff_stack_headers, read_tiff_weighted_headers, save_image_2d, save_image_2d_channels, unit_to_meters, Image2D,
+    Image3D, Image3DFile, RangeSource, TiffStackWriter,
+};
+use crate::intensity::{Histogram, OutputDtype, PixelConversion};
+use crate::pair_cache::PairCache;
+use crate::pyramid_tiff::save_pyramid_tiff;
+use crate::stitch2d::{self, IBox2D, Stitch2DResult};
+use crate::stitch3d::{self, Stitch3DResult};
+use crate::tile_configuration::write_tile_configuration;
+use crate::timelapse::{measure_drift_2d, measure_drift_3d};
+
+/**
+ * Run the full 3D pipeline described by the config: read tile headers,
+ * align (or load `alignment_file`), and fuse every subgraph into `output_path`.
+ * Time-lapse configs are aligned on their registered timepoints and every timepoint is fused.
+ */
+pub fn stitch_3d(config: StitchConfig) -> Result<()> {
+    let mut timepoint_paths = match &config.timelapse {
+        Some(timelapse) => timelapse.tile_paths.clone(),
+        None => vec![config.tile_paths.clone()],
+    };
+    let reference = config.timelapse.as_ref().map_or(0, |timelapse| timelapse.reference);
+
+    let mut temp_dir = PathBuf::new();
+    if config.copy_files {
+        println!("Copying files to temp directory...");
+        temp_dir = std::env::temp_dir();
+        temp_dir = temp_dir.join("stitch3d");
+        let random: u32 = rand::random();
+        temp_dir = temp_dir.join(format!("temp_{}", random));
+
+        for (t, tile_paths) in timepoint_paths.iter_mut().enumerate() {
+            // Tiles of different timepoints may share file names
+            let copy_dir = if config.timelapse.is_some() {
+                temp_dir.join(format!("t{}", t))
+            } else {
+                temp_dir.clone()
+            };
+            *tile_paths = copy_to_temp_dir(tile_paths, &copy_dir)?;
+        }
+    }
+
+    let images = read_3d_headers(&config, &timepoint_paths[reference])?;
+    let stitched_result = align_3d(&config, &images, None)?;
+
+    let tile_sizes = images
+        .iter()
+        .map(|image| (image.width, image.height, image.depth))
+        .collect::<Vec<_>>();
+    export_registration(&config, &tile_sizes, &stitched_result.subgraphs, &stitched_result.offsets)?;
+
+    let mut results = HashMap::new();
+    if let Some(timelapse) = &config.timelapse {
+        for t in timelapse.registered_timepoints().into_iter().skip(1) {
+            let images = read_3d_headers(&config, &timepoint_paths[t])?;
+            let result = align_3d(&config, &images, Some(t))?;
+            results.insert(t, (images, result));
+        }
+    }
+    results.insert(reference, (images, stitched_result));
+
+    if config.no_fuse {
+        return Ok(());
+    }
+
+    println!("Fusing images...");
+    let start = std::time::Instant::now();
+
+    match &config.timelapse {
+        None => {
+            let (images, result) = &results[&reference];
+            fuse_3d_outputs(&config, images, &result.subgraphs, &result.offsets, None)?;
+        }
+        Some(timelapse) => {
+            let mut drifts = vec![];
+            for (t, tile_paths) in timepoint_paths.iter().enumerate() {
+                let source = timelapse.alignment_source(t);
+                let (source_images, result) = &results[&source];
+                let mut offsets = result.offsets.clone();
+
+                if t == source {
+                    fuse_3d_outputs(&config, source_images, &result.subgraphs, &offsets, Some(t))?;
+                    continue;
+                }
+
+                let images = read_3d_headers(&config, tile_paths)?;
+                if timelapse.correct_drift {
+                    let drift = measure_drift_3d(&config, source_images, &images)?;
+                    match drift {
+                        Some(drift) => {
+                            println!("Timepoint {} drifted by {:?} from timepoint {}", t, drift, source);
+                            offsets.iter_mut().flatten().for_each(|offset| {
+                                *offset = (offset.0 + drift.0, offset.1 + drift.1, offset.2 + drift.2);
+                            });
+                        }
+                        None => println!("Could not measure drift of timepoint {}, using the alignment unchanged", t),
+                    }
+                    drifts.push(json!({ "timepoint": t, "source": source, "drift": drift.map(|d| [d.0, d.1, d.2]) }));
+                }
+                fuse_3d_outputs(&config, &images, &result.subgraphs, &offsets, Some(t))?;
+            }
+
+            if timelapse.correct_drift {
+                write_drifts(&config, &drifts)?;
+            }
+        }
+    }
+
+    println!("Time to fuse images: {:?}", start.elapsed());
+
+    if config.copy_files {
+        // Delete temp directory
+        std::fs::remove_dir_all(&temp_dir).map_err(|e| StitchError::io(&temp_dir, e))?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Copy tiles into `temp_dir` and return their new paths
+ */
+fn copy_to_temp_dir(tile_paths: &[PathBuf], temp_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !temp_dir.exists() {
+        std::fs::create_dir_all(temp_dir).map_err(|e| StitchError::io(temp_dir, e))?;
+    }
+
+    tile_paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| StitchError::config("tile_paths", format!("Invalid tile path {:?}", path)))?;
+            let temp_path = temp_dir.join(file_name);
+            if path.is_dir() {
+                copy_dir(path, &temp_path)?;
+            } else {
+                std::fs::copy(path, temp_path.clone()).map_err(|e| StitchError::io(path, e))?;
+            }
+            println!(
+                "[{}/{}] Copied file: {:?} to {:?}",
+                i + 1,
+                tile_paths.len(),
+                path,
+                temp_path
+            );
+            Ok(temp_path)
+        })
+        .collect()
+}
+
+/**
+ * Copy the files of a DICOM series directory
+ */
+fn copy_dir(source: &Path, target: &Path) -> Result<()> {
+    std::fs::create_dir_all(target).map_err(|e| StitchError::io(target, e))?;
+    for entry in std::fs::read_dir(source).map_err(|e| StitchError::io(source, e))? {
+        let path = entry.map_err(|e| StitchError::io(source, e))?.path();
+        if let (true, Some(file_name)) = (path.is_file(), path.file_name()) {
+            std::fs::copy(&path, target.join(file_name)).map_err(|e| StitchError::io(&path, e))?;
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Read the size and value range of the registration channel of every 3D tile
+ */
+fn read_3d_headers(config: &StitchConfig, tile_paths: &[PathBuf]) -> Result<Vec<Image3DFile>> {
+    println!("Reading files for size information...");
+    let start = std::time::Instant::now();
+    let mut images = tile_paths
+        .par_iter()
+        .map(|path| {
+            if is_dcm(path) {
+                read_dcm_headers(path)
+            } else if let Some(weights) = &config.channel_weights {
+                read_tiff_weighted_headers(path, weights, config.timepoint, config.rgb)
+            } else {
+                read_tiff_stack_headers(path, config.channel, config.timepoint, config.rgb)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    resample_3d_tiles(config, &mut images);
+
+    // Print file sizes
+    images.iter().for_each(|image| {
+        println!(
+            "Width: {}, Height: {}, Depth: {} Min: {} Max: {}",
+            image.width, image.height, image.depth, image.min, image.max
+        );
+    });
+
+    println!("Time to read files: {:?}", start.elapsed());
+    Ok(images)
+}
+
+/**
+ * Resample the tiles with their own `voxel_size` onto the pixel grid of the layout
+ */
+fn resample_3d_tiles(config: &StitchConfig, images: &mut [Image3DFile]) {
+    for (i, image) in images.iter_mut().enumerate() {
+        if let Some(scale) = config.tile_scale(i) {
+            image.resample(scale);
+        }
+    }
+}
+
+/**
+ * Register 3D tiles, or load `alignment_file` for the reference timepoint (`timepoint` of `None`).
+ * The result is saved to `align_values.json`, or `align_values_t<timepoint>.json`.
+ */
+fn align_3d(config: &StitchConfig, images: &[Image3DFile], timepoint: Option<usize>) -> Result<Stitch3DResult> {
+    if let Some(alignment_file) = config.alignment_file.as_ref().filter(|_| timepoint.is_none()) {
+        let res: Stitch3DResult = load_alignment(alignment_file)?;
+        check_alignment(&res.subgraphs, &res.offsets, images.len())?;
+        println!("Alignment file loaded");
+        return Ok(res);
+    }
+
+    println!("Aligning images...");
+    let start = std::time::Instant::now();
+    let pair_cache = config.pair_cache.as_deref().map(PairCache::open).transpose()?;
+    let result = stitch3d::stitch(
+        images,
+        &config.tile_layout,
+        config.overlap_ratio,
+        config.check_peaks,
+        config.correlation_threshold,
+        config.relative_error_threshold,
+        config.absolute_error_threshold,
+        config.dimension_mask,
+        config.use_phase_correlation,
+        config.use_prior,
+        config.prior_sigmas,
+        config.merge_subgraphs,
+        config.subpixel_accuracy,
+        &config.preprocessing,
+        config.registration_downsampling,
+        config.registration_memory_budget,
+        pair_cache.as_ref(),
+    )?;
+    println!("Time to find alignment: {:?}", start.elapsed());
+
+    save_alignment(config, &json!(result), timepoint)?;
+    Ok(result)
+}
+
+/**
+ * Fuse every subgraph of 3D tiles once per fused channel
+ */
+fn fuse_3d_outputs(
+    config: &StitchConfig,
+    images: &[Image3DFile],
+    subgraphs: &[Vec<usize>],
+    offsets: &[Vec<(f32, f32, f32)>],
+    timepoint: Option<usize>,
+) -> Result<()> {
+    let first_tile = &images[0].path;
+    let channel_count = if is_dcm(first_tile) { 1 } else { read_tiff_channel_count(first_tile, config.rgb)? };
+    let fuse_channels = config
+        .fuse_channels
+        .clone()
+        .unwrap_or_else(|| (0..channel_count).collect());
+
+    // The registration images can be fused directly when they are exactly one fused channel
+    let mut channel_images = fuse_channels
+        .iter()
+        .map(|&channel| {
+            if channel == config.channel && config.channel_weights.is_none() {
+                return Ok(images.to_vec());
+            }
+            let mut images = images
+                .par_iter()
+                .map(|image| {
+                    if is_dcm(&image.path) && channel == 0 {
+                        read_dcm_headers(&image.path)
+                    } else {
+                        read_tiff_stack_headers(&image.path, channel, config.timepoint, config.rgb)
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            resample_3d_tiles(config, &mut images);
+            Ok(images)
+        })
+        .collect::<Result<Vec<Vec<Image3DFile>>>>()?;
+    for channel_images in &mut channel_images {
+        measure_ranges(config, channel_images)?;
+    }
+
+    for (i, offset) in offsets.iter().enumerate() {
+        let dicom_header = match config.output_format {
+            OutputFormat::Tiff => None,
+            OutputFormat::Dcm | OutputFormat::DcmSeries => Some(dicom_header(config, images, &subgraphs[i], offset)?),
+        };
+        let fusers = channel_images
+            .iter()
+            .map(|channel_images| {
+                SlabFuser::new(channel_images, &subgraphs[i], offset, config.fuse_mode)
+            })
+            .collect::<Vec<_>>();
+        fuse_3d_subgraph(config, &fusers, &fuse_channels, i, timepoint, dicom_header.as_ref())?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Decode tiles to find their value range when the output scaling depends on it and their
+ * headers don't give one, or every tile with `measure_intensity`
+ */
+fn measure_ranges(config: &StitchConfig, images: &mut [Image3DFile]) -> Result<()> {
+    let needs_range = config.output_scaling().needs_input_range();
+    let mut unmeasured = images
+        .iter_mut()
+        .filter(|image| {
+            config.measure_intensity || (needs_range && image.range_source == RangeSource::Unknown)
+        })
+        .collect::<Vec<_>>();
+    if unmeasured.is_empty() {
+        return Ok(());
+    }
+
+    println!("Measuring the intensity range of {} tiles", unmeasured.len());
+    unmeasured
+        .par_iter_mut()
+        .try_for_each(|image| image.measure_range())
+}
+
+/**
+ * DICOM geometry of a fused subgraph. Patient and study tags, orientation and position come from
+ * its first tile when that is a DICOM file, otherwise the origin follows the tile layout.
+ */
+fn dicom_header(
+    config: &StitchConfig,
+    images: &[Image3DFile],
+    subgraph: &[usize],
+    offsets: &[(f32, f32, f32)],
+) -> Result<DicomHeader> {
+    let millimetres = unit_to_meters(&config.unit).unwrap_or(1e-6) * 1e3;
+    let spacing = config.voxel_size.map(|voxel_size| {
+        (
+            voxel_size.0 as f64 * millimetres,
+            voxel_size.1 as f64 * millimetres,
+            voxel_size.2 as f64 * millimetres,
+        )
+    });
+    let shift = layout_shift(config, subgraph, offsets);
+    let origin = spacing.map_or([0.0; 3], |spacing| {
+        [shift.0 as f64 * spacing.0, shift.1 as f64 * spacing.1, shift.2 as f64 * spacing.2]
+    });
+
+    // A series is represented by its first slice in z order
+    let first_tile = &images[subgraph[0]].path;
+    let reference = match first_tile.is_dir() {
+        true => Some(read_dcm_series(first_tile)?.swap_remove(0).path),
+        false => is_dcm(first_tile).then(|| first_tile.clone()),
+    };
+    DicomHeader::new(reference.as_deref(), spacing, origin, offsets[0])
+}
+
+/**
+ * Fuse one subgraph and write it to its output files. With `fuse_memory_budget` the volume is
+ * fused in slabs that fit the budget per channel, each written as soon as it is done.
+ */
+fn fuse_3d_subgraph(
+    config: &StitchConfig,
+    fusers: &[SlabFuser],
+    fuse_channels: &[usize],
+    subgraph: usize,
+    timepoint: Option<usize>,
+    dicom_header: Option<&DicomHeader>,
+) -> Result<()> {
+    let (width, height, depth) = (fusers[0].width, fusers[0].height, fusers[0].depth);
+    // Chunked writers keep a converted copy of the slices of a channel until a row of chunks is full
+    let mut chunked_output = config.chunked_output.clone().filter(|_| dicom_header.is_none());
+    let writer_slice = match chunked_output {
+        Some(_) => width * height * std::mem::size_of::<f32>(),
+        None => 0,
+    };
+    let mut slab_depth = match config.fuse_memory_budget {
+        Some(memory_budget) => fusers
+            .iter()
+            .map(|fuser| fuser.slab_depth(memory_budget / fusers.len(), writer_slice))
+            .min()
+            .unwrap_or(1),
+        None => depth.max(1),
+    };
+
+    // Streamed slabs fill whole rows of chunks, so that the writer never holds more than a slab
+    if let Some(output) = chunked_output.as_mut().filter(|_| slab_depth < depth) {
+        if output.chunk_size.2 > slab_depth {
+            println!(
+                "Chunk depth lowered from {} to {} slices to fit the fuse memory budget",
+                output.chunk_size.2, slab_depth
+            );
+            output.chunk_size.2 = slab_depth;
+        }
+        slab_depth -= slab_depth % output.chunk_size.2;
+    }
+    println!("Fusing image {} x {} x {}", width, height, depth);
+    if slab_depth < depth {
+        println!("Fusing in slabs of {} slices", slab_depth);
+    }
+
+    // Percentiles are only known once every slab has been fused. Streamed volumes take an extra
+    // pass for that, while a volume fused in one slab is kept and written afterwards.
+    let scaling = config.output_scaling();
+    let mut fused_volume = None;
+    let histograms = if scaling.needs_histogram() {
+        let mut histograms = fusers
+            .iter()
+            .map(|fuser| Histogram::new(fuser.min, fuser.max))
+            .collect::<Vec<_>>();
+        if slab_depth < depth {
+            println!("Measuring intensities for percentile scaling");
+        }
+        for z_start in (0..depth).step_by(slab_depth) {
+            let z_end = (z_start + slab_depth).min(depth);
+            let slabs = fusers
+                .iter()
+                .map(|fuser| fuser.fuse_slab(z_start, z_end))
+                .collect::<Result<Vec<_>>>()?;
+            for (histogram, slab) in histograms.iter_mut().zip(&slabs) {
+                histogram.add(&slab.data);
+            }
+            if slab_depth >= depth {
+                fused_volume = Some(slabs);
+            }
+        }
+        Some(histograms)
+    } else {
+        None
+    };
+    let conversions = fusers
+        .iter()
+        .enumerate()
+        .map(|(c, fuser)| {
+            let histogram = histograms.as_ref().map(|histograms| &histograms[c]);
+            PixelConversion::new(config.output_dtype(), scaling, (fuser.min, fuser.max), histogram)
+        })
+        .collect::<Vec<_>>();
+
+    let size = (width, height, depth);
+    let chunked_output = chunked_output.as_ref();
+    let mut writers = if config.multichannel_output {
+        vec![FusedWriter::create(config, chunked_output, subgraph, None, timepoint, &conversions, size, dicom_header)?]
+    } else {
+        fuse_channels
+            .iter()
+            .zip(&conversions)
+            .map(|(&channel, conversion)| {
+                let channel = Some(channel).filter(|_| fuse_channels.len() > 1);
+                let conversions = std::slice::from_ref(conversion);
+                FusedWriter::create(config, chunked_output, subgraph, channel, timepoint, conversions, size, dicom_header)
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    for z_start in (0..depth).step_by(slab_depth) {
+        let z_end = (z_start + slab_depth).min(depth);
+        let slabs = match fused_volume.take() {
+            Some(slabs) => slabs,
+            None => fusers
+                .iter()
+                .map(|fuser| fuser.fuse_slab(z_start, z_end))
+                .collect::<Result<Vec<_>>>()?,
+        };
+
+        if config.multichannel_output {
+            writers[0].write_slab(&slabs)?;
+        } else {
+            for (writer, slab) in writers.iter_mut().zip(&slabs) {
+                writer.write_slab(std::slice::from_ref(slab))?;
+            }
+        }
+        if slab_depth < depth {
+            println!("Slices {}..{} of {} written", z_start, z_end, depth);
+        }
+    }
+
+    for writer in writers {
+        writer.finish()?;
+    }
+    println!("Image fused!");
+    Ok(())
+}
+
+/**
+ * Output file of a fused 3D image: a TIFF, a chunked store with `chunked_output`, or DICOM with
+ * `output_format`. Several channels are interleaved as an ImageJ hyperstack or stored along a
+ * channel axis.
+ */
+enum FusedWriter {
+    Tiff(TiffStackWriter, Vec<PixelConversion>),
+    Chunked(ChunkedWriter),
+    Dicom(DicomWriter),
+}
+
+impl FusedWriter {
+    fn create(
+        config: &StitchConfig,
+        chunked_output: Option<&ChunkedOutput>,
+        subgraph: usize,
+        channel: Option<usize>,
+        timepoint: Option<usize>,
+        conversions: &[PixelConversion],
+        size: (usize, usize, usize),
+        dicom_header: Option<&DicomHeader>,
+    ) -> Result<FusedWriter> {
+        if let Some(header) = dicom_header {
+            if conversions.len() > 1 {
+                return Err(StitchError::config(
+                    "output_format",
+                    "DICOM files hold one channel, unset \"multichannel_output\"",
+                ));
+            }
+            let series = config.output_format == OutputFormat::DcmSeries;
+            let name = fused_file_name(subgraph, channel, timepoint, "dcm");
+            let path = match series {
+                true => config.output_path.join(name.trim_end_matches(".dcm")),
+                false => config.output_path.join(name),
+            };
+            let writer = DicomWriter::create(&path, header, series, &conversions[0], size)?;
+            return Ok(FusedWriter::Dicom(writer));
+        }
+
+        match chunked_output {
+            Some(output) => {
+                let path = config
+                    .output_path
+                    .join(fused_file_name(subgraph, channel, timepoint, output.format.extension()));
+                let size = (size.0, size.1, Some(size.2));
+                let writer = ChunkedWriter::create(&path, output, conversions, size, config.voxel_size, &config.unit)?;
+                Ok(FusedWriter::Chunked(writer))
+            }
+            None => {
+                let path = config.output_path.join(fused_file_name(subgraph, channel, timepoint, "tiff"));
+                let description = config
+                    .multichannel_output
+                    .then(|| hyperstack_description(conversions.len(), size.2));
+                let writer = TiffStackWriter::create(&path, description)?;
+                Ok(FusedWriter::Tiff(writer, conversions.to_vec()))
+            }
+        }
+    }
+
+    /**
+     * Write the same slab of every channel in the file
+     */
+    fn write_slab(&mut self, slabs: &[Image3D]) -> Result<()> {
+        match self {
+            FusedWriter::Tiff(writer, conversions) => {
+                let (width, height) = (slabs[0].width, slabs[0].height);
+                let slice = width * height;
+                for z in 0..slabs[0].depth {
+                    for (slab, conversion) in slabs.iter().zip(conversions.iter()) {
+                        let frame = conversion.convert(&slab.data[z * slice..(z + 1) * slice]);
+                        writer.write_slices(&frame, width, height)?;
+                    }
+                }
+            }
+            FusedWriter::Chunked(writer) => {
+                for (channel, slab) in slabs.iter().enumerate() {
+                    writer.write_slices(channel, &slab.data)?;
+                }
+            }
+            FusedWriter::Dicom(writer) => writer.write_slices(&slabs[0].data)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            FusedWriter::Tiff(..) => Ok(()),
+            FusedWriter::Chunked(writer) => writer.finish(),
+            FusedWriter::Dicom(writer) => writer.finish(),
+        }
+    }
+}
+
+/**
+ * Run the full 2D pipeline described by the config: read tiles, align
+ * (or load `alignment_file`), and fuse every subgraph into `output_path`.
+ * Time-lapse configs are aligned on their registered timepoints and every timepoint is fused.
+ */
+pub fn stitch_2d(config: StitchConfig) -> Result<()> {
+    let timepoint_paths = match &config.timelapse {
+        Some(timelapse) => timelapse.tile_paths.clone(),
+        None => vec![config.tile_paths.clone()],
+    };
+    let reference = config.timelapse.as_ref().map_or(0, |timelapse| timelapse.reference);
+
+    let (images, channel_images) = read_2d_tiles(&config, &timepoint_paths[reference])?;
+    let stitched_result = align_2d(&config, &images, None)?;
+
+    let tile_sizes = images
+        .iter()
+        .map(|image| (image.width, image.height, 1))
+        .collect::<Vec<_>>();
+    let offsets = stitched_result
+        .offsets
+        .iter()
+        .map(|offsets| offsets.iter().map(|offset| (offset.0, offset.1, 0.0)).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    export_registration(&config, &tile_sizes, &stitched_result.subgraphs, &offsets)?;
+
+    let mut results = HashMap::new();
+    if let Some(timelapse) = &config.timelapse {
+        for t in timelapse.registered_timepoints().into_iter().skip(1) {
+            let (images, _) = read_2d_tiles(&config, &timepoint_paths[t])?;
+            let result = align_2d(&config, &images, Some(t))?;
+            results.insert(t, (images, result));
+        }
+    }
+
+    if config.no_fuse {
+        return Ok(());
+    }
+
+    println!("Fusing images...");
+    let start = std::time::Instant::now();
+
+    let Some(timelapse) = &config.timelapse else {
+        fuse_2d_outputs(&config, &channel_images, &stitched_result.subgraphs, &stitched_result.offsets, None)?;
+        println!("Time to fuse images: {:?}", start.elapsed());
+        return Ok(());
+    };
+
+    results.insert(reference, (images, stitched_result));
+    let mut channel_images = Some(channel_images);
+    let mut drifts = vec![];
+    for (t, tile_paths) in timepoint_paths.iter().enumerate() {
+        let source = timelapse.alignment_source(t);
+        let (source_images, result) = &results[&source];
+        let mut offsets = result.offsets.clone();
+
+        let (images, channel_images) = match channel_images.take().filter(|_| t == reference) {
+            Some(channel_images) => (vec![], channel_images),
+            None => read_2d_tiles(&config, tile_paths)?,
+        };
+
+        if timelapse.correct_drift && t != source {
+            let drift = measure_drift_2d(&config, source_images, &images)?;
+            match drift {
+                Some(drift) => {
+                    println!("Timepoint {} drifted by {:?} from timepoint {}", t, drift, source);
+                    offsets.iter_mut().flatten().for_each(|offset| {
+                        *offset = (offset.0 + drift.0, offset.1 + drift.1);
+                    });
+                }
+                None => println!("Could not measure drift of timepoint {}, using the alignment unchanged", t),
+            }
+            drifts.push(json!({ "timepoint": t, "source": source, "drift": drift.map(|d| [d.0, d.1]) }));
+        }
+        fuse_2d_outputs(&config, &channel_images, &result.subgraphs, &offsets, Some(t))?;
+    }
+
+    if timelapse.correct_drift {
+        write_drifts(&config, &drifts)?;
+    }
+
+    println!("Time to fuse images: {:?}", start.elapsed());
+
+    Ok(())
+}
+
+/**
+ * Read every channel of the 2D tiles. Returns the images used for registration (the configured
+ * channel or the weighted sum of channels) and the tiles of every channel, indexed by channel.
+ */
+fn read_2d_tiles(config: &StitchConfig, tile_paths: &[PathBuf]) -> Result<(Vec<Image2D>, Vec<Vec<Image2D>>)> {
+    println!("Reading files...");
+    let start = std::time::Instant::now();
+    let tiles = tile_paths
+        .par_iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let channels = read_image_2d_channels(path, config.timepoint, config.rgb)?;
+            Ok(match config.tile_scale(i) {
+                Some(scale) => channels.iter().map(|channel| channel.resampled((scale.0, scale.1))).collect(),
+                None => channels,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let channel_count = tiles[0].len();
+    if let Some(i) = tiles.iter().position(|channels| channels.len() != channel_count) {
+        return Err(StitchError::format(
+            &tile_paths[i],
+            format!("Expected {} channels like the first tile but got {}", channel_count, tiles[i].len()),
+        ));
+    }
+
+    let images = match &config.channel_weights {
+        Some(weights) if weights.len() != channel_count => {
+            return Err(StitchError::config(
+                "channel_weights",
+                format!("Tiles have {} channels but {} weights were given", channel_count, weights.len()),
+            ));
+        }
+        Some(weights) => tiles
+            .iter()
+            .map(|channels| Image2D::weighted_sum(channels, weights))
+            .collect::<Vec<_>>(),
+        None if config.channel >= channel_count => {
+            return Err(StitchError::config(
+                "channel",
+                format!("Tiles have {} channels, channel {} does not exist", channel_count, config.channel),
+            ));
+        }
+        None => tiles
+            .iter()
+            .map(|channels| channels[config.channel].clone())
+            .collect::<Vec<_>>(),
+    };
+
+    let mut channel_images = vec![vec![]; channel_count];
+    for channels in tiles {
+        for (channel, image) in channels.into_iter().enumerate() {
+            channel_images[channel].push(image);
+        }
+    }
+
+    println!("Time to read files: {:?}", start.elapsed());
+    Ok((images, channel_images))
+}
+
+/**
+ * Register 2D tiles, or load `alignment_file` for the reference timepoint, see [`align_3d`]
+ */
+fn align_2d(config: &StitchConfig, images: &[Image2D], timepoint: Option<usize>) -> Result<Stitch2DResult> {
+    if let Some(alignment_file) = config.alignment_file.as_ref().filter(|_| timepoint.is_none()) {
+        let res: Stitch2DResult = load_alignment(alignment_file)?;
+        check_alignment(&res.subgraphs, &res.offsets, images.len())?;
+        println!("Alignment file loaded");
+        return Ok(res);
+    }
+
+    println!("Aligning images...");
+    let start = std::time::Instant::now();
+    let pair_cache = config.pair_cache.as_deref().map(PairCache::open).transpose()?;
+    let dim_mask = (config.dimension_mask.0, config.dimension_mask.1);
+    let tile_layout = config
+        .tile_layout
+        .iter()
+        .map(|layout| IBox2D::new(layout.x, layout.y, layout.width, layout.height))
+        .collect::<Vec<_>>();
+    let result = stitch2d::stitch(
+        images,
+        &tile_layout,
+        (config.overlap_ratio.0, config.overlap_ratio.1),
+        config.check_peaks,
+        config.correlation_threshold,
+        config.relative_error_threshold,
+        config.absolute_error_threshold,
+        dim_mask,
+        config.use_phase_correlation,
+        config.use_prior,
+        (config.prior_sigmas.0, config.prior_sigmas.1),
+        config.merge_subgraphs,
+        config.subpixel_accuracy,
+        &config.preprocessing,
+        pair_cache.as_ref(),
+    )?;
+    println!("Time to find alignment: {:?}", start.elapsed());
+
+    save_alignment(config, &json!(result), timepoint)?;
+    Ok(result)
+}
+
+/**
+ * Fuse every subgraph of 2D tiles once per fused channel
+ */
+fn fuse_2d_outputs(
+    config: &StitchConfig,
+    channel_images: &[Vec<Image2D>],
+    subgraphs: &[Vec<usize>],
+    offsets: &[Vec<(f32, f32)>],
+    timepoint: Option<usize>,
+) -> Result<()> {
+    let fuse_channels = config
+        .fuse_channels
+        .clone()
+        .unwrap_or_else(|| (0..channel_images.len()).collect());
+    if let Some(channel) = fuse_channels.iter().find(|&&channel| channel >= channel_images.len()) {
+        return Err(StitchError::config(
+            "fuse_channels",
+            format!("Tiles have {} channels, channel {} does not exist", channel_images.len(), channel),
+        ));
+    }
+
+    for (i, offset) in offsets.iter().enumerate() {
+        let mut fused_channels = vec![];
+        for &channel in &fuse_channels {
+            let fused_image = fuse_2d(
+                &channel_images[channel],
+                &subgraphs[i],
+                offset,
+                config.fuse_mode,
+            );
+
+            if config.multichannel_output {
+                fused_channels.push(fused_image);
+                continue;
+            }
+
+            let channel = Some(channel).filter(|_| fuse_channels.len() > 1);
+            save_fused_2d(config, i, channel, timepoint, &[fused_image])?;
+        }
+
+        if config.multichannel_output {
+            save_fused_2d(config, i, None, timepoint, &fused_channels)?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Save a fused 2D image in the configured output format: a chunked store, a pyramid TIFF, or
+ * a PNG. Several channels are only passed with `multichannel_output` and go to a TIFF when no
+ * other format is configured, as do float pixels.
+ */
+fn save_fused_2d(
+    config: &StitchConfig,
+    subgraph: usize,
+    channel: Option<usize>,
+    timepoint: Option<usize>,
+    channels: &[Image2D],
+) -> Result<()> {
+    let conversions = channels
+        .iter()
+        .map(|image| {
+            let (min, max) = image.calc_min_max();
+            let mut histogram = Histogram::new(min, max);
+            histogram.add(&image.data);
+            PixelConversion::new(config.output_dtype(), config.output_scaling(), (image.min, image.max), Some(&histogram))
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(output) = &config.chunked_output {
+        let path = config
+            .output_path
+            .join(fused_file_name(subgraph, channel, timepoint, output.format.extension()));
+        let size = (channels[0].width, channels[0].height, None);
+        let mut writer = ChunkedWriter::create(&path, output, &conversions, size, config.voxel_size, &config.unit)?;
+        for (channel, image) in channels.iter().enumerate() {
+            writer.write_slices(channel, &image.data)?;
+        }
+        return writer.finish();
+    }
+
+    if let Some(output) = &config.pyramid_tiff {
+        let buf = config.output_path.join(fused_file_name(subgraph, channel, timepoint, "tiff"));
+        return save_pyramid_tiff(&buf, output, channels, &conversions, config.voxel_size, &config.unit);
+    }
+
+    if config.multichannel_output || config.output_dtype() == OutputDtype::F32 {
+        let buf = config.output_path.join(fused_file_name(subgraph, channel, timepoint, "tiff"));
+        save_image_2d_channels(&buf, channels, &conversions)
+    } else {
+        let buf = config.output_path.join(fused_file_name(subgraph, channel, timepoint, "png"));
+        save_image_2d(&buf, &channels[0], &conversions[0])
+    }
+}
+
+/**
+ * Read an `align_values.json` given as `alignment_file`
+ */
+fn load_alignment<T: DeserializeOwned>(alignment_file: &Path) -> Result<T> {
+    if !alignment_file.exists() {
+        return Err(StitchError::config(
+            "alignment_file",
+            format!("{:?} does not exist", alignment_file),
+        ));
+    }
+    let json_str = std::fs::read_to_string(alignment_file).map_err(|e| StitchError::io(alignment_file, e))?;
+    serde_json::from_str(&json_str).map_err(|e| StitchError::format(alignment_file, e))
+}
+
+/**
+ * Check that a loaded alignment places every one of `tile_count` tiles exactly once
+ */
+fn check_alignment<T>(subgraphs: &[Vec<usize>], offsets: &[Vec<T>], tile_count: usize) -> Result<()> {
+    let mut placed = vec![false; tile_count];
+    for (subgraph, offsets) in subgraphs.iter().zip(offsets) {
+        if subgraph.len() != offsets.len() {
+            return Err(StitchError::config(
+                "alignment_file",
+                format!("A subgraph has {} tiles but {} offsets", subgraph.len(), offsets.len()),
+            ));
+        }
+        for &tile in subgraph {
+            if tile >= tile_count || std::mem::replace(&mut placed[tile], true) {
+                return Err(StitchError::config(
+                    "alignment_file",
+                    format!("Tile {} does not match the {} tiles of the config", tile, tile_count),
+                ));
+            }
+        }
+    }
+    if subgraphs.len() != offsets.len() || placed.contains(&false) {
+        return Err(StitchError::config(
+            "alignment_file",
+            format!("The alignment does not place all {} tiles of the config", tile_count),
+        ));
+    }
+    Ok(())
+}
+
+fn save_alignment(config: &StitchConfig, json: &Value, timepoint: Option<usize>) -> Result<()> {
+    let file_name = match timepoint {
+        Some(t) => format!("align_values_t{}.json", t),
+        None => "align_values.json".to_string(),
+    };
+    let json_path = config.output_path.join(file_name);
+    std::fs::write(&json_path, json.to_string()).map_err(|e| StitchError::io(&json_path, e))?;
+    println!("Alignment values saved to: {:?}", json_path);
+    Ok(())
+}
+
+fn write_drifts(config: &StitchConfig, drifts: &[Value]) -> Result<()> {
+    let json_path = config.output_path.join("drift.json");
+    std::fs::write(&json_path, json!(drifts).to_string()).map_err(|e| StitchError::io(&json_path, e))?;
+    println!("Drift values saved to: {:?}", json_path);
+    Ok(())
+}
+
+/**
+ * Name of a fused image. The channel is given when more than one channel is written to separate
+ * files and the timepoint for time-lapse configs, so plain configs keep the `fused_<subgraph>` name.
+ */
+fn fused_file_name(subgraph: usize, channel: Option<usize>, timepoint: Option<usize>, extension: &str) -> String {
+    let mut name = format!("fused_{}", subgraph);
+    if let Some(channel) = channel {
+        name.push_str(&format!("_c{}", channel));
+    }
+    if let Some(timepoint) = timepoint {
+        name.push_str(&format!("_t{}", timepoint));
+    }
+    format!("{}.{}", name, extension)
+}
+
+/**
+ * Write the registered positions of every subgraph as a Fiji TileConfiguration and a BigStitcher
+ * dataset. Files get a `_<subgraph>` suffix when there is more than one subgraph.
+ */
+fn export_registration(
+    config: &StitchConfig,
+    tile_sizes: &[(usize, usize, usize)],
+    subgraphs: &[Vec<usize>],
+    offsets: &[Vec<(f32, f32, f32)>],
+) -> Result<()> {
+    for (i, (subgraph, offsets)) in subgraphs.iter().zip(offsets).enumerate() {
+        let suffix = if subgraphs.len() > 1 { format!("_{}", i) } else { String::new() };
+
+        let tile_configuration_path = config
+            .output_path
+            .join(format!("TileConfiguration{}.registered.txt", suffix));
+        write_tile_configuration(
+            &tile_configuration_path,
+            &config.tile_paths,
+            subgraph,
+            offsets,
+            config.mode,
+        )?;
+
+        let dataset_path = config.output_path.join(format!("dataset{}.xml", suffix));
+        write_bigstitcher_xml(
+            &dataset_path,
+            &config.tile_paths,
+            tile_sizes,
+            subgraph,
+            offsets,
+            config.voxel_size.map(|voxel_size| (voxel_size, config.unit.as_str())),
+        )?;
+    }
+
+    let positions = subgraphs
+        .iter()
+        .zip(offsets)
+        .map(|(subgraph, offsets)| tile_positions(config, subgraph, offsets))
+        .collect::<Vec<_>>();
+    let json = json!({
+        "unit": config.unit,
+        "voxel_size": config.voxel_size.map(|v| [v.0, v.1, v.2]),
+        "subgraphs": positions,
+    });
+    let json_path = config.output_path.join("tile_positions.json");
+    std::fs::write(&json_path, json.to_string()).map_err(|e| StitchError::io(&json_path, e))?;
+
+    println!("Registered positions exported to: {:?}", config.output_path);
+    Ok(())
+}
+
+/**
+ * Registered tile positions of one subgraph in fused image pixels and, when the config has a voxel
+ * size, in physical units. Physical positions are anchored so their mean matches the layout.
+ */
+fn tile_positions(config: &StitchConfig, subgraph: &[usize], offsets: &[(f32, f32, f32)]) -> Value {
+    let shift = layout_shift(config, subgraph, offsets);
+
+    let tiles = subgraph
+        .iter()
+        .zip(offsets)
+        .map(|(&tile, offset)| {
+            let physical = config.voxel_size.map(|voxel_size| {
+                [
+                    (offset.0 + shift.0) * voxel_size.0,
+                    (offset.1 + shift.1) * voxel_size.1,
+                    (offset.2 + shift.2) * voxel_size.2,
+                ]
+            });
+            json!({
+                "tile": tile,
+                "path": config.tile_paths[tile],
+                "pixel": [offset.0, offset.1, offset.2],
+                "physical": physical,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({ "tiles": tiles })
+}
+
+/**
+ * Layout position of the fused image origin in pixels: the mean difference between the layout
+ * and registered positions of the subgraph's tiles
+ */
+fn layout_shift(config: &StitchConfig, subgraph: &[usize], offsets: &[(f32, f32, f32)]) -> (f32, f32, f32) {
+    let count = subgraph.len().max(1) as f32;
+    subgraph.iter().zip(offsets).fold((0.0, 0.0, 0.0), |acc, (&tile, offset)| {
+        let layout = &config.tile_layout[tile];
+        (
+            acc.0 + (layout.x as f32 - offset.0) / count,
+            acc.1 + (layout.y as f32 - offset.1) / count,
+            acc.2 + (layout.z as f32 - offset.2) / count,
+        )
+    })
+}
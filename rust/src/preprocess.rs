@@ -0,0 +1,324 @@
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::error::{Result, StitchError};
+
+/**
+ * Apodization window applied to the overlap of a tile before phase correlation
+ */
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Window {
+    None,
+    Hann,
+    /// Flat in the middle with cosine tapers over `tukey_alpha` of each axis.
+    Tukey,
+}
+
+/**
+ * Filter applied to the overlap of a tile before phase correlation
+ */
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PreprocessFilter {
+    None,
+    /// Subtract a Gaussian blur with the outer sigma.
+    HighPass,
+    /// Gaussian blur with the inner sigma minus Gaussian blur with the outer sigma.
+    DifferenceOfGaussians,
+    /// Gradient magnitude of a Gaussian blur with the inner sigma.
+    GradientMagnitude,
+}
+
+/**
+ * `preprocessing` section of the config. A present section defaults to a Hann window and mean
+ * subtraction, unlike `Preprocessing::default`.
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreprocessingSection {
+    pub window: Option<Window>,
+    pub tukey_alpha: Option<f32>,
+    pub subtract_mean: Option<bool>,
+    pub filter: Option<PreprocessFilter>,
+    /// Inner and outer Gaussian sigma in pixels, or one sigma for both.
+    pub sigmas: Option<Vec<f32>>,
+}
+
+/**
+ * Validated `preprocessing` settings. The default leaves images unchanged.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Preprocessing {
+    pub window: Window,
+    /// Fraction of each axis covered by the tapers of the Tukey window.
+    pub tukey_alpha: f32,
+    pub subtract_mean: bool,
+    pub filter: PreprocessFilter,
+    /// Inner and outer Gaussian sigma of `filter` in pixels.
+    pub sigmas: (f32, f32),
+}
+
+impl Default for Preprocessing {
+    fn default() -> Self {
+        Preprocessing {
+            window: Window::None,
+            tukey_alpha: 0.5,
+            subtract_mean: false,
+            filter: PreprocessFilter::None,
+            sigmas: (1.0, 4.0),
+        }
+    }
+}
+
+impl PreprocessingSection {
+    pub fn build(&self) -> Result<Preprocessing> {
+        let defaults = Preprocessing::default();
+        let tukey_alpha = self.tukey_alpha.unwrap_or(defaults.tukey_alpha);
+        if !(0.0..=1.0).contains(&tukey_alpha) {
+            return Err(StitchError::config("preprocessing.tukey_alpha", "Must be between 0 and 1"));
+        }
+
+        let sigmas = match self.sigmas.as_deref() {
+            None => defaults.sigmas,
+            Some(&[sigma]) => (sigma, sigma),
+            Some(&[inner, outer]) => (inner, outer),
+            Some(_) => {
+                return Err(StitchError::config("preprocessing.sigmas", "Give one sigma or an inner and outer sigma"))
+            }
+        };
+        if !(sigmas.0 > 0.0 && sigmas.1 > 0.0) {
+            return Err(StitchError::config("preprocessing.sigmas", "Sigmas must be positive"));
+        }
+
+        let filter = self.filter.unwrap_or(PreprocessFilter::None);
+        if filter == PreprocessFilter::DifferenceOfGaussians && sigmas.0 >= sigmas.1 {
+            return Err(StitchError::config(
+                "preprocessing.sigmas",
+                "Difference of Gaussians needs an inner sigma smaller than the outer sigma",
+            ));
+        }
+
+        Ok(Preprocessing {
+            window: self.window.unwrap_or(Window::Hann),
+            tukey_alpha,
+            subtract_mean: self.subtract_mean.unwrap_or(true),
+            filter,
+            sigmas,
+        })
+    }
+}
+
+impl Preprocessing {
+    /**
+     * Filter, subtract the mean from and window an image of `size` (width, height, depth) stored
+     * x fastest. Returns `None` when there is nothing to do. Non-finite pixels are replaced by the
+     * mean of the others first.
+     */
+    pub fn apply(&self, data: &[f32], size: (usize, usize, usize)) -> Option<Vec<f32>> {
+        if self.window == Window::None && !self.subtract_mean && self.filter == PreprocessFilter::None {
+            return None;
+        }
+
+        let (sum, count) = data
+            .iter()
+            .filter(|value| value.is_finite())
+            .fold((0.0f64, 0usize), |(sum, count), value| (sum + *value as f64, count + 1));
+        let mean = if count > 0 { (sum / count as f64) as f32 } else { 0.0 };
+        let mut data = data
+            .iter()
+            .map(|value| if value.is_finite() { *value } else { mean })
+            .collect::<Vec<_>>();
+
+        data = match self.filter {
+            PreprocessFilter::None => data,
+            PreprocessFilter::HighPass => {
+                let background = gaussian_blur(&data, size, self.sigmas.1);
+                data.iter().zip(&background).map(|(value, background)| value - background).collect()
+            }
+            PreprocessFilter::DifferenceOfGaussians => {
+                let inner = gaussian_blur(&data, size, self.sigmas.0);
+                let outer = gaussian_blur(&inner, size, (self.sigmas.1.powi(2) - self.sigmas.0.powi(2)).sqrt());
+                inner.iter().zip(&outer).map(|(inner, outer)| inner - outer).collect()
+            }
+            PreprocessFilter::GradientMagnitude => gradient_magnitude(&gaussian_blur(&data, size, self.sigmas.0), size),
+        };
+
+        if self.subtract_mean {
+            let mean = (data.iter().map(|value| *value as f64).sum::<f64>() / data.len().max(1) as f64) as f32;
+            data.iter_mut().for_each(|value| *value -= mean);
+        }
+
+        if self.window != Window::None {
+            let weights = [size.0, size.1, size.2].map(|len| self.window_weights(len));
+            data.par_iter_mut().enumerate().for_each(|(i, value)| {
+                let (x, y, z) = (i % size.0, i / size.0 % size.1, i / (size.0 * size.1));
+                *value *= weights[0][x] * weights[1][y] * weights[2][z];
+            });
+        }
+
+        Some(data)
+    }
+
+    /**
+     * Window weights along an axis of `len` pixels. Axes of a single pixel are not windowed.
+     */
+    fn window_weights(&self, len: usize) -> Vec<f32> {
+        if len < 2 {
+            return vec![1.0; len];
+        }
+
+        let last = (len - 1) as f32;
+        let alpha = match self.window {
+            Window::None => 0.0,
+            Window::Hann => 1.0,
+            Window::Tukey => self.tukey_alpha,
+        };
+        let taper = alpha * last / 2.0;
+        (0..len)
+            .map(|i| {
+                let edge = (i as f32).min(last - i as f32);
+                if edge >= taper {
+                    1.0
+                } else {
+                    0.5 * (1.0 - (std::f32::consts::PI * edge / taper).cos())
+                }
+            })
+            .collect()
+    }
+}
+
+/**
+ * Separable Gaussian blur along every axis longer than one pixel, repeating the edge pixels
+ */
+fn gaussian_blur(data: &[f32], size: (usize, usize, usize), sigma: f32) -> Vec<f32> {
+    let radius = (3.0 * sigma).ceil() as i64;
+    let kernel = (-radius..=radius)
+        .map(|x| (-0.5 * (x as f32 / sigma).powi(2)).exp())
+        .collect::<Vec<_>>();
+    let total = kernel.iter().sum::<f32>();
+    let kernel = kernel.iter().map(|weight| weight / total).collect::<Vec<_>>();
+
+    let mut data = data.to_vec();
+    for (len, stride) in [(size.0, 1), (size.1, size.0), (size.2, size.0 * size.1)] {
+        if len < 2 {
+            continue;
+        }
+        data = (0..data.len())
+            .into_par_iter()
+            .map(|i| {
+                let pos = (i / stride % len) as i64;
+                let line_start = i - pos as usize * stride;
+                kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, weight)| {
+                        let src = (pos + k as i64 - radius).clamp(0, len as i64 - 1) as usize;
+                        weight * data[line_start + src * stride]
+                    })
+                    .sum()
+            })
+            .collect();
+    }
+    data
+}
+
+/**
+ * Magnitude of the central difference gradient along every axis longer than one pixel
+ */
+fn gradient_magnitude(data: &[f32], size: (usize, usize, usize)) -> Vec<f32> {
+    let axes = [(size.0, 1), (size.1, size.0), (size.2, size.0 * size.1)];
+    (0..data.len())
+        .into_par_iter()
+        .map(|i| {
+            axes.iter()
+                .filter(|(len, _)| *len > 1)
+                .map(|&(len, stride)| {
+                    let pos = i / stride % len;
+                    let before = if pos > 0 { data[i - stride] } else { data[i] };
+                    let after = if pos + 1 < len { data[i + stride] } else { data[i] };
+                    let distance = (pos + 1).min(len - 1) - pos.saturating_sub(1);
+                    ((after - before) / distance as f32).powi(2)
+                })
+                .sum::<f32>()
+                .sqrt()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(json: &str) -> Preprocessing {
+        serde_json::from_str::<PreprocessingSection>(json).unwrap().build().unwrap()
+    }
+
+    fn only(filter: PreprocessFilter) -> Preprocessing {
+        Preprocessing {
+            filter,
+            ..Preprocessing::default()
+        }
+    }
+
+    #[test]
+    fn windows_fall_to_zero_at_both_ends() {
+        let hann = section("{}").window_weights(9);
+        assert_eq!((hann[0], hann[8]), (0.0, 0.0));
+        assert!((hann[4] - 1.0).abs() < 1e-6);
+        assert!((0..9).all(|i| (hann[i] - hann[8 - i]).abs() < 1e-6));
+        assert!((hann[2] - 0.5).abs() < 1e-6);
+
+        let tukey = section(r#"{ "window": "tukey", "tukey_alpha": 0.5 }"#).window_weights(9);
+        assert_eq!((tukey[0], tukey[8]), (0.0, 0.0));
+        assert!((0..9).all(|i| (tukey[i] - tukey[8 - i]).abs() < 1e-6));
+        // Tapers over a quarter of the axis at each end, flat in between
+        assert!((tukey[1] - 0.5).abs() < 1e-6);
+        assert!(tukey[2..7].iter().all(|weight| *weight == 1.0));
+
+        assert_eq!(section("{}").window_weights(1), vec![1.0]);
+    }
+
+    #[test]
+    fn empty_section_windows_and_subtracts_the_mean() {
+        let preprocessing = section("{}");
+        assert_eq!((preprocessing.window, preprocessing.subtract_mean), (Window::Hann, true));
+        assert_eq!(Preprocessing::default().apply(&[1.0, 2.0], (2, 1, 1)), None);
+
+        let mean_only = Preprocessing {
+            subtract_mean: true,
+            ..Preprocessing::default()
+        };
+        let data = mean_only.apply(&[1.0, 2.0, f32::NAN, 7.0, 4.0, 10.0], (3, 2, 1)).unwrap();
+        assert!(data.iter().sum::<f32>().abs() < 1e-5);
+        // The missing pixel is replaced by the mean, so it is 0 afterwards
+        assert_eq!(data[2], 0.0);
+    }
+
+    #[test]
+    fn filters_respond_to_edges_only() {
+        let size = (16, 4, 1);
+        let constant = vec![5.0; 64];
+        for filter in [
+            PreprocessFilter::HighPass,
+            PreprocessFilter::DifferenceOfGaussians,
+            PreprocessFilter::GradientMagnitude,
+        ] {
+            let data = only(filter).apply(&constant, size).unwrap();
+            assert!(data.iter().all(|value| value.abs() < 1e-4), "{:?}", filter);
+        }
+
+        let step = (0..64).map(|i| if i % 16 < 8 { 0.0 } else { 1.0 }).collect::<Vec<_>>();
+        let gradient = only(PreprocessFilter::GradientMagnitude).apply(&step, size).unwrap();
+        let row = &gradient[16..32];
+        assert!(row[7] > 0.2 && row[8] > 0.2);
+        assert!(row[0] < 1e-3 && row[15] < 1e-3);
+
+        // Negative on the dark side of the step and positive on the bright side, antisymmetric around it
+        for filter in [PreprocessFilter::HighPass, PreprocessFilter::DifferenceOfGaussians] {
+            let row = only(filter).apply(&step, size).unwrap()[16..32].to_vec();
+            assert!(row[7] < -0.1 && row[8] > 0.1, "{:?}", filter);
+            assert!((0..16).all(|x| (row[x] + row[15 - x]).abs() < 1e-4), "{:?}", filter);
+        }
+    }
+}
@@ -0,0 +1,495 @@
+use flate2::write::ZlibEncoder;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::error::{Result, StitchError};
+use crate::image::{unit_to_meters, Image2D};
+use crate::intensity::{OutputDtype, PixelConversion};
+
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TiffCompression {
+    None,
+    Deflate,
+    Lzw,
+}
+
+impl TiffCompression {
+    /// Value of the TIFF Compression tag.
+    fn tag_value(&self) -> u16 {
+        match self {
+            TiffCompression::None => 1,
+            TiffCompression::Lzw => 5,
+            TiffCompression::Deflate => 8,
+        }
+    }
+}
+
+/**
+ * `pyramid_tiff` section of the config: write 2D fused images as tiled BigTIFFs with the lower
+ * resolutions in SubIFDs
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PyramidTiffSection {
+    pub tile_size: Option<usize>,
+    pub compression: Option<TiffCompression>,
+    /// Number of resolution levels including the full resolution one.
+    pub levels: Option<usize>,
+}
+
+/**
+ * Validated `pyramid_tiff` settings
+ */
+#[derive(Clone, Debug)]
+pub struct PyramidTiff {
+    pub tile_size: usize,
+    pub compression: TiffCompression,
+    /// Number of resolution levels, or `None` to downsample until a level fits in one tile.
+    pub levels: Option<usize>,
+}
+
+impl PyramidTiffSection {
+    pub fn build(&self) -> Result<PyramidTiff> {
+        let tile_size = self.tile_size.unwrap_or(512);
+        if tile_size == 0 || !tile_size.is_multiple_of(16) {
+            return Err(StitchError::config(
+                "pyramid_tiff.tile_size",
+                "TIFF tile sizes must be a positive multiple of 16",
+            ));
+        }
+        if self.levels == Some(0) {
+            return Err(StitchError::config("pyramid_tiff.levels", "At least one level is required"));
+        }
+
+        Ok(PyramidTiff {
+            tile_size,
+            compression: self.compression.unwrap_or(TiffCompression::Deflate),
+            levels: self.levels,
+        })
+    }
+}
+
+/**
+ * Save channels of the same size as a tiled BigTIFF, each converted with its own entry of
+ * `conversions`. Every channel is one page whose downsampled levels are stored in its SubIFDs,
+ * described by an OME-XML header so viewers pick up the pyramid, the channels and the pixel size.
+ */
+pub fn save_pyramid_tiff(
+    file_path: &Path,
+    output: &PyramidTiff,
+    channels: &[Image2D],
+    conversions: &[PixelConversion],
+    voxel_size: Option<(f32, f32, f32)>,
+    unit: &str,
+) -> Result<()> {
+    let (width, height) = (channels[0].width, channels[0].height);
+    if channels.iter().any(|channel| channel.width != width || channel.height != height) {
+        return Err(StitchError::format(file_path, "Channels do not have the same size"));
+    }
+
+    let file = std::fs::File::create(file_path).map_err(|e| StitchError::io(file_path, e))?;
+    let mut writer = TiffWriter {
+        file: BufWriter::new(file),
+        path: file_path,
+    };
+    let dtype = conversions[0].dtype;
+    let description = ome_xml(file_path, channels.len(), dtype, (width, height), voxel_size, unit);
+
+    // BigTIFF header, the first IFD offset is filled in once it is known
+    writer.write(b"II")?;
+    writer.write(&43u16.to_le_bytes())?;
+    writer.write(&8u16.to_le_bytes())?;
+    writer.write(&0u16.to_le_bytes())?;
+    let mut next_ifd_field = writer.position()?;
+    writer.write(&0u64.to_le_bytes())?;
+
+    for (c, (channel, conversion)) in channels.iter().zip(conversions).enumerate() {
+        let mut level = (conversion.scale_all(&channel.data), width, height);
+        let mut levels = vec![];
+        loop {
+            levels.push(writer.write_tiles(&level.0, level.1, level.2, output, dtype)?);
+            let done = match output.levels {
+                Some(count) => levels.len() >= count,
+                None => level.1 <= output.tile_size && level.2 <= output.tile_size,
+            };
+            if done || (level.1 == 1 && level.2 == 1) {
+                break;
+            }
+            level = downsample(&level.0, level.1, level.2, dtype);
+        }
+
+        let mut sub_ifds = vec![];
+        for tiles in &levels[1..] {
+            sub_ifds.push(writer.write_ifd(tiles, output, dtype, true, None, &[])?.0);
+        }
+        let description = (c == 0).then_some(description.as_str());
+        let (offset, next_field) = writer.write_ifd(&levels[0], output, dtype, false, description, &sub_ifds)?;
+
+        writer.patch(next_ifd_field, offset)?;
+        next_ifd_field = next_field;
+    }
+
+    writer.file.flush().map_err(|e| StitchError::io(file_path, e))
+}
+
+/**
+ * Position and compressed tiles of one resolution level
+ */
+struct TiledLevel {
+    width: usize,
+    height: usize,
+    offsets: Vec<u64>,
+    byte_counts: Vec<u64>,
+}
+
+struct TiffWriter<'a> {
+    file: BufWriter<std::fs::File>,
+    path: &'a Path,
+}
+
+impl TiffWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes).map_err(|e| StitchError::io(self.path, e))
+    }
+
+    fn position(&mut self) -> Result<u64> {
+        self.file.stream_position().map_err(|e| StitchError::io(self.path, e))
+    }
+
+    /**
+     * Pad to an even offset, as TIFF requires for IFDs and tag values
+     */
+    fn align(&mut self) -> Result<()> {
+        if !self.position()?.is_multiple_of(2) {
+            self.write(&[0])?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Overwrite the offset stored at `field` and return to the end of the file
+     */
+    fn patch(&mut self, field: u64, offset: u64) -> Result<()> {
+        let end = self.position()?;
+        self.file.seek(SeekFrom::Start(field)).map_err(|e| StitchError::io(self.path, e))?;
+        self.write(&offset.to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(end)).map_err(|e| StitchError::io(self.path, e))?;
+        Ok(())
+    }
+
+    /**
+     * Compress and write every tile of a level, padding the edge tiles to the full tile size
+     */
+    fn write_tiles(
+        &mut self,
+        data: &[f32],
+        width: usize,
+        height: usize,
+        output: &PyramidTiff,
+        dtype: OutputDtype,
+    ) -> Result<TiledLevel> {
+        let (size, path) = (output.tile_size, self.path);
+        let sample_bytes = dtype.bits() as usize / 8;
+        let tiles = (0..height.div_ceil(size))
+            .flat_map(|y| (0..width.div_ceil(size)).map(move |x| (x, y)))
+            .collect::<Vec<_>>();
+
+        let compressed = tiles
+            .into_par_iter()
+            .map(|(x, y)| {
+                let mut bytes = vec![0u8; size * size * sample_bytes];
+                for row in 0..size.min(height - y * size) {
+                    let start = (y * size + row) * width + x * size;
+                    let values = &data[start..start + size.min(width - x * size)];
+                    for (i, value) in values.iter().enumerate() {
+                        let sample = &mut bytes[(row * size + i) * sample_bytes..(row * size + i + 1) * sample_bytes];
+                        match dtype {
+                            OutputDtype::U8 => sample[0] = *value as u8,
+                            OutputDtype::U16 => sample.copy_from_slice(&(*value as u16).to_le_bytes()),
+                            OutputDtype::F32 => sample.copy_from_slice(&value.to_le_bytes()),
+                        }
+                    }
+                }
+                compress(path, bytes, output.compression)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut level = TiledLevel {
+            width,
+            height,
+            offsets: vec![],
+            byte_counts: vec![],
+        };
+        for tile in compressed {
+            level.offsets.push(self.position()?);
+            level.byte_counts.push(tile.len() as u64);
+            self.write(&tile)?;
+        }
+        Ok(level)
+    }
+
+    /**
+     * Write the IFD of a level. Returns its offset and the offset of its next IFD field.
+     */
+    fn write_ifd(
+        &mut self,
+        level: &TiledLevel,
+        output: &PyramidTiff,
+        dtype: OutputDtype,
+        reduced: bool,
+        description: Option<&str>,
+        sub_ifds: &[u64],
+    ) -> Result<(u64, u64)> {
+        const SHORT: u16 = 3;
+        const LONG: u16 = 4;
+        const ASCII: u16 = 2;
+        const LONG8: u16 = 16;
+        const IFD8: u16 = 18;
+
+        let short = |value: u16| value.to_le_bytes().to_vec();
+        let long = |value: u32| value.to_le_bytes().to_vec();
+        let long8 = |values: &[u64]| values.iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<_>>();
+
+        // Entries in ascending tag order: tag, type, count, value
+        let mut entries: Vec<(u16, u16, u64, Vec<u8>)> = vec![
+            (254, LONG, 1, long(reduced as u32)),
+            (256, LONG, 1, long(level.width as u32)),
+            (257, LONG, 1, long(level.height as u32)),
+            (258, SHORT, 1, short(dtype.bits())),
+            (259, SHORT, 1, short(output.compression.tag_value())),
+            (262, SHORT, 1, short(1)),
+        ];
+        if let Some(description) = description {
+            let mut bytes = description.as_bytes().to_vec();
+            bytes.push(0);
+            entries.push((270, ASCII, bytes.len() as u64, bytes));
+        }
+        entries.extend([
+            (277, SHORT, 1, short(1)),
+            (284, SHORT, 1, short(1)),
+            (322, LONG, 1, long(output.tile_size as u32)),
+            (323, LONG, 1, long(output.tile_size as u32)),
+            (324, LONG8, level.offsets.len() as u64, long8(&level.offsets)),
+            (325, LONG8, level.byte_counts.len() as u64, long8(&level.byte_counts)),
+        ]);
+        if !sub_ifds.is_empty() {
+            entries.push((330, IFD8, sub_ifds.len() as u64, long8(sub_ifds)));
+        }
+        let sample_format = if dtype == OutputDtype::F32 { 3 } else { 1 };
+        entries.push((339, SHORT, 1, short(sample_format)));
+
+        // Values longer than the 8 byte field are written ahead of the IFD
+        let mut fields = vec![];
+        for (_, _, _, value) in &entries {
+            if value.len() > 8 {
+                self.align()?;
+                fields.push(self.position()?.to_le_bytes().to_vec());
+                self.write(value)?;
+            } else {
+                let mut field = value.clone();
+                field.resize(8, 0);
+                fields.push(field);
+            }
+        }
+
+        self.align()?;
+        let offset = self.position()?;
+        self.write(&(entries.len() as u64).to_le_bytes())?;
+        for ((tag, field_type, count, _), field) in entries.iter().zip(&fields) {
+            self.write(&tag.to_le_bytes())?;
+            self.write(&field_type.to_le_bytes())?;
+            self.write(&count.to_le_bytes())?;
+            self.write(field)?;
+        }
+        let next_field = self.position()?;
+        self.write(&0u64.to_le_bytes())?;
+
+        Ok((offset, next_field))
+    }
+}
+
+fn compress(file_path: &Path, bytes: Vec<u8>, compression: TiffCompression) -> Result<Vec<u8>> {
+    match compression {
+        TiffCompression::None => Ok(bytes),
+        TiffCompression::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&bytes).map_err(|e| StitchError::io(file_path, e))?;
+            encoder.finish().map_err(|e| StitchError::io(file_path, e))
+        }
+        TiffCompression::Lzw => weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+            .encode(&bytes)
+            .map_err(|e| StitchError::format(file_path, e)),
+    }
+}
+
+/**
+ * Halve an image by averaging 2 x 2 blocks, rounding for integer types
+ */
+fn downsample(data: &[f32], width: usize, height: usize, dtype: OutputDtype) -> (Vec<f32>, usize, usize) {
+    let (new_width, new_height) = (width.div_ceil(2), height.div_ceil(2));
+    let mut downsampled = vec![0f32; new_width * new_height];
+    downsampled
+        .par_chunks_mut(new_width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, value) in row.iter_mut().enumerate() {
+                let (mut sum, mut count) = (0.0, 0);
+                for sy in 2 * y..(2 * y + 2).min(height) {
+                    for sx in 2 * x..(2 * x + 2).min(width) {
+                        sum += data[sy * width + sx];
+                        count += 1;
+                    }
+                }
+                *value = sum / count as f32;
+                if dtype != OutputDtype::F32 {
+                    *value = value.round();
+                }
+            }
+        });
+    (downsampled, new_width, new_height)
+}
+
+/**
+ * OME-XML header of a pyramid with one page per channel
+ */
+fn ome_xml(
+    file_path: &Path,
+    channels: usize,
+    dtype: OutputDtype,
+    (width, height): (usize, usize),
+    voxel_size: Option<(f32, f32, f32)>,
+    unit: &str,
+) -> String {
+    let name = file_path
+        .file_stem()
+        .map(|name| name.to_string_lossy().replace('&', "&amp;").replace('<', "&lt;").replace('"', "&quot;"))
+        .unwrap_or_default();
+
+    // Micrometres are the OME default, so the unit is only written for other units
+    let physical_size = match voxel_size {
+        Some((x, y, _)) => {
+            let unit = match unit_to_meters(unit) {
+                Some(meters) if (meters / 1e-6 - 1.0).abs() < 1e-9 => None,
+                Some(meters) => [(1.0, "m"), (1e-2, "cm"), (1e-3, "mm"), (1e-9, "nm"), (0.0254, "in")]
+                    .into_iter()
+                    .find(|(size, _)| (meters / size - 1.0).abs() < 1e-9)
+                    .map(|(_, symbol)| symbol),
+                None => None,
+            };
+            match unit {
+                Some(unit) => format!(
+                    " PhysicalSizeX=\"{x}\" PhysicalSizeXUnit=\"{unit}\" PhysicalSizeY=\"{y}\" PhysicalSizeYUnit=\"{unit}\""
+                ),
+                None => format!(" PhysicalSizeX=\"{x}\" PhysicalSizeY=\"{y}\""),
+            }
+        }
+        None => String::new(),
+    };
+
+    let channel_elements = (0..channels)
+        .map(|c| format!("<Channel ID=\"Channel:0:{c}\" SamplesPerPixel=\"1\"/>"))
+        .collect::<String>();
+
+    let pixel_type = match dtype {
+        OutputDtype::U8 => "uint8",
+        OutputDtype::U16 => "uint16",
+        OutputDtype::F32 => "float",
+    };
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <OME xmlns=\"http://www.openmicroscopy.org/Schemas/OME/2016-06\">\
+         <Image ID=\"Image:0\" Name=\"{name}\">\
+         <Pixels ID=\"Pixels:0\" DimensionOrder=\"XYCZT\" Type=\"{pixel_type}\" SizeX=\"{width}\" SizeY=\"{height}\" \
+         SizeC=\"{channels}\" SizeZ=\"1\" SizeT=\"1\"{physical_size}>\
+         {channel_elements}<TiffData IFD=\"0\" PlaneCount=\"{channels}\"/>\
+         </Pixels></Image></OME>"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{read_image_2d_channels, RgbMode};
+    use crate::intensity::OutputScaling;
+    use std::collections::HashMap;
+
+    /// Entries of the BigTIFF IFD at `offset` as tag to values, and the offset of the next IFD.
+    fn read_ifd(bytes: &[u8], offset: usize) -> (HashMap<u16, Vec<u64>>, u64) {
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let count = u64_at(offset) as usize;
+        let mut entries = HashMap::new();
+        for entry in (0..count).map(|i| offset + 8 + i * 20) {
+            let (tag, field_type, values) = (u16_at(entry), u16_at(entry + 2), u64_at(entry + 4) as usize);
+            let size = match field_type {
+                3 => 2,
+                4 => 4,
+                16 | 18 => 8,
+                _ => 1,
+            };
+            let start = if size * values > 8 { u64_at(entry + 12) as usize } else { entry + 12 };
+            let value = |i: usize| {
+                let mut field = [0u8; 8];
+                field[..size].copy_from_slice(&bytes[start + i * size..start + (i + 1) * size]);
+                u64::from_le_bytes(field)
+            };
+            entries.insert(tag, (0..values).map(value).collect());
+        }
+        (entries, u64_at(offset + 8 + count * 20))
+    }
+
+    #[test]
+    fn channels_are_pages_with_their_levels_in_sub_ifds() {
+        let path = std::env::temp_dir().join(format!("stitch-pyramid-{}.tif", std::process::id()));
+        let channels = (0..2)
+            .map(|c| {
+                let mut image = Image2D::new(40, 24, 0.0, 1000.0);
+                image.data.iter_mut().enumerate().for_each(|(i, value)| *value = (i + 100 * c) as f32);
+                image
+            })
+            .collect::<Vec<_>>();
+        let raw = PixelConversion::new(OutputDtype::U16, OutputScaling::Raw, (0.0, 0.0), None);
+        let output = PyramidTiff {
+            tile_size: 16,
+            compression: TiffCompression::Lzw,
+            levels: None,
+        };
+        save_pyramid_tiff(&path, &output, &channels, &[raw; 2], Some((0.5, 0.5, 1.0)), "um").unwrap();
+
+        // One page per channel, 40 x 24 halves until a level fits in one 16 x 16 tile
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], b"II\x2b\x00");
+        let mut pages = vec![];
+        let mut offset = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        while offset != 0 {
+            let (entries, next) = read_ifd(&bytes, offset as usize);
+            pages.push(entries);
+            offset = next;
+        }
+        assert_eq!(pages.len(), 2);
+        for page in &pages {
+            assert_eq!((page[&256][0], page[&257][0], page[&254][0]), (40, 24, 0));
+            assert_eq!((page[&322][0], page[&323][0]), (16, 16));
+            assert_eq!(page[&324].len(), 3 * 2);
+            let levels = page[&330]
+                .iter()
+                .map(|sub_ifd| {
+                    let (entries, _) = read_ifd(&bytes, *sub_ifd as usize);
+                    (entries[&256][0], entries[&257][0], entries[&254][0], entries[&324].len())
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(levels, [(20, 12, 1, 2), (10, 6, 1, 1)]);
+        }
+
+        // The OME-XML header lets the full resolution be read back as channels
+        let read = read_image_2d_channels(&path, 0, RgbMode::Luminance).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!((read[1].width, read[1].height), (40, 24));
+        assert_eq!(read[1].data, channels[1].data);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,617 @@
+use serde_json::{json, Value};
+
+use crate::chunked::ChunkedOutputSection;
+use crate::config::{AxisValues, StitchConfig, StitchConfigFile, StitchMode, TileEntry, CONFIG_VERSION};
+use crate::fuse::FuseMode;
+use crate::grid::{GridLayout, GridOrder};
+use crate::preprocess::PreprocessingSection;
+use crate::pyramid_tiff::PyramidTiffSection;
+use crate::timelapse::TimepointsSection;
+
+/**
+ * JSON Schema fragment describing how a config type is written in stitch_config.json
+ */
+pub trait ConfigSchema {
+    fn schema() -> Value;
+}
+
+/**
+ * Complete JSON Schema document for stitch_config.json
+ */
+pub fn config_schema() -> Value {
+    let mut schema = StitchConfigFile::schema();
+    let root = schema.as_object_mut().unwrap();
+    root.insert("$schema".into(), json!("https://json-schema.org/draft/2020-12/schema"));
+    root.insert("title".into(), json!("stitch_config.json"));
+    root.insert(
+        "$defs".into(),
+        json!({
+            "axis_values": AxisValues::schema(),
+            "tile": TileEntry::schema(),
+            "box": box_schema(),
+        }),
+    );
+    schema
+}
+
+impl ConfigSchema for StitchConfigFile {
+    fn schema() -> Value {
+        let defaults = StitchConfig::new();
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["mode"],
+            "anyOf": [
+                { "required": ["tiles"] },
+                { "required": ["tile_paths", "tile_layout"] },
+                { "required": ["grid"] }
+            ],
+            "properties": {
+                "$schema": {
+                    "type": "string",
+                    "description": "Schema this file conforms to, used by editors"
+                },
+                "version": {
+                    "type": "string",
+                    "pattern": format!("^{}(\\.[0-9]+)*$", CONFIG_VERSION.split('.').next().unwrap()),
+                    "default": CONFIG_VERSION,
+                    "description": "Config format version"
+                },
+                "mode": StitchMode::schema(),
+                "overlap_ratio": with_default(
+                    "#/$defs/axis_values",
+                    json!([number(defaults.overlap_ratio.0), number(defaults.overlap_ratio.1), number(defaults.overlap_ratio.2)]),
+                    "Expected overlap between neighbouring tiles as a fraction of the tile size"
+                ),
+                "correlation_threshold": {
+                    "type": "number",
+                    "default": number(defaults.correlation_threshold),
+                    "description": "Minimum cross correlation for a pairwise match to be kept"
+                },
+                "relative_error_threshold": {
+                    "type": "number",
+                    "default": number(defaults.relative_error_threshold),
+                    "description": "Links whose error exceeds this multiple of the average error are removed during global optimization"
+                },
+                "absolute_error_threshold": {
+                    "type": "number",
+                    "default": number(defaults.absolute_error_threshold),
+                    "description": "Links whose error exceeds this many pixels are removed during global optimization"
+                },
+                "check_peaks": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "default": defaults.check_peaks,
+                    "description": "Number of phase correlation peaks tested per pair"
+                },
+                "save_float": {
+                    "type": "boolean",
+                    "default": defaults.save_float,
+                    "description": "Write the fused 3D image as 32-bit float TIFF"
+                },
+                "measure_intensity": {
+                    "type": "boolean",
+                    "default": defaults.measure_intensity,
+                    "description": "Decode every 3D tile before fusing to find its actual value range"
+                },
+                "output_format": {
+                    "enum": ["tiff", "dcm", "dcm-series"],
+                    "default": "tiff",
+                    "description": "File format of fused 3D volumes: a TIFF stack, one multi-frame DICOM file, or a directory with one DICOM file per slice"
+                },
+                "output_dtype": {
+                    "enum": ["u8", "u16", "f32"],
+                    "description": "Pixel type of fused images. Defaults to f32 for 3D TIFFs or with save_float and u16 otherwise"
+                },
+                "output_scaling": {
+                    "oneOf": [
+                        { "enum": ["raw", "input-range"] },
+                        {
+                            "type": "object",
+                            "properties": {
+                                "percentiles": {
+                                    "type": "array",
+                                    "items": { "type": "number", "minimum": 0, "maximum": 100 },
+                                    "minItems": 2,
+                                    "maxItems": 2
+                                }
+                            },
+                            "required": ["percentiles"],
+                            "additionalProperties": false
+                        },
+                        {
+                            "type": "object",
+                            "properties": {
+                                "range": { "type": "array", "items": { "type": "number" }, "minItems": 2, "maxItems": 2 }
+                            },
+                            "required": ["range"],
+                            "additionalProperties": false
+                        }
+                    ],
+                    "description": "Mapping of fused values to output pixels. Defaults to raw for f32 and input-range for integer types"
+                },
+                "dimension_mask": {
+                    "type": "array",
+                    "items": { "type": "boolean" },
+                    "minItems": 2,
+                    "maxItems": 3,
+                    "default": [defaults.dimension_mask.0, defaults.dimension_mask.1, defaults.dimension_mask.2],
+                    "description": "Axes along which tiles may be shifted during registration"
+                },
+                "fuse_mode": FuseMode::schema(),
+                "use_phase_correlation": {
+                    "type": "boolean",
+                    "default": defaults.use_phase_correlation,
+                    "description": "Register pairs with phase correlation instead of only using the layout"
+                },
+                "subpixel_accuracy": {
+                    "type": "boolean",
+                    "default": defaults.subpixel_accuracy,
+                    "description": "Refine pairwise shifts to fractions of a pixel by fitting a parabola to the correlation peak"
+                },
+                "preprocessing": PreprocessingSection::schema(),
+                "registration_downsampling": {
+                    "$ref": "#/$defs/axis_values",
+                    "description": "Phase correlate 3D pairs downsampled by these whole factors along x, y and z, then refine the shift at full resolution. Use a smaller z factor for anisotropic volumes"
+                },
+                "registration_memory_gb": {
+                    "type": "number",
+                    "exclusiveMinimum": 0,
+                    "description": "Register several 3D pairs at once within this many gigabytes, half of which caches decoded tiles. Must hold two tiles and the largest pair"
+                },
+                "no_fuse": {
+                    "type": "boolean",
+                    "default": defaults.no_fuse,
+                    "description": "Only compute the alignment, do not write a fused image"
+                },
+                "use_prior": {
+                    "type": "boolean",
+                    "default": defaults.use_prior,
+                    "description": "Weight pairwise offsets by a Gaussian prior centered on the layout"
+                },
+                "merge_subgraphs": {
+                    "type": "boolean",
+                    "default": defaults.merge_subgraphs,
+                    "description": "Connect disjoint groups of registered tiles using their layout positions"
+                },
+                "prior_sigma": with_default(
+                    "#/$defs/axis_values",
+                    json!([number(defaults.prior_sigmas.0), number(defaults.prior_sigmas.1), number(defaults.prior_sigmas.2)]),
+                    "Standard deviation of the prior in pixels"
+                ),
+                "fuse_memory_gb": {
+                    "type": "number",
+                    "exclusiveMinimum": 0,
+                    "description": "Fuse 3D volumes in z-slabs that fit in this many gigabytes and stream them to the output TIFF instead of fusing in memory"
+                },
+                "output_path": {
+                    "type": "string",
+                    "default": "output",
+                    "description": "Output directory, relative to the config file"
+                },
+                "alignment_file": {
+                    "type": "string",
+                    "description": "Previously saved align_values.json to reuse instead of registering again"
+                },
+                "pair_cache": {
+                    "type": "string",
+                    "description": "JSON file, relative to the config file, keeping pairwise registration results so that runs changing only thresholds or merge_subgraphs skip phase correlation"
+                },
+                "tiles": {
+                    "type": "array",
+                    "items": { "$ref": "#/$defs/tile" },
+                    "minItems": 1,
+                    "description": "Tile files with their approximate positions"
+                },
+                "tile_paths": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "minItems": 1,
+                    "description": "Tile files, relative to the config file. Positions are given in tile_layout or read with layout_from_metadata"
+                },
+                "tile_layout": {
+                    "type": "array",
+                    "items": { "$ref": "#/$defs/box" },
+                    "minItems": 1,
+                    "description": "Approximate position of each entry of tile_paths"
+                },
+                "grid": GridLayout::schema(),
+                "timepoints": TimepointsSection::schema(),
+                "chunked_output": ChunkedOutputSection::schema(),
+                "pyramid_tiff": PyramidTiffSection::schema(),
+                "voxel_size": {
+                    "$ref": "#/$defs/axis_values",
+                    "description": "Physical size of a pixel, used to convert stage positions. When set, prior_sigma is in physical units too"
+                },
+                "unit": {
+                    "type": "string",
+                    "default": defaults.unit,
+                    "description": "Unit of voxel_size and stage positions"
+                },
+                "channel": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "default": defaults.channel,
+                    "description": "Channel of the tiles used for registration"
+                },
+                "channel_weights": {
+                    "type": "array",
+                    "items": { "type": "number" },
+                    "minItems": 1,
+                    "description": "Register on the weighted sum of all channels, one weight per channel. Used instead of channel"
+                },
+                "rgb": {
+                    "enum": ["luminance", "channels"],
+                    "default": "luminance",
+                    "description": "Read color tiles as their luminance, or with red, green, blue (and alpha for 3D TIFF pages) as channels 0 to 3"
+                },
+                "fuse_channels": {
+                    "type": "array",
+                    "items": { "type": "integer", "minimum": 0 },
+                    "minItems": 1,
+                    "description": "Channels to fuse with the registered offsets, all channels by default"
+                },
+                "multichannel_output": {
+                    "type": "boolean",
+                    "default": defaults.multichannel_output,
+                    "description": "Write all fused channels into one TIFF hyperstack instead of one file per channel"
+                },
+                "timepoint": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "default": defaults.timepoint,
+                    "description": "Timepoint of OME-TIFF tiles used for registration"
+                },
+                "layout_from_metadata": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Place tiles without coordinates, or tile_paths without tile_layout, by the pixel spacing and position in their DICOM or TIFF headers"
+                }
+            }
+        })
+    }
+}
+
+impl ConfigSchema for GridLayout {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["size", "tile_size", "pattern"],
+            "description": "Regular grid of tiles, used instead of tiles or tile_paths",
+            "properties": {
+                "size": {
+                    "type": "array",
+                    "items": { "type": "integer", "minimum": 1 },
+                    "minItems": 2,
+                    "maxItems": 3,
+                    "description": "Number of tiles along x, y and optionally z"
+                },
+                "order": GridOrder::schema(),
+                "tile_size": {
+                    "type": "array",
+                    "items": { "type": "integer", "minimum": 1 },
+                    "minItems": 2,
+                    "maxItems": 3,
+                    "description": "Size of a single tile in pixels"
+                },
+                "overlap": with_default(
+                    "#/$defs/axis_values",
+                    json!(0),
+                    "Overlap between neighbouring tiles as a fraction of the tile size"
+                ),
+                "reverse": {
+                    "type": "array",
+                    "items": { "type": "boolean" },
+                    "minItems": 2,
+                    "maxItems": 3,
+                    "description": "Start from the opposite end along x, y and optionally z"
+                },
+                "pattern": {
+                    "type": "string",
+                    "description": "File name pattern with {x}, {y}, {z} grid positions and {i} acquisition index, optionally zero padded as in {x:03}. {t} is left for the timepoints section"
+                },
+                "first_index": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "default": 0,
+                    "description": "Value of the first grid position and acquisition index in file names"
+                }
+            }
+        })
+    }
+}
+
+impl ConfigSchema for TimepointsSection {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["count"],
+            "description": "Time-lapse of the same tiles, with {t} or a zero padded {t:03} in every tile path standing for the timepoint",
+            "properties": {
+                "count": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Number of timepoints"
+                },
+                "first_index": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "default": 0,
+                    "description": "Value of {t} for the first timepoint"
+                },
+                "reference": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "default": 0,
+                    "description": "Timepoint whose tiles are registered, counted from 0"
+                },
+                "register_every": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Also register every Nth timepoint from the reference, later timepoints reuse the closest earlier registration"
+                },
+                "correct_drift": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Register each timepoint to the timepoint it takes its alignment from and shift it by the measured drift"
+                }
+            }
+        })
+    }
+}
+
+impl ConfigSchema for ChunkedOutputSection {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["format"],
+            "description": "Write fused images as a chunked multiscale store instead of TIFF or PNG",
+            "properties": {
+                "format": {
+                    "enum": ["zarr", "n5"],
+                    "description": "OME-Zarr (Zarr v2 directory store) or N5"
+                },
+                "chunk_size": {
+                    "type": "array",
+                    "items": { "type": "integer", "minimum": 1 },
+                    "minItems": 1,
+                    "maxItems": 3,
+                    "description": "Chunk size along x, y and z, or one size for every axis. Defaults to 64 in 3D and 256 in 2D"
+                },
+                "compression": {
+                    "enum": ["none", "gzip"],
+                    "default": "gzip",
+                    "description": "Compression of every chunk"
+                },
+                "compression_level": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "maximum": 9,
+                    "default": 5,
+                    "description": "Gzip compression level"
+                },
+                "levels": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Number of resolution levels, each downsampled by 2 from the previous one. By default levels are added until one fits in a single chunk in x and y"
+                }
+            }
+        })
+    }
+}
+
+impl ConfigSchema for PyramidTiffSection {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "description": "Write 2D fused images as tiled 16-bit BigTIFFs with downsampled levels in SubIFDs instead of PNG",
+            "properties": {
+                "tile_size": {
+                    "type": "integer",
+                    "minimum": 16,
+                    "multipleOf": 16,
+                    "default": 512,
+                    "description": "Width and height of the TIFF tiles"
+                },
+                "compression": {
+                    "enum": ["none", "deflate", "lzw"],
+                    "default": "deflate",
+                    "description": "Compression of every tile"
+                },
+                "levels": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Number of resolution levels, each downsampled by 2 from the previous one. By default levels are added until one fits in a single tile"
+                }
+            }
+        })
+    }
+}
+
+impl ConfigSchema for PreprocessingSection {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "description": "Window and filter the overlap of each tile before phase correlation. Without this section overlaps are used as they are, an empty section applies a Hann window and subtracts the mean",
+            "properties": {
+                "window": {
+                    "enum": ["none", "hann", "tukey"],
+                    "default": "hann",
+                    "description": "Apodization window that fades the overlap to zero at its borders"
+                },
+                "tukey_alpha": {
+                    "type": "number",
+                    "minimum": 0,
+                    "maximum": 1,
+                    "default": 0.5,
+                    "description": "Fraction of each axis covered by the tapers of the Tukey window"
+                },
+                "subtract_mean": {
+                    "type": "boolean",
+                    "default": true,
+                    "description": "Subtract the mean intensity of the overlap before windowing"
+                },
+                "filter": {
+                    "enum": ["none", "high-pass", "difference-of-gaussians", "gradient-magnitude"],
+                    "default": "none",
+                    "description": "Subtract a blur with the outer sigma, take the difference of blurs with the inner and outer sigma, or the gradient magnitude of a blur with the inner sigma"
+                },
+                "sigmas": {
+                    "type": "array",
+                    "items": { "type": "number", "exclusiveMinimum": 0 },
+                    "minItems": 1,
+                    "maxItems": 2,
+                    "default": [1, 4],
+                    "description": "Inner and outer Gaussian sigma of the filter in pixels, or one sigma for both"
+                }
+            }
+        })
+    }
+}
+
+impl ConfigSchema for GridOrder {
+    fn schema() -> Value {
+        json!({
+            "enum": ["row-by-row", "snake-by-row", "column-by-column", "snake-by-column"],
+            "default": "row-by-row",
+            "description": "Order in which tiles were acquired"
+        })
+    }
+}
+
+impl ConfigSchema for TileEntry {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["path"],
+            "anyOf": [
+                { "required": ["box"] },
+                { "required": ["x", "y"] },
+                { "required": ["position"] },
+                { "not": { "anyOf": [{ "required": ["x"] }, { "required": ["y"] }] } }
+            ],
+            "properties": {
+                "path": { "type": "string", "description": "Tile file, relative to the config file" },
+                "box": { "$ref": "#/$defs/box" },
+                "x": { "type": "integer" },
+                "y": { "type": "integer" },
+                "z": { "type": "integer", "default": 0 },
+                "width": { "type": "integer", "minimum": 1, "default": 1 },
+                "height": { "type": "integer", "minimum": 1, "default": 1 },
+                "depth": { "type": "integer", "minimum": 1, "default": 1 },
+                "position": {
+                    "type": "array",
+                    "items": { "type": "number" },
+                    "minItems": 2,
+                    "maxItems": 3,
+                    "description": "Stage position in physical units, requires voxel_size. Missing sizes are read from the image"
+                },
+                "voxel_size": {
+                    "$ref": "#/$defs/axis_values",
+                    "description": "Pixel spacing of this tile when it differs from the config voxel_size, which it is resampled to. Requires position"
+                }
+            }
+        })
+    }
+}
+
+impl ConfigSchema for AxisValues {
+    fn schema() -> Value {
+        json!({
+            "oneOf": [
+                { "type": "number" },
+                {
+                    "type": "array",
+                    "items": { "type": "number" },
+                    "minItems": 2,
+                    "maxItems": 3
+                }
+            ]
+        })
+    }
+}
+
+impl ConfigSchema for StitchMode {
+    fn schema() -> Value {
+        json!({
+            "enum": ["2d", "3d"],
+            "description": "Whether tiles are 2D images or 3D volumes"
+        })
+    }
+}
+
+impl ConfigSchema for FuseMode {
+    fn schema() -> Value {
+        json!({
+            "enum": ["linear", "average", "min", "max", "overwrite", "overwrite-prioritize-center"],
+            "default": "linear",
+            "description": "How overlapping tiles are blended in the fused image"
+        })
+    }
+}
+
+/**
+ * Layout box given as [x, y], [x, y, z], [x, y, width, height] or [x, y, z, width, height, depth]
+ */
+fn box_schema() -> Value {
+    json!({
+        "type": "array",
+        "items": { "type": "integer" },
+        "anyOf": [
+            { "minItems": 2, "maxItems": 3 },
+            { "minItems": 4, "maxItems": 4 },
+            { "minItems": 6, "maxItems": 6 }
+        ],
+        "description": "[x, y], [x, y, z], [x, y, width, height] or [x, y, z, width, height, depth]"
+    })
+}
+
+fn with_default(reference: &str, default: Value, description: &str) -> Value {
+    json!({
+        "$ref": reference,
+        "default": default,
+        "description": description
+    })
+}
+
+/**
+ * Emit an f32 default with its shortest decimal form instead of the widened f64 value
+ */
+fn number(value: f32) -> Value {
+    json!(value.to_string().parse::<f64>().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use std::collections::BTreeSet;
+
+    /** Field names serde accepts for `T`, read from its error for an unknown field */
+    fn serde_fields<T: DeserializeOwned>() -> BTreeSet<String> {
+        let error = serde_json::from_str::<T>(r#"{ "not a field": 0 }"#).err().unwrap().to_string();
+        let expected = error.split_once("expected").map(|(_, fields)| fields).unwrap_or_default();
+        expected.split('`').skip(1).step_by(2).map(str::to_string).collect()
+    }
+
+    fn schema_properties(schema: Value) -> BTreeSet<String> {
+        schema["properties"].as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn schema_lists_every_config_field() {
+        assert_eq!(schema_properties(StitchConfigFile::schema()), serde_fields::<StitchConfigFile>());
+        assert_eq!(schema_properties(TileEntry::schema()), serde_fields::<TileEntry>());
+        assert_eq!(schema_properties(GridLayout::schema()), serde_fields::<GridLayout>());
+        assert_eq!(schema_properties(TimepointsSection::schema()), serde_fields::<TimepointsSection>());
+        assert_eq!(schema_properties(ChunkedOutputSection::schema()), serde_fields::<ChunkedOutputSection>());
+        assert_eq!(schema_properties(PyramidTiffSection::schema()), serde_fields::<PyramidTiffSection>());
+        assert_eq!(schema_properties(PreprocessingSection::schema()), serde_fields::<PreprocessingSection>());
+    }
+}
@@ -5,9 +5,12 @@ use rustfft::{num_complex::Complex, num_traits::Zero, FftNum, FftPlanner};
 use serde::{Deserialize, Serialize};
 use transpose::transpose;
 
+use crate::error::{Result, StitchError};
 use crate::image::Image2D;
+use crate::pair_cache::{image_fingerprint, CachedPair, PairKey, PairCache};
+use crate::preprocess::Preprocessing;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct IBox2D {
     pub x: i64,
     pub y: i64,
@@ -81,31 +84,30 @@ pub struct StitchGraph2D {
     pub num_nodes: usize,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Stitch2DResult {
     pub pairs: Vec<Pair2D>,
     pub subgraphs: Vec<Vec<usize>>,
     pub offsets: Vec<Vec<(f32, f32)>>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Pair2D {
     pub i: usize,
     pub j: usize,
-    pub offset: (i64, i64),
+    pub offset: (f32, f32),
     pub weight: f32,
     pub valid: bool,
 }
 
 pub fn guassian_2d(x: f32, y: f32, x0: f32, y0: f32, s0: f32, s1: f32) -> f32 {
-    let x = x as f32 - x0;
-    let y = y as f32 - y0;
+    let x = x - x0;
+    let y = y - y0;
     let x = x * x;
     let y = y * y;
     let s0 = s0 * s0;
     let s1 = s1 * s1;
-    let res = (-0.5 * (x / s0 + y / s1)).exp();
-    res
+    (-0.5 * (x / s0 + y / s1)).exp()
 }
 
 #[allow(dead_code)]
@@ -122,7 +124,10 @@ pub fn stitch(
     use_prior: bool,
     prior_sigmas: (f32, f32),
     merge_subgraphs: bool,
-) -> Stitch2DResult {
+    subpixel_accuracy: bool,
+    preprocessing: &Preprocessing,
+    pair_cache: Option<&PairCache>,
+) -> Result<Stitch2DResult> {
     let mut overlap_map = create_overlap_map(images, layout, overlap_ratio);
     println!("Overlap map: {:?}", overlap_map);
     overlap_map
@@ -137,7 +142,12 @@ pub fn stitch(
     let done = Mutex::new(0);
 
     let mut pairs: Vec<Pair2D> = if dimension_mask.0 || dimension_mask.1 {
-        overlap_map
+        let cached_before = pair_cache.map_or(0, |pair_cache| pair_cache.hits());
+        let fingerprints = match pair_cache {
+            Some(_) => images.par_iter().map(image_fingerprint).collect::<Vec<_>>(),
+            None => vec![],
+        };
+        let pairs = overlap_map
         .par_iter()
         .enumerate()
         .flat_map(|(i, overlap_list)| {
@@ -160,9 +170,6 @@ pub fn stitch(
                         overlap_ratio,
                     );
 
-                    let ref_img = extract_image_with_roi(&image_ref, &ref_roi);
-                    let mov_img = extract_image_with_roi(&image_move, &mov_roi);
-
                     let max_size = (
                         ref_roi.width.max(mov_roi.width) as usize,
                         ref_roi.height.max(mov_roi.height) as usize,
@@ -173,198 +180,247 @@ pub fn stitch(
                         return Pair2D {
                             i,
                             j,
-                            offset: (0, 0),
+                            offset: (0.0, 0.0),
                             weight: 0.0,
                             valid: false,
                         };
                     }
 
-                    let mut ref_fft = to_complex_with_padding(&ref_img, max_size.0, max_size.1);
-                    let mut mov_fft = to_complex_with_padding(&mov_img, max_size.0, max_size.1);
+                    // Candidate peaks only depend on the tiles, their overlap and these settings
+                    let key = pair_cache.map(|_| {
+                        PairKey::new(fingerprints[i], fingerprints[j])
+                            .ints(&[ref_roi.x, ref_roi.y, ref_roi.width, ref_roi.height])
+                            .ints(&[mov_roi.x, mov_roi.y, mov_roi.width, mov_roi.height])
+                            .ints(&[check_peaks as i64])
+                            .flags(&[dimension_mask.0, dimension_mask.1])
+                            .flags(&[use_phase_correlation, use_prior, subpixel_accuracy])
+                            .floats(&[prior_sigmas.0, prior_sigmas.1])
+                            .preprocessing(preprocessing)
+                            .finish()
+                    });
+                    let cached = pair_cache.zip(key.as_deref()).and_then(|(pair_cache, key)| pair_cache.get(key));
+                    let (peaks, subpixel) = match cached {
+                        Some(cached) => (
+                            cached.peaks.iter().map(|peak| (peak.0, peak.1, peak.3)).collect::<Vec<_>>(),
+                            (cached.subpixel.0, cached.subpixel.1),
+                        ),
+                        None => {
+                            let ref_img = extract_image_with_roi(image_ref, &ref_roi);
+                            let mov_img = extract_image_with_roi(image_move, &mov_roi);
+
+                            let mut ref_fft = to_complex_with_padding(&ref_img, max_size.0, max_size.1, preprocessing);
+                            let mut mov_fft = to_complex_with_padding(&mov_img, max_size.0, max_size.1, preprocessing);
+
+                            fft_2d(
+                                max_size.0,
+                                max_size.1,
+                                &mut ref_fft,
+                                rustfft::FftDirection::Forward,
+                            );
 
-                    fft_2d(
-                        max_size.0,
-                        max_size.1,
-                        &mut ref_fft,
-                        rustfft::FftDirection::Forward,
-                    );
+                            fft_2d(
+                                max_size.0,
+                                max_size.1,
+                                &mut mov_fft,
+                                rustfft::FftDirection::Forward,
+                            );
 
-                    fft_2d(
-                        max_size.0,
-                        max_size.1,
-                        &mut mov_fft,
-                        rustfft::FftDirection::Forward,
-                    );
+                            let mut phase_corr = ref_fft
+                                .iter()
+                                .zip(mov_fft.iter())
+                                .map(|(a, b)| {
+                                    let res = a * b.conj();
+                                    if !use_phase_correlation {
+                                        return res;
+                                    }
+
+                                    let norm = res.norm();
+                                    if norm > f32::EPSILON {
+                                        res / norm
+                                    } else {
+                                        Complex::zero()
+                                    }
+                                })
+                                .collect::<Vec<_>>();
+
+                            fft_2d(
+                                max_size.1,
+                                max_size.0,
+                                &mut phase_corr,
+                                rustfft::FftDirection::Inverse,
+                            );
 
-                    let mut phase_corr = ref_fft
-                        .iter()
-                        .zip(mov_fft.iter())
-                        .map(|(a, b)| {
-                            let res = a * b.conj();
-                            if !use_phase_correlation {
-                                return res;
-                            }
+                            let mut image = Image2D {
+                                width: max_size.0,
+                                height: max_size.1,
+                                data: phase_corr
+                                    .iter()
+                                    .map(|x| x.norm())
+                                    .collect::<Vec<_>>(),
+                                min: 0.0,
+                                max: 0.0,
+                            };
 
-                            let norm = res.norm();
-                            if norm > f32::EPSILON {
-                                res / norm
-                            } else {
-                                Complex::zero()
+                            // Compute prior and apply
+                            if use_prior {
+                                let half_w = max_size.0 as i32 / 2;
+                                let half_h = max_size.1 as i32 / 2;
+                                image.data.iter_mut().enumerate().for_each(|(i, val)| {
+                                    let x = (i % max_size.0) as i32;
+                                    let y = (i / max_size.0) as i32;
+
+                                    let x = if x >= half_w {
+                                        x - max_size.0 as i32
+                                    } else {
+                                        x
+                                    };
+
+                                    let y = if y >= half_h {
+                                        y - max_size.1 as i32
+                                    } else {
+                                        y
+                                    };
+
+                                    let prior = guassian_2d(
+                                        x as f32,
+                                        y as f32,
+                                        0.0,
+                                        0.0,
+                                        prior_sigmas.0,
+                                        prior_sigmas.1,
+                                    );
+
+                                    *val *= prior;
+                                });
                             }
-                        })
-                        .collect::<Vec<_>>();
-
-                    fft_2d(
-                        max_size.1,
-                        max_size.0,
-                        &mut phase_corr,
-                        rustfft::FftDirection::Inverse,
-                    );
 
-                    let mut image = Image2D {
-                        width: max_size.0,
-                        height: max_size.1,
-                        data: phase_corr
-                            .iter()
-                            .map(|x| x.norm() as f32)
-                            .collect::<Vec<_>>(),
-                        min: 0.0,
-                        max: 0.0,
-                    };
-
-                    // Compute prior and apply
-                    if use_prior {
-                        let half_w = max_size.0 as i32 / 2;
-                        let half_h = max_size.1 as i32 / 2;
-                        image.data.iter_mut().enumerate().for_each(|(i, val)| {
-                            let x = (i % max_size.0) as i32;
-                            let y = (i / max_size.0) as i32;
-
-                            let x = if x >= half_w {
-                                x - max_size.0 as i32
-                            } else {
-                                x
-                            };
+                            let mut peaks = find_peaks_2d(&image, check_peaks)
+                                .iter()
+                                .flat_map(|peak| disambiguate_2d(max_size.0, max_size.1, *peak))
+                                .collect::<Vec<_>>();
 
-                            let y = if y >= half_h {
-                                y - max_size.1 as i32
-                            } else {
-                                y
-                            };
-
-                            let prior = guassian_2d(
-                                x as f32,
-                                y as f32,
-                                0.0,
-                                0.0,
-                                prior_sigmas.0,
-                                prior_sigmas.1,
+                            // Filter peaks
+                            let ratio = 0.75;
+                            let max_shift = (
+                                (max_size.0 as f32 * ratio) as i64,
+                                (max_size.0 as f32 * ratio) as i64,
                             );
 
-                            *val *= prior;
-                        });
-                    }
-
-                    let mut peaks = find_peaks_2d(&image, check_peaks)
-                        .iter()
-                        .flat_map(|peak| disambiguate_2d(max_size.0, max_size.1, *peak))
-                        .collect::<Vec<_>>();
-
-                    // Filter peaks
-                    let ratio = 0.75;
-                    let max_shift = (
-                        (max_size.0 as f32 * ratio) as i64,
-                        (max_size.0 as f32 * ratio) as i64,
-                    );
-
-                    peaks.retain(|peak| {
-                        peak.0 >= -max_shift.0
-                            && peak.0 <= max_shift.0
-                            && peak.1 >= -max_shift.1
-                            && peak.1 <= max_shift.1
-                    });
-
-                    // Mask peaks
-                    peaks.iter_mut().for_each(|peak| {
-                        if !dimension_mask.0 {
-                            peak.0 = 0;
-                        }
-
-                        if !dimension_mask.1 {
-                            peak.1 = 0;
-                        }
-                    });
+                            peaks.retain(|peak| {
+                                peak.0 >= -max_shift.0
+                                    && peak.0 <= max_shift.0
+                                    && peak.1 >= -max_shift.1
+                                    && peak.1 <= max_shift.1
+                            });
+
+                            // Mask peaks
+                            peaks.iter_mut().for_each(|peak| {
+                                if !dimension_mask.0 {
+                                    peak.0 = 0;
+                                }
+
+                                if !dimension_mask.1 {
+                                    peak.1 = 0;
+                                }
+                            });
+
+                            // Test peaks
+                            peaks.iter_mut().for_each(|peak| {
+                                let res = test_cross_2d(&ref_img, &mov_img, (peak.0, peak.1), 0.01);
+                                peak.2 = res.0;
+                            });
+
+                            // Multiply cc by prior
+                            if use_prior {
+                                peaks.iter_mut().for_each(|peak| {
+                                    let x = peak.0 as i32;
+                                    let y = peak.1 as i32;
+                                    peak.2 *= guassian_2d(
+                                        x as f32,
+                                        y as f32,
+                                        0.0,
+                                        0.0,
+                                        prior_sigmas.0,
+                                        prior_sigmas.1,
+                                    );
+                                });
+                            }
 
-                    // Test peaks
-                    peaks.iter_mut().for_each(|peak| {
-                        let res = test_cross_2d(&ref_img, &mov_img, (peak.0, peak.1), 0.01);
-                        peak.2 = res.0;
-                    });
+                            // Sort by highest R
+                            peaks.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+                            // Refine the best peak on the correlation surface, which wraps around like
+                            // the disambiguated shifts
+                            let subpixel = match peaks.first() {
+                                Some(peak) if subpixel_accuracy => {
+                                    let (x, y) = subpixel_peak_2d(&image, (peak.0, peak.1));
+                                    (if dimension_mask.0 { x } else { 0.0 }, if dimension_mask.1 { y } else { 0.0 })
+                                }
+                                _ => (0.0, 0.0),
+                            };
 
-                    // Multiply cc by prior
-                    if use_prior {
-                        peaks.iter_mut().for_each(|peak| {
-                            let x = peak.0 as i32;
-                            let y = peak.1 as i32;
-                            peak.2 *= guassian_2d(
-                                x as f32,
-                                y as f32,
-                                0.0,
-                                0.0,
-                                prior_sigmas.0,
-                                prior_sigmas.1,
+                            // Adjust peaks by roi
+                            // find roi center pos
+                            let ref_roi_center = (
+                                ref_roi.x + ref_roi.width / 2,
+                                ref_roi.y + ref_roi.height / 2,
                             );
-                        });
-                    }
 
-                    // Sort by highest R
-                    peaks.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
-
-                    // Adjust peaks by roi
-                    // find roi center pos
-                    let ref_roi_center = (
-                        ref_roi.x + ref_roi.width / 2,
-                        ref_roi.y + ref_roi.height / 2,
-                    );
-
-                    let mov_roi_center = (
-                        mov_roi.x + mov_roi.width / 2,
-                        mov_roi.y + mov_roi.height / 2,
-                    );
+                            let mov_roi_center = (
+                                mov_roi.x + mov_roi.width / 2,
+                                mov_roi.y + mov_roi.height / 2,
+                            );
 
-                    let diff = (
-                        mov_roi_center.0 - ref_roi_center.0,
-                        mov_roi_center.1 - ref_roi_center.1,
-                    );
-                    peaks.iter_mut().for_each(|peak| {
-                        peak.0 += -diff.0;
-                        peak.1 += -diff.1;
-                    });
+                            let diff = (
+                                mov_roi_center.0 - ref_roi_center.0,
+                                mov_roi_center.1 - ref_roi_center.1,
+                            );
+                            peaks.iter_mut().for_each(|peak| {
+                                peak.0 += -diff.0;
+                                peak.1 += -diff.1;
+                            });
+
+                            if let (Some(pair_cache), Some(key)) = (pair_cache, key) {
+                                let cached = CachedPair {
+                                    peaks: peaks.iter().map(|peak| (peak.0, peak.1, 0, peak.2)).collect(),
+                                    subpixel: (subpixel.0, subpixel.1, 0.0),
+                                };
+                                pair_cache.insert(key, cached);
+                            }
+                            (peaks, subpixel)
+                        }
+                    };
 
                     let mut done2 = done.lock().unwrap();
                     *done2 += 1;
                     let first_peak = peaks.first().unwrap_or(&(0, 0, 0.0));
                     
                     println!(
-                        "Progress {}/{}: {} - {} {:?}",
+                        "Progress {}/{}: {} - {} {:?} {:?}",
                         *done2,
                         todo,
                         i,
                         j,
-                        first_peak
+                        first_peak,
+                        subpixel
                     );
 
                     Pair2D {
                         i,
                         j,
-                        offset: (first_peak.0, first_peak.1),
+                        offset: (first_peak.0 as f32 + subpixel.0, first_peak.1 as f32 + subpixel.1),
                         weight: first_peak.2,
-                        valid: peaks.len() > 0 && first_peak.2 > correlation_threshold,
+                        valid: !peaks.is_empty() && first_peak.2 > correlation_threshold,
                     }
                 })
                 .collect::<Vec<_>>()
         })
-        .collect::<Vec<_>>()
+        .collect::<Vec<_>>();
+        if let Some(pair_cache) = pair_cache {
+            pair_cache.save()?;
+            println!("Reused {} of {} pairs from the pair cache", pair_cache.hits() - cached_before, todo);
+        }
+        pairs
     } else {
         println!("No dimension mask, skipping pair generation");
         vec![]
@@ -384,7 +440,7 @@ pub fn stitch(
                 let subgraph = extract_subgraph(&graph, subgraph_indexes);
                 calculate_offsets_from_graph(&subgraph)
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
 
         for (i, subgraph) in subgraphs.iter().enumerate() {
             let (mean_error, max_error, mean_dst, max_dst, worst_pair_index) =
@@ -434,25 +490,22 @@ pub fn stitch(
         subgraphs = find_subgraphs(&graph);
 
         // Iterate through overlap_map
-        overlap_map
-            .iter()
-            .enumerate()
-            .for_each(|(i, overlap_list)| {
-                overlap_list.iter().for_each(|&j| {
+        for (i, overlap_list) in overlap_map.iter().enumerate() {
+            for &j in overlap_list.iter() {
                     // Check if connection crosses a subgraph boundary
                     let graph_i = subgraphs.iter().position(|subgraph| subgraph.contains(&i));
 
                     let graph_j = subgraphs.iter().position(|subgraph| subgraph.contains(&j));
 
-                    if graph_i.is_none() || graph_j.is_none() {
-                        panic!("Invalid subgraph");
-                    }
-
-                    let graph_i = graph_i.unwrap();
-                    let graph_j = graph_j.unwrap();
+                    let (Some(graph_i), Some(graph_j)) = (graph_i, graph_j) else {
+                        return Err(StitchError::Numerical(format!(
+                            "Tiles {} and {} are not part of any subgraph",
+                            i, j
+                        )));
+                    };
 
                     if graph_i == graph_j {
-                        return;
+                        continue;
                     }
 
                     // Calculate relative offset
@@ -468,14 +521,14 @@ pub fn stitch(
                     pairs.push(Pair2D {
                         i,
                         j,
-                        offset: (offset.0 as i64, offset.1 as i64),
+                        offset,
                         weight: 0.1,
                         valid: true,
                     });
 
                     println!("Added prior pair to link {} to {}: {} {} {:?}", graph_i, graph_j, i, j, offset);
-                })
-            });
+            }
+        }
 
         // Recalculate offsets
         let graph = pairs_to_graph(&pairs, images.len());
@@ -486,14 +539,14 @@ pub fn stitch(
                 let subgraph = extract_subgraph(&graph, subgraph_indexes);
                 calculate_offsets_from_graph(&subgraph)
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
     }
 
-    Stitch2DResult {
+    Ok(Stitch2DResult {
         pairs,
         subgraphs,
         offsets,
-    }
+    })
 }
 
 fn check_offsets(
@@ -530,8 +583,8 @@ fn check_offsets(
         let offset_j = offsets[sub_j];
 
         let diff = (
-            offset_j.0 - offset_i.0 - peak.0 as f32,
-            offset_j.1 - offset_i.1 - peak.1 as f32,
+            offset_j.0 - offset_i.0 - peak.0,
+            offset_j.1 - offset_i.1 - peak.1,
         );
 
         let dst = (diff.0.powi(2) + diff.1.powi(2)).sqrt();
@@ -558,10 +611,20 @@ fn check_offsets(
     (mean_error, max_error, mean_dst, max_dst, worst_pair_index)
 }
 
-fn to_complex_with_padding(image: &Image2D, width: usize, height: usize) -> Vec<Complex<f32>> {
+/**
+ * Preprocess the image and center it in a zero-padded complex buffer of `width` x `height`
+ */
+fn to_complex_with_padding(
+    image: &Image2D,
+    width: usize,
+    height: usize,
+    preprocessing: &Preprocessing,
+) -> Vec<Complex<f32>> {
     let mut data = vec![Complex::zero(); width * height];
     let old_width = image.width;
     let old_height = image.height;
+    let filtered = preprocessing.apply(&image.data, (old_width, old_height, 1));
+    let source = filtered.as_deref().unwrap_or(&image.data);
 
     let start_x = (width - old_width) / 2;
     let start_y = (height - old_height) / 2;
@@ -572,7 +635,7 @@ fn to_complex_with_padding(image: &Image2D, width: usize, height: usize) -> Vec<
         for x in start_x..end_x {
             let src_x = x - start_x;
             let src_y = y - start_y;
-            let val = image.get(src_x, src_y);
+            let val = source[src_y * old_width + src_x];
             if val.is_finite() {
                 data[x + y * width].re = val;
             }
@@ -693,7 +756,7 @@ fn extract_image_with_roi(image: &Image2D, roi: &IBox2D) -> Image2D {
     let mut new_image = Image2D {
         width,
         height,
-        data: vec![0.0; (width * height) as usize],
+        data: vec![0.0; width * height],
         min: image.min,
         max: image.max,
     };
@@ -710,14 +773,14 @@ fn extract_image_with_roi(image: &Image2D, roi: &IBox2D) -> Image2D {
     new_image
 }
 
-fn calculate_offsets_from_graph(graph: &StitchGraph2D) -> Vec<(f32, f32)> {
+fn calculate_offsets_from_graph(graph: &StitchGraph2D) -> Result<Vec<(f32, f32)>> {
     let num_nodes = graph.num_nodes;
     if num_nodes == 0 {
-        panic!("Empty graph");
+        return Err(StitchError::Numerical("Empty graph".to_string()));
     }
 
     if num_nodes == 1 {
-        return vec![(0.0, 0.0)];
+        return Ok(vec![(0.0, 0.0)]);
     }
 
     let n = num_nodes - 1;
@@ -756,6 +819,12 @@ fn calculate_offsets_from_graph(graph: &StitchGraph2D) -> Vec<(f32, f32)> {
     let mut x_vec_y = solve_linear_system(&laplacian, &y_vec_y, n);
     x_vec_y.push(0.0);
 
+    if x_vec_x.iter().chain(x_vec_y.iter()).any(|x| !x.is_finite()) {
+        return Err(StitchError::Numerical(
+            "Global optimization produced non-finite offsets".to_string(),
+        ));
+    }
+
     // Find minimum
     let mut min_x = f32::INFINITY;
     let mut min_y = f32::INFINITY;
@@ -781,7 +850,7 @@ fn calculate_offsets_from_graph(graph: &StitchGraph2D) -> Vec<(f32, f32)> {
         .map(|(x, y)| (*x, *y))
         .collect::<Vec<_>>();
 
-    return zipped;
+    Ok(zipped)
 }
 
 fn extract_subgraph(graph: &StitchGraph2D, subgraph_indexes: &[usize]) -> StitchGraph2D {
@@ -822,10 +891,10 @@ fn pairs_to_graph(pairs: &[Pair2D], num_images: usize) -> StitchGraph2D {
             let ji_index = j * num_images + i;
 
             let peak = &pair.offset;
-            shift_x[ij_index] = peak.0 as f32;
-            shift_y[ij_index] = peak.1 as f32;
-            shift_x[ji_index] = -peak.0 as f32;
-            shift_y[ji_index] = -peak.1 as f32;
+            shift_x[ij_index] = peak.0;
+            shift_y[ij_index] = peak.1;
+            shift_x[ji_index] = -peak.0;
+            shift_y[ji_index] = -peak.1;
 
             adjacency_matrix[ij_index] = 1.0;
             adjacency_matrix[ji_index] = 1.0;
@@ -874,7 +943,7 @@ fn find_subgraphs(graph: &StitchGraph2D) -> Vec<Vec<usize>> {
     }
 
     // Sort subgraphs by size
-    subgraphs.sort_by(|a, b| b.len().cmp(&a.len()));
+    subgraphs.sort_by_key(|b| std::cmp::Reverse(b.len()));
 
     subgraphs
 }
@@ -902,9 +971,7 @@ fn solve_linear_system(a: &[f32], b: &[f32], size: usize) -> Vec<f32> {
 
         // Swap rows
         for j in i..(size + 1) {
-            let temp = augmented_matrix[i * (size + 1) + j];
-            augmented_matrix[i * (size + 1) + j] = augmented_matrix[max_row * (size + 1) + j];
-            augmented_matrix[max_row * (size + 1) + j] = temp;
+            augmented_matrix.swap(i * (size + 1) + j, max_row * (size + 1) + j);
         }
 
         // Eliminate
@@ -961,13 +1028,13 @@ fn create_overlap_map(images: &[Image2D], layout: &[IBox2D], overlap_ratio: (f32
         let mut list_with_dist = list.iter().map(|&x| {
             let other_layout = &layout[x];
             let other_box = IBox2D::from_image(&images[x]);
-            let (ref_roi, mov_roi) = get_intersection(&reference_box, &reference_layout, &other_box, &other_layout, overlap_ratio);
+            let (ref_roi, mov_roi) = get_intersection(&reference_box, reference_layout, &other_box, other_layout, overlap_ratio);
             let overlap_pixel_count = (ref_roi.width * ref_roi.height).max(mov_roi.width * mov_roi.height);
-            return (x, overlap_pixel_count);
+            (x, overlap_pixel_count)
         }).collect::<Vec<_>>();
 
         // Sort by overlap amount, highest first
-        list_with_dist.sort_by(|a, b| b.1.cmp(&a.1));
+        list_with_dist.sort_by_key(|b| std::cmp::Reverse(b.1));
 
         // Remove occluded images
         let reference_center = reference_layout.get_center();
@@ -1067,11 +1134,11 @@ fn find_peaks_2d(image: &Image2D, check_peaks: usize) -> Vec<(i64, i64, f32)> {
     let half_h = h as i64 / 2;
     peaks.iter_mut().for_each(|peak| {
         if peak.0 >= half_w {
-            peak.0 = peak.0 - w as i64;
+            peak.0 -= w as i64;
         }
 
         if peak.1 >= half_h {
-            peak.1 = peak.1 - h as i64;
+            peak.1 -= h as i64;
         }
     });
 
@@ -1102,7 +1169,34 @@ fn is_local_maxima_2d(image: &Image2D, pos: (usize, usize)) -> bool {
         }
     }
 
-    return true;
+    true
+}
+
+/**
+ * Sub-pixel position of the correlation peak at `shift`, relative to it, from a parabola through
+ * the peak and its neighbours along each axis. Positions wrap around the correlation image.
+ */
+fn subpixel_peak_2d(image: &Image2D, shift: (i64, i64)) -> (f32, f32) {
+    let (width, height) = (image.width as i64, image.height as i64);
+    let value = |x: i64, y: i64| image.data[(y.rem_euclid(height) * width + x.rem_euclid(width)) as usize];
+    let (x, y) = shift;
+    (
+        parabola_vertex(value(x - 1, y), value(x, y), value(x + 1, y)),
+        parabola_vertex(value(x, y - 1), value(x, y), value(x, y + 1)),
+    )
+}
+
+/**
+ * Position of the vertex of the parabola through `(-1, left)`, `(0, center)` and `(1, right)`,
+ * clamped to half a pixel, or 0 if the three values do not form a maximum
+ */
+pub(crate) fn parabola_vertex(left: f32, center: f32, right: f32) -> f32 {
+    let curvature = left - 2.0 * center + right;
+    if !curvature.is_finite() || curvature >= 0.0 {
+        return 0.0;
+    }
+
+    (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
 }
 
 fn disambiguate_2d(width: usize, height: usize, shift: (i64, i64, f32)) -> Vec<(i64, i64, f32)> {
@@ -1121,7 +1215,7 @@ fn disambiguate_2d(width: usize, height: usize, shift: (i64, i64, f32)) -> Vec<(
         points[0].1 - height as i64
     };
 
-    points[3] = (points[1].0, points[2].0, shift.2);
+    points[3] = (points[1].0, points[2].1, shift.2);
 
     points
 }
@@ -1218,5 +1312,47 @@ fn test_cross_2d(
 
     let r = covar / (std1 * std2);
 
-    return (r as f32, ssq as f32, count);
+    (r as f32, ssq as f32, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parabola_vertex_is_recovered_and_clamped() {
+        let parabola = |t: f32| 2.0 - (t - 0.3) * (t - 0.3);
+        assert!((parabola_vertex(parabola(-1.0), parabola(0.0), parabola(1.0)) - 0.3).abs() < 1e-5);
+
+        // Rising to the right: the vertex lies beyond the next pixel
+        assert_eq!(parabola_vertex(-1.0, 0.0, 0.5), 0.5);
+        assert_eq!(parabola_vertex(0.5, 0.0, -1.0), -0.5);
+        // Not a maximum
+        assert_eq!(parabola_vertex(1.0, 0.0, 1.0), 0.0);
+        assert_eq!(parabola_vertex(f32::NAN, 1.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn subpixel_peak_2d_wraps_around() {
+        let mut image = Image2D::new(8, 6, 0.0, 1.0);
+        let peak = (-0.25, 0.4);
+        for y in 0..6 {
+            for x in 0..8 {
+                // Distance to the peak at the origin, wrapped around the image
+                let dx = if x < 4 { x as f32 } else { x as f32 - 8.0 } - peak.0;
+                let dy = if y < 3 { y as f32 } else { y as f32 - 6.0 } - peak.1;
+                image.data[y * 8 + x] = 10.0 - dx * dx - dy * dy;
+            }
+        }
+
+        let (x, y) = subpixel_peak_2d(&image, (0, 0));
+        assert!((x - peak.0).abs() < 1e-5 && (y - peak.1).abs() < 1e-5);
+        assert_eq!(subpixel_peak_2d(&image, (-1, 0)).0, 0.5);
+    }
+
+    #[test]
+    fn disambiguation_wraps_both_axes() {
+        let points = disambiguate_2d(10, 8, (3, -2, 0.5));
+        assert_eq!(points, vec![(3, -2, 0.5), (-7, -2, 0.5), (3, 6, 0.5), (-7, 6, 0.5)]);
+    }
 }
@@ -1,12 +1,19 @@
 use rayon::prelude::*;
 use rustfft::{num_complex::Complex, num_traits::Zero, FftNum, FftPlanner};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use transpose::transpose;
 
+use crate::error::{Result, StitchError};
 use crate::image::{Image3D, Image3DFile};
+use crate::pair_cache::{file_fingerprint, CachedPair, PairKey, PairCache};
+use crate::pair_scheduler::{peak_concurrency, tile_bytes, MemoryPool, TileCache};
+use crate::preprocess::Preprocessing;
+use crate::stitch2d::parabola_vertex;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct IBox3D {
     pub x: i64,
     pub y: i64,
@@ -103,16 +110,16 @@ pub struct StitchGraph3D {
     pub num_nodes: usize,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Pair3D {
     pub i: usize,
     pub j: usize,
-    pub offset: (i64, i64, i64),
+    pub offset: (f32, f32, f32),
     pub weight: f32,
     pub valid: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Stitch3DResult {
     pub pairs: Vec<Pair3D>,
     pub subgraphs: Vec<Vec<usize>>,
@@ -130,17 +137,16 @@ pub fn guassian_3d(
     s1: f32,
     s2: f32,
 ) -> f32 {
-    let x = x as f32 - x0;
-    let y = y as f32 - y0;
-    let z = z as f32 - z0;
+    let x = x - x0;
+    let y = y - y0;
+    let z = z - z0;
     let x = x * x;
     let y = y * y;
     let z = z * z;
     let s0 = s0 * s0;
     let s1 = s1 * s1;
     let s2 = s2 * s2;
-    let res = (-0.5 * (x / s0 + y / s1 + z / s2)).exp();
-    res
+    (-0.5 * (x / s0 + y / s1 + z / s2)).exp()
 }
 
 pub fn stitch(
@@ -156,7 +162,12 @@ pub fn stitch(
     use_prior: bool,
     prior_sigmas: (f32, f32, f32),
     merge_subgraphs: bool,
-) -> Stitch3DResult {
+    subpixel_accuracy: bool,
+    preprocessing: &Preprocessing,
+    registration_downsampling: Option<(usize, usize, usize)>,
+    memory_budget: Option<usize>,
+    pair_cache: Option<&PairCache>,
+) -> Result<Stitch3DResult> {
     let mut overlap_map = create_overlap_map(images, layout, overlap_ratio);
     println!("Overlap map: {:?}", overlap_map);
     overlap_map
@@ -168,307 +179,297 @@ pub fn stitch(
 
     println!("Computing shifts");
 
-    let todo: usize = overlap_map.iter().map(|x| x.len()).sum();
+    let pair_list = overlap_map
+        .iter()
+        .enumerate()
+        .flat_map(|(i, overlap_list)| overlap_list.iter().map(move |&j| (i, j)))
+        .collect::<Vec<_>>();
+    let todo = pair_list.len();
     let done = Mutex::new(0);
     let mut pairs: Vec<Pair3D> = if dimension_mask.0 || dimension_mask.1 || dimension_mask.2 {
-        overlap_map
+        let pair_bytes = pair_list
             .iter()
-            .enumerate()
-            .flat_map(|(i, overlap_list)| {
-                let image_ref_file = &images[i];
-                let image_ref = image_ref_file.get_image();
-
-                overlap_list
-                    .iter()
-                    .map(|&j| {
-                        let layout_ref = &layout[i];
-                        let layout_move = &layout[j];
-                        let image_move = images[j].get_image();
-
-                        println!("Processing {} - {}", i, j);
-
-                        let start = std::time::Instant::now();
-
-                        let ref_box = IBox3D::from_image(&image_ref);
-                        let move_box = IBox3D::from_image(&image_move);
-
-                        let (ref_roi, mov_roi) = get_intersection(
-                            &ref_box,
-                            layout_ref,
-                            &move_box,
-                            layout_move,
-                            overlap_ratio,
-                        );
-
-                        let ref_img = extract_image_with_roi(&image_ref, &ref_roi);
-                        let mov_img = extract_image_with_roi(&image_move, &mov_roi);
-
-                        drop(image_move);
-
-                        let max_size = (
-                            ref_roi.width.max(mov_roi.width) as usize,
-                            ref_roi.height.max(mov_roi.height) as usize,
-                            ref_roi.depth.max(mov_roi.depth) as usize,
-                        );
-
-                        if max_size.0 * max_size.1 * max_size.2 == 0 {
-                            println!("No overlap");
-                            return Pair3D {
-                                i,
-                                j,
-                                offset: (0, 0, 0),
-                                weight: 0.0,
-                                valid: false,
-                            };
-                        }
+            .map(|&(i, j)| pair_memory_3d(images, layout, i, j, overlap_ratio, registration_downsampling))
+            .collect::<Vec<_>>();
+        let largest_tile = images.iter().map(tile_bytes).max().unwrap_or(0);
+        let all_tiles = images.iter().map(tile_bytes).sum::<usize>();
+
+        // Without a budget pairs are registered one at a time next to the two tiles they need.
+        // With one, half of it caches tiles and the rest is shared by the running pairs, keeping
+        // room for the largest pair so that the budget is never exceeded.
+        let (workers, cache_bytes, pool_bytes) = match memory_budget {
+            None => (1, 2 * largest_tile, usize::MAX),
+            Some(budget) => {
+                let largest_pair = pair_bytes.iter().copied().max().unwrap_or(0);
+                let minimum = 2 * largest_tile + largest_pair;
+                if budget < minimum {
+                    return Err(StitchError::config(
+                        "registration_memory_gb",
+                        format!(
+                            "Budget of {:.2} GB is below the {:.2} GB needed for two tiles and the largest pair",
+                            budget as f64 / 1e9,
+                            minimum as f64 / 1e9
+                        ),
+                    ));
+                }
+                let cache_bytes = (budget / 2).max(2 * largest_tile).min(budget - largest_pair).min(all_tiles);
+                (rayon::current_num_threads(), cache_bytes, budget - cache_bytes)
+            }
+        };
+        let (concurrent, concurrent_bytes) = peak_concurrency(&pair_bytes, pool_bytes, workers);
+        let peak = cache_bytes + concurrent_bytes;
+        println!(
+            "Estimated peak memory: {:.2} GB ({:.2} GB of tiles, {} pairs at once using {:.2} GB)",
+            peak as f64 / 1e9,
+            cache_bytes as f64 / 1e9,
+            concurrent,
+            concurrent_bytes as f64 / 1e9
+        );
+        let cached_before = pair_cache.map_or(0, |pair_cache| pair_cache.hits());
+        let fingerprints = match pair_cache {
+            Some(_) => images.par_iter().map(file_fingerprint).collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+        let tiles = TileCache::new(images, cache_bytes);
+        let pool = MemoryPool::new(pool_bytes);
+        let register_pair = |i: usize, j: usize| -> Result<Pair3D> {
+            let layout_ref = &layout[i];
+            let layout_move = &layout[j];
+
+            println!("Processing {} - {}", i, j);
+
+            let start = std::time::Instant::now();
+
+            let ref_box = IBox3D::from_image_file(&images[i]);
+            let move_box = IBox3D::from_image_file(&images[j]);
+
+            let (ref_roi, mov_roi) = get_intersection(
+                &ref_box,
+                layout_ref,
+                &move_box,
+                layout_move,
+                overlap_ratio,
+            );
 
-                        println!("Intersection took {:?}", start.elapsed());
-
-                        // if i == 0 && j == 5 {
-                        //     let file_path = "ref.dcm";
-                        //     save_as_dcm(Path::new(file_path), &ref_img);
-                        //     let file_path = "mov.dcm";
-                        //     save_as_dcm(Path::new(file_path), &mov_img);
-                        // }
-
-                        let start = std::time::Instant::now();
-
-                        let mut ref_fft =
-                            to_complex_with_padding(&ref_img, max_size.0, max_size.1, max_size.2);
-                        let mut mov_fft =
-                            to_complex_with_padding(&mov_img, max_size.0, max_size.1, max_size.2);
-
-                        println!("Padding took {:?}", start.elapsed());
-
-                        let start = std::time::Instant::now();
-
-                        fft_3d_par(
-                            max_size.0,
-                            max_size.1,
-                            max_size.2,
-                            &mut ref_fft,
-                            rustfft::FftDirection::Forward,
-                        );
-
-                        fft_3d_par(
-                            max_size.0,
-                            max_size.1,
-                            max_size.2,
-                            &mut mov_fft,
-                            rustfft::FftDirection::Forward,
-                        );
-
-                        println!("FFT took {:?}", start.elapsed());
-
-                        let start = std::time::Instant::now();
-
-                        let mut phase_corr = ref_fft
-                            .par_iter()
-                            .zip(mov_fft.par_iter())
-                            .map(|(a, b)| {
-                                let res = a * b.conj();
-                                if !use_phase_correlation {
-                                    return res;
-                                }
+            let max_size = (
+                ref_roi.width.max(mov_roi.width) as usize,
+                ref_roi.height.max(mov_roi.height) as usize,
+                ref_roi.depth.max(mov_roi.depth) as usize,
+            );
+
+            if max_size.0 * max_size.1 * max_size.2 == 0 {
+                println!("No overlap");
+                return Ok(Pair3D {
+                    i,
+                    j,
+                    offset: (0.0, 0.0, 0.0),
+                    weight: 0.0,
+                    valid: false,
+                });
+            }
 
-                                let norm = res.norm();
-                                if norm > f32::EPSILON {
-                                    res / norm
-                                } else {
-                                    Complex::zero()
+            // Candidate peaks only depend on the tiles, their overlap and these settings
+            let key = pair_cache.map(|_| {
+                PairKey::new(fingerprints[i], fingerprints[j])
+                    .ints(&[ref_roi.x, ref_roi.y, ref_roi.z, ref_roi.width, ref_roi.height, ref_roi.depth])
+                    .ints(&[mov_roi.x, mov_roi.y, mov_roi.z, mov_roi.width, mov_roi.height, mov_roi.depth])
+                    .ints(&[check_peaks as i64])
+                    .flags(&[dimension_mask.0, dimension_mask.1, dimension_mask.2])
+                    .flags(&[use_phase_correlation, use_prior, subpixel_accuracy])
+                    .floats(&[prior_sigmas.0, prior_sigmas.1, prior_sigmas.2])
+                    .preprocessing(preprocessing)
+                    .flags(&[registration_downsampling.is_some()])
+                    .ints(&registration_downsampling.map_or(vec![], |factors| {
+                        vec![factors.0 as i64, factors.1 as i64, factors.2 as i64]
+                    }))
+                    .finish()
+            });
+            let cached = pair_cache.zip(key.as_deref()).and_then(|(pair_cache, key)| pair_cache.get(key));
+            let (peaks, subpixel) = match cached {
+                Some(cached) => (cached.peaks, cached.subpixel),
+                None => {
+                    let (image_ref, image_move) = tiles.get_pair(i, j)?;
+                    let ref_img = extract_image_with_roi(&image_ref, &ref_roi);
+                    let mov_img = extract_image_with_roi(&image_move, &mov_roi);
+
+                    drop(image_ref);
+                    drop(image_move);
+
+                    println!("Intersection took {:?}", start.elapsed());
+
+                    // if i == 0 && j == 5 {
+                    //     let file_path = "ref.dcm";
+                    //     save_as_dcm(Path::new(file_path), &ref_img);
+                    //     let file_path = "mov.dcm";
+                    //     save_as_dcm(Path::new(file_path), &mov_img);
+                    // }
+
+                    let (mut peaks, subpixel) = match registration_downsampling {
+                        None => {
+                            let (peaks, image) = correlation_peaks_3d(
+                                &ref_img,
+                                &mov_img,
+                                check_peaks,
+                                dimension_mask,
+                                use_phase_correlation,
+                                use_prior,
+                                prior_sigmas,
+                                preprocessing,
+                            );
+
+                            // Refine the best peak on the correlation surface, which wraps
+                            // around like the disambiguated shifts
+                            let subpixel = match peaks.first() {
+                                Some(peak) if subpixel_accuracy => {
+                                    let (x, y, z) = subpixel_peak_3d(&image, (peak.0, peak.1, peak.2));
+                                    (
+                                        if dimension_mask.0 { x } else { 0.0 },
+                                        if dimension_mask.1 { y } else { 0.0 },
+                                        if dimension_mask.2 { z } else { 0.0 },
+                                    )
                                 }
-                            })
-                            .collect::<Vec<_>>();
-
-                        fft_3d_par(
-                            max_size.2,
-                            max_size.1,
-                            max_size.0,
-                            &mut phase_corr,
-                            rustfft::FftDirection::Inverse,
-                        );
-
-                        println!("Phase correlation took {:?}", start.elapsed());
-
-                        drop(ref_fft);
-                        drop(mov_fft);
-
-                        let mut image = Image3D {
-                            width: max_size.0,
-                            height: max_size.1,
-                            depth: max_size.2,
-                            min: 0.0,
-                            max: 0.0,
-                            data: phase_corr.par_iter().map(|x| x.norm()).collect::<Vec<_>>(),
-                        };
-
-                        drop(phase_corr);
-
-                        // Compute prior and apply
-                        if use_prior {
-                            let half_w = max_size.0 as i32 / 2;
-                            let half_h = max_size.1 as i32 / 2;
-                            let half_d = max_size.2 as i32 / 2;
-                            image.data.iter_mut().enumerate().for_each(|(i, val)| {
-                                let x = i as i32 % max_size.0 as i32;
-                                let y = (i as i32 / max_size.0 as i32) % max_size.1 as i32;
-                                let z = i as i32 / (max_size.0 * max_size.1) as i32;
-
-                                let x = if x >= half_w {
-                                    x - max_size.0 as i32
-                                } else {
-                                    x
-                                };
-
-                                let y = if y >= half_h {
-                                    y - max_size.1 as i32
-                                } else {
-                                    y
-                                };
-
-                                let z = if z >= half_d {
-                                    z - max_size.2 as i32
-                                } else {
-                                    z
-                                };
-
-                                *val *= guassian_3d(
-                                    x as f32,
-                                    y as f32,
-                                    z as f32,
-                                    0.0,
-                                    0.0,
-                                    0.0,
-                                    prior_sigmas.0,
-                                    prior_sigmas.1,
-                                    prior_sigmas.2,
-                                );
-                            });
+                                _ => (0.0, 0.0, 0.0),
+                            };
+                            (peaks, subpixel)
                         }
+                        Some(factors) => {
+                            // Find the best shifts on downsampled overlaps, then climb from each
+                            // to the full resolution maximum within one coarse pixel
+                            let (coarse_peaks, _) = correlation_peaks_3d(
+                                &downsample_3d(&ref_img, factors),
+                                &downsample_3d(&mov_img, factors),
+                                check_peaks,
+                                dimension_mask,
+                                use_phase_correlation,
+                                use_prior,
+                                (
+                                    prior_sigmas.0 / factors.0 as f32,
+                                    prior_sigmas.1 / factors.1 as f32,
+                                    prior_sigmas.2 / factors.2 as f32,
+                                ),
+                                preprocessing,
+                            );
+
+                            let start = std::time::Instant::now();
+                            let (peaks, subpixel) = refine_coarse_peaks_3d(
+                                &ref_img,
+                                &mov_img,
+                                &coarse_peaks[..coarse_peaks.len().min(check_peaks)],
+                                factors,
+                                dimension_mask,
+                                use_prior.then_some(prior_sigmas),
+                            );
+                            println!("Full resolution refinement took {:?}", start.elapsed());
+                            (peaks, if subpixel_accuracy { subpixel } else { (0.0, 0.0, 0.0) })
+                        }
+                    };
 
-                        let start = std::time::Instant::now();
-
-                        let mut peaks = find_peaks_3d(&image, check_peaks)
-                            .iter()
-                            .flat_map(|peak| disambiguate_3d(max_size.0, max_size.1, max_size.2, *peak))
-                            .collect::<Vec<_>>();
-
-                        // Filter peaks
-                        let ratio = 0.75;
-                        let max_shift = (
-                            (max_size.0 as f32 * ratio) as i64,
-                            (max_size.0 as f32 * ratio) as i64,
-                            (max_size.0 as f32 * ratio) as i64,
-                        );
-
-                        peaks.retain(|peak| {
-                            peak.0 >= -max_shift.0
-                                && peak.0 <= max_shift.0
-                                && peak.1 >= -max_shift.1
-                                && peak.1 <= max_shift.1
-                                && peak.2 >= -max_shift.2
-                                && peak.2 <= max_shift.2
-                        });
-
-                        // Mask peaks
-                        peaks.iter_mut().for_each(|peak| {
-                            if !dimension_mask.0 {
-                                peak.0 = 0;
-                            }
+                    drop(ref_img);
+                    drop(mov_img);
 
-                            if !dimension_mask.1 {
-                                peak.1 = 0;
-                            }
+                    // Adjust peaks by roi
+                    // find roi center pos
+                    let ref_roi_center = (
+                        ref_roi.x, // + ref_roi.width / 2,
+                        ref_roi.y, //+ ref_roi.height / 2,
+                        ref_roi.z //+ ref_roi.depth / 2,
+                    );
 
-                            if !dimension_mask.2 {
-                                peak.2 = 0;
-                            }
-                        });
-
-                        // Test peaks
-                        peaks.par_iter_mut().for_each(|peak| {
-                            let res = test_cross_3d(&ref_img, &mov_img, (peak.0, peak.1, peak.2), 0.01);
-                            peak.3 = res.0;
-                        });
-
-                        drop(ref_img);
-                        drop(mov_img);
-
-                        // Multiply cc by prior
-                        if use_prior {
-                            peaks.iter_mut().for_each(|peak| {
-                                let x = peak.0 as i32;
-                                let y = peak.1 as i32;
-                                let z = peak.2 as i32;
-                                peak.3 *= guassian_3d(
-                                    x as f32,
-                                    y as f32,
-                                    z as f32,
-                                    0.0,
-                                    0.0,
-                                    0.0,
-                                    prior_sigmas.0,
-                                    prior_sigmas.1,
-                                    prior_sigmas.2,
-                                );
-                            });
-                        }
+                    let mov_roi_center = (
+                        mov_roi.x, //+ mov_roi.width / 2,
+                        mov_roi.y, //+ mov_roi.height / 2,
+                        mov_roi.z //+ mov_roi.depth / 2,
+                    );
+
+                    let diff = (
+                        mov_roi_center.0 - ref_roi_center.0,
+                        mov_roi_center.1 - ref_roi_center.1,
+                        mov_roi_center.2 - ref_roi_center.2,
+                    );
+                    peaks.iter_mut().for_each(|peak| {
+                        peak.0 += -diff.0;
+                        peak.1 += -diff.1;
+                        peak.2 += -diff.2;
+                    });
+
+                    if let (Some(pair_cache), Some(key)) = (pair_cache, key) {
+                        pair_cache.insert(key, CachedPair { peaks: peaks.clone(), subpixel });
+                    }
+                    (peaks, subpixel)
+                }
+            };
+
+            let mut done2 = done.lock().unwrap();
+            *done2 += 1;
+            let first_peak = peaks.first().unwrap_or(&(0, 0, 0, 0.0));
+
+            println!(
+                "Progress {}/{}: {} - {} {:?} {:?}",
+                *done2,
+                todo,
+                i,
+                j,
+                first_peak,
+                subpixel
+            );
 
-                        // Sort by highest R
-                        peaks.sort_by(|a, b| b.3.partial_cmp(&a.3).unwrap());
-
-                        // Adjust peaks by roi
-                        // find roi center pos
-                        let ref_roi_center = (
-                            ref_roi.x, // + ref_roi.width / 2,
-                            ref_roi.y, //+ ref_roi.height / 2,
-                            ref_roi.z //+ ref_roi.depth / 2,
-                        );
-
-                        let mov_roi_center = (
-                            mov_roi.x, //+ mov_roi.width / 2,
-                            mov_roi.y, //+ mov_roi.height / 2,
-                            mov_roi.z //+ mov_roi.depth / 2,
-                        );
-
-                        let diff = (
-                            mov_roi_center.0 - ref_roi_center.0,
-                            mov_roi_center.1 - ref_roi_center.1,
-                            mov_roi_center.2 - ref_roi_center.2,
-                        );
-                        peaks.iter_mut().for_each(|peak| {
-                            peak.0 += -diff.0;
-                            peak.1 += -diff.1;
-                            peak.2 += -diff.2;
-                        });
-
-                        println!("Peak finding took {:?}", start.elapsed());
-
-                        let mut done2 = done.lock().unwrap();
-                        *done2 += 1;
-                        let first_peak = peaks.first().unwrap_or(&(0, 0, 0, 0.0));
-
-                        println!(
-                            "Progress {}/{}: {} - {} {:?}",
-                            *done2,
-                            todo,
-                            i,
-                            j,
-                            first_peak
-                        );
-
-                    
-                        Pair3D {
-                            i,
-                            j,
-                            offset: (first_peak.0, first_peak.1, first_peak.2),
-                            weight: first_peak.3,
-                            valid: peaks.len() > 0 && first_peak.3 > correlation_threshold,
+
+            Ok(Pair3D {
+                i,
+                j,
+                offset: (
+                    first_peak.0 as f32 + subpixel.0,
+                    first_peak.1 as f32 + subpixel.1,
+                    first_peak.2 as f32 + subpixel.2,
+                ),
+                weight: first_peak.3,
+                valid: !peaks.is_empty() && first_peak.3 > correlation_threshold,
+            })
+        };
+
+        // Workers take pairs in order so that consecutive pairs share their reference tile
+        let next = Mutex::new(0);
+        let failed = AtomicBool::new(false);
+        let mut results = std::thread::scope(|scope| {
+            let workers = (0..workers.min(todo).max(1))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = vec![];
+                        while !failed.load(Ordering::Relaxed) {
+                            let index = {
+                                let mut next = next.lock().unwrap();
+                                *next += 1;
+                                *next - 1
+                            };
+                            let Some(&(i, j)) = pair_list.get(index) else {
+                                break;
+                            };
+                            let _reservation = pool.reserve(pair_bytes[index]);
+                            let result = register_pair(i, j);
+                            if result.is_err() {
+                                failed.store(true, Ordering::Relaxed);
+                            }
+                            results.push((index, result));
                         }
+                        results
                     })
-                    .collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        results.sort_by_key(|(index, _)| *index);
+        println!("Read {} tiles from disk for {} pairs", tiles.reads(), todo);
+        if let Some(pair_cache) = pair_cache {
+            pair_cache.save()?;
+            println!("Reused {} of {} pairs from the pair cache", pair_cache.hits() - cached_before, todo);
+        }
+        results
+            .into_iter()
+            .map(|(_, result)| result)
+            .collect::<Result<Vec<_>>>()?
     } else {
         println!("Dimension mask is not set, skipping pair generation");
         vec![]
@@ -488,7 +489,7 @@ pub fn stitch(
                 let subgraph = extract_subgraph(&graph, subgraph_indexes);
                 calculate_offsets_from_graph(&subgraph)
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
 
         for (i, subgraph) in subgraphs.iter().enumerate() {
             let (mean_error, max_error, mean_dst, max_dst, worst_pair_index) =
@@ -533,25 +534,22 @@ pub fn stitch(
         subgraphs = find_subgraphs(&graph);
 
         // Iterate through overlap_map
-        overlap_map
-            .iter()
-            .enumerate()
-            .for_each(|(i, overlap_list)| {
-                overlap_list.iter().for_each(|&j| {
+        for (i, overlap_list) in overlap_map.iter().enumerate() {
+            for &j in overlap_list.iter() {
                     // Check if connection crosses a subgraph boundary
                     let graph_i = subgraphs.iter().position(|subgraph| subgraph.contains(&i));
 
                     let graph_j = subgraphs.iter().position(|subgraph| subgraph.contains(&j));
 
-                    if graph_i.is_none() || graph_j.is_none() {
-                        panic!("Invalid subgraph");
-                    }
-
-                    let graph_i = graph_i.unwrap();
-                    let graph_j = graph_j.unwrap();
+                    let (Some(graph_i), Some(graph_j)) = (graph_i, graph_j) else {
+                        return Err(StitchError::Numerical(format!(
+                            "Tiles {} and {} are not part of any subgraph",
+                            i, j
+                        )));
+                    };
 
                     if graph_i == graph_j {
-                        return;
+                        continue;
                     }
 
                     // Calculate relative offset
@@ -568,14 +566,14 @@ pub fn stitch(
                     pairs.push(Pair3D {
                         i,
                         j,
-                        offset: (offset.0 as i64, offset.1 as i64, offset.2 as i64),
+                        offset,
                         weight: 0.1,
                         valid: true,
                     });
 
                     println!("Added prior pair to link {} to {}: {} {} {:?}", graph_i, graph_j, i, j, offset);
-                })
-            });
+            }
+        }
 
         // Recalculate offsets
         let graph = pairs_to_graph(&pairs, images.len());
@@ -586,14 +584,14 @@ pub fn stitch(
                 let subgraph = extract_subgraph(&graph, subgraph_indexes);
                 calculate_offsets_from_graph(&subgraph)
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
     }
 
-    Stitch3DResult {
+    Ok(Stitch3DResult {
         pairs,
         subgraphs,
         offsets,
-    }
+    })
 }
 
 fn check_offsets(
@@ -630,9 +628,9 @@ fn check_offsets(
         let offset_j = offsets[sub_j];
 
         let diff = (
-            offset_j.0 - offset_i.0 - peak.0 as f32,
-            offset_j.1 - offset_i.1 - peak.1 as f32,
-            offset_j.2 - offset_i.2 - peak.2 as f32,
+            offset_j.0 - offset_i.0 - peak.0,
+            offset_j.1 - offset_i.1 - peak.1,
+            offset_j.2 - offset_i.2 - peak.2,
         );
 
         //println!("Diff: {:?} I: {:?} J: {:?} O: {:?}", diff, offset_i, offset_j, peak);
@@ -660,16 +658,22 @@ fn check_offsets(
     (mean_error, max_error, mean_dst, max_dst, worst_pair_index)
 }
 
+/**
+ * Preprocess the image and center it in a zero-padded complex buffer of `width` x `height` x `depth`
+ */
 fn to_complex_with_padding(
     image: &Image3D,
     width: usize,
     height: usize,
     depth: usize,
+    preprocessing: &Preprocessing,
 ) -> Vec<Complex<f32>> {
     let mut data = vec![Complex::zero(); width * height * depth];
     let old_width = image.width;
     let old_height = image.height;
     let old_depth = image.depth;
+    let filtered = preprocessing.apply(&image.data, (old_width, old_height, old_depth));
+    let source = filtered.as_deref().unwrap_or(&image.data);
 
     let start_x = (width - old_width) / 2;
     let start_y = (height - old_height) / 2;
@@ -684,7 +688,7 @@ fn to_complex_with_padding(
             let src_y = y - start_y;
             for x in start_x..end_x {
                 let src_x = x - start_x;
-                let val = image.get(src_x, src_y, src_z);
+                let val = source[(src_z * old_height + src_y) * old_width + src_x];
                 if val.is_finite() {
                     data[x + y * width + z * width * height].re = val;
                 }
@@ -852,7 +856,7 @@ fn extract_image_with_roi(image: &Image3D, roi: &IBox3D) -> Image3D {
         depth,
         min: image.min,
         max: image.max,
-        data: vec![0.0; (width * height * depth) as usize],
+        data: vec![0.0; width * height * depth],
     };
 
     for z in 0..depth {
@@ -872,14 +876,14 @@ fn extract_image_with_roi(image: &Image3D, roi: &IBox3D) -> Image3D {
     new_image
 }
 
-fn calculate_offsets_from_graph(graph: &StitchGraph3D) -> Vec<(f32, f32, f32)> {
+fn calculate_offsets_from_graph(graph: &StitchGraph3D) -> Result<Vec<(f32, f32, f32)>> {
     let num_nodes = graph.num_nodes;
     if num_nodes == 0 {
-        panic!("Empty graph");
+        return Err(StitchError::Numerical("Empty graph".to_string()));
     }
 
     if num_nodes == 1 {
-        return vec![(0.0, 0.0, 0.0)];
+        return Ok(vec![(0.0, 0.0, 0.0)]);
     }
 
     let n = num_nodes - 1;
@@ -925,6 +929,12 @@ fn calculate_offsets_from_graph(graph: &StitchGraph3D) -> Vec<(f32, f32, f32)> {
     let mut x_vec_z = solve_linear_system(&laplacian, &y_vec_z, n);
     x_vec_z.push(0.0);
 
+    if x_vec_x.iter().chain(x_vec_y.iter()).chain(x_vec_z.iter()).any(|x| !x.is_finite()) {
+        return Err(StitchError::Numerical(
+            "Global optimization produced non-finite offsets".to_string(),
+        ));
+    }
+
     // Find minimum
     let mut min_x = f32::INFINITY;
     let mut min_y = f32::INFINITY;
@@ -956,7 +966,7 @@ fn calculate_offsets_from_graph(graph: &StitchGraph3D) -> Vec<(f32, f32, f32)> {
         .map(|((x, y), z)| (*x, *y, *z))
         .collect::<Vec<_>>();
 
-    return zipped;
+    Ok(zipped)
 }
 
 fn extract_subgraph(graph: &StitchGraph3D, subgraph_indexes: &[usize]) -> StitchGraph3D {
@@ -1001,13 +1011,13 @@ fn pairs_to_graph(pairs: &[Pair3D], num_images: usize) -> StitchGraph3D {
             let ji_index = j * num_images + i;
 
             let peak = &pair.offset;
-            shift_x[ij_index] = peak.0 as f32;
-            shift_y[ij_index] = peak.1 as f32;
-            shift_z[ij_index] = peak.2 as f32;
+            shift_x[ij_index] = peak.0;
+            shift_y[ij_index] = peak.1;
+            shift_z[ij_index] = peak.2;
 
-            shift_x[ji_index] = -peak.0 as f32;
-            shift_y[ji_index] = -peak.1 as f32;
-            shift_z[ji_index] = -peak.2 as f32;
+            shift_x[ji_index] = -peak.0;
+            shift_y[ji_index] = -peak.1;
+            shift_z[ji_index] = -peak.2;
 
             adjacency_matrix[ij_index] = 1.0;
             adjacency_matrix[ji_index] = 1.0;
@@ -1057,7 +1067,7 @@ fn find_subgraphs(graph: &StitchGraph3D) -> Vec<Vec<usize>> {
     }
 
     // Sort subgraphs by size
-    subgraphs.sort_by(|a, b| b.len().cmp(&a.len()));
+    subgraphs.sort_by_key(|b| std::cmp::Reverse(b.len()));
 
     subgraphs
 }
@@ -1085,9 +1095,7 @@ fn solve_linear_system(a: &[f32], b: &[f32], size: usize) -> Vec<f32> {
 
         // Swap rows
         for j in i..(size + 1) {
-            let temp = augmented_matrix[i * (size + 1) + j];
-            augmented_matrix[i * (size + 1) + j] = augmented_matrix[max_row * (size + 1) + j];
-            augmented_matrix[max_row * (size + 1) + j] = temp;
+            augmented_matrix.swap(i * (size + 1) + j, max_row * (size + 1) + j);
         }
 
         // Eliminate
@@ -1144,13 +1152,13 @@ fn create_overlap_map(images: &[Image3DFile], layout: &[IBox3D], overlap_ratio:
         let mut list_with_dist = list.iter().map(|&x| {
             let other_layout = &layout[x];
             let other_box = IBox3D::from_image_file(&images[x]);
-            let (ref_roi, mov_roi) = get_intersection(&reference_box, &reference_layout, &other_box, &other_layout, overlap_ratio);
+            let (ref_roi, mov_roi) = get_intersection(&reference_box, reference_layout, &other_box, other_layout, overlap_ratio);
             let overlap_pixel_count = (ref_roi.width * ref_roi.height * ref_roi.depth).max(mov_roi.width * mov_roi.height * mov_roi.depth);
-            return (x, overlap_pixel_count);
+            (x, overlap_pixel_count)
         }).collect::<Vec<_>>();
 
         // Sort by overlap amount, highest first
-        list_with_dist.sort_by(|a, b| b.1.cmp(&a.1));
+        list_with_dist.sort_by_key(|b| std::cmp::Reverse(b.1));
 
         // Remove occluded images
         let reference_center = reference_layout.get_center();
@@ -1357,15 +1365,15 @@ fn find_peaks_3d(image: &Image3D, check_peaks: usize) -> Vec<(i64, i64, i64, f32
     let half_d = d as i64 / 2;
     peaks.iter_mut().for_each(|peak| {
         if peak.0 >= half_w {
-            peak.0 = peak.0 - w as i64;
+            peak.0 -= w as i64;
         }
 
         if peak.1 >= half_h {
-            peak.1 = peak.1 - h as i64;
+            peak.1 -= h as i64;
         }
 
         if peak.2 >= half_d {
-            peak.2 = peak.2 - d as i64;
+            peak.2 -= d as i64;
         }
     });
 
@@ -1402,7 +1410,423 @@ fn is_local_maxima_3d(image: &Image3D, pos: (usize, usize, usize)) -> bool {
         }
     }
 
-    return true;
+    true
+}
+
+/**
+ * Phase correlate two overlaps and score the shifts of the `check_peaks` highest correlation
+ * peaks, in every disambiguation, by the cross-correlation of the overlaps. Returns the shifts
+ * sorted by score and the correlation image.
+ */
+fn correlation_peaks_3d(
+    ref_img: &Image3D,
+    mov_img: &Image3D,
+    check_peaks: usize,
+    dimension_mask: (bool, bool, bool),
+    use_phase_correlation: bool,
+    use_prior: bool,
+    prior_sigmas: (f32, f32, f32),
+    preprocessing: &Preprocessing,
+) -> (Vec<(i64, i64, i64, f32)>, Image3D) {
+    let max_size = (
+        ref_img.width.max(mov_img.width),
+        ref_img.height.max(mov_img.height),
+        ref_img.depth.max(mov_img.depth),
+    );
+
+    let start = std::time::Instant::now();
+
+    let mut ref_fft =
+        to_complex_with_padding(ref_img, max_size.0, max_size.1, max_size.2, preprocessing);
+    let mut mov_fft =
+        to_complex_with_padding(mov_img, max_size.0, max_size.1, max_size.2, preprocessing);
+
+    println!("Padding took {:?}", start.elapsed());
+
+    let start = std::time::Instant::now();
+
+    fft_3d_par(
+        max_size.0,
+        max_size.1,
+        max_size.2,
+        &mut ref_fft,
+        rustfft::FftDirection::Forward,
+    );
+
+    fft_3d_par(
+        max_size.0,
+        max_size.1,
+        max_size.2,
+        &mut mov_fft,
+        rustfft::FftDirection::Forward,
+    );
+
+    println!("FFT took {:?}", start.elapsed());
+
+    let start = std::time::Instant::now();
+
+    let mut phase_corr = ref_fft
+        .par_iter()
+        .zip(mov_fft.par_iter())
+        .map(|(a, b)| {
+            let res = a * b.conj();
+            if !use_phase_correlation {
+                return res;
+            }
+
+            let norm = res.norm();
+            if norm > f32::EPSILON {
+                res / norm
+            } else {
+                Complex::zero()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    fft_3d_par(
+        max_size.2,
+        max_size.1,
+        max_size.0,
+        &mut phase_corr,
+        rustfft::FftDirection::Inverse,
+    );
+
+    println!("Phase correlation took {:?}", start.elapsed());
+
+    drop(ref_fft);
+    drop(mov_fft);
+
+    let mut image = Image3D {
+        width: max_size.0,
+        height: max_size.1,
+        depth: max_size.2,
+        min: 0.0,
+        max: 0.0,
+        data: phase_corr.par_iter().map(|x| x.norm()).collect::<Vec<_>>(),
+    };
+
+    drop(phase_corr);
+
+    // Compute prior and apply
+    if use_prior {
+        let half_w = max_size.0 as i32 / 2;
+        let half_h = max_size.1 as i32 / 2;
+        let half_d = max_size.2 as i32 / 2;
+        image.data.iter_mut().enumerate().for_each(|(i, val)| {
+            let x = i as i32 % max_size.0 as i32;
+            let y = (i as i32 / max_size.0 as i32) % max_size.1 as i32;
+            let z = i as i32 / (max_size.0 * max_size.1) as i32;
+
+            let x = if x >= half_w {
+                x - max_size.0 as i32
+            } else {
+                x
+            };
+
+            let y = if y >= half_h {
+                y - max_size.1 as i32
+            } else {
+                y
+            };
+
+            let z = if z >= half_d {
+                z - max_size.2 as i32
+            } else {
+                z
+            };
+
+            *val *= guassian_3d(
+                x as f32,
+                y as f32,
+                z as f32,
+                0.0,
+                0.0,
+                0.0,
+                prior_sigmas.0,
+                prior_sigmas.1,
+                prior_sigmas.2,
+            );
+        });
+    }
+
+    let start = std::time::Instant::now();
+
+    let mut peaks = find_peaks_3d(&image, check_peaks)
+        .iter()
+        .flat_map(|peak| disambiguate_3d(max_size.0, max_size.1, max_size.2, *peak))
+        .collect::<Vec<_>>();
+
+    // Filter peaks
+    let ratio = 0.75;
+    let max_shift = (
+        (max_size.0 as f32 * ratio) as i64,
+        (max_size.0 as f32 * ratio) as i64,
+        (max_size.0 as f32 * ratio) as i64,
+    );
+
+    peaks.retain(|peak| {
+        peak.0 >= -max_shift.0
+            && peak.0 <= max_shift.0
+            && peak.1 >= -max_shift.1
+            && peak.1 <= max_shift.1
+            && peak.2 >= -max_shift.2
+            && peak.2 <= max_shift.2
+    });
+
+    // Mask peaks
+    peaks.iter_mut().for_each(|peak| {
+        if !dimension_mask.0 {
+            peak.0 = 0;
+        }
+
+        if !dimension_mask.1 {
+            peak.1 = 0;
+        }
+
+        if !dimension_mask.2 {
+            peak.2 = 0;
+        }
+    });
+
+    // Test peaks
+    peaks.par_iter_mut().for_each(|peak| {
+        let res = test_cross_3d(ref_img, mov_img, (peak.0, peak.1, peak.2), 0.01);
+        peak.3 = res.0;
+    });
+
+    // Multiply cc by prior
+    if use_prior {
+        peaks.iter_mut().for_each(|peak| {
+            let x = peak.0 as i32;
+            let y = peak.1 as i32;
+            let z = peak.2 as i32;
+            peak.3 *= guassian_3d(
+                x as f32,
+                y as f32,
+                z as f32,
+                0.0,
+                0.0,
+                0.0,
+                prior_sigmas.0,
+                prior_sigmas.1,
+                prior_sigmas.2,
+            );
+        });
+    }
+
+    // Sort by highest R
+    peaks.sort_by(|a, b| b.3.total_cmp(&a.3));
+
+    println!("Peak finding took {:?}", start.elapsed());
+    (peaks, image)
+}
+
+/**
+ * Estimated bytes needed to register tiles `i` and `j` besides the tiles themselves: both
+ * overlaps, then for the correlation grid (downsampled if asked) the downsampled and filtered
+ * overlaps, the two transforms, their product and the transform scratch buffer
+ */
+fn pair_memory_3d(
+    images: &[Image3DFile],
+    layout: &[IBox3D],
+    i: usize,
+    j: usize,
+    overlap_ratio: (f32, f32, f32),
+    downsampling: Option<(usize, usize, usize)>,
+) -> usize {
+    let (ref_roi, mov_roi) = get_intersection(
+        &IBox3D::from_image_file(&images[i]),
+        &layout[i],
+        &IBox3D::from_image_file(&images[j]),
+        &layout[j],
+        overlap_ratio,
+    );
+    let voxels = |roi: &IBox3D| (roi.width.max(0) * roi.height.max(0) * roi.depth.max(0)) as usize;
+    let factors = downsampling.unwrap_or((1, 1, 1));
+    let grid = (ref_roi.width.max(mov_roi.width).max(0) as usize).div_ceil(factors.0)
+        * (ref_roi.height.max(mov_roi.height).max(0) as usize).div_ceil(factors.1)
+        * (ref_roi.depth.max(mov_roi.depth).max(0) as usize).div_ceil(factors.2);
+
+    let real = std::mem::size_of::<f32>();
+    let complex = std::mem::size_of::<Complex<f32>>();
+    (voxels(&ref_roi) + voxels(&mov_roi)) * real + grid * (4 * real + 4 * complex)
+}
+
+/**
+ * Average blocks of `factors` voxels, ignoring non-finite values. Blocks at the far edges may be
+ * smaller.
+ */
+fn downsample_3d(image: &Image3D, factors: (usize, usize, usize)) -> Image3D {
+    let width = image.width.div_ceil(factors.0);
+    let height = image.height.div_ceil(factors.1);
+    let depth = image.depth.div_ceil(factors.2);
+    let data = (0..width * height * depth)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y, z) = (i % width * factors.0, i / width % height * factors.1, i / (width * height) * factors.2);
+            let (mut sum, mut count) = (0.0, 0);
+            for src_z in z..(z + factors.2).min(image.depth) {
+                for src_y in y..(y + factors.1).min(image.height) {
+                    for src_x in x..(x + factors.0).min(image.width) {
+                        let value = image.get(src_x, src_y, src_z);
+                        if value.is_finite() {
+                            sum += value;
+                            count += 1;
+                        }
+                    }
+                }
+            }
+            if count > 0 { sum / count as f32 } else { f32::NAN }
+        })
+        .collect();
+
+    Image3D {
+        width,
+        height,
+        depth,
+        data,
+        min: image.min,
+        max: image.max,
+    }
+}
+
+/**
+ * Climb the cross-correlation of two full resolution overlaps from `start` to a local maximum,
+ * one voxel at a time along unmasked axes and at most `radius` from `start`. Returns the shift
+ * with its R and the sub-pixel position of the maximum from a parabola through its neighbours.
+ */
+fn refine_shift_3d(
+    ref_img: &Image3D,
+    mov_img: &Image3D,
+    start: (i64, i64, i64),
+    radius: (usize, usize, usize),
+    dimension_mask: (bool, bool, bool),
+) -> ((i64, i64, i64, f32), (f32, f32, f32)) {
+    let mut scores: HashMap<(i64, i64, i64), f32> = HashMap::new();
+    let mut score = |shifts: Vec<(i64, i64, i64)>| -> Vec<f32> {
+        let missing = shifts
+            .iter()
+            .filter(|shift| !scores.contains_key(shift))
+            .copied()
+            .collect::<Vec<_>>();
+        let results = missing
+            .par_iter()
+            .map(|shift| test_cross_3d(ref_img, mov_img, *shift, 0.01).0)
+            .collect::<Vec<_>>();
+        scores.extend(missing.into_iter().zip(results));
+        shifts.iter().map(|shift| scores[shift]).collect()
+    };
+
+    let steps = [
+        (dimension_mask.0, (1, 0, 0)),
+        (dimension_mask.1, (0, 1, 0)),
+        (dimension_mask.2, (0, 0, 1)),
+    ]
+    .iter()
+    .filter(|(unmasked, _)| *unmasked)
+    .flat_map(|(_, (x, y, z))| [(*x, *y, *z), (-x, -y, -z)])
+    .collect::<Vec<_>>();
+    let within = |shift: &(i64, i64, i64)| {
+        (shift.0 - start.0).unsigned_abs() as usize <= radius.0
+            && (shift.1 - start.1).unsigned_abs() as usize <= radius.1
+            && (shift.2 - start.2).unsigned_abs() as usize <= radius.2
+    };
+
+    let mut best = start;
+    let mut best_score = score(vec![start])[0];
+    loop {
+        let neighbours = steps
+            .iter()
+            .map(|step| (best.0 + step.0, best.1 + step.1, best.2 + step.2))
+            .filter(within)
+            .collect::<Vec<_>>();
+        let next = neighbours
+            .iter()
+            .zip(score(neighbours.clone()))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        match next {
+            Some((&shift, value)) if value > best_score => (best, best_score) = (shift, value),
+            _ => break,
+        }
+    }
+
+    let mut vertex = |unmasked: bool, step: (i64, i64, i64)| {
+        if !unmasked {
+            return 0.0;
+        }
+        let around = score(vec![
+            (best.0 - step.0, best.1 - step.1, best.2 - step.2),
+            (best.0 + step.0, best.1 + step.1, best.2 + step.2),
+        ]);
+        parabola_vertex(around[0], best_score, around[1])
+    };
+    let subpixel = (
+        vertex(dimension_mask.0, (1, 0, 0)),
+        vertex(dimension_mask.1, (0, 1, 0)),
+        vertex(dimension_mask.2, (0, 0, 1)),
+    );
+
+    ((best.0, best.1, best.2, best_score), subpixel)
+}
+
+/**
+ * Refine shifts found on overlaps downsampled by `factors` at full resolution. Each coarse shift
+ * is scaled up and climbed to its full resolution maximum, which is scored by cross-correlation
+ * and weighted by the prior when `prior_sigmas` are given, so that a coarse peak scored too high
+ * by the downsampling, such as the wrap-around alias of the shift, does not hide the right one.
+ * Returns the refined shifts sorted by score and the sub-pixel position of the best one.
+ */
+fn refine_coarse_peaks_3d(
+    ref_img: &Image3D,
+    mov_img: &Image3D,
+    coarse_peaks: &[(i64, i64, i64, f32)],
+    factors: (usize, usize, usize),
+    dimension_mask: (bool, bool, bool),
+    prior_sigmas: Option<(f32, f32, f32)>,
+) -> (Vec<(i64, i64, i64, f32)>, (f32, f32, f32)) {
+    let mut refined: Vec<((i64, i64, i64, f32), (f32, f32, f32))> = vec![];
+    for peak in coarse_peaks {
+        let shift = (peak.0 * factors.0 as i64, peak.1 * factors.1 as i64, peak.2 * factors.2 as i64);
+        let (mut peak, subpixel) = refine_shift_3d(ref_img, mov_img, shift, factors, dimension_mask);
+        if refined.iter().any(|(other, _)| (other.0, other.1, other.2) == (peak.0, peak.1, peak.2)) {
+            continue;
+        }
+        if let Some(sigmas) = prior_sigmas {
+            peak.3 *= guassian_3d(
+                peak.0 as f32,
+                peak.1 as f32,
+                peak.2 as f32,
+                0.0,
+                0.0,
+                0.0,
+                sigmas.0,
+                sigmas.1,
+                sigmas.2,
+            );
+        }
+        refined.push((peak, subpixel));
+    }
+
+    refined.sort_by(|a, b| b.0.3.total_cmp(&a.0.3));
+    let subpixel = refined.first().map_or((0.0, 0.0, 0.0), |(_, subpixel)| *subpixel);
+    (refined.into_iter().map(|(peak, _)| peak).collect(), subpixel)
+}
+
+/**
+ * Sub-pixel position of the correlation peak at `shift`, relative to it, from a parabola through
+ * the peak and its neighbours along each axis. Positions wrap around the correlation image.
+ */
+fn subpixel_peak_3d(image: &Image3D, shift: (i64, i64, i64)) -> (f32, f32, f32) {
+    let (width, height, depth) = (image.width as i64, image.height as i64, image.depth as i64);
+    let value = |x: i64, y: i64, z: i64| {
+        image.data[((z.rem_euclid(depth) * height + y.rem_euclid(height)) * width + x.rem_euclid(width)) as usize]
+    };
+    let (x, y, z) = shift;
+    (
+        parabola_vertex(value(x - 1, y, z), value(x, y, z), value(x + 1, y, z)),
+        parabola_vertex(value(x, y - 1, z), value(x, y, z), value(x, y + 1, z)),
+        parabola_vertex(value(x, y, z - 1), value(x, y, z), value(x, y, z + 1)),
+    )
 }
 
 fn disambiguate_3d(
@@ -1515,8 +1939,8 @@ fn test_cross_3d(
         let plane1 = (z - offset_img1_z) * w1 * h1;
         let plane2 = (z - offset_img2_z) * w2 * h2;
         for y in start_y..end_y {
-            let start_x1 = plane1 + (y - offset_img1_y) * w1;
-            let start_x2 = plane2 + (y - offset_img2_y) * w2;
+            let start_x1 = plane1 + (y - offset_img1_y) * w1 + start_x - offset_img1_x;
+            let start_x2 = plane2 + (y - offset_img2_y) * w2 + start_x - offset_img2_x;
             let w = end_x - start_x;
             let slice1 = &img1.data[start_x1..start_x1 + w];
             let slice2 = &img2.data[start_x2..start_x2 + w];
@@ -1555,5 +1979,61 @@ fn test_cross_3d(
 
     let r = covar / (std1 * std2);
 
-    return (r as f32, ssq as f32, count);
+    (r as f32, ssq as f32, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** Overlap of `size` cut at `origin` from a fixed noise texture */
+    fn noise_crop(origin: (usize, usize, usize), size: (usize, usize, usize)) -> Image3D {
+        let mut image = Image3D::new(size.0, size.1, size.2, 0.0, 1.0);
+        for z in 0..size.2 {
+            for y in 0..size.1 {
+                for x in 0..size.0 {
+                    let seed = ((x + origin.0) * 73856093) ^ ((y + origin.1) * 19349663) ^ ((z + origin.2) * 83492791);
+                    let hash = (seed as u64).wrapping_mul(6364136223846793005).rotate_right(29);
+                    image.data[(z * size.1 + y) * size.0 + x] = (hash % 1000) as f32 / 1000.0;
+                }
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn subpixel_peak_3d_is_recovered_and_clamped() {
+        let mut image = Image3D::new(6, 5, 4, 0.0, 1.0);
+        let peak = (2.2, 2.65, 1.1);
+        for z in 0..4 {
+            for y in 0..5 {
+                for x in 0..6 {
+                    let (dx, dy, dz) = (x as f32 - peak.0, y as f32 - peak.1, z as f32 - peak.2);
+                    image.data[(z * 5 + y) * 6 + x] = 10.0 - dx * dx - dy * dy - dz * dz;
+                }
+            }
+        }
+
+        let (x, y, z) = subpixel_peak_3d(&image, (2, 3, 1));
+        assert!((x - 0.2).abs() < 1e-5 && (y + 0.35).abs() < 1e-5 && (z - 0.1).abs() < 1e-5);
+        assert_eq!(subpixel_peak_3d(&image, (1, 3, 1)).0, 0.5);
+    }
+
+    #[test]
+    fn refines_every_coarse_peak_and_keeps_the_best() {
+        let size = (24, 16, 8);
+        let ref_img = noise_crop((0, 0, 0), size);
+        let mov_img = noise_crop((6, 4, 0), size);
+
+        // The coarse maximum is the wrap-around alias of the shift on the 12 voxel wide grid
+        let coarse_peaks = [(-9, 2, 0, 0.9), (3, 2, 0, 0.5)];
+        let (peaks, subpixel) =
+            refine_coarse_peaks_3d(&ref_img, &mov_img, &coarse_peaks, (2, 2, 1), (true, true, true), None);
+
+        assert_eq!(peaks.len(), 2);
+        assert_eq!((peaks[0].0, peaks[0].1, peaks[0].2), (6, 4, 0));
+        assert!((peaks[0].3 - 1.0).abs() < 1e-4);
+        assert!(peaks[1].3 < 0.5);
+        assert!(subpixel.0.abs() <= 0.5 && subpixel.1.abs() <= 0.5 && subpixel.2.abs() <= 0.5);
+    }
 }
@@ -0,0 +1,282 @@
+use image::ImageReader;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::config::{StitchConfig, StitchMode};
+use crate::error::{Result, StitchError};
+use crate::image::{is_dcm, read_dcm_headers, read_tiff_headers};
+use crate::stitch3d::IBox3D;
+
+/**
+ * Check whether a file looks like an ImageJ/Fiji TileConfiguration file rather than a JSON config
+ */
+pub fn is_tile_configuration(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "txt")
+}
+
+/**
+ * Read a TileConfiguration.txt (or .registered.txt) written by the Grid/Collection stitching plugin.
+ *
+ * Positions in these files already include the overlap, so the overlap ratio is set to zero and
+ * tile sizes are read from the image headers. Other parameters keep their defaults.
+ */
+pub fn read_tile_configuration(path: &Path) -> Result<StitchConfig> {
+    let base_path = path
+        .parent()
+        .ok_or_else(|| StitchError::config("path", "Invalid base path"))?;
+    let text = std::fs::read_to_string(path).map_err(|e| StitchError::io(path, e))?;
+    let (dim, positions) = parse_tile_configuration(path, &text)?;
+
+    let mut config = StitchConfig::new();
+    config.mode = if dim == 2 { StitchMode::TwoD } else { StitchMode::ThreeD };
+    config.overlap_ratio = (0.0, 0.0, 0.0);
+    config.output_path = base_path.join("output");
+
+    let sizes = positions
+        .par_iter()
+        .map(|(tile_path, _)| read_tile_size(&base_path.join(tile_path), config.mode))
+        .collect::<Result<Vec<_>>>()?;
+
+    for ((tile_path, coordinates), (width, height, depth)) in positions.into_iter().zip(sizes) {
+        config.tile_layout.push(IBox3D::new(
+            coordinates[0].round() as i64,
+            coordinates[1].round() as i64,
+            coordinates.get(2).map_or(0, |z| z.round() as i64),
+            width as i64,
+            height as i64,
+            depth as i64,
+        ));
+        config.tile_paths.push(base_path.join(tile_path));
+    }
+
+    Ok(config)
+}
+
+/**
+ * Dimension and tiles of the TileConfiguration text read from `path`: every tile file as written
+ * in the text, with its 2 or 3 coordinates
+ */
+fn parse_tile_configuration(path: &Path, text: &str) -> Result<(usize, Vec<(PathBuf, Vec<f64>)>)> {
+    let mut dimensions = None;
+    let mut positions = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line_error = |message: String| StitchError::format(path, format!("line {}: {}", i + 1, message));
+
+        if let Some(value) = line.strip_prefix("dim").and_then(|rest| rest.trim_start().strip_prefix('=')) {
+            dimensions = match value.trim() {
+                "2" => Some(2),
+                "3" => Some(3),
+                other => return Err(line_error(format!("Invalid dimension \"{}\", expected 2 or 3", other))),
+            };
+            continue;
+        }
+
+        // ImageJ writes this for files holding several series, which are read as one image here
+        if let Some(value) = line.strip_prefix("multiseries").and_then(|rest| rest.trim_start().strip_prefix('=')) {
+            if value.trim() != "false" {
+                return Err(line_error("Multi-series tile files are not supported, list one file per tile".into()));
+            }
+            continue;
+        }
+
+        let dim = dimensions.ok_or_else(|| line_error("Expected \"dim = 2\" or \"dim = 3\" before tiles".into()))?;
+
+        let fields = line.split(';').map(|field| field.trim()).collect::<Vec<_>>();
+        if fields.len() != 3 {
+            return Err(line_error("Expected \"file; ; (x, y[, z])\"".into()));
+        }
+        if !fields[1].is_empty() {
+            return Err(line_error(format!(
+                "Series index \"{}\" is not supported, list one file per tile",
+                fields[1]
+            )));
+        }
+
+        let coordinates = fields[2]
+            .strip_prefix('(')
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or_else(|| line_error(format!("Expected coordinates in parentheses, got \"{}\"", fields[2])))?
+            .split(',')
+            .map(|value| {
+                value
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| line_error(format!("Invalid coordinate \"{}\"", value.trim())))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if coordinates.len() != dim {
+            return Err(line_error(format!(
+                "Expected {} coordinates but got {}",
+                dim,
+                coordinates.len()
+            )));
+        }
+
+        positions.push((PathBuf::from(fields[0]), coordinates));
+    }
+
+    let dim = dimensions.ok_or_else(|| StitchError::format(path, "Missing \"dim = 2\" or \"dim = 3\""))?;
+    if positions.is_empty() {
+        return Err(StitchError::format(path, "No tiles listed"));
+    }
+    Ok((dim, positions))
+}
+
+/**
+ * Size of a tile in pixels, read from the image headers
+ */
+pub(crate) fn read_tile_size(path: &Path, mode: StitchMode) -> Result<(usize, usize, usize)> {
+    let image = match mode {
+        StitchMode::TwoD => {
+            let (width, height) = ImageReader::open(path)
+                .map_err(|e| StitchError::io(path, e))?
+                .into_dimensions()
+                .map_err(|e| StitchError::format(path, e))?;
+            return Ok((width as usize, height as usize, 1));
+        }
+        StitchMode::ThreeD if is_dcm(path) => read_dcm_headers(path)?,
+        StitchMode::ThreeD => read_tiff_headers(path)?,
+    };
+
+    Ok((image.width, image.height, image.depth))
+}
+
+/**
+ * Write registered tile positions of one subgraph in the TileConfiguration format read by Fiji.
+ * Tile names are written relative to the directory of the output file.
+ */
+pub fn write_tile_configuration(
+    path: &Path,
+    tile_paths: &[PathBuf],
+    subgraph: &[usize],
+    offsets: &[(f32, f32, f32)],
+    mode: StitchMode,
+) -> Result<()> {
+    let base_path = path.parent().unwrap_or(Path::new(""));
+    let dim = if mode == StitchMode::TwoD { 2 } else { 3 };
+
+    let mut text = String::new();
+    text.push_str("# Define the number of dimensions we are working on\n");
+    text.push_str(&format!("dim = {}\n\n", dim));
+    text.push_str("# Define the image coordinates\n");
+    for (&tile, offset) in subgraph.iter().zip(offsets) {
+        let name = relative_path(&tile_paths[tile], base_path)?;
+        if dim == 2 {
+            text.push_str(&format!("{}; ; ({}, {})\n", name.display(), offset.0, offset.1));
+        } else {
+            text.push_str(&format!(
+                "{}; ; ({}, {}, {})\n",
+                name.display(),
+                offset.0,
+                offset.1,
+                offset.2
+            ));
+        }
+    }
+
+    std::fs::write(path, text).map_err(|e| StitchError::io(path, e))
+}
+
+/**
+ * Express `path` relative to the directory `base`, falling back to `..` components where needed
+ */
+pub(crate) fn relative_path(path: &Path, base: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path).map_err(|e| StitchError::io(path, e))?;
+    let base = std::path::absolute(base).map_err(|e| StitchError::io(base, e))?;
+
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    for component in path.components().skip(common) {
+        relative.push(component);
+    }
+
+    Ok(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<(usize, Vec<(PathBuf, Vec<f64>)>)> {
+        parse_tile_configuration(Path::new("TileConfiguration.txt"), text)
+    }
+
+    fn format_message(result: Result<(usize, Vec<(PathBuf, Vec<f64>)>)>) -> String {
+        match result {
+            Err(StitchError::Format { message, .. }) => message,
+            other => panic!("Expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_fiji_output() {
+        let text = "# Define the number of dimensions we are working on\ndim = 3\n\n\
+                    # Define the image coordinates\n\
+                    tile_0.tif; ; (0.0, 0.0, 0.0)\n\
+                    tile_1.tif; ; (921.6, -3.2, 1.5)\n";
+        let (dim, positions) = parse(text).unwrap();
+        assert_eq!(dim, 3);
+        assert_eq!(positions.len(), 2);
+        assert_eq!(positions[1].0, PathBuf::from("tile_1.tif"));
+        assert_eq!(positions[1].1, vec![921.6, -3.2, 1.5]);
+    }
+
+    #[test]
+    fn accepts_compact_dim_and_single_series() {
+        let (dim, positions) = parse("dim=2\nmultiseries = false\n  a.png ;; ( 1 , 2 )  \n").unwrap();
+        assert_eq!(dim, 2);
+        assert_eq!(positions, vec![(PathBuf::from("a.png"), vec![1.0, 2.0])]);
+    }
+
+    #[test]
+    fn rejects_multiseries_files() {
+        let error = parse("dim = 2\nmultiseries = true\na.lif; ; (0, 0)\n").unwrap_err().to_string();
+        assert!(error.contains("line 2") && error.contains("Multi-series"), "{}", error);
+
+        let error = parse("dim = 2\na.lif; 3; (0, 0)\n").unwrap_err().to_string();
+        assert!(error.contains("line 2") && error.contains("Series index \"3\""), "{}", error);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let cases = [
+            ("dim = 4\n", "line 1: Invalid dimension \"4\", expected 2 or 3"),
+            ("a.png; ; (1, 2)\n", "line 1: Expected \"dim = 2\" or \"dim = 3\" before tiles"),
+            ("dim = 2\na.png; (1, 2)\n", "line 2: Expected \"file; ; (x, y[, z])\""),
+            ("dim = 2\na.png; ; 1, 2\n", "line 2: Expected coordinates in parentheses, got \"1, 2\""),
+            ("dim = 2\na.png; ; (1, x)\n", "line 2: Invalid coordinate \"x\""),
+            ("dim = 3\n\n# comment\na.tif; ; (1, 2)\n", "line 4: Expected 3 coordinates but got 2"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(format_message(parse(text)), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_missing_dim_or_tiles() {
+        assert_eq!(format_message(parse("# empty\n")), "Missing \"dim = 2\" or \"dim = 3\"");
+        assert_eq!(format_message(parse("dim = 2\n")), "No tiles listed");
+    }
+
+    #[test]
+    fn relative_paths_climb_out_of_the_base() {
+        let relative = relative_path(Path::new("/data/tiles/a.tif"), Path::new("/data/output")).unwrap();
+        assert_eq!(relative, PathBuf::from("../tiles/a.tif"));
+        let relative = relative_path(Path::new("/data/output/a.tif"), Path::new("/data/output")).unwrap();
+        assert_eq!(relative, PathBuf::from("a.tif"));
+    }
+}
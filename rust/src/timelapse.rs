@@ -0,0 +1,324 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::config::StitchConfig;
+use crate::error::{Result, StitchError};
+use crate::image::{Image2D, Image3DFile};
+use crate::pair_cache::PairCache;
+use crate::stitch2d::{self, IBox2D};
+use crate::stitch3d::{self, IBox3D};
+
+/**
+ * `timepoints` section of the config: the same tiles acquired repeatedly, with `{t}` in every
+ * tile path standing for the timepoint
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimepointsSection {
+    /// Number of timepoints.
+    pub count: usize,
+    /// Value of `{t}` for the first timepoint.
+    #[serde(default)]
+    pub first_index: usize,
+    /// Timepoint whose tiles are registered, counted from 0.
+    #[serde(default)]
+    pub reference: usize,
+    /// Also register every Nth timepoint instead of reusing the reference alignment.
+    pub register_every: Option<usize>,
+    /// Register every timepoint to the one it takes its alignment from and shift it accordingly.
+    #[serde(default)]
+    pub correct_drift: bool,
+}
+
+/**
+ * Tile files of every timepoint and how alignments are shared between them
+ */
+#[derive(Clone, Debug)]
+pub struct Timelapse {
+    /// Tile paths indexed as `tile_paths[timepoint][tile]`.
+    pub tile_paths: Vec<Vec<PathBuf>>,
+    pub reference: usize,
+    pub register_every: Option<usize>,
+    pub correct_drift: bool,
+}
+
+impl TimepointsSection {
+    /**
+     * Substitute every timepoint into the tile paths
+     */
+    pub fn expand(&self, tile_paths: &[PathBuf]) -> Result<Timelapse> {
+        if self.count == 0 {
+            return Err(StitchError::config("timepoints.count", "At least one timepoint is required"));
+        }
+        if self.reference >= self.count {
+            return Err(StitchError::config(
+                "timepoints.reference",
+                format!("Reference timepoint {} is out of range for {} timepoints", self.reference, self.count),
+            ));
+        }
+        if self.register_every == Some(0) {
+            return Err(StitchError::config("timepoints.register_every", "Must be at least 1"));
+        }
+        if let Some(path) = tile_paths.iter().find(|path| !path.to_string_lossy().contains("{t")) {
+            return Err(StitchError::config(
+                "timepoints",
+                format!("Tile path {:?} has no \"{{t}}\" placeholder for the timepoint", path),
+            ));
+        }
+
+        let tile_paths = (0..self.count)
+            .map(|t| {
+                tile_paths
+                    .iter()
+                    .map(|path| timepoint_path(path, self.first_index + t))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Timelapse {
+            tile_paths,
+            reference: self.reference,
+            register_every: self.register_every,
+            correct_drift: self.correct_drift,
+        })
+    }
+}
+
+impl Timelapse {
+    pub fn count(&self) -> usize {
+        self.tile_paths.len()
+    }
+
+    /**
+     * Timepoints that get their own registration, reference first
+     */
+    pub fn registered_timepoints(&self) -> Vec<usize> {
+        let mut timepoints = vec![self.reference];
+        if let Some(every) = self.register_every {
+            timepoints.extend(
+                (0..self.count()).filter(|&t| t != self.reference && t.abs_diff(self.reference) % every == 0),
+            );
+        }
+        timepoints
+    }
+
+    /**
+     * Registered timepoint whose alignment is used for timepoint `t`: the closest one before it,
+     * or the reference for timepoints before every registered one
+     */
+    pub fn alignment_source(&self, t: usize) -> usize {
+        self.registered_timepoints()
+            .into_iter()
+            .filter(|&registered| registered <= t)
+            .max()
+            .unwrap_or(self.reference)
+    }
+}
+
+/**
+ * Replace `{t}` or a zero padded `{t:03}` in a path with the timepoint
+ */
+fn timepoint_path(path: &Path, t: usize) -> Result<PathBuf> {
+    let path = path.to_string_lossy();
+    let mut name = String::new();
+    let mut rest = path.as_ref();
+    while let Some(open) = rest.find("{t") {
+        name.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| StitchError::config("timepoints", "Unclosed \"{t\" in tile path"))?
+            + open;
+        let width = match &rest[open + 2..close] {
+            "" => 0,
+            spec => spec
+                .strip_prefix(':')
+                .and_then(|width| width.parse::<usize>().ok())
+                .ok_or_else(|| {
+                    StitchError::config("timepoints", format!("Invalid placeholder \"{}\"", &rest[open..=close]))
+                })?,
+        };
+        name.push_str(&format!("{:0width$}", t, width = width));
+        rest = &rest[close + 1..];
+    }
+    name.push_str(rest);
+
+    Ok(PathBuf::from(name))
+}
+
+/**
+ * Shift of a 2D timepoint relative to another, as the median over tiles of the registered offset
+ * between the same tile at both timepoints. Returns `None` if no tile could be registered.
+ */
+pub fn measure_drift_2d(config: &StitchConfig, reference: &[Image2D], moving: &[Image2D]) -> Result<Option<(f32, f32)>> {
+    let pair_cache = config.pair_cache.as_deref().map(PairCache::open).transpose()?;
+    let mut drifts = vec![];
+    for (reference, moving) in reference.iter().zip(moving) {
+        let layout = IBox2D::new(0, 0, reference.width as i64, reference.height as i64);
+        let result = stitch2d::stitch(
+            &[reference.clone(), moving.clone()],
+            &[layout, layout],
+            (0.0, 0.0),
+            config.check_peaks,
+            config.correlation_threshold,
+            config.relative_error_threshold,
+            config.absolute_error_threshold,
+            (config.dimension_mask.0, config.dimension_mask.1),
+            true,
+            false,
+            (config.prior_sigmas.0, config.prior_sigmas.1),
+            false,
+            config.subpixel_accuracy,
+            &config.preprocessing,
+            pair_cache.as_ref(),
+        )?;
+
+        if let Some((reference, moving)) = pair_offsets(&result.subgraphs, &result.offsets) {
+            drifts.push((moving.0 - reference.0, moving.1 - reference.1, 0.0));
+        }
+    }
+
+    Ok(median_drift(drifts).map(|drift| (drift.0, drift.1)))
+}
+
+/**
+ * Shift of a 3D timepoint relative to another, see [`measure_drift_2d`]
+ */
+pub fn measure_drift_3d(
+    config: &StitchConfig,
+    reference: &[Image3DFile],
+    moving: &[Image3DFile],
+) -> Result<Option<(f32, f32, f32)>> {
+    let pair_cache = config.pair_cache.as_deref().map(PairCache::open).transpose()?;
+    let mut drifts = vec![];
+    for (reference, moving) in reference.iter().zip(moving) {
+        let layout = IBox3D::from_image_file(reference);
+        let result = stitch3d::stitch(
+            &[reference.clone(), moving.clone()],
+            &[layout, layout],
+            (0.0, 0.0, 0.0),
+            config.check_peaks,
+            config.correlation_threshold,
+            config.relative_error_threshold,
+            config.absolute_error_threshold,
+            config.dimension_mask,
+            true,
+            false,
+            config.prior_sigmas,
+            false,
+            config.subpixel_accuracy,
+            &config.preprocessing,
+            config.registration_downsampling,
+            config.registration_memory_budget,
+            pair_cache.as_ref(),
+        )?;
+
+        if let Some((reference, moving)) = pair_offsets(&result.subgraphs, &result.offsets) {
+            drifts.push((moving.0 - reference.0, moving.1 - reference.1, moving.2 - reference.2));
+        }
+    }
+
+    Ok(median_drift(drifts))
+}
+
+/**
+ * Offsets of tiles 0 and 1 of a two tile registration, if they ended up in the same subgraph
+ */
+fn pair_offsets<T: Copy>(subgraphs: &[Vec<usize>], offsets: &[Vec<T>]) -> Option<(T, T)> {
+    subgraphs.iter().zip(offsets).find_map(|(subgraph, offsets)| {
+        let reference = subgraph.iter().position(|&tile| tile == 0)?;
+        let moving = subgraph.iter().position(|&tile| tile == 1)?;
+        Some((offsets[reference], offsets[moving]))
+    })
+}
+
+fn median_drift(drifts: Vec<(f32, f32, f32)>) -> Option<(f32, f32, f32)> {
+    if drifts.is_empty() {
+        return None;
+    }
+
+    let median = |mut values: Vec<f32>| {
+        values.sort_by(|a, b| a.total_cmp(b));
+        values[values.len() / 2]
+    };
+    Some((
+        median(drifts.iter().map(|drift| drift.0).collect()),
+        median(drifts.iter().map(|drift| drift.1).collect()),
+        median(drifts.iter().map(|drift| drift.2).collect()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(json: &str) -> TimepointsSection {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    fn config_error(section: &TimepointsSection, tile_paths: &[&str]) -> (String, String) {
+        match section.expand(&paths(tile_paths)) {
+            Err(StitchError::Config { key, message, .. }) => (key, message),
+            other => panic!("Expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn substitutes_padded_timepoints_from_first_index() {
+        let timelapse = section(r#"{"count": 2, "first_index": 9}"#)
+            .expand(&paths(&["t{t}/a_{t:03}.tif", "t{t}/b.tif"]))
+            .unwrap();
+        assert_eq!(timelapse.tile_paths[0], paths(&["t9/a_009.tif", "t9/b.tif"]));
+        assert_eq!(timelapse.tile_paths[1], paths(&["t10/a_010.tif", "t10/b.tif"]));
+    }
+
+    #[test]
+    fn rejects_invalid_sections() {
+        let cases = [
+            (r#"{"count": 0}"#, "timepoints.count"),
+            (r#"{"count": 2, "reference": 2}"#, "timepoints.reference"),
+            (r#"{"count": 2, "register_every": 0}"#, "timepoints.register_every"),
+        ];
+        for (json, expected) in cases {
+            assert_eq!(config_error(&section(json), &["{t}.tif"]).0, expected, "{}", json);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_placeholders() {
+        let timepoints = section(r#"{"count": 1}"#);
+        let cases = [
+            ("a.tif", "Tile path \"a.tif\" has no \"{t}\" placeholder for the timepoint"),
+            ("a_{t.tif", "Unclosed \"{t\" in tile path"),
+            ("a_{t:x}.tif", "Invalid placeholder \"{t:x}\""),
+            ("a_{tile}.tif", "Invalid placeholder \"{tile}\""),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(config_error(&timepoints, &["{t}.tif", path]), ("timepoints".to_string(), expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn alignments_come_from_the_closest_earlier_registration() {
+        let timelapse = section(r#"{"count": 7, "reference": 3, "register_every": 2}"#)
+            .expand(&paths(&["{t}.tif"]))
+            .unwrap();
+        assert_eq!(timelapse.registered_timepoints(), [3, 1, 5]);
+        let sources = (0..7).map(|t| timelapse.alignment_source(t)).collect::<Vec<_>>();
+        assert_eq!(sources, [3, 1, 1, 3, 3, 5, 5]);
+
+        let timelapse = section(r#"{"count": 3, "reference": 1}"#).expand(&paths(&["{t}.tif"])).unwrap();
+        assert_eq!(timelapse.registered_timepoints(), [1]);
+        assert_eq!((0..3).map(|t| timelapse.alignment_source(t)).collect::<Vec<_>>(), [1, 1, 1]);
+    }
+
+    #[test]
+    fn drift_is_the_median_of_every_axis() {
+        assert_eq!(median_drift(vec![]), None);
+        let drifts = vec![(1.0, -5.0, 0.0), (9.0, 2.0, 0.5), (2.0, 1.0, -0.5)];
+        assert_eq!(median_drift(drifts), Some((2.0, 1.0, 0.0)));
+    }
+}